@@ -69,10 +69,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
     let mut logits = vec![0.0f32; 65536];
     let no_cache_config = kbnf::config::Config {
-        engine_config: EngineConfig {
-            cache_enabled: false,
-            compaction_enabled: true,
-        },
+        engine_config: EngineConfig::new(false, true),
         ..Default::default()
     };
     let mut engine = Engine::with_config(
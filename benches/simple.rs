@@ -73,10 +73,7 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| run_an_engine(black_box(&mut engine), 100, 124, &mut logits))
     });
     let no_cache_config = kbnf::config::Config {
-        engine_config: EngineConfig {
-            cache_enabled: false,
-            compaction_enabled: true,
-        },
+        engine_config: EngineConfig::new(false, true),
         ..Default::default()
     };
     let mut engine = Engine::with_config(
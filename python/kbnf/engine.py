@@ -42,15 +42,24 @@ def _torch_slice_converter(module:types.ModuleType):
 
 def _torch_fast_mask_logits(module:types.ModuleType):
     ninf = -math.inf
+    def add_bonuses(tensor:typing.Any, engine:"Engine")->typing.Any:
+        # The bonuses depend on the Earley items rather than the allowed token IDs, so they are not cached.
+        bonuses = engine.get_token_bonuses_from_last_computation()
+        if bonuses:
+            token_ids, values = zip(*bonuses)
+            token_ids = module.tensor(token_ids, device=tensor.device, dtype=module.int64)
+            values = module.tensor(values, device=tensor.device, dtype=tensor.dtype)
+            tensor.index_add_(0, token_ids, values)
+        return tensor
     def mask_logits_fast(tensor:typing.Any, engine:"Engine")->typing.Optional[typing.Any]:
         if isinstance(tensor, module.Tensor):
             assert tensor.dim() == 1, f"Only 1D tensor is supported, while the actual tensor shape is {tensor.shape}"
             index = engine.get_index_of_allowed_token_ids()
             num_of_disallowed = engine.get_number_of_disallowed_token_ids()
+            if num_of_disallowed == 0: # Rust FFI requires non-null pointer
+                return add_bonuses(tensor, engine)
             if index not in engine._cache:
                 length = num_of_disallowed
-                if length == 0: # Rust FFI requires non-null pointer
-                    return tensor
                 pinned = tensor.is_cuda # only pin if the logits is on CUDA, which implies the user is using CUDA for its LLM
                 disallowed = module.empty((length,), device="cpu",dtype=module.int64, pin_memory=pinned)
                 data_ptr = disallowed.data_ptr()
@@ -64,14 +73,19 @@ def _torch_fast_mask_logits(module:types.ModuleType):
                 engine._cache[index] = (disallowed, allowed)
             else:
                 disallowed, allowed = engine._cache[index]
+            penalty = engine.get_disallowed_token_penalty()
+            if penalty is not None: # the disallowed tokens are discouraged rather than forbidden
+                disallowed = disallowed.to(device=tensor.device,non_blocking=True)
+                tensor.index_copy_(0,disallowed,tensor.index_select(0,disallowed)+penalty)
+                return add_bonuses(tensor, engine)
             if num_of_disallowed>tensor.shape[-1]/2: # we have more disallowed than allowed
                 new_tensor = module.full_like(tensor,fill_value=ninf)
                 allowed = allowed.to(device=tensor.device,non_blocking=True)
                 new_tensor.put_(allowed, tensor.take(allowed))
-                return new_tensor
+                return add_bonuses(new_tensor, engine)
             else: # we have more allowed than disallowed
                 tensor.index_fill_(0,disallowed.to(device=tensor.device,non_blocking=True),ninf)
-                return tensor
+                return add_bonuses(tensor, engine)
         return None
     return mask_logits_fast
 
@@ -165,6 +179,8 @@ The logits will be updated in-place if:
 a tuple (logits, result). The logits is the same object as the input logits if the input logits is updated in-place.
 Otherwise, a new object with the same type as the input logits is returned. 
 The `result` is the result of accepting the token ID.
+Once the token is accepted, the failure of computing the allowed token IDs, e.g. a dead end,
+is not raised and can be obtained from `get_allowed_token_ids_error_from_last_computation`.
 
 # Exceptions
 
@@ -0,0 +1,79 @@
+import math
+
+import numpy as np
+import pytest
+
+import kbnf
+
+TOKENS = ["a", "b", "c", ";"]
+
+
+def make_engine(grammar: str, config=None) -> kbnf.Engine:
+    vocab = kbnf.Vocabulary(
+        {i: kbnf.Token(token.encode()) for i, token in enumerate(TOKENS)},
+        {i: token for i, token in enumerate(TOKENS)},
+    )
+    engine = kbnf.Engine(grammar, vocab, config)
+    engine.compute_allowed_token_ids()
+    return engine
+
+
+def mask_with_rust(engine: kbnf.Engine) -> np.ndarray:
+    # numpy arrays are always masked by the Rust implementation.
+    return engine.mask_logits(np.zeros(len(TOKENS), dtype=np.float32))
+
+
+def test_numpy_mask_logits_adds_bonuses():
+    engine = make_engine("start::=x ';' | 'b';x::='a';bonus x = 2;")
+    logits = mask_with_rust(engine)
+    assert logits[TOKENS.index("a")] == pytest.approx(2.0)
+    assert logits[TOKENS.index("b")] == 0.0
+    assert logits[TOKENS.index("c")] == -math.inf
+
+
+@pytest.mark.parametrize(
+    "grammar",
+    [
+        # fewer disallowed tokens than allowed ones
+        "start::=x ';' | 'b' | 'c';x::='a';bonus x = 2;",
+        # more disallowed tokens than allowed ones
+        "start::=x ';';x::='a';bonus x = 2;",
+        # no disallowed token
+        "start::=x ';' | 'b' | 'c' | ';';x::='a';bonus x = 2;",
+    ],
+)
+def test_torch_mask_logits_adds_bonuses(grammar: str):
+    torch = pytest.importorskip("torch")
+    engine = make_engine(grammar)
+    expected = mask_with_rust(engine)
+    logits = engine.mask_logits(torch.zeros(len(TOKENS), dtype=torch.float32))
+    assert logits.tolist() == pytest.approx(expected.tolist())
+
+
+def penalty_config(penalty: float) -> kbnf.Config:
+    config = kbnf.Config()
+    engine_config = config.engine_config
+    engine_config.disallowed_token_penalty = penalty
+    config.engine_config = engine_config
+    return config
+
+
+@pytest.mark.parametrize(
+    "grammar",
+    [
+        # fewer disallowed tokens than allowed ones
+        "start::='a' ';' | 'b' | 'c';",
+        # more disallowed tokens than allowed ones
+        "start::='a' ';';",
+        # the weights of the alternatives are bonuses as well
+        "start::='a' ';' @0.8 | 'b' @0.2;",
+    ],
+)
+def test_torch_mask_logits_adds_penalty(grammar: str):
+    torch = pytest.importorskip("torch")
+    engine = make_engine(grammar, penalty_config(-5.0))
+    assert engine.get_disallowed_token_penalty() == -5.0
+    expected = mask_with_rust(engine)
+    assert math.isfinite(expected[TOKENS.index(";")])
+    logits = engine.mask_logits(torch.zeros(len(TOKENS), dtype=torch.float32))
+    assert logits.tolist() == pytest.approx(expected.tolist())
@@ -0,0 +1,284 @@
+//! The beam search module that finds the best token sequences accepted by an engine according to a language model.
+use crate::engine_like::{
+    AcceptTokenError, AcceptTokenResult, ComputeAllowedTokenIdsError, EngineLike,
+};
+
+/// The configuration of a [BeamSearch].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearchConfig {
+    /// The number of unfinished hypotheses kept after each step.
+    /// The default is 4.
+    pub beam_width: usize,
+    /// The maximum number of finished hypotheses returned.
+    /// The default is 4.
+    pub n_best: usize,
+    /// The maximum number of tokens in a hypothesis.
+    /// The hypotheses that do not finish within this number of tokens are discarded.
+    /// The default is 256.
+    pub max_tokens: usize,
+    /// The exponent of the length by which the log-probability of a hypothesis is divided to get its score.
+    /// `0.0` ranks the hypotheses by their log-probabilities, which favors shorter hypotheses,
+    /// while larger values favor longer hypotheses.
+    /// The default is 0.0.
+    pub length_penalty: f32,
+}
+
+impl Default for BeamSearchConfig {
+    fn default() -> Self {
+        Self {
+            beam_width: 4,
+            n_best: 4,
+            max_tokens: 256,
+            length_penalty: 0.0,
+        }
+    }
+}
+
+/// The error type for errors in beam search.
+#[derive(Debug, thiserror::Error, Clone, PartialEq)]
+pub enum BeamSearchError {
+    #[error("The configuration is invalid: {0}")]
+    /// [BeamSearchConfig::beam_width] or [BeamSearchConfig::n_best] is zero,
+    /// or [BeamSearchConfig::length_penalty] is not finite.
+    InvalidConfig(String),
+    #[error(
+        "The logits of {0:?} are of length {1}, which is smaller than the vocabulary size {2}."
+    )]
+    /// The scoring callback returns logits shorter than the vocabulary size.
+    /// Contains the token ids passed to the callback, the length of the logits and the vocabulary size.
+    InvalidLogitsLength(Vec<u32>, usize, usize),
+    #[error("The engine fails to accept the allowed token {1} after accepting {0:?}: {2}")]
+    /// The engine fails to accept a token it allows, which indicates a bug in the engine.
+    /// Contains the token ids accepted before, the failed token id and the error.
+    AcceptTokenError(Vec<u32>, u32, AcceptTokenError),
+    #[error("A lazy DFA cache of the engine is too small to compute the allowed tokens after accepting {0:?}.")]
+    /// A transition of a lazy DFA does not fit in its cache, so the allowed tokens cannot be computed.
+    /// Contains the token ids of the hypothesis.
+    LazyDfaCacheFull(Vec<u32>),
+}
+
+/// A finished hypothesis found by a [BeamSearch].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The token ids of the hypothesis, excluding the tokens accepted by the engine before the search.
+    pub token_ids: Vec<u32>,
+    /// The sum of the log-probabilities of the tokens, including the bonuses of the grammar.
+    pub log_probability: f32,
+    /// The score by which the hypotheses are ranked,
+    /// i.e. the log-probability divided by the length to the power of [BeamSearchConfig::length_penalty].
+    pub score: f32,
+}
+
+/// An unfinished hypothesis along with the engine that has accepted its tokens.
+struct Beam<E> {
+    engine: E,
+    token_ids: Vec<u32>,
+    log_probability: f64,
+}
+
+/// A beam search driver that finds the best token sequences accepted by an [EngineLike] according to a language model.
+///
+/// The engine is cloned for every hypothesis, so each hypothesis keeps its own parsing state.
+/// This is useful for short structured outputs like classification labels or SQL fragments,
+/// where the few best grammar-valid completions are wanted rather than a single sample.
+#[derive(Debug, Clone)]
+pub struct BeamSearch {
+    config: BeamSearchConfig,
+}
+
+impl BeamSearch {
+    /// Create a new [BeamSearch].
+    pub fn new(config: BeamSearchConfig) -> Self {
+        Self { config }
+    }
+
+    /// Search the best finished hypotheses from the current state of the engine.
+    ///
+    /// At each step, every unfinished hypothesis is extended with its [BeamSearchConfig::beam_width] best allowed tokens,
+    /// and the [BeamSearchConfig::beam_width] best extensions are kept.
+    /// The log-probability of a token is its log-softmax over the whole logits plus its bonus from the grammar,
+    /// and the allowed tokens whose logits are not finite are never chosen.
+    /// The search stops early when no unfinished hypothesis can beat the [BeamSearchConfig::n_best] finished ones,
+    /// which is only certain when extending a hypothesis never increases its score,
+    /// i.e. [BeamSearchConfig::length_penalty] is not positive and the grammar has no positive bonuses.
+    /// Otherwise the search runs until [BeamSearchConfig::max_tokens] tokens or until no unfinished hypothesis is left.
+    /// The engine itself is not modified.
+    ///
+    /// # Arguments
+    ///
+    /// * `engine` - The engine that constrains the hypotheses.
+    /// * `score` - The callback that returns the logits of the next token given the token ids of a hypothesis,
+    ///   excluding the tokens accepted by the engine before the search.
+    ///   The logits may be longer than the vocabulary size, in which case the extra logits are ignored.
+    ///
+    /// # Returns
+    ///
+    /// At most [BeamSearchConfig::n_best] finished hypotheses in descending order of scores.
+    /// If the engine is already finished, the only hypothesis is the empty one.
+    /// It is empty if no hypothesis finishes within [BeamSearchConfig::max_tokens] tokens.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [BeamSearchError] if the configuration is invalid, the callback returns logits that are too short
+    /// or a lazy DFA cache of the engine is full.
+    /// Hypotheses that run into a dead end or reach the output limit of the engine are dropped instead.
+    pub fn search<E, F>(&self, engine: &E, mut score: F) -> Result<Vec<Hypothesis>, BeamSearchError>
+    where
+        E: EngineLike + Clone,
+        F: FnMut(&[u32]) -> Vec<f32>,
+    {
+        self.validate_config()?;
+        let config = &self.config;
+        let vocab_size = engine.vocab().vocab_size();
+        let mut finished = Vec::new();
+        if engine.is_finished() {
+            finished.push(self.hypothesis(Vec::new(), 0.0));
+            return Ok(finished);
+        }
+        let mut beams = vec![Beam {
+            engine: engine.clone(),
+            token_ids: Vec::new(),
+            log_probability: 0.0,
+        }];
+        // Extending a hypothesis may increase its score under a positive length penalty or bonus.
+        let may_stop_early = config.length_penalty <= 0.0 && !engine.has_positive_bonuses();
+        // The extensions of the beams, as the log-probabilities, the beam indices and the token ids.
+        let mut extensions: Vec<(f64, usize, u32)> = Vec::new();
+        for _ in 0..config.max_tokens {
+            extensions.clear();
+            for (index, beam) in beams.iter_mut().enumerate() {
+                match beam.engine.try_compute_allowed_token_ids() {
+                    Ok(()) => {}
+                    // The hypothesis can never finish, so it is dropped.
+                    Err(
+                        ComputeAllowedTokenIdsError::DeadEnd
+                        | ComputeAllowedTokenIdsError::OutputLimitReached,
+                    ) => continue,
+                    Err(ComputeAllowedTokenIdsError::LazyDfaCacheFull) => {
+                        return Err(BeamSearchError::LazyDfaCacheFull(beam.token_ids.clone()));
+                    }
+                }
+                let logits = score(&beam.token_ids);
+                if logits.len() < vocab_size {
+                    return Err(BeamSearchError::InvalidLogitsLength(
+                        beam.token_ids.clone(),
+                        logits.len(),
+                        vocab_size,
+                    ));
+                }
+                let normalizer = log_sum_exp(&logits[..vocab_size]);
+                let start = extensions.len();
+                let mut bonuses = beam
+                    .engine
+                    .token_bonuses_from_last_computation()
+                    .iter()
+                    .peekable();
+                for token_id in beam.engine.allowed_token_ids_from_last_computation().ones() {
+                    let mut logit = logits[token_id] as f64;
+                    if let Some(&(_, bonus)) = bonuses.next_if(|(x, _)| *x == token_id) {
+                        logit += bonus as f64;
+                    }
+                    if logit.is_finite() {
+                        extensions.push((
+                            beam.log_probability + logit - normalizer,
+                            index,
+                            token_id as u32,
+                        ));
+                    }
+                }
+                keep_best(&mut extensions, start, config.beam_width);
+            }
+            keep_best(&mut extensions, 0, config.beam_width);
+            extensions.sort_unstable_by(descending);
+            let mut next_beams = Vec::with_capacity(extensions.len());
+            for &(log_probability, index, token_id) in extensions.iter() {
+                let beam = &beams[index];
+                let mut engine = beam.engine.clone();
+                let mut token_ids = beam.token_ids.clone();
+                let result = engine.try_accept_new_token(token_id).map_err(|e| {
+                    BeamSearchError::AcceptTokenError(token_ids.clone(), token_id, e)
+                })?;
+                token_ids.push(token_id);
+                match result {
+                    AcceptTokenResult::Ongoing => next_beams.push(Beam {
+                        engine,
+                        token_ids,
+                        log_probability,
+                    }),
+                    AcceptTokenResult::Finished => {
+                        finished.push(self.hypothesis(token_ids, log_probability))
+                    }
+                }
+            }
+            beams = next_beams;
+            finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+            finished.truncate(config.n_best);
+            let best_unfinished = beams
+                .iter()
+                .map(|x| self.score(x.token_ids.len(), x.log_probability))
+                .fold(f32::NEG_INFINITY, f32::max);
+            if beams.is_empty()
+                || (may_stop_early
+                    && finished.len() == config.n_best
+                    && finished.last().unwrap().score >= best_unfinished)
+            {
+                break;
+            }
+        }
+        Ok(finished)
+    }
+
+    fn validate_config(&self) -> Result<(), BeamSearchError> {
+        let config = &self.config;
+        if config.beam_width == 0 || config.n_best == 0 {
+            return Err(BeamSearchError::InvalidConfig(
+                "beam_width and n_best must be positive".to_string(),
+            ));
+        }
+        if !config.length_penalty.is_finite() {
+            return Err(BeamSearchError::InvalidConfig(
+                "length_penalty must be finite".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn score(&self, length: usize, log_probability: f64) -> f32 {
+        (log_probability / (length.max(1) as f64).powf(self.config.length_penalty as f64)) as f32
+    }
+
+    fn hypothesis(&self, token_ids: Vec<u32>, log_probability: f64) -> Hypothesis {
+        Hypothesis {
+            score: self.score(token_ids.len(), log_probability),
+            log_probability: log_probability as f32,
+            token_ids,
+        }
+    }
+}
+
+/// Orders the extensions by descending log-probabilities, breaking the ties by the beam indices and the token ids.
+fn descending(a: &(f64, usize, u32), b: &(f64, usize, u32)) -> std::cmp::Ordering {
+    b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2))
+}
+
+/// Keeps only the `k` best extensions in `extensions[start..]`, in an unspecified order.
+fn keep_best(extensions: &mut Vec<(f64, usize, u32)>, start: usize, k: usize) {
+    if extensions.len() - start > k {
+        extensions[start..].select_nth_unstable_by(k - 1, descending);
+        extensions.truncate(start + k);
+    }
+}
+
+/// Computes `ln(sum(exp(x)))` over the logits in a numerically stable way, ignoring NaNs.
+fn log_sum_exp(logits: &[f32]) -> f64 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+    if !max.is_finite() {
+        return max;
+    }
+    let sum: f64 = logits
+        .iter()
+        .filter(|x| !x.is_nan())
+        .map(|&x| (x as f64 - max).exp())
+        .sum();
+    max + sum.ln()
+}
@@ -0,0 +1,384 @@
+//! The completion module that computes the fewest bytes needed to finish the derivations of a [Grammar].
+use std::collections::VecDeque;
+use std::hash::Hash;
+
+#[cfg(feature = "python")]
+use pyo3::pyclass;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use jaggedarray::jagged_array::JaggedArrayViewTrait;
+use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::util::start;
+use kbnf_regex_automata::Anchored;
+use num::traits::{NumAssign, NumOps};
+use num::{
+    cast::AsPrimitive,
+    traits::{ConstOne, ConstZero},
+    Num,
+};
+
+use crate::grammar::{Grammar, HIRNode, IntersectionRole, NonterminalID};
+use crate::regex::{FiniteStateAutomaton, LazyDfa};
+use crate::utils::{self, FsaStateStatus};
+use crate::vocabulary::{Token, Vocabulary};
+
+/// The shortest bytes that finish the grammar from the current state of an engine.
+///
+/// It is returned by [Engine::shortest_completion](crate::engine::Engine::shortest_completion).
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[cfg_attr(feature = "wasm", wasm_bindgen(inspectable, getter_with_clone))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Completion {
+    /// The bytes that finish the grammar. Its length is the fewest bytes needed to finish the grammar.
+    pub bytes: Vec<u8>,
+    /// The fewest tokens in the vocabulary that spell [`Completion::bytes`].
+    /// `None` if the bytes cannot be spelled by the tokens.
+    pub token_ids: Option<Vec<u32>>,
+}
+
+/// The error type for errors when enumerating the completions of an engine.
+///
+/// It is returned by [Engine::enumerate_completions](crate::engine::Engine::enumerate_completions).
+#[derive(Debug, thiserror::Error, Clone, PartialEq, Eq)]
+pub enum EnumerateCompletionsError {
+    #[error("More than {0} token sequences finish the grammar.")]
+    /// More token sequences than the given maximum finish the grammar.
+    TooManyCompletions(usize),
+    #[error("The engine accepts more than {0} tokens without finishing.")]
+    /// A token sequence longer than the given maximum keeps the engine unfinished.
+    /// It happens when the completions are just long, and when the grammar can be extended indefinitely
+    /// in a way that [EnumerateCompletionsError::InfiniteLanguage] does not detect, e.g. by recursion in the middle of a production.
+    DepthLimitExceeded(usize),
+    #[error("Infinitely many token sequences finish the grammar.")]
+    /// The engine returns to the same state after accepting some tokens without finishing, e.g. by left recursion or repetition,
+    /// so the tokens can be repeated indefinitely before the grammar is finished.
+    InfiniteLanguage,
+    #[error("A transition of a lazy DFA does not fit in its cache, so some token sequences cannot be checked.")]
+    /// A transition of a lazy DFA does not fit in its cache, so the completions cannot all be checked.
+    /// A larger [RegexConfig::cache_capacity](crate::config::RegexConfig::cache_capacity) is needed.
+    LazyDfaCacheFull,
+}
+
+/// The error type for errors when finding the shortest completion of an engine.
+///
+/// It is returned by [Engine::shortest_completion](crate::engine::Engine::shortest_completion).
+#[derive(Debug, thiserror::Error, Clone, PartialEq, Eq)]
+pub enum ShortestCompletionError {
+    #[error("The grammar can never be finished from the current state.")]
+    /// No bytes finish the grammar from the current state.
+    Unfinishable,
+    #[error("The shortest completion is not found within {0} search states.")]
+    /// The shortest completion is not found within the given number of search states,
+    /// which is [MAX_COMPLETION_SEARCH_STATES](crate::engine_base::MAX_COMPLETION_SEARCH_STATES).
+    /// It happens when the completion is long or the guidance of the search is loose, e.g. in the grammars with intersections.
+    SearchLimitExceeded(usize),
+}
+
+impl Completion {
+    /// Create a new [Completion] by spelling the bytes with the fewest tokens in the vocabulary.
+    pub(crate) fn new(bytes: Vec<u8>, vocabulary: &Vocabulary) -> Self {
+        // The fewest tokens that spell the first i bytes, along with the last token and where it starts
+        let mut spellings: Vec<Option<(usize, u32, usize)>> = vec![None; bytes.len() + 1];
+        spellings[0] = Some((0, 0, 0));
+        let max_token_length = vocabulary.max_token_length();
+        for start in 0..bytes.len() {
+            let Some((count, _, _)) = spellings[start] else {
+                continue;
+            };
+            for end in start + 1..=bytes.len().min(start + max_token_length) {
+                let token = Token(bytes[start..end].into());
+                if let Some(token_id) = vocabulary.token_id(&token) {
+                    if spellings[end].is_none_or(|(x, _, _)| count + 1 < x) {
+                        spellings[end] = Some((count + 1, token_id, start));
+                    }
+                }
+            }
+        }
+        let mut token_ids = Vec::new();
+        let mut end = bytes.len();
+        while end != 0 {
+            let Some((_, token_id, start)) = spellings[end] else {
+                return Self {
+                    bytes,
+                    token_ids: None,
+                };
+            };
+            token_ids.push(token_id);
+            end = start;
+        }
+        token_ids.reverse();
+        Self {
+            bytes,
+            token_ids: Some(token_ids),
+        }
+    }
+}
+
+/// The fewest bytes needed to finish every nonterminal, regex state and production suffix of a [Grammar].
+///
+/// [usize::MAX] means the derivation can never be finished.
+/// The lengths are exact except for lazy DFAs, whose states other than the start state are only known at runtime,
+/// so a regex compiled into a lazy DFA is assumed to end after one more byte once it leaves its start state.
+#[derive(Debug, Clone)]
+pub(crate) struct CompletionTable {
+    /// The fewest bytes every nonterminal derives, indexed by nonterminal id.
+    nonterminals: Vec<usize>,
+    /// The length of every terminal, indexed by terminal id.
+    terminals: Vec<usize>,
+    /// The fewest bytes to end every regex, indexed by regex id.
+    regexes: Vec<RegexLengths>,
+    /// The fewest bytes derived by the nodes after every dot position,
+    /// indexed by nonterminal id, production id and then dot position.
+    suffixes: Vec<Vec<Vec<usize>>>,
+}
+
+/// The fewest bytes to end a regex.
+#[derive(Debug, Clone)]
+enum RegexLengths {
+    /// The lengths from every state of a dense DFA, indexed by state index.
+    Dfa(Vec<usize>),
+    /// The length from the anchored start state of a lazy DFA.
+    LazyDfa { start_state: usize, length: usize },
+}
+
+impl CompletionTable {
+    /// Create a new [CompletionTable] from the grammar.
+    ///
+    /// # Arguments
+    ///
+    /// * `grammar` - The grammar to analyze.
+    pub(crate) fn new<TI>(grammar: &Grammar<TI>) -> Self
+    where
+        TI: Num
+            + AsPrimitive<usize>
+            + ConstOne
+            + ConstZero
+            + NumOps
+            + NumAssign
+            + std::cmp::PartialOrd
+            + std::convert::TryFrom<usize>
+            + num::Bounded
+            + Hash
+            + Eq,
+        usize: num::traits::AsPrimitive<TI>,
+    {
+        let id_to_terminals = grammar.id_to_terminals();
+        let terminals = (0..id_to_terminals.len())
+            .map(|i| id_to_terminals.view::<1, 1>([i]).len())
+            .collect();
+        let regexes = grammar
+            .id_to_regexes()
+            .iter()
+            .map(|regex| match regex.as_ref() {
+                FiniteStateAutomaton::Dfa(dfa) => RegexLengths::Dfa(Self::regex_lengths(dfa)),
+                FiniteStateAutomaton::LazyDfa(dfa) => {
+                    let start_state = dfa.anchored_start_state();
+                    RegexLengths::LazyDfa {
+                        start_state: LazyDfa::raw_state_id(start_state),
+                        length: dfa.shortest_match_length(&mut dfa.create_cache(), start_state),
+                    }
+                }
+            })
+            .collect();
+        let mut table = Self {
+            nonterminals: vec![usize::MAX; grammar.nonterminals_size()],
+            terminals,
+            regexes,
+            suffixes: Vec::new(),
+        };
+        let productions = grammar.productions();
+        // The trackers of intersections never complete their roots.
+        let trackers: Vec<Option<usize>> = (0..productions.len())
+            .map(|nonterminal_id| {
+                match grammar.intersection_role(NonterminalID(nonterminal_id.as_())) {
+                    Some((i, IntersectionRole::Root)) => {
+                        Some(grammar.intersection(i).tracker.0.as_())
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        let starts: Vec<Vec<Vec<usize>>> = productions
+            .iter()
+            .map(|alternations| {
+                alternations
+                    .iter()
+                    .map(|production| {
+                        production
+                            .iter()
+                            .map(|node| Self::initial_state(grammar, *node))
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (nonterminal_id, alternations) in productions.iter().enumerate() {
+                let length = alternations
+                    .iter()
+                    .zip(starts[nonterminal_id].iter())
+                    .filter(|(production, _)| {
+                        !matches!(production.as_slice(), [HIRNode::Nonterminal(x)]
+                            if Some(x.0.as_()) == trackers[nonterminal_id])
+                    })
+                    .map(|(production, states)| {
+                        production
+                            .iter()
+                            .zip(states.iter())
+                            .fold(0usize, |sum, (node, state)| {
+                                sum.saturating_add(table.node(*node, *state))
+                            })
+                    })
+                    .min()
+                    .unwrap_or(usize::MAX);
+                if length < table.nonterminals[nonterminal_id] {
+                    table.nonterminals[nonterminal_id] = length;
+                    changed = true;
+                }
+            }
+        }
+        table.suffixes = productions
+            .iter()
+            .zip(starts.iter())
+            .map(|(alternations, states)| {
+                alternations
+                    .iter()
+                    .zip(states.iter())
+                    .map(|(production, states)| {
+                        let mut suffix = vec![0usize; production.len()];
+                        for dot in (0..production.len().saturating_sub(1)).rev() {
+                            suffix[dot] = suffix[dot + 1]
+                                .saturating_add(table.node(production[dot + 1], states[dot + 1]));
+                        }
+                        suffix
+                    })
+                    .collect()
+            })
+            .collect();
+        table
+    }
+
+    /// Computes the fewest bytes to end the regex from every state of its dense DFA.
+    ///
+    /// The engine ends a regex only after scanning a byte, so even a match state needs one more byte.
+    fn regex_lengths(dfa: &kbnf_regex_automata::dfa::dense::DFA<Vec<u32>>) -> Vec<usize> {
+        let stride2 = dfa.stride2();
+        let state_len = dfa.state_len();
+        let representatives: Vec<u8> = dfa
+            .byte_classes()
+            .representatives(..)
+            .filter_map(|x| x.as_u8())
+            .collect();
+        // The fewest bytes to reach a match state, including zero bytes.
+        let mut distances = vec![usize::MAX; state_len];
+        let mut predecessors = vec![Vec::new(); state_len];
+        let mut queue = VecDeque::new();
+        for state in dfa.states() {
+            let index = state.id().as_usize() >> stride2;
+            match utils::check_dfa_state_status(state.id(), dfa) {
+                FsaStateStatus::Reject => continue,
+                FsaStateStatus::Accept => {
+                    distances[index] = 0;
+                    queue.push_back(index);
+                }
+                FsaStateStatus::InProgress => {}
+            }
+            for byte in representatives.iter().copied() {
+                let next = dfa.next_state(state.id(), byte).as_usize() >> stride2;
+                predecessors[next].push(index);
+            }
+        }
+        while let Some(index) = queue.pop_front() {
+            for predecessor in std::mem::take(&mut predecessors[index]) {
+                if distances[predecessor] == usize::MAX {
+                    distances[predecessor] = distances[index] + 1;
+                    queue.push_back(predecessor);
+                }
+            }
+        }
+        dfa.states()
+            .map(|state| {
+                representatives
+                    .iter()
+                    .map(|byte| {
+                        let next = dfa.next_state(state.id(), *byte).as_usize() >> stride2;
+                        distances[next].saturating_add(1)
+                    })
+                    .min()
+                    .unwrap_or(usize::MAX)
+            })
+            .collect()
+    }
+
+    /// Get the state of the node right after it is predicted, in the same form as the state ids of the Earley items.
+    fn initial_state<TI>(grammar: &Grammar<TI>, node: HIRNode<TI>) -> usize
+    where
+        TI: Num
+            + AsPrimitive<usize>
+            + ConstOne
+            + ConstZero
+            + NumOps
+            + NumAssign
+            + std::cmp::PartialOrd
+            + std::convert::TryFrom<usize>
+            + num::Bounded
+            + Hash
+            + Eq,
+        usize: num::traits::AsPrimitive<TI>,
+    {
+        match node {
+            HIRNode::RegexString(id) | HIRNode::EarlyEndRegexString(id) => {
+                match grammar.regex(id) {
+                    FiniteStateAutomaton::Dfa(dfa) => dfa
+                        .start_state(&start::Config::new().anchored(Anchored::Yes))
+                        .map_or(0, |x| x.as_usize() >> dfa.stride2()),
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        LazyDfa::raw_state_id(dfa.anchored_start_state())
+                    }
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Get the fewest bytes needed to finish the node from the state.
+    ///
+    /// Only the start states of lazy DFAs have exact lengths, and their other states are assumed to need one byte.
+    ///
+    /// # Arguments
+    ///
+    /// * `node` - The node at the dot position of an Earley item.
+    /// * `state` - The state id of the Earley item. It is the number of bytes already matched for terminals,
+    ///   the state index for dense DFAs and the raw state id for lazy DFAs.
+    pub(crate) fn node<TI>(&self, node: HIRNode<TI>, state: usize) -> usize
+    where
+        TI: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+    {
+        match node {
+            HIRNode::Terminal(id) => self.terminals[id.0.as_()] - state,
+            HIRNode::RegexString(id) | HIRNode::EarlyEndRegexString(id) => {
+                match &self.regexes[id.0.as_()] {
+                    RegexLengths::Dfa(lengths) => lengths[state],
+                    RegexLengths::LazyDfa {
+                        start_state,
+                        length,
+                    } if *start_state == state => *length,
+                    RegexLengths::LazyDfa { .. } => 1,
+                }
+            }
+            // The engine never completes a regex complement.
+            HIRNode::RegexComplement(_) => usize::MAX,
+            HIRNode::Substrings(_) => 1,
+            HIRNode::Nonterminal(id) => self.nonterminals[id.0.as_()],
+        }
+    }
+
+    /// Get the fewest bytes derived by the nodes after the dot position of the production.
+    pub(crate) fn suffix(&self, nonterminal_id: usize, production_id: usize, dot: usize) -> usize {
+        self.suffixes[nonterminal_id][production_id][dot]
+    }
+}
@@ -1,10 +1,17 @@
 //! The configuration module of the KBNF engine.
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+
 use kbnf_syntax::regex::FiniteStateAutomatonConfig;
 #[cfg(feature = "python")]
 use pyo3::pyclass;
 use serde::{Deserialize, Serialize};
 
 use crate::engine::EngineConfig;
+use crate::import::{GrammarLoader, StandardLibrary};
+use crate::regex::LazyDfaConfig;
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 #[derive(Debug, Clone)]
@@ -18,6 +25,14 @@ pub struct InternalConfig {
     pub engine_config: EngineConfig,
     /// The start nonterminal of the grammar.
     pub start_nonterminal: String,
+    /// The configuration of the lazy DFAs. `None` means the regular expressions are compiled into dense DFAs.
+    pub lazy_dfa_config: Option<LazyDfaConfig>,
+    /// Whether to normalize the terminals into Unicode Normalization Form C. Other byte sources are not normalized.
+    pub nfc_normalization: bool,
+    /// The loader of the grammars imported by `import` statements.
+    pub loader: Arc<dyn GrammarLoader>,
+    /// The documents bound to the `#documents"name"` symbols, keyed by name.
+    pub documents: AHashMap<String, Vec<String>>,
 }
 /// The configuration of the [`Engine`](crate::engine::Engine) struct. This should suffice most scenarios.
 #[cfg_attr(feature = "python", pyclass)]
@@ -40,6 +55,22 @@ pub struct Config {
     pub expected_output_length: usize,
     /// The configuration of the terminals compression.
     pub compression_config: CompressionConfig,
+    /// Whether to normalize the terminals, including case-insensitive ones, into Unicode Normalization Form C.
+    /// This is useful when the model output is normalized into NFC before it is fed to the engine.
+    /// Only the terminals are normalized. Regexes, the documents of substrings symbols, the strings of dynamic alternatives
+    /// and the tokens of the vocabulary are used as they are given.
+    /// The default is `false`.
+    pub nfc_normalization: bool,
+    /// The documents bound to the `#documents"name"` symbols, keyed by name.
+    /// The output of such a symbol is constrained to be a substring of any of its documents.
+    /// The default is empty.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    pub documents: BTreeMap<String, Vec<String>>,
+    /// Whether to run [Grammar::lint](crate::grammar::Grammar::lint) and
+    /// [Grammar::lint_vocabulary_bytes](crate::grammar::Grammar::lint_vocabulary_bytes) when an [`Engine`](crate::engine::Engine) is created.
+    /// The lints analyze the whole grammar, so they are only worth running while the grammar is being written.
+    /// The default is `false`.
+    pub lint: bool,
 }
 /// The type of the Finite State Automaton to be used.
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
@@ -51,6 +82,11 @@ pub enum Fsa {
     /// It is the fastest type of finite automaton, but it is also the most memory-consuming.
     /// In particular, construction time and space required could be exponential in the worst case.
     Dfa,
+    /// The lazy Deterministic Finite Automaton.
+    /// It is a deterministic finite automaton that computes the state transitions on demand and caches them.
+    /// Its construction time and space are linear in the size of the regular expression,
+    /// at the cost of slower transitions and no eager regex cache.
+    LazyDfa,
 }
 /// The configuration of regular expressions.
 #[cfg_attr(feature = "python", pyclass)]
@@ -60,6 +96,7 @@ pub enum Fsa {
 pub struct RegexConfig {
     /// The maximum memory usage in bytes allowed when compiling the regex.
     /// If the memory usage exceeds this limit, an error will be returned.
+    /// For lazy DFAs, this limits the size of the underlying NFA.
     /// The default is `None`, which means no limit for dfa.
     pub max_memory_usage: Option<usize>,
     /// The type of the Finite State Automaton to be used.
@@ -69,6 +106,25 @@ pub struct RegexConfig {
     /// `None` means that the cache will be disabled.
     /// The default is `Some(1000)`.
     pub min_tokens_required_for_eager_regex_cache: Option<usize>,
+    /// The capacity in bytes of the transition cache of each lazy DFA. Every engine has caches of its own.
+    /// It is only used when [`RegexConfig::fsa_type`] is [`Fsa::LazyDfa`].
+    /// When a transition does not fit in the cache, the cache is rebuilt with only the states the engine refers to and tried again.
+    /// A token whose transitions do not fit in the rebuilt cache either fails with the `LazyDfaCacheFull` errors of [`EngineLike`](crate::engine_like::EngineLike).
+    /// The default is `None`, which means the default capacity of the lazy DFA(2MB).
+    pub cache_capacity: Option<usize>,
+}
+
+impl RegexConfig {
+    /// Returns the configuration of the lazy DFAs, or `None` if dense DFAs are used.
+    pub(crate) fn lazy_dfa_config(&self) -> Option<LazyDfaConfig> {
+        match self.fsa_type {
+            Fsa::Dfa => None,
+            Fsa::LazyDfa => Some(LazyDfaConfig {
+                cache_capacity: self.cache_capacity,
+                nfa_size_limit: self.max_memory_usage,
+            }),
+        }
+    }
 }
 
 /// The configuration of regular expressions.
@@ -88,27 +144,27 @@ impl Default for Config {
                 max_memory_usage: None,
                 fsa_type: Fsa::Dfa,
                 min_tokens_required_for_eager_regex_cache: Some(1000),
+                cache_capacity: None,
             },
-            engine_config: EngineConfig {
-                cache_enabled: true,
-                compaction_enabled: true,
-            },
+            engine_config: EngineConfig::default(),
             start_nonterminal: "start".to_string(),
             compression_config: CompressionConfig { min_terminals: 5 },
             expected_output_length: u32::MAX as usize,
+            nfc_normalization: false,
+            documents: BTreeMap::new(),
+            lint: false,
         }
     }
 }
 impl Config {
     /// Converts the configuration to the internal configuration.
     pub fn internal_config(self) -> InternalConfig {
-        let regex_config = match self.regex_config.fsa_type {
-            Fsa::Dfa => FiniteStateAutomatonConfig::Dfa(
-                kbnf_regex_automata::dfa::dense::Config::new()
-                    .dfa_size_limit(self.regex_config.max_memory_usage)
-                    .start_kind(kbnf_regex_automata::dfa::StartKind::Both),
-            ),
-        };
+        let regex_config = FiniteStateAutomatonConfig::Dfa(
+            kbnf_regex_automata::dfa::dense::Config::new()
+                .dfa_size_limit(self.regex_config.max_memory_usage)
+                .start_kind(kbnf_regex_automata::dfa::StartKind::Both),
+        );
+        let lazy_dfa_config = self.regex_config.lazy_dfa_config();
         let compression_config = kbnf_syntax::config::CompressionConfig {
             min_terminals: self.compression_config.min_terminals,
             regex_config: FiniteStateAutomatonConfig::Dfa(
@@ -120,6 +176,10 @@ impl Config {
             compression_config,
             engine_config: self.engine_config,
             start_nonterminal: self.start_nonterminal,
+            lazy_dfa_config,
+            nfc_normalization: self.nfc_normalization,
+            loader: Arc::new(StandardLibrary),
+            documents: self.documents.into_iter().collect(),
         }
     }
 }
@@ -0,0 +1,241 @@
+//! The diagnostic module that locates grammar errors in the KBNF source and renders them for humans.
+use std::fmt::Display;
+use std::ops::Range;
+
+use kbnf_syntax::semantic_error::SemanticError;
+use nom::error::VerboseErrorKind;
+#[cfg(feature = "python")]
+use pyo3::pyclass;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::*;
+
+use crate::grammar::CreateGrammarError;
+use crate::preprocessor::{self, Preprocessed, TokenKind};
+
+/// A position in the KBNF source.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[cfg_attr(feature = "wasm", wasm_bindgen(inspectable))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// The byte offset from the start of the source.
+    pub offset: usize,
+    /// The line number, starting from 1.
+    pub line: usize,
+    /// The column number in characters, starting from 1.
+    pub column: usize,
+}
+
+/// A range in the KBNF source.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[cfg_attr(feature = "wasm", wasm_bindgen(inspectable))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Span {
+    /// The start of the range, inclusive.
+    pub start: Location,
+    /// The end of the range, exclusive.
+    pub end: Location,
+}
+
+/// A structured description of an error in the KBNF source.
+#[cfg_attr(feature = "python", pyclass)]
+#[cfg_attr(feature = "python", pyo3(get_all))]
+#[cfg_attr(feature = "wasm", wasm_bindgen(inspectable, getter_with_clone))]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Diagnostic {
+    /// The error message.
+    pub message: String,
+    /// The range of the source that causes the error. `None` if the error cannot be located.
+    pub span: Option<Span>,
+    /// The source text within [`Diagnostic::span`]. Empty if the span is empty or unknown.
+    pub snippet: String,
+    /// The whole source line where [`Diagnostic::span`] starts. Empty if the span is unknown.
+    pub source_line: String,
+}
+
+impl Diagnostic {
+    /// Create a new [`Diagnostic`].
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The error message.
+    /// * `source` - The KBNF source.
+    /// * `range` - The byte range of the source that causes the error, if known.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` is out of bounds or not on UTF-8 character boundaries.
+    pub fn new(message: impl Into<String>, source: &str, range: Option<Range<usize>>) -> Self {
+        let message = message.into();
+        let Some(range) = range else {
+            return Self {
+                message,
+                span: None,
+                snippet: String::new(),
+                source_line: String::new(),
+            };
+        };
+        let line_start = source[..range.start].rfind('\n').map_or(0, |x| x + 1);
+        let line_end = source[range.start..]
+            .find('\n')
+            .map_or(source.len(), |x| range.start + x);
+        Self {
+            message,
+            span: Some(Span {
+                start: Self::location(source, range.start),
+                end: Self::location(source, range.end),
+            }),
+            snippet: source[range].to_string(),
+            source_line: source[line_start..line_end]
+                .trim_end_matches('\r')
+                .to_string(),
+        }
+    }
+
+    fn location(source: &str, offset: usize) -> Location {
+        let before = &source[..offset];
+        let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+        Location {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// Render the diagnostic into a human-readable string, with the offending source underlined by carets.
+    pub fn render(&self) -> String {
+        let mut output = format!("error: {}", self.message);
+        let Some(span) = self.span else {
+            return output;
+        };
+        let line_number = span.start.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let start_column = span.start.column;
+        let end_column = if span.end.line == span.start.line {
+            span.end.column
+        } else {
+            self.source_line.chars().count() + 1
+        };
+        let carets = "^".repeat(end_column.saturating_sub(start_column).max(1));
+        output.push_str(&format!(
+            "\n{gutter}--> {}:{}\n{gutter} |\n{line_number} | {}\n{gutter} | {}{carets}",
+            span.start.line,
+            start_column,
+            self.source_line,
+            " ".repeat(start_column - 1),
+        ));
+        output
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render())
+    }
+}
+
+/// Attaches a [`Diagnostic`] to parsing and semantic errors of the grammar in `source`.
+///
+/// `preprocessed` is the result of preprocessing `source`, which the parsing errors of [kbnf_syntax] refer to.
+/// Other errors are returned unchanged.
+pub(crate) fn locate_error(
+    error: CreateGrammarError,
+    source: &str,
+    preprocessed: Option<&Preprocessed>,
+) -> CreateGrammarError {
+    let (message, range) = match &error {
+        CreateGrammarError::ParsingError(e) => {
+            let (message, range) = match e {
+                nom::Err::Error(e) | nom::Err::Failure(e) => {
+                    // The entry with the shortest remaining input is where the parser got the furthest.
+                    match e.errors.iter().min_by_key(|(remaining, _)| remaining.len()) {
+                        Some((remaining, kind)) => {
+                            let message = match kind {
+                                VerboseErrorKind::Context(context) => context.to_string(),
+                                VerboseErrorKind::Char(c) => format!("expected `{c}`"),
+                                VerboseErrorKind::Nom(_) => "invalid syntax".to_string(),
+                            };
+                            let offset = match preprocessed {
+                                Some(preprocessed) => preprocessed.source_offset(
+                                    preprocessed.text.len().saturating_sub(remaining.len()),
+                                ),
+                                None => source.len().saturating_sub(remaining.len()),
+                            };
+                            (message, Some(token_range(source, offset)))
+                        }
+                        None => ("invalid syntax".to_string(), None),
+                    }
+                }
+                nom::Err::Incomplete(_) => ("unexpected end of input".to_string(), None),
+            };
+            (format!("KBNF parsing error: {message}"), range)
+        }
+        CreateGrammarError::SemanticError(e) => {
+            let range = match e.as_ref() {
+                SemanticError::UndefinedNonterminal(name)
+                | SemanticError::InvalidExceptedNonterminal(name) => {
+                    locate_identifier(source, name)
+                }
+                SemanticError::InvalidExceptedTerminal(_) => None,
+                SemanticError::DfaRegexBuildError(_) | SemanticError::LazyDfaRegexBuildError(_) => {
+                    locate_invalid_regex(source)
+                }
+            };
+            (format!("KBNF semantics error: {e}"), range)
+        }
+        _ => return error,
+    };
+    CreateGrammarError::Located {
+        diagnostic: Box::new(Diagnostic::new(message, source, range)),
+        error: Box::new(error),
+    }
+}
+
+/// Returns the range of the token starting at `offset`, which ends at the next whitespace or `;`.
+fn token_range(source: &str, offset: usize) -> Range<usize> {
+    let mut offset = offset.min(source.len());
+    while !source.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let length = source[offset..]
+        .char_indices()
+        .find(|&(i, c)| c.is_whitespace() || (c == ';' && i > 0))
+        .map_or(
+            source.len() - offset,
+            |(i, c)| if i == 0 { c.len_utf8() } else { i },
+        );
+    offset..offset + length
+}
+
+/// Returns the range of the first use of the nonterminal `name` on the right-hand side of a rule,
+/// or its first occurrence if it is never used there.
+fn locate_identifier(source: &str, name: &str) -> Option<Range<usize>> {
+    let tokens = preprocessor::tokenize(source)?;
+    let mut first = None;
+    for (i, token) in tokens.iter().enumerate() {
+        if !token.is(TokenKind::Identifier, name) {
+            continue;
+        }
+        if !tokens.get(i + 1).is_some_and(|x| x.is_punctuation("::=")) {
+            return token.span();
+        }
+        first = first.or(token.span());
+    }
+    first
+}
+
+/// Returns the range of the first regex literal that cannot be parsed as a regex.
+fn locate_invalid_regex(source: &str) -> Option<Range<usize>> {
+    let tokens = preprocessor::tokenize(source)?;
+    tokens
+        .iter()
+        .filter(|x| x.kind == TokenKind::Literal)
+        .find(|x| {
+            let quote = x.text.find(['"', '\'']).unwrap_or(0);
+            matches!(&x.text[..quote], "#" | "#e" | "#ex")
+                && kbnf_regex_automata::util::syntax::parse(&x.text[quote + 1..x.text.len() - 1])
+                    .is_err()
+        })
+        .and_then(|x| x.span())
+}
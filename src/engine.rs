@@ -9,7 +9,17 @@ use serde::{Deserialize, Serialize};
 use wasm_bindgen::prelude::*;
 
 use crate::{
-    config::Config, engine_base::EngineBase, engine_like::EngineLike, grammar::Grammar, utils,
+    completion::{Completion, CompletionTable, EnumerateCompletionsError, ShortestCompletionError},
+    config::{Config, Fsa},
+    diagnostic::Diagnostic,
+    engine_base::EngineBase,
+    engine_like::{AcceptTokenError, AcceptTokenResult, ComputeAllowedTokenIdsError, EngineLike},
+    grammar::{Grammar, SetDynamicError},
+    import::{GrammarLoader, StandardLibrary},
+    lint::LintWarning,
+    preprocessor::RegexPlaceholder,
+    regex::LAZY_STATE_ID_UPPER_BOUND,
+    utils,
     vocabulary::Vocabulary,
 };
 
@@ -29,6 +39,123 @@ pub struct EngineConfig {
     /// speeds up the engine in most cases. In particular, cache usually requires compaction to be effective.
     /// It is enabled by default.
     pub compaction_enabled: bool,
+    /// The maximum number of bytes the engine accepts, counting both tokens and bytes.
+    /// A byte is rejected if the grammar cannot be finished within the remaining bytes afterwards.
+    /// When it is set, the allowed tokens are not taken from the eager regex cache,
+    /// and they are cached separately for every number of remaining bytes, so the cache is hit less often.
+    /// The default is `None`, which means no limit.
+    pub max_output_bytes: Option<usize>,
+    /// The maximum number of tokens the engine accepts. Bytes accepted directly are not counted.
+    /// The last token must finish the grammar,
+    /// and the other tokens are limited as if every remaining token were as long as the longest token in the vocabulary.
+    /// When it is set, the allowed tokens are not taken from the eager regex cache,
+    /// and they are cached separately for every number of remaining bytes.
+    /// The default is `None`, which means no limit.
+    pub max_output_tokens: Option<usize>,
+    /// The penalty added to the logits of the disallowed tokens when masking logits.
+    /// This discourages rather than forbids the tokens outside the grammar,
+    /// although the engine still rejects them when they are accepted.
+    /// Hence a disallowed token may still be sampled, and the caller must handle
+    /// [AcceptTokenError::Rejected](crate::engine_like::AcceptTokenError::Rejected),
+    /// e.g. by sampling again without the token, since the engine is left unchanged.
+    /// The default is `None`, which means the logits of the disallowed tokens are set to negative infinity.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(skip))]
+    pub disallowed_token_penalty: Option<Penalty>,
+}
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self::new(true, true)
+    }
+}
+impl EngineConfig {
+    /// Create a new [EngineConfig] without output limits or a penalty for the disallowed tokens.
+    pub fn new(cache_enabled: bool, compaction_enabled: bool) -> Self {
+        Self {
+            cache_enabled,
+            compaction_enabled,
+            max_output_bytes: None,
+            max_output_tokens: None,
+            disallowed_token_penalty: None,
+        }
+    }
+    /// Set [EngineConfig::max_output_bytes].
+    pub fn with_max_output_bytes(mut self, max_output_bytes: Option<usize>) -> Self {
+        self.max_output_bytes = max_output_bytes;
+        self
+    }
+    /// Set [EngineConfig::max_output_tokens].
+    pub fn with_max_output_tokens(mut self, max_output_tokens: Option<usize>) -> Self {
+        self.max_output_tokens = max_output_tokens;
+        self
+    }
+    /// Set [EngineConfig::disallowed_token_penalty].
+    pub fn with_disallowed_token_penalty(mut self, penalty: Option<f32>) -> Self {
+        self.disallowed_token_penalty = penalty.map(Penalty::new);
+        self
+    }
+}
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl EngineConfig {
+    /// Get [EngineConfig::disallowed_token_penalty].
+    #[wasm_bindgen(getter)]
+    pub fn disallowed_token_penalty(&self) -> Option<f32> {
+        self.disallowed_token_penalty.map(Penalty::value)
+    }
+    /// Set [EngineConfig::disallowed_token_penalty].
+    #[wasm_bindgen(setter)]
+    pub fn set_disallowed_token_penalty(&mut self, penalty: Option<f32>) {
+        self.disallowed_token_penalty = penalty.map(Penalty::new);
+    }
+}
+/// A penalty added to logits, stored as the bits of an `f32` so that [EngineConfig] can be compared and hashed.
+///
+/// Two penalties are equal if and only if their bits are, so `0.0` and `-0.0` differ while a NaN equals itself.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "f32", into = "f32")]
+pub struct Penalty(u32);
+
+impl Penalty {
+    /// Create a new [Penalty].
+    pub fn new(value: f32) -> Self {
+        Self(value.to_bits())
+    }
+    /// Get the value of the penalty.
+    pub fn value(self) -> f32 {
+        f32::from_bits(self.0)
+    }
+}
+
+impl From<f32> for Penalty {
+    fn from(value: f32) -> Self {
+        Self::new(value)
+    }
+}
+
+impl From<Penalty> for f32 {
+    fn from(penalty: Penalty) -> Self {
+        penalty.value()
+    }
+}
+
+impl std::fmt::Debug for Penalty {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Penalty").field(&self.value()).finish()
+    }
+}
+
+#[cfg(feature = "python")]
+impl pyo3::IntoPy<pyo3::PyObject> for Penalty {
+    fn into_py(self, py: pyo3::Python<'_>) -> pyo3::PyObject {
+        pyo3::IntoPy::into_py(self.value(), py)
+    }
+}
+
+#[cfg(feature = "python")]
+impl<'py> pyo3::FromPyObject<'py> for Penalty {
+    fn extract_bound(object: &pyo3::Bound<'py, pyo3::PyAny>) -> pyo3::PyResult<Self> {
+        pyo3::types::PyAnyMethods::extract::<f32>(object).map(Self::new)
+    }
 }
 #[derive(Debug, Clone)]
 /// An enum that represents the common type combinations of [`EngineBase`].
@@ -43,10 +170,19 @@ pub(crate) enum EngineUnion {
 #[cfg_attr(feature = "python", pyclass(subclass))]
 #[cfg_attr(feature = "python", pyo3(name = "InternalEngine"))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 /// The main struct that wraps the [`EngineBase`] so the user do not have to specify the generic type every time for common cases.
 pub struct Engine {
     union: EngineUnion,
+    lint_warnings: Vec<LintWarning>,
+}
+impl std::fmt::Debug for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The lint warnings are omitted since they are already logged when the engine is created.
+        f.debug_struct("Engine")
+            .field("union", &self.union)
+            .finish()
+    }
 }
 #[derive(Debug, thiserror::Error)]
 /// Represents the error type for the [`Engine`] creation.
@@ -68,6 +204,16 @@ pub enum CreateEngineError {
     InvalidInputError,
 }
 
+impl CreateEngineError {
+    /// Get the diagnostic that points to the source of the error, if the error is located in the KBNF grammar string.
+    pub fn diagnostic(&self) -> Option<&Diagnostic> {
+        match self {
+            CreateEngineError::GrammarError(e) => e.diagnostic(),
+            _ => None,
+        }
+    }
+}
+
 impl Engine {
     /// Create a new [`Engine`] from an KBNF grammar string and a [`Vocabulary`].
     ///
@@ -116,24 +262,80 @@ impl Engine {
         vocabulary: Vocabulary,
         config: Config,
     ) -> Result<Engine, CreateEngineError> {
-        let tsp = config.expected_output_length;
+        Self::with_loader(
+            kbnf_syntax_grammar_str,
+            vocabulary,
+            config,
+            Arc::new(StandardLibrary),
+        )
+    }
+    /// Create a new [`Engine`] from an KBNF grammar string, a [`Vocabulary`], a [`Config`],
+    /// and a [`GrammarLoader`] that loads the grammars imported by the grammar string.
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `vocabulary` - The [`Vocabulary`] object.
+    /// * `config` - The [`Config`] object.
+    /// * `loader` - The [`GrammarLoader`] object. Chain it with [`StandardLibrary`] to keep the standard library available.
+    ///
+    /// # Returns
+    ///
+    /// * [`Engine`] - The new [`Engine`] object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`CreateEngineError`] when the grammar is empty or the grammar and/or config's value range is not supported by the Engine.
+    pub fn with_loader(
+        kbnf_syntax_grammar_str: &str,
+        vocabulary: Vocabulary,
+        config: Config,
+        loader: Arc<dyn GrammarLoader>,
+    ) -> Result<Engine, CreateEngineError> {
+        // The Earley sets never outnumber the accepted bytes.
+        let tsp = config
+            .expected_output_length
+            .min(config.engine_config.max_output_bytes.unwrap_or(usize::MAX));
         let regex_config = config.regex_config;
-        let internal_config = config.internal_config();
-        let grammar =
-            utils::construct_kbnf_syntax_grammar(kbnf_syntax_grammar_str, internal_config.clone())?;
+        let lint = config.lint;
+        let mut internal_config = config.internal_config();
+        internal_config.loader = loader;
+        let (grammar, metadata) = utils::construct_kbnf_syntax_grammar_with_metadata(
+            kbnf_syntax_grammar_str,
+            internal_config.clone(),
+        )?;
         if grammar.is_empty() {
             return Err(CreateEngineError::EmptyGrammarError);
         }
         let td = utils::find_max_dotted_position_from_kbnf_syntax_grammar(&grammar);
         let tp = utils::find_max_production_id_from_kbnf_syntax_grammar(&grammar);
-        let ts = utils::find_max_state_id_from_kbnf_syntax_grammar(&grammar);
+        let mut ts = utils::find_max_state_id_from_kbnf_syntax_grammar(&grammar);
+        if regex_config.fsa_type == Fsa::LazyDfa && !grammar.id_to_regex.is_empty() {
+            // Lazy DFA state IDs are only known at runtime, so the largest possible one is assumed.
+            ts = ts.max(LAZY_STATE_ID_UPPER_BOUND);
+        }
+        if metadata
+            .regex_placeholders
+            .values()
+            .any(|x| matches!(x, RegexPlaceholder::Dynamic(_)))
+        {
+            // The dynamic alternatives are only compiled in Engine::set_dynamic, so the widest state IDs are reserved for them.
+            ts = ts.max(u32::MAX as usize);
+        }
+        let lint_warnings;
         let engine = if Self::check_id_length(&grammar, u8::MAX.into())
             && td <= u8::MAX.into()
             && tp <= u8::MAX.into()
             && tsp <= u8::MAX.into()
             && ts <= u32::MAX as usize
         {
-            let grammar: Grammar<u8> = Grammar::new(grammar, &vocabulary, regex_config)?;
+            let grammar: Grammar<u8> =
+                Grammar::new_with_metadata(grammar, metadata, &vocabulary, regex_config)?;
+            lint_warnings = if lint {
+                Self::lint(&grammar, &vocabulary)
+            } else {
+                Vec::new()
+            };
             let grammar = Arc::new(grammar);
             let vocabulary = Arc::new(vocabulary);
             EngineUnion::U8U8U8U8U32(EngineBase::new(
@@ -147,7 +349,13 @@ impl Engine {
             && tsp <= u16::MAX.into()
             && ts <= u16::MAX as usize
         {
-            let grammar: Grammar<u8> = Grammar::new(grammar, &vocabulary, regex_config)?;
+            let grammar: Grammar<u8> =
+                Grammar::new_with_metadata(grammar, metadata, &vocabulary, regex_config)?;
+            lint_warnings = if lint {
+                Self::lint(&grammar, &vocabulary)
+            } else {
+                Vec::new()
+            };
             let grammar = Arc::new(grammar);
             let vocabulary = Arc::new(vocabulary);
             EngineUnion::U8U8U16U16U16(EngineBase::new(
@@ -161,7 +369,13 @@ impl Engine {
             && tsp <= u32::MAX as usize
             && ts <= u32::MAX as usize
         {
-            let grammar: Grammar<u16> = Grammar::new(grammar, &vocabulary, regex_config)?;
+            let grammar: Grammar<u16> =
+                Grammar::new_with_metadata(grammar, metadata, &vocabulary, regex_config)?;
+            lint_warnings = if lint {
+                Self::lint(&grammar, &vocabulary)
+            } else {
+                Vec::new()
+            };
             let grammar = Arc::new(grammar);
             let vocabulary = Arc::new(vocabulary);
             EngineUnion::U16U16U32U32U32(EngineBase::new(
@@ -172,7 +386,41 @@ impl Engine {
         } else {
             return Err(CreateEngineError::InvalidInputError);
         };
-        Ok(Self { union: engine })
+        Ok(Self {
+            union: engine,
+            lint_warnings,
+        })
+    }
+
+    fn lint<TI>(grammar: &Grammar<TI>, vocabulary: &Vocabulary) -> Vec<LintWarning>
+    where
+        TI: num::Num
+            + num::cast::AsPrimitive<usize>
+            + num::traits::ConstOne
+            + num::traits::ConstZero
+            + num::traits::NumOps
+            + num::traits::NumAssign
+            + std::cmp::PartialOrd
+            + std::convert::TryFrom<usize>
+            + num::Bounded
+            + std::hash::Hash
+            + Eq,
+        usize: num::traits::AsPrimitive<TI>,
+    {
+        let mut warnings = grammar.lint();
+        warnings.extend(grammar.lint_vocabulary_bytes(vocabulary));
+        warnings.sort();
+        for warning in warnings.iter() {
+            log::warn!("{warning}");
+        }
+        warnings
+    }
+
+    /// Get the warnings reported by [`Grammar::lint`] and [`Grammar::lint_vocabulary_bytes`] when the engine was created.
+    /// They are also logged with [`log::warn!`] at that time.
+    /// Empty unless [`Config::lint`] is enabled.
+    pub fn lint_warnings(&self) -> &[LintWarning] {
+        &self.lint_warnings
     }
 }
 
@@ -186,6 +434,232 @@ macro_rules! match_engine_union {
     }
 }
 
+impl Engine {
+    /// Replace the strings matched by the dynamic alternative `#dynamic"name"` and reset the engine.
+    ///
+    /// Only the regex standing for the dynamic alternative is recompiled, so this is much cheaper than creating a new engine.
+    /// The cache is cleared since the allowed tokens may change.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the dynamic alternative.
+    /// * `strings` - The strings to be matched. An empty slice makes the dynamic alternative match nothing again.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`SetDynamicError`] if the dynamic alternative does not exist, if any of the strings is empty,
+    /// or if the strings cannot be compiled into a regex supported by the engine.
+    /// The engine is not modified in this case.
+    pub fn set_dynamic(&mut self, name: &str, strings: &[&str]) -> Result<(), SetDynamicError> {
+        match_engine_union!(EngineBase::set_dynamic[&mut self.union, name, strings])
+    }
+
+    /// Get the penalty added to the logits of the disallowed tokens, as set by [EngineConfig::disallowed_token_penalty].
+    ///
+    /// `None` means the logits of the disallowed tokens are set to negative infinity.
+    pub fn disallowed_token_penalty(&self) -> Option<f32> {
+        match_engine_union!(EngineBase::disallowed_token_penalty[&self.union])
+    }
+
+    /// Find the shortest bytes that finish the grammar from the current state, along with the fewest tokens that spell them.
+    ///
+    /// It is intended for graceful truncation: the completion can be appended when the output budget runs out.
+    /// The engine state is not changed.
+    ///
+    /// # Returns
+    ///
+    /// The shortest completion. Its bytes are empty if the engine is already finished.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`ShortestCompletionError`] if the grammar can never be finished from the current state,
+    /// or the completion is not found within [MAX_COMPLETION_SEARCH_STATES](crate::engine_base::MAX_COMPLETION_SEARCH_STATES) search states.
+    pub fn shortest_completion(&self) -> Result<Completion, ShortestCompletionError> {
+        match_engine_union!(EngineBase::shortest_completion[&self.union])
+    }
+
+    /// Enumerate every token sequence that finishes the grammar from the current state.
+    ///
+    /// It is intended for classification-style grammars, whose completions can be scored all at once.
+    /// The sequences are found by a depth-first search over the allowed tokens that forks the state of the engine for every token,
+    /// so they are in lexicographic order of token ids, and different tokenizations of the same bytes are different sequences.
+    /// The search stops with [`EnumerateCompletionsError::InfiniteLanguage`] once it returns to the state of an earlier step that can still finish,
+    /// which requires [EngineConfig::compaction_enabled].
+    /// Recursion in the middle of a production never returns to the same state,
+    /// so `max_tokens` still caps the depth of the search and must exceed the longest completion.
+    /// The engine state is not changed.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_completions` - The maximum number of token sequences to enumerate.
+    /// * `max_tokens` - The maximum number of tokens in a token sequence.
+    ///
+    /// # Returns
+    ///
+    /// The token sequences. It only contains the empty sequence if the engine is already finished,
+    /// and it is empty if the grammar can never be finished from the current state.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`EnumerateCompletionsError`] if more than `max_completions` token sequences finish the grammar,
+    /// if infinitely many token sequences are found to finish the grammar,
+    /// if more than `max_tokens` tokens can be accepted without finishing,
+    /// or if a lazy DFA cache is too small to check every token sequence.
+    pub fn enumerate_completions(
+        &self,
+        max_completions: usize,
+        max_tokens: usize,
+    ) -> Result<Vec<Vec<u32>>, EnumerateCompletionsError> {
+        if self.is_finished() {
+            return Ok(vec![Vec::new()]);
+        }
+        let mut completions = Vec::new();
+        let engine = self.fork(true);
+        let table = engine.completion_table();
+        Self::enumerate_completions_from(
+            &mut vec![engine],
+            &table,
+            &mut Vec::new(),
+            &mut completions,
+            max_completions,
+            max_tokens,
+        )?;
+        Ok(completions)
+    }
+
+    /// Enumerates the completions from the last engine of `path`, which holds the engines after every token in `token_ids`.
+    ///
+    /// The cache of allowed token IDs moves along the path, so it is shared by every engine of the search.
+    fn enumerate_completions_from(
+        path: &mut Vec<Self>,
+        table: &CompletionTable,
+        token_ids: &mut Vec<u32>,
+        completions: &mut Vec<Vec<u32>>,
+        max_completions: usize,
+        max_tokens: usize,
+    ) -> Result<(), EnumerateCompletionsError> {
+        let engine = path.last_mut().unwrap();
+        match engine.try_compute_allowed_token_ids() {
+            Ok(()) => {}
+            // Nothing finishes the grammar from a dead end or once the output limit is reached.
+            Err(
+                ComputeAllowedTokenIdsError::DeadEnd
+                | ComputeAllowedTokenIdsError::OutputLimitReached,
+            ) => return Ok(()),
+            Err(ComputeAllowedTokenIdsError::LazyDfaCacheFull) => {
+                return Err(EnumerateCompletionsError::LazyDfaCacheFull)
+            }
+        }
+        let allowed: Vec<usize> = engine
+            .allowed_token_ids_from_last_computation()
+            .ones()
+            .collect();
+        if !allowed.is_empty() && token_ids.len() == max_tokens {
+            return Err(EnumerateCompletionsError::DepthLimitExceeded(max_tokens));
+        }
+        for token_id in allowed {
+            let mut next = path.last().unwrap().fork(false);
+            let result = match next.try_accept_new_token(token_id as u32) {
+                Ok(result) => result,
+                Err(AcceptTokenError::LazyDfaCacheFull) => {
+                    return Err(EnumerateCompletionsError::LazyDfaCacheFull)
+                }
+                Err(_) => continue,
+            };
+            match result {
+                AcceptTokenResult::Finished => {
+                    if completions.len() == max_completions {
+                        return Err(EnumerateCompletionsError::TooManyCompletions(
+                            max_completions,
+                        ));
+                    }
+                    token_ids.push(token_id as u32);
+                    completions.push(token_ids.clone());
+                    token_ids.pop();
+                }
+                AcceptTokenResult::Ongoing => {
+                    // Every completion from a repeated state can be preceded by any number of repetitions.
+                    // A repeated state that cannot finish has nothing to enumerate.
+                    if path.iter().any(|x| x.has_same_state(&next)) {
+                        if next.can_finish(table) {
+                            return Err(EnumerateCompletionsError::InfiniteLanguage);
+                        }
+                        continue;
+                    }
+                    path.last_mut().unwrap().move_cache_to(&mut next);
+                    path.push(next);
+                    token_ids.push(token_id as u32);
+                    let result = Self::enumerate_completions_from(
+                        path,
+                        table,
+                        token_ids,
+                        completions,
+                        max_completions,
+                        max_tokens,
+                    );
+                    token_ids.pop();
+                    let mut next = path.pop().unwrap();
+                    next.move_cache_to(path.last_mut().unwrap());
+                    result?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the engine is in the same state as the other engine forked from the same engine.
+    fn has_same_state(&self, other: &Self) -> bool {
+        match (&self.union, &other.union) {
+            (EngineUnion::U8U8U8U8U32(x), EngineUnion::U8U8U8U8U32(y)) => x.has_same_state(y),
+            (EngineUnion::U8U8U16U16U16(x), EngineUnion::U8U8U16U16U16(y)) => x.has_same_state(y),
+            (EngineUnion::U16U16U32U32U32(x), EngineUnion::U16U16U32U32U32(y)) => {
+                x.has_same_state(y)
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves the cache of allowed token IDs to the other engine forked from the same engine.
+    fn move_cache_to(&mut self, other: &mut Self) {
+        match (&mut self.union, &mut other.union) {
+            (EngineUnion::U8U8U8U8U32(x), EngineUnion::U8U8U8U8U32(y)) => x.move_cache_to(y),
+            (EngineUnion::U8U8U16U16U16(x), EngineUnion::U8U8U16U16U16(y)) => x.move_cache_to(y),
+            (EngineUnion::U16U16U32U32U32(x), EngineUnion::U16U16U32U32U32(y)) => {
+                x.move_cache_to(y)
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the [CompletionTable] of the grammar, which is built if the engine does not keep it.
+    fn completion_table(&self) -> Arc<CompletionTable> {
+        match_engine_union!(EngineBase::completion_table[&self.union])
+    }
+
+    /// Whether the grammar can still be finished from the current state according to the table from [Engine::completion_table].
+    fn can_finish(&mut self, table: &CompletionTable) -> bool {
+        match_engine_union!(EngineBase::can_finish[&mut self.union, table])
+    }
+
+    /// Clones the state of the engine without its lint warnings.
+    /// The cache of allowed token IDs is only cloned if `with_cache` is true.
+    fn fork(&self, with_cache: bool) -> Self {
+        let union = match &self.union {
+            EngineUnion::U8U8U8U8U32(engine) => EngineUnion::U8U8U8U8U32(engine.fork(with_cache)),
+            EngineUnion::U8U8U16U16U16(engine) => {
+                EngineUnion::U8U8U16U16U16(engine.fork(with_cache))
+            }
+            EngineUnion::U16U16U32U32U32(engine) => {
+                EngineUnion::U16U16U32U32U32(engine.fork(with_cache))
+            }
+        };
+        Self {
+            union,
+            lint_warnings: Vec::new(),
+        }
+    }
+}
+
 impl crate::engine_like::sealed::Sealed for Engine {}
 
 impl EngineLike for Engine {
@@ -211,6 +685,44 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::mask_logits[&self.union, logits])
     }
 
+    fn mask_logits_f16(
+        &self,
+        logits: &mut [half::f16],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_f16[&self.union, logits])
+    }
+
+    fn mask_logits_bf16(
+        &self,
+        logits: &mut [half::bf16],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_bf16[&self.union, logits])
+    }
+
+    fn mask_logits_2d(
+        &self,
+        logits: &mut [f32],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_2d[&self.union, logits, row_stride])
+    }
+
+    fn mask_logits_2d_f16(
+        &self,
+        logits: &mut [half::f16],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_2d_f16[&self.union, logits, row_stride])
+    }
+
+    fn mask_logits_2d_bf16(
+        &self,
+        logits: &mut [half::bf16],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        match_engine_union!(EngineLike::mask_logits_2d_bf16[&self.union, logits, row_stride])
+    }
+
     fn update_logits(
         &mut self,
         token_id: u32,
@@ -223,6 +735,20 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::allowed_token_ids_from_last_computation[&self.union])
     }
 
+    fn allowed_token_ids_error_from_last_computation(
+        &self,
+    ) -> Option<crate::engine_like::ComputeAllowedTokenIdsError> {
+        match_engine_union!(EngineLike::allowed_token_ids_error_from_last_computation[&self.union])
+    }
+
+    fn token_bonuses_from_last_computation(&self) -> &[(usize, f32)] {
+        match_engine_union!(EngineLike::token_bonuses_from_last_computation[&self.union])
+    }
+
+    fn has_positive_bonuses(&self) -> bool {
+        match_engine_union!(EngineLike::has_positive_bonuses[&self.union])
+    }
+
     fn write_disallowed_token_ids_to_buffer(
         &self,
         buffer: &mut [usize],
@@ -237,6 +763,20 @@ impl EngineLike for Engine {
         match_engine_union!(EngineLike::write_allowed_token_ids_to_buffer[&self.union, buffer])
     }
 
+    fn write_allowed_bitmask(
+        &self,
+        buffer: &mut [u32],
+    ) -> Result<(), crate::engine_like::WriteBufferError> {
+        match_engine_union!(EngineLike::write_allowed_bitmask[&self.union, buffer])
+    }
+
+    fn write_allowed_bitmask_u64(
+        &self,
+        buffer: &mut [u64],
+    ) -> Result<(), crate::engine_like::WriteBufferError> {
+        match_engine_union!(EngineLike::write_allowed_bitmask_u64[&self.union, buffer])
+    }
+
     fn is_finished(&self) -> bool {
         match_engine_union!(EngineLike::is_finished[&self.union])
     }
@@ -5,32 +5,45 @@ use jaggedarray::jagged_array::JaggedArray;
 use jaggedarray::jagged_array::JaggedArrayViewTrait;
 use jaggedarray::JaggedArrayMutViewTrait;
 use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::hybrid::{CacheError, LazyStateID};
 use kbnf_regex_automata::util::primitives::StateID;
-use kbnf_syntax::regex::FiniteStateAutomaton;
 use num::{
     cast::AsPrimitive,
     traits::{ConstOne, ConstZero, NumAssign, NumOps},
     Num,
 };
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fmt::Debug;
 use std::hint::unreachable_unchecked;
 use std::sync::Arc;
 
-use crate::engine::EngineConfig;
+use crate::completion::{Completion, CompletionTable, ShortestCompletionError};
+use crate::engine::{EngineConfig, Penalty};
+use crate::engine_like::ComputeAllowedTokenIdsError;
 use crate::engine_like::EngineLike;
 use crate::engine_like::WriteBufferError;
-use crate::grammar::RegexType;
+use crate::grammar::{IntersectionRole, RegexType, SetDynamicError};
+use crate::regex::{FiniteStateAutomaton, LazyDfaCaches, LAZY_STATE_ID_UPPER_BOUND};
 use crate::utils;
 use crate::utils::dispatch_by_dfa_state_status;
-use crate::utils::ByteSet;
+use crate::utils::{ByteSet, FsaStateStatus};
 use crate::vocabulary::TokenIterItem;
 use crate::AcceptTokenResult;
 use crate::{
-    grammar::{Grammar, HIRNode, NonterminalID},
+    grammar::{Grammar, HIRNode, NonterminalID, RegexID},
     vocabulary::Vocabulary,
 };
 type EarleySets<TN, TD, TP, TSP, TS> = JaggedArray<EarleyItem<TN, TD, TP, TSP, TS>, Vec<usize>, 2>;
+/// The fewest bytes needed to finish the grammar after each nonterminal completes at each Earley set,
+/// indexed by the Earley sets.
+///
+/// An Earley set does not change while the later Earley sets depend on it, so its lengths are computed once.
+/// They are removed whenever an Earley set is created or moved at or before its index.
+type ColumnLengths<TN> = Vec<Option<AHashMap<NonterminalID<TN>, usize>>>;
 const USIZE_WIDTH: usize = std::mem::size_of::<usize>();
+/// The maximum number of states visited by [EngineBase::shortest_completion] before it gives up.
+pub const MAX_COMPLETION_SEARCH_STATES: usize = 4096;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 struct EarleyItem<TN, TD, TP, TSP, TS>
 where
@@ -118,6 +131,20 @@ where
                             )
                         )
                     }
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        // The cache of the engine cannot be modified while formatting, so a copy is used.
+                        let mut cache = engine.lazy_dfa_caches.get_cloned(id.0.as_(), dfa);
+                        format!(
+                            "[{}({})]",
+                            self.state_id.as_(),
+                            dfa.state_status(
+                                &mut cache,
+                                EngineBase::<TN, TD, TP, TSP, TS>::from_state_id_to_lazy_dfa_state_id(
+                                    self.state_id
+                                )
+                            )
+                        )
+                    }
                 },
                 HIRNode::Nonterminal(_) => String::new(),
                 HIRNode::Substrings(_) => {
@@ -351,8 +378,15 @@ where
     grammar: Arc<Grammar<TI>>,
     allowed_first_bytes: ByteSet,
     allowed_token_ids: FixedBitSet,
+    allowed_token_ids_error: Option<ComputeAllowedTokenIdsError>,
+    // Only computed when the grammar has bonuses.
+    token_bonuses: Vec<(usize, f32)>,
     earley_sets: EarleySets<TI, TD, TP, TSP, TS>,
-    cache: AHashMap<EarleySets<TI, TD, TP, TSP, TS>, FixedBitSet>,
+    // The allowed tokens keyed by the Earley sets,
+    // and then by the remaining output bytes and whether the next token must finish the grammar.
+    cache: AHashMap<EarleySets<TI, TD, TP, TSP, TS>, AHashMap<(Option<usize>, bool), FixedBitSet>>,
+    // The lazy DFA states in the Earley sets and the cache above refer to these caches.
+    lazy_dfa_caches: LazyDfaCaches,
     to_be_completed_items: AHashSet<ToBeCompletedItem<TI, TSP>>,
     to_be_completed_items_buffer: AHashSet<ToBeCompletedItem<TI, TSP>>,
     deduplication_buffer: AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
@@ -368,6 +402,13 @@ where
     already_predicted_nonterminals: FixedBitSet,
     finished: bool,
     config: EngineConfig,
+    // Only computed when the output length is limited.
+    completion_table: Option<Arc<CompletionTable>>,
+    // The lengths after the completions at the Earley sets, see [ColumnLengths].
+    column_lengths: ColumnLengths<TI>,
+    output_bytes: usize,
+    output_tokens: usize,
+    max_token_length: usize,
 }
 
 impl<TI, TD, TP, TSP, TS> Debug for EngineBase<TI, TD, TP, TSP, TS>
@@ -412,11 +453,20 @@ where
             .field(
                 "cache",
                 &utils::get_deterministic_display_form_from_hash_map(&self.cache, |(k, v)| {
+                    // The entries for different remaining output bytes are listed in that order.
+                    let mut limits: Vec<_> = v.keys().copied().collect();
+                    limits.sort_unstable();
                     (
                         self.get_display_form_from_earley_sets(k),
-                        (self.get_display_form_from_token_ids(v),),
+                        limits
+                            .iter()
+                            .map(|x| self.get_display_form_from_token_ids(&v[x]))
+                            .collect::<Vec<_>>(),
                     )
-                }),
+                })
+                .into_iter()
+                .flat_map(|(k, v)| v.into_iter().map(move |x| (k.clone(), (x,))))
+                .collect::<Vec<_>>(),
             )
             .field("to_be_completed_items", {
                 &utils::get_deterministic_display_form_from_hash_set(
@@ -560,13 +610,20 @@ where
         let already_predicted_nonterminals =
             FixedBitSet::with_capacity(grammar.nonterminals_size());
         let postdot_items = AHashMap::default();
+        let completion_table = (config.max_output_bytes.is_some()
+            || config.max_output_tokens.is_some())
+        .then(|| Arc::new(CompletionTable::new(&grammar)));
+        let max_token_length = vocabulary.max_token_length();
         let mut engine = Self {
             vocabulary,
             grammar,
             allowed_first_bytes,
             allowed_token_ids,
+            allowed_token_ids_error: None,
+            token_bonuses: Vec::new(),
             earley_sets,
             cache,
+            lazy_dfa_caches: LazyDfaCaches::default(),
             to_be_completed_items,
             already_predicted_nonterminals,
             config,
@@ -578,11 +635,217 @@ where
             postdot_items_since_last_commit: AHashSet::default(),
             deduplication_buffer: AHashSet::default(),
             column_to_postdot_nonterminals: AHashMap::default(),
+            completion_table,
+            column_lengths: Vec::new(),
+            output_bytes: 0,
+            output_tokens: 0,
+            max_token_length,
         };
         engine.reset();
         Ok(engine)
     }
 
+    /// Replace the strings matched by the dynamic alternative `#dynamic"name"` and reset the engine.
+    ///
+    /// The grammar is cloned first if it is shared with other engines, which only copies its regexes and caches
+    /// by reference. The cache is cleared.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the dynamic alternative.
+    /// * `strings` - The strings to be matched. An empty slice makes the dynamic alternative match nothing again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dynamic alternative does not exist, if any of the strings is empty,
+    /// or if the strings cannot be compiled into a regex that fits in StateID(TS).
+    /// The engine is not modified in this case.
+    pub fn set_dynamic(&mut self, name: &str, strings: &[&str]) -> Result<(), SetDynamicError> {
+        let (regex_id, fsa) = self.grammar.compile_dynamic(name, strings)?;
+        if let Some((len, max)) = Self::regex_too_large(&fsa) {
+            return Err(SetDynamicError::RegexTooLarge(len, max));
+        }
+        Arc::make_mut(&mut self.grammar).replace_regex(regex_id, fsa, &self.vocabulary);
+        self.lazy_dfa_caches.remove(regex_id.0.as_());
+        if self.completion_table.is_some() {
+            self.completion_table = Some(Arc::new(CompletionTable::new(&self.grammar)));
+        }
+        self.cache.clear();
+        self.reset();
+        Ok(())
+    }
+
+    /// Get the penalty added to the logits of the disallowed tokens, as set by [EngineConfig::disallowed_token_penalty].
+    ///
+    /// `None` means the logits of the disallowed tokens are set to negative infinity.
+    pub fn disallowed_token_penalty(&self) -> Option<f32> {
+        self.config.disallowed_token_penalty.map(Penalty::value)
+    }
+
+    /// Find the shortest bytes that finish the grammar from the current state.
+    ///
+    /// The search is guided by the fewest bytes needed to finish every Earley item,
+    /// which are precomputed over the nonterminals, regexes and suffix automata of the grammar.
+    /// The guidance is exact unless the grammar contains intersections,
+    /// in which case more states may be visited before the shortest bytes are found.
+    /// The search runs on a fork of the engine, so the engine state is not changed.
+    ///
+    /// # Returns
+    ///
+    /// The shortest bytes along with the fewest tokens that spell them.
+    /// The bytes are empty if the engine is already finished.
+    ///
+    /// # Errors
+    ///
+    /// Returns [ShortestCompletionError::Unfinishable] if the grammar can never be finished from the current state,
+    /// or [ShortestCompletionError::SearchLimitExceeded] if the bytes are not found within [MAX_COMPLETION_SEARCH_STATES] states.
+    pub fn shortest_completion(&self) -> Result<Completion, ShortestCompletionError> {
+        if self.finished {
+            return Ok(Completion::new(Vec::new(), &self.vocabulary));
+        }
+        self.fork(false).search_shortest_completion()
+    }
+
+    /// Get the [CompletionTable] of the engine, or build one if the engine does not keep it.
+    pub(crate) fn completion_table(&self) -> Arc<CompletionTable> {
+        self.completion_table
+            .clone()
+            .unwrap_or_else(|| Arc::new(CompletionTable::new(&self.grammar)))
+    }
+
+    /// Searches for the shortest completion, leaving the engine in an intermediate state of the search.
+    fn search_shortest_completion(&mut self) -> Result<Completion, ShortestCompletionError> {
+        let table = self.completion_table();
+        let original_earley_set_len = self.earley_sets.len();
+        let length = Self::fewest_bytes_to_finish(
+            &self.grammar,
+            &mut self.lazy_dfa_caches,
+            &table,
+            &self.earley_sets,
+            &self.leo_items,
+            &mut self.column_lengths,
+        );
+        if length == usize::MAX {
+            return Err(ShortestCompletionError::Unfinishable);
+        }
+        // A* search ordered by the estimated total length, preferring the deeper states on ties
+        let mut heap = BinaryHeap::from([(Reverse(length), 0usize, Reverse(Vec::<u8>::new()))]);
+        let mut visited_states = 0;
+        while let Some((_, depth, Reverse(bytes))) = heap.pop() {
+            let mut accepted = true;
+            for byte in bytes.iter().copied() {
+                if Self::accept_byte(
+                    &self.grammar,
+                    &mut self.lazy_dfa_caches,
+                    &mut self.earley_sets,
+                    &mut self.to_be_completed_items,
+                    &mut self.to_be_completed_items_buffer,
+                    &mut self.leo_items,
+                    &mut self.leo_items_buffer,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    |_| {},
+                    |_| {},
+                    &mut self.already_predicted_nonterminals,
+                    &mut self.deduplication_buffer,
+                    original_earley_set_len,
+                    &mut self.finished,
+                    |_, _, _| {},
+                    &mut self.column_lengths,
+                    None,
+                    byte,
+                )
+                .is_err()
+                {
+                    accepted = false;
+                    break;
+                }
+            }
+            if !accepted {
+                continue;
+            }
+            if self.finished {
+                return Ok(Completion::new(bytes, &self.vocabulary));
+            }
+            visited_states += 1;
+            if visited_states > MAX_COMPLETION_SEARCH_STATES {
+                return Err(ShortestCompletionError::SearchLimitExceeded(
+                    MAX_COMPLETION_SEARCH_STATES,
+                ));
+            }
+            let mut staged_changes = StagedChanges {
+                earley_sets_len_since_last_commit: original_earley_set_len,
+                postdot_items_since_last_commit: self.postdot_items_since_last_commit.clone(),
+            };
+            Self::commit_change(&mut self.postdot_items_since_last_commit);
+            let len = self.earley_sets.len();
+            self.update_allowed_first_bytes();
+            for byte in self.allowed_first_bytes.ones() {
+                if Self::accept_byte(
+                    &self.grammar,
+                    &mut self.lazy_dfa_caches,
+                    &mut self.earley_sets,
+                    &mut self.to_be_completed_items,
+                    &mut self.to_be_completed_items_buffer,
+                    &mut self.leo_items,
+                    &mut self.leo_items_buffer,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    |_| {},
+                    |_| {},
+                    &mut self.already_predicted_nonterminals,
+                    &mut self.deduplication_buffer,
+                    len,
+                    &mut self.finished,
+                    |_, _, _| {},
+                    &mut self.column_lengths,
+                    None,
+                    byte as u8,
+                )
+                .is_err()
+                {
+                    continue;
+                }
+                let length = if self.finished {
+                    0
+                } else {
+                    Self::fewest_bytes_to_finish(
+                        &self.grammar,
+                        &mut self.lazy_dfa_caches,
+                        &table,
+                        &self.earley_sets,
+                        &self.leo_items,
+                        &mut self.column_lengths,
+                    )
+                };
+                Self::revert_change(
+                    &mut self.earley_sets,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    &mut self.leo_items,
+                    |_| {},
+                    len,
+                    &mut self.finished,
+                );
+                if length != usize::MAX {
+                    let mut bytes = bytes.clone();
+                    bytes.push(byte as u8);
+                    heap.push((Reverse(depth + 1 + length), depth + 1, Reverse(bytes)));
+                }
+            }
+            Self::revert_change(
+                &mut self.earley_sets,
+                &mut self.postdot_items,
+                &mut staged_changes.postdot_items_since_last_commit,
+                &mut self.leo_items,
+                |_| {},
+                staged_changes.earley_sets_len_since_last_commit,
+                &mut self.finished,
+            );
+        }
+        Err(ShortestCompletionError::Unfinishable)
+    }
+
     fn get_display_form_from_earley_sets(
         &self,
         sets: &EarleySets<TI, TD, TP, TSP, TS>,
@@ -621,20 +884,24 @@ where
     }
 
     fn validate_ts_size_for_regexes(grammar: &Grammar<TI>) -> Result<(), CreateEngineBaseError> {
-        let regexes = grammar.id_to_regexes();
-        let max: usize = 2usize.saturating_pow(Self::STATE_ID_TYPE_BIT) - 1;
-        for fsa in regexes {
-            match fsa {
-                FiniteStateAutomaton::Dfa(dfa) => {
-                    if dfa.state_len() > max {
-                        return Err(CreateEngineBaseError::RegexTooLarge(dfa.state_len(), max));
-                    }
-                }
+        for fsa in grammar.id_to_regexes() {
+            if let Some((len, max)) = Self::regex_too_large(fsa) {
+                return Err(CreateEngineBaseError::RegexTooLarge(len, max));
             }
         }
         Ok(())
     }
 
+    /// Returns the regex length and the maximum length allowed by StateID(TS) if the former exceeds the latter.
+    fn regex_too_large(fsa: &FiniteStateAutomaton) -> Option<(usize, usize)> {
+        let max: usize = 2usize.saturating_pow(Self::STATE_ID_TYPE_BIT) - 1;
+        let len = match fsa {
+            FiniteStateAutomaton::Dfa(dfa) => dfa.state_len(),
+            FiniteStateAutomaton::LazyDfa(_) => LAZY_STATE_ID_UPPER_BOUND,
+        };
+        (len > max).then_some((len, max))
+    }
+
     fn validate_ts_size_for_suffix_automata(
         grammar: &Grammar<TI>,
     ) -> Result<(), CreateEngineBaseError> {
@@ -699,6 +966,9 @@ where
                         };
                         Self::from_dfa_state_id_to_state_id(start, dfa.stride2())
                     }
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        Self::from_lazy_dfa_state_id_to_state_id(dfa.anchored_start_state())
+                    }
                 }
             }
             HIRNode::RegexComplement(regex_id) => {
@@ -706,14 +976,18 @@ where
                 match fsa {
                     FiniteStateAutomaton::Dfa(dfa) => {
                         // SAFETY: start_error will not happen since that will result in an error in Grammar::new() method
-                        let start = unsafe{dfa
-                            .start_state(
+                        let start = unsafe {
+                            dfa.start_state(
                                 &kbnf_regex_automata::util::start::Config::new()
                                     .anchored(kbnf_regex_automata::Anchored::No),
                             )
-                            .unwrap_unchecked()};
+                            .unwrap_unchecked()
+                        };
                         Self::from_dfa_state_id_to_state_id(start, dfa.stride2())
                     }
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        Self::from_lazy_dfa_state_id_to_state_id(dfa.unanchored_start_state())
+                    }
                 }
             }
             HIRNode::Substrings(_) => {
@@ -767,135 +1041,662 @@ where
         let earley_set_index = self.earley_sets.len() - 1;
         let earley_set = self.earley_sets.view::<1, 1>([earley_set_index]).as_slice();
         for item in earley_set.iter().copied() {
-            let node = *self.grammar.node(
-                item.nonterminal_id,
-                item.dot_position,
-                item.production_index,
+            Self::add_first_bytes(
+                &self.grammar,
+                &mut self.lazy_dfa_caches,
+                item,
+                &mut self.allowed_first_bytes,
             );
-            match node {
-                HIRNode::Terminal(terminal_id) => {
-                    self.allowed_first_bytes
-                        .insert(self.grammar.terminal(terminal_id)[item.state_id.as_()].as_());
-                }
-                HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id) => {
-                    if let Some(first_bytes) = self.grammar.first_bytes_from_regex(
-                        regex_id,
-                        Self::from_state_id_to_dfa_state_id(
-                            item.state_id,
-                            match self.grammar.regex(regex_id) {
-                                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
-                            },
-                        ),
-                    ) {
-                        self.allowed_first_bytes.union_with(first_bytes);
+        }
+    }
+
+    /// Adds the bytes the Earley item can scan next to `first_bytes`.
+    fn add_first_bytes(
+        grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
+        item: EarleyItem<TI, TD, TP, TSP, TS>,
+        first_bytes: &mut ByteSet,
+    ) {
+        let node = *grammar.node(
+            item.nonterminal_id,
+            item.dot_position,
+            item.production_index,
+        );
+        match node {
+            HIRNode::Terminal(terminal_id) => {
+                first_bytes.insert(grammar.terminal(terminal_id)[item.state_id.as_()].as_());
+            }
+            HIRNode::RegexString(regex_id) | HIRNode::EarlyEndRegexString(regex_id) => {
+                match grammar.regex(regex_id) {
+                    FiniteStateAutomaton::Dfa(dfa) => {
+                        if let Some(bytes) = grammar.first_bytes_from_regex(
+                            regex_id,
+                            Self::from_state_id_to_dfa_state_id(item.state_id, dfa.stride2()),
+                        ) {
+                            first_bytes.union_with(bytes);
+                        }
+                    }
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        first_bytes.union_with(&dfa.first_bytes(
+                            lazy_dfa_caches.get(regex_id.0.as_(), dfa),
+                            Self::from_state_id_to_lazy_dfa_state_id(item.state_id),
+                            false,
+                        ));
                     }
                 }
-                HIRNode::RegexComplement(regex_id) => {
-                    if let Some(first_bytes) = self.grammar.complement_first_bytes_from_regex(
+            }
+            HIRNode::RegexComplement(regex_id) => match grammar.regex(regex_id) {
+                FiniteStateAutomaton::Dfa(dfa) => {
+                    if let Some(bytes) = grammar.complement_first_bytes_from_regex(
                         regex_id,
-                        Self::from_state_id_to_dfa_state_id(
-                            item.state_id,
-                            match self.grammar.regex(regex_id) {
-                                FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
-                            },
-                        ),
+                        Self::from_state_id_to_dfa_state_id(item.state_id, dfa.stride2()),
                     ) {
-                        self.allowed_first_bytes.union_with(first_bytes);
+                        first_bytes.union_with(bytes);
                     }
                 }
-                HIRNode::Substrings(_) => {
-                    let first_bytes = self
-                        .grammar
-                        .first_bytes_from_suffix_automaton(item.state_id.as_());
-                    self.allowed_first_bytes.union_with(first_bytes);
+                FiniteStateAutomaton::LazyDfa(dfa) => {
+                    first_bytes.union_with(&dfa.first_bytes(
+                        lazy_dfa_caches.get(regex_id.0.as_(), dfa),
+                        Self::from_state_id_to_lazy_dfa_state_id(item.state_id),
+                        true,
+                    ));
                 }
-                _ => {}
-            }
-        }
-    }
-    #[inline]
-    fn item_should_be_completed(
-        grammar: &Grammar<TI>,
-        nonterminal_id: NonterminalID<TI>,
-        new_dot_position: TD,
-        production_id: TP,
-    ) -> bool
-    where
-        TP: Num + AsPrimitive<usize> + ConstOne + ConstZero,
-        TD: Num + AsPrimitive<usize> + ConstOne + ConstZero,
-    {
-        // SAFETY: nonterminal_id is guaranteed to be valid since it always comes from the grammar, in other words, the jagged array.
-        let view = unsafe { grammar.dotted_productions(nonterminal_id) };
-        let new_dot_position = new_dot_position.as_();
-        if new_dot_position < view.len() {
-            // SAFETY: new_dot_position is guaranteed to be valid since we checked it in the previous line
-            let view = unsafe { view.view_unchecked::<1, 1>([new_dot_position]) };
-            if production_id.as_() < view.len() {
-                return false;
+            },
+            HIRNode::Substrings(_) => {
+                first_bytes
+                    .union_with(grammar.first_bytes_from_suffix_automaton(item.state_id.as_()));
             }
+            _ => {}
         }
-        true
     }
 
-    fn advance_item<T>(
-        grammar: &Grammar<TI>,
-        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
-        add_to_earley_set: T,
-        mut item: EarleyItem<TI, TD, TP, TSP, TS>,
-    ) where
-        T: FnOnce(EarleyItem<TI, TD, TP, TSP, TS>),
-    {
-        let new_dotted_position = item.dot_position + TD::ONE;
-        if !Self::item_should_be_completed(
-            grammar,
-            item.nonterminal_id,
-            new_dotted_position,
-            item.production_index,
-        ) {
-            item.dot_position = new_dotted_position;
-            item.state_id = Self::initialize_state_id_based_on_node(
-                grammar,
-                // SAFETY:
-                // nonterminal_id is guaranteed to be valid since it always comes from the grammar, in other words, the jagged array.
-                // dot_position is guaranteed to be valid since we checked it in Self::item_should_be_completed
-                // production_index is guaranteed to be valid since we checked it in Self::item_should_be_completed
-                unsafe {
-                    *grammar.node_unchecked(
-                        item.nonterminal_id,
-                        new_dotted_position,
-                        item.production_index,
-                    )
-                },
+    /// Computes the bonus of each Earley item of the last Earley set that can scan a byte, or `None` for the other items,
+    /// along with the distinct bonuses of the items that can scan each byte.
+    ///
+    /// Only the items predicted in the last Earley set can start a nonterminal with a bonus,
+    /// so the items started in an earlier Earley set get no bonus.
+    fn first_byte_bonuses(&mut self) -> (Vec<Option<f32>>, Vec<Vec<f32>>) {
+        let mut bonuses = vec![Vec::new(); 256];
+        let mut item_bonuses = Vec::new();
+        let mut memo = AHashMap::default();
+        let mut first_bytes = ByteSet::with_capacity(256);
+        let earley_set_index = self.earley_sets.len() - 1;
+        let earley_set = self.earley_sets.view::<1, 1>([earley_set_index]).as_slice();
+        for item in earley_set.iter().copied() {
+            first_bytes.clear();
+            Self::add_first_bytes(
+                &self.grammar,
+                &mut self.lazy_dfa_caches,
+                item,
+                &mut first_bytes,
             );
-            add_to_earley_set(item);
-        } else {
-            to_be_completed_items.insert(ToBeCompletedItem {
-                nonterminal_id: item.nonterminal_id,
-                start_position: item.start_position,
-            });
+            if first_bytes.is_clear() {
+                item_bonuses.push(None);
+                continue;
+            }
+            let bonus = if item.start_position.as_() == earley_set_index {
+                self.context_bonus(
+                    Dotted {
+                        postdot_nonterminal_id: item.nonterminal_id,
+                        column: item.start_position,
+                    },
+                    &mut memo,
+                )
+            } else {
+                0.0
+            };
+            item_bonuses.push(Some(bonus));
+            for byte in first_bytes.ones() {
+                if !bonuses[byte].contains(&bonus) {
+                    bonuses[byte].push(bonus);
+                }
+            }
         }
+        (item_bonuses, bonuses)
     }
 
-    #[inline]
-    /// # Safety
+    /// Whether the token is accepted when its first byte is only scanned by the Earley items with `bonus`.
     ///
-    /// earley_sets must has enough capacity to push one new item.
-    unsafe fn advance_item_normal_unchecked(
-        grammar: &Grammar<TI>,
-        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
-        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
-        item: EarleyItem<TI, TD, TP, TSP, TS>,
-    ) {
-        Self::advance_item(
-            grammar,
-            to_be_completed_items,
-            |new_item| {
-                earley_sets.push_to_last_row_unchecked(new_item);
-            },
-            item,
+    /// The other items that can scan a byte are removed from the last Earley set during the check,
+    /// so the token must be accepted through the items with `bonus`, including the bytes after they are completed.
+    fn accepts_token_with_bonus(
+        &mut self,
+        item_bonuses: &[Option<f32>],
+        bonus: f32,
+        token: &[u8],
+    ) -> bool {
+        let earley_set_index = self.earley_sets.len() - 1;
+        let original = self
+            .earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .to_vec();
+        self.replace_last_earley_set(
+            original
+                .iter()
+                .zip(item_bonuses)
+                .filter(|(_, x)| x.is_none_or(|x| x == bonus))
+                .map(|(item, _)| *item),
         );
-    }
-
+        let len = self.earley_sets.len();
+        let remaining_bytes = self.remaining_bytes();
+        let mut accepted = true;
+        for (depth, byte) in token.iter().copied().enumerate() {
+            if Self::accept_byte(
+                &self.grammar,
+                &mut self.lazy_dfa_caches,
+                &mut self.earley_sets,
+                &mut self.to_be_completed_items,
+                &mut self.to_be_completed_items_buffer,
+                &mut self.leo_items,
+                &mut self.leo_items_buffer,
+                &mut self.postdot_items,
+                &mut self.postdot_items_since_last_commit,
+                |_| {},
+                |_| {},
+                &mut self.already_predicted_nonterminals,
+                &mut self.deduplication_buffer,
+                len,
+                &mut self.finished,
+                |_, _, _| {},
+                &mut self.column_lengths,
+                self.completion_table
+                    .as_deref()
+                    .zip(remaining_bytes.map(|x| x.saturating_sub(depth))),
+                byte,
+            )
+            .is_err()
+            {
+                accepted = false;
+                break;
+            }
+        }
+        if accepted {
+            Self::revert_change(
+                &mut self.earley_sets,
+                &mut self.postdot_items,
+                &mut self.postdot_items_since_last_commit,
+                &mut self.leo_items,
+                |_| {},
+                len,
+                &mut self.finished,
+            );
+        }
+        self.replace_last_earley_set(original.into_iter());
+        accepted
+    }
+
+    /// Replaces the items of the last Earley set.
+    fn replace_last_earley_set(
+        &mut self,
+        items: impl Iterator<Item = EarleyItem<TI, TD, TP, TSP, TS>>,
+    ) {
+        let earley_set_index = self.earley_sets.len() - 1;
+        self.column_lengths.truncate(earley_set_index);
+        let len = self.earley_sets.view::<1, 1>([earley_set_index]).len();
+        for _ in 0..len {
+            self.earley_sets.pop_from_last_row();
+        }
+        for item in items {
+            self.earley_sets.push_to_last_row(item);
+        }
+    }
+
+    /// Computes the bonus of the Earley items of `dotted.postdot_nonterminal_id` starting at `dotted.column`,
+    /// which is the bonus of the innermost nonterminal with a bonus among the nonterminal and its ancestors
+    /// that also start at `dotted.column`.
+    ///
+    /// The ancestors are found through the postdot items, or the Leo items when the intermediate items are skipped.
+    /// An ancestor started in an earlier column is already past its first byte, so its bonus is not applied again.
+    /// The largest bonus is taken among the parents of an ambiguous parse.
+    fn context_bonus(
+        &self,
+        dotted: Dotted<TI, TSP>,
+        memo: &mut AHashMap<Dotted<TI, TSP>, f32>,
+    ) -> f32 {
+        let parents = |dotted: &Dotted<TI, TSP>| -> Vec<Dotted<TI, TSP>> {
+            let to_dotted = |item: &EarleyItem<TI, TD, TP, TSP, TS>| Dotted {
+                postdot_nonterminal_id: item.nonterminal_id,
+                column: item.start_position,
+            };
+            let parents: Vec<_> = match self.postdot_items.get(dotted) {
+                Some(PostDotItems::LeoEligible(item)) => vec![to_dotted(item)],
+                Some(PostDotItems::NormalItems(items)) => items.iter().map(to_dotted).collect(),
+                None => self
+                    .leo_items
+                    .get(dotted)
+                    .map(|item| Dotted {
+                        postdot_nonterminal_id: item.nonterminal_id,
+                        column: item.start_position,
+                    })
+                    .into_iter()
+                    .collect(),
+            };
+            parents
+                .into_iter()
+                .filter(|x| x.column == dotted.column)
+                .collect()
+        };
+        let mut stack = vec![(dotted, false)];
+        while let Some((current, expanded)) = stack.pop() {
+            let bonus = self
+                .grammar
+                .nonterminal_bonus(current.postdot_nonterminal_id);
+            if expanded {
+                let inherited = parents(&current)
+                    .iter()
+                    .filter_map(|x| memo.get(x).copied())
+                    .reduce(f32::max);
+                memo.insert(current, inherited.unwrap_or(0.0));
+                continue;
+            }
+            if memo.contains_key(&current) {
+                continue;
+            }
+            if bonus != 0.0 {
+                memo.insert(current, bonus);
+                continue;
+            }
+            // The provisional bonus breaks the cycles of nonterminals predicting each other in the same column.
+            memo.insert(current, 0.0);
+            stack.push((current, true));
+            stack.extend(
+                parents(&current)
+                    .into_iter()
+                    .filter(|x| !memo.contains_key(x))
+                    .map(|x| (x, false)),
+            );
+        }
+        memo[&dotted]
+    }
+
+    fn compute_allowed_token_ids_without_bonuses(
+        &mut self,
+    ) -> Result<(), ComputeAllowedTokenIdsError> {
+        self.allowed_token_ids.clear();
+        if self.is_finished() {
+            return Ok(());
+        }
+        if self
+            .config
+            .max_output_tokens
+            .is_some_and(|x| self.output_tokens >= x)
+        {
+            return Err(ComputeAllowedTokenIdsError::OutputLimitReached);
+        }
+        // The allowed tokens also depend on the output length when it is limited.
+        let table = self.completion_table.clone();
+        let remaining_bytes = self.remaining_bytes();
+        let must_finish = self.is_last_token();
+        let length_limit = |depth: usize| {
+            table
+                .as_deref()
+                .zip(remaining_bytes.map(|x| x.saturating_sub(depth)))
+        };
+        if self.config.cache_enabled {
+            if let Some(allowed_ids) = self
+                .cache
+                .get(&self.earley_sets)
+                .and_then(|x| x.get(&(remaining_bytes, must_finish)))
+            {
+                self.allowed_token_ids.union_with(allowed_ids);
+                return self.check_dead_end();
+            }
+        }
+        let mut eager_cache = false;
+        if self.grammar.has_eager_regex_cache() && table.is_none() {
+            eager_cache = self.add_tokens_from_eager_regex_cache();
+        }
+        let original_earley_set_len = self.earley_sets.len();
+        let mut cache_full = false;
+        self.update_allowed_first_bytes();
+        let mut invalid_next_bytes = ByteSet::with_capacity(256);
+        for byte in self.allowed_first_bytes.ones() {
+            invalid_next_bytes.clear();
+            // An allowed first byte can still be rejected by an intersection.
+            if Self::is_rejected_by(
+                Self::accept_byte(
+                    &self.grammar,
+                    &mut self.lazy_dfa_caches,
+                    &mut self.earley_sets,
+                    &mut self.to_be_completed_items,
+                    &mut self.to_be_completed_items_buffer,
+                    &mut self.leo_items,
+                    &mut self.leo_items_buffer,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    |_| {},
+                    |_| {},
+                    &mut self.already_predicted_nonterminals,
+                    &mut self.deduplication_buffer,
+                    original_earley_set_len,
+                    &mut self.finished,
+                    |_, _, _| {},
+                    &mut self.column_lengths,
+                    length_limit(0),
+                    byte as u8,
+                ),
+                &mut cache_full,
+            ) {
+                continue;
+            }
+            let mut staged_changes = StagedChanges {
+                earley_sets_len_since_last_commit: original_earley_set_len,
+                postdot_items_since_last_commit: self.postdot_items_since_last_commit.clone(),
+            };
+            let len = self.earley_sets.len();
+            // Reverting to the first byte clears the flag, so it is kept for the one-byte tokens.
+            let first_byte_finished = self.finished;
+            Self::commit_change(&mut self.postdot_items_since_last_commit);
+            let mut current_token_id: usize = usize::MAX;
+            let mut token_iter = self.vocabulary.normal_tokens_from_first_byte(byte as u8);
+            let mut rejected = true;
+            let mut accepted = false;
+            let mut second_byte_unseen = false;
+            while let Some(token_byte) = token_iter.next() {
+                match token_byte {
+                    TokenIterItem::TokenByte(token_byte) => {
+                        let token_byte = token_byte.get();
+                        if second_byte_unseen
+                        // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
+                            && unsafe { invalid_next_bytes.contains_unchecked(token_byte.into()) }
+                        {
+                            rejected = true;
+                            token_iter.next_token();
+                            continue;
+                        }
+                        let depth = self.earley_sets.len() - original_earley_set_len;
+                        if Self::is_rejected_by(
+                            Self::accept_byte(
+                                &self.grammar,
+                                &mut self.lazy_dfa_caches,
+                                &mut self.earley_sets,
+                                &mut self.to_be_completed_items,
+                                &mut self.to_be_completed_items_buffer,
+                                &mut self.leo_items,
+                                &mut self.leo_items_buffer,
+                                &mut self.postdot_items,
+                                &mut self.postdot_items_since_last_commit,
+                                |_| {},
+                                |_| {},
+                                &mut self.already_predicted_nonterminals,
+                                &mut self.deduplication_buffer,
+                                len,
+                                &mut self.finished,
+                                |_, _, _| {},
+                                &mut self.column_lengths,
+                                length_limit(depth),
+                                token_byte,
+                            ),
+                            &mut cache_full,
+                        )
+                        // The token is rejected
+                        {
+                            if second_byte_unseen {
+                                // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
+                                unsafe { invalid_next_bytes.insert_unchecked(token_byte.into()) };
+                            }
+                            rejected = true;
+                            token_iter.next_token();
+                        }
+                        second_byte_unseen = false;
+                    }
+                    TokenIterItem::NewToken => {
+                        // The token is accepted
+                        second_byte_unseen = true;
+                        let finished =
+                            self.finished || (first_byte_finished && self.earley_sets.len() == len);
+                        if !accepted && !rejected && (!must_finish || finished) {
+                            Self::revert_change(
+                                &mut self.earley_sets,
+                                &mut self.postdot_items,
+                                &mut self.postdot_items_since_last_commit,
+                                &mut self.leo_items,
+                                |_| {},
+                                len,
+                                &mut self.finished,
+                            );
+                            self.allowed_token_ids.insert(current_token_id);
+                        }
+                        current_token_id = token_iter.current_token_id();
+                        rejected = false;
+                        accepted = eager_cache && self.allowed_token_ids.contains(current_token_id);
+                        if accepted {
+                            token_iter.next_token();
+                        }
+                    }
+                }
+            }
+            // reach the end of the token iterator, revert the last token's change
+            let finished = self.finished || (first_byte_finished && self.earley_sets.len() == len);
+            Self::revert_change(
+                &mut self.earley_sets,
+                &mut self.postdot_items,
+                &mut self.postdot_items_since_last_commit,
+                &mut self.leo_items,
+                |_| {},
+                len,
+                &mut self.finished,
+            );
+            if !rejected && !accepted && (!must_finish || finished) {
+                self.allowed_token_ids.insert(current_token_id);
+            }
+            Self::revert_change(
+                &mut self.earley_sets,
+                &mut self.postdot_items,
+                &mut staged_changes.postdot_items_since_last_commit,
+                &mut self.leo_items,
+                |_| {},
+                staged_changes.earley_sets_len_since_last_commit,
+                &mut self.finished,
+            )
+        }
+        for (token_id, token) in self.vocabulary.tokens_containing_separators() {
+            let mut accepted = true;
+            for (depth, byte) in token.0.iter().copied().enumerate() {
+                if Self::is_rejected_by(
+                    Self::accept_byte(
+                        &self.grammar,
+                        &mut self.lazy_dfa_caches,
+                        &mut self.earley_sets,
+                        &mut self.to_be_completed_items,
+                        &mut self.to_be_completed_items_buffer,
+                        &mut self.leo_items,
+                        &mut self.leo_items_buffer,
+                        &mut self.postdot_items,
+                        &mut self.postdot_items_since_last_commit,
+                        |_| {},
+                        |_| {},
+                        &mut self.already_predicted_nonterminals,
+                        &mut self.deduplication_buffer,
+                        original_earley_set_len,
+                        &mut self.finished,
+                        |_, _, _| {},
+                        &mut self.column_lengths,
+                        length_limit(depth),
+                        byte,
+                    ),
+                    &mut cache_full,
+                )
+                // The token is rejected
+                {
+                    accepted = false;
+                    break;
+                }
+            }
+            if accepted {
+                if !must_finish || self.finished {
+                    self.allowed_token_ids.insert(token_id as usize);
+                }
+                Self::revert_change(
+                    &mut self.earley_sets,
+                    &mut self.postdot_items,
+                    &mut self.postdot_items_since_last_commit,
+                    &mut self.leo_items,
+                    |_| {},
+                    original_earley_set_len,
+                    &mut self.finished,
+                );
+            }
+        }
+        Self::commit_change(&mut self.postdot_items_since_last_commit);
+        if cache_full {
+            return Err(ComputeAllowedTokenIdsError::LazyDfaCacheFull);
+        }
+        if self.config.cache_enabled {
+            self.cache
+                .entry(self.earley_sets.clone())
+                .or_default()
+                .insert(
+                    (remaining_bytes, must_finish),
+                    self.allowed_token_ids.clone(),
+                );
+        }
+        self.check_dead_end()
+    }
+
+    /// Records the bonuses of the allowed tokens.
+    ///
+    /// The bonus of a token is the largest bonus among the Earley items of the last Earley set
+    /// that the token can be accepted through, i.e. the items that scan its first byte.
+    /// The token is only checked against the items when the items that can scan its first byte disagree.
+    fn update_token_bonuses(&mut self) {
+        self.token_bonuses.clear();
+        if !self.grammar.has_bonuses() || self.allowed_token_ids.is_clear() {
+            return;
+        }
+        let (item_bonuses, mut bonuses) = self.first_byte_bonuses();
+        for bonuses in bonuses.iter_mut() {
+            bonuses.sort_unstable_by(|x, y| y.total_cmp(x));
+        }
+        let vocabulary = self.vocabulary.clone();
+        for token_id in self.allowed_token_ids.clone().ones() {
+            let Some(token) = vocabulary
+                .token(token_id as u32)
+                .filter(|x| !x.0.is_empty())
+            else {
+                continue;
+            };
+            let bonus = match bonuses[token.0[0] as usize].as_slice() {
+                [] => continue,
+                &[bonus] => bonus,
+                bonuses => match bonuses
+                    .iter()
+                    .find(|&&x| self.accepts_token_with_bonus(&item_bonuses, x, &token.0))
+                {
+                    Some(&bonus) => bonus,
+                    None => continue,
+                },
+            };
+            if bonus != 0.0 {
+                self.token_bonuses.push((token_id, bonus));
+            }
+        }
+    }
+    /// Masks every row of the row-major logits and adds the bonuses of the allowed tokens.
+    fn mask_logits_rows<T: utils::Logit>(
+        &self,
+        logits: &mut [T],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        utils::mask_logits_2d(
+            &self.allowed_token_ids,
+            logits,
+            row_stride,
+            self.config.disallowed_token_penalty.map(Penalty::value),
+        )?;
+        if !self.token_bonuses.is_empty() {
+            for row in logits.chunks_exact_mut(row_stride) {
+                for &(token_id, bonus) in self.token_bonuses.iter() {
+                    row[token_id] = row[token_id].shifted(bonus);
+                }
+            }
+        }
+        Ok(())
+    }
+    #[inline]
+    fn item_should_be_completed(
+        grammar: &Grammar<TI>,
+        nonterminal_id: NonterminalID<TI>,
+        new_dot_position: TD,
+        production_id: TP,
+    ) -> bool
+    where
+        TP: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+        TD: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+    {
+        // SAFETY: nonterminal_id is guaranteed to be valid since it always comes from the grammar, in other words, the jagged array.
+        let view = unsafe { grammar.dotted_productions(nonterminal_id) };
+        let new_dot_position = new_dot_position.as_();
+        if new_dot_position < view.len() {
+            // SAFETY: new_dot_position is guaranteed to be valid since we checked it in the previous line
+            let view = unsafe { view.view_unchecked::<1, 1>([new_dot_position]) };
+            if production_id.as_() < view.len() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn advance_item<T>(
+        grammar: &Grammar<TI>,
+        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        add_to_earley_set: T,
+        mut item: EarleyItem<TI, TD, TP, TSP, TS>,
+    ) where
+        T: FnOnce(EarleyItem<TI, TD, TP, TSP, TS>),
+    {
+        let new_dotted_position = item.dot_position + TD::ONE;
+        if !Self::item_should_be_completed(
+            grammar,
+            item.nonterminal_id,
+            new_dotted_position,
+            item.production_index,
+        ) {
+            item.dot_position = new_dotted_position;
+            item.state_id = Self::initialize_state_id_based_on_node(
+                grammar,
+                // SAFETY:
+                // nonterminal_id is guaranteed to be valid since it always comes from the grammar, in other words, the jagged array.
+                // dot_position is guaranteed to be valid since we checked it in Self::item_should_be_completed
+                // production_index is guaranteed to be valid since we checked it in Self::item_should_be_completed
+                unsafe {
+                    *grammar.node_unchecked(
+                        item.nonterminal_id,
+                        new_dotted_position,
+                        item.production_index,
+                    )
+                },
+            );
+            add_to_earley_set(item);
+        } else {
+            to_be_completed_items.insert(ToBeCompletedItem {
+                nonterminal_id: item.nonterminal_id,
+                start_position: item.start_position,
+            });
+        }
+    }
+
+    #[inline]
+    /// # Safety
+    ///
+    /// earley_sets must has enough capacity to push one new item.
+    unsafe fn advance_item_normal_unchecked(
+        grammar: &Grammar<TI>,
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
+        item: EarleyItem<TI, TD, TP, TSP, TS>,
+    ) {
+        Self::advance_item(
+            grammar,
+            to_be_completed_items,
+            |new_item| {
+                earley_sets.push_to_last_row_unchecked(new_item);
+            },
+            item,
+        );
+    }
+
     #[inline]
     fn from_state_id_to_index(state_id: TS) -> usize {
         state_id.as_()
@@ -917,6 +1718,18 @@ where
         unsafe { std::mem::transmute((state_id.as_() << stride2) as u32) }
     }
     #[inline]
+    fn from_lazy_dfa_state_id_to_state_id(state_id: LazyStateID) -> TS {
+        // SAFETY: LazyStateID is a u32 due to #[repr(transparent)] attribute
+        let id: u32 = unsafe { std::mem::transmute(state_id) };
+        // SAFETY: id is guaranteed to be representable as a state_id or an error will be returned in Self::new() method
+        (id as usize).as_()
+    }
+    #[inline]
+    fn from_state_id_to_lazy_dfa_state_id(state_id: TS) -> LazyStateID {
+        // SAFETY: LazyStateID is a u32 due to #[repr(transparent)] attribute
+        unsafe { std::mem::transmute(state_id.as_() as u32) }
+    }
+    #[inline]
     fn from_suffix_automaton_node_id_to_state_id(node_id: usize) -> TS {
         node_id.as_()
     }
@@ -925,12 +1738,19 @@ where
         state_id.as_()
     }
 
+    /// Scans the last Earley set with `byte` into a new Earley set.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [CacheError] if a lazy DFA transition does not fit in its cache.
+    /// The new Earley set is left incomplete in this case.
     fn scan(
         grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
         to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
         byte: u8,
-    ) {
+    ) -> Result<(), CacheError> {
         let earley_set_index: usize = earley_sets.len() - 1; // Interestingly usize seems to be faster than i32
                                                              // SAFETY: earley_set_index is guaranteed to be valid since earley_sets is never empty
         let earley_set_len =
@@ -1022,6 +1842,40 @@ where
                                 }
                             );
                         }
+                        FiniteStateAutomaton::LazyDfa(dfa) => {
+                            let (state_id, status) = dfa.next_state(
+                                lazy_dfa_caches.get(regex_id.0.as_(), dfa),
+                                Self::from_state_id_to_lazy_dfa_state_id(item.state_id),
+                                byte,
+                            )?;
+                            match status {
+                                FsaStateStatus::Accept => {
+                                    // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                                    unsafe {
+                                        Self::advance_item_normal_unchecked(
+                                            grammar,
+                                            earley_sets,
+                                            to_be_completed_items,
+                                            item,
+                                        )
+                                    };
+                                    // Only keep for normal regex
+                                    if let HIRNode::RegexString(_) = node {
+                                        item.state_id =
+                                            Self::from_lazy_dfa_state_id_to_state_id(state_id);
+                                        // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                                        unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                                    }
+                                }
+                                FsaStateStatus::Reject => {}
+                                FsaStateStatus::InProgress => {
+                                    item.state_id =
+                                        Self::from_lazy_dfa_state_id_to_state_id(state_id);
+                                    // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                                    unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                                }
+                            }
+                        }
                     }
                 }
                 HIRNode::RegexComplement(regex_id) => {
@@ -1049,6 +1903,18 @@ where
                                 }
                             );
                         }
+                        FiniteStateAutomaton::LazyDfa(dfa) => {
+                            let (state_id, status) = dfa.next_state(
+                                lazy_dfa_caches.get(regex_id.0.as_(), dfa),
+                                Self::from_state_id_to_lazy_dfa_state_id(item.state_id),
+                                byte,
+                            )?;
+                            if status == FsaStateStatus::InProgress {
+                                item.state_id = Self::from_lazy_dfa_state_id_to_state_id(state_id);
+                                // SAFETY: line 1055 ensures earley_sets has enough capacity to push one new item
+                                unsafe { earley_sets.push_to_last_row_unchecked(item) };
+                            }
+                        }
                     }
                 }
                 HIRNode::Substrings(suffix_automata_id) => {
@@ -1078,6 +1944,7 @@ where
                 HIRNode::Nonterminal(_) => {}
             }
         }
+        Ok(())
     }
     fn update_postdot_items(
         grammar: &Grammar<TI>,
@@ -1131,9 +1998,13 @@ where
                 }
             }
         }
-        for v in postdot_items.values_mut() {
+        for (postdot, v) in postdot_items.iter_mut() {
             if let &mut PostDotItems::LeoEligible(item) = v {
-                if !Self::item_should_be_completed(
+                // Leo items would skip the completions of the operands and the trackers of intersections.
+                if matches!(
+                    grammar.intersection_role(postdot.postdot_nonterminal_id),
+                    Some((_, IntersectionRole::Operand | IntersectionRole::Tracker))
+                ) || !Self::item_should_be_completed(
                     grammar,
                     item.nonterminal_id,
                     item.dot_position + TD::ONE,
@@ -1203,8 +2074,21 @@ where
         postdot_items: &AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
         to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
         deduplication_buffer: &mut AHashSet<EarleyItem<TI, TD, TP, TSP, TS>>,
+        completed_trackers: &[ToBeCompletedItem<TI, TSP>],
         is_finished: &mut bool,
     ) {
+        match grammar.intersection_role(to_be_completed_item.nonterminal_id) {
+            Some((_, IntersectionRole::Tracker)) => return,
+            Some((i, IntersectionRole::Operand))
+                if !completed_trackers.contains(&ToBeCompletedItem {
+                    nonterminal_id: grammar.intersection(i).tracker,
+                    start_position: to_be_completed_item.start_position,
+                }) =>
+            {
+                return
+            }
+            _ => {}
+        }
         if let Some(postdot) = postdot_items.get(&Dotted {
             postdot_nonterminal_id: to_be_completed_item.nonterminal_id,
             column: to_be_completed_item.start_position,
@@ -1248,6 +2132,21 @@ where
         finished: &mut bool,
     ) {
         to_be_completed_items_buffer.clear();
+        // The trackers of intersections are regexes, so they can only be completed by the scan.
+        let completed_trackers: Vec<_> = if grammar.has_intersections() {
+            to_be_completed_items
+                .iter()
+                .filter(|x| {
+                    matches!(
+                        grammar.intersection_role(x.nonterminal_id),
+                        Some((_, IntersectionRole::Tracker))
+                    )
+                })
+                .copied()
+                .collect()
+        } else {
+            Vec::new()
+        };
         while !to_be_completed_items.is_empty() {
             for item in to_be_completed_items.drain() {
                 if let Some(topmost_item) =
@@ -1259,114 +2158,503 @@ where
                         postdot_items,
                         to_be_completed_items_buffer,
                         deduplication_buffer,
+                        &completed_trackers,
+                        finished,
+                    );
+                } else {
+                    Self::earley_complete_one_item(
+                        grammar,
+                        item,
+                        postdot_items,
+                        to_be_completed_items_buffer,
+                        deduplication_buffer,
+                        &completed_trackers,
                         finished,
                     );
+                }
+            }
+            std::mem::swap(to_be_completed_items, to_be_completed_items_buffer);
+        }
+        for item in deduplication_buffer.drain() {
+            earley_sets.push_to_last_row(item);
+        }
+    }
+
+    fn revert_change(
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        added_postdot_items: &mut AHashSet<Dotted<TI, TSP>>,
+        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        mut column_to_postdot_nonterminal_operation: impl FnMut(TSP),
+        earley_set_length: usize,
+        finished: &mut bool,
+    ) {
+        earley_sets.truncate::<0>(earley_set_length);
+        *finished = false;
+        for postdot in added_postdot_items.iter() {
+            // interestingly, this is faster than drain
+            postdot_items.remove(postdot);
+            leo_items.remove(postdot);
+            column_to_postdot_nonterminal_operation(postdot.column);
+        }
+        added_postdot_items.clear();
+    }
+    #[inline]
+    fn commit_change(postdot_items_since_last_commit: &mut AHashSet<Dotted<TI, TSP>>) {
+        postdot_items_since_last_commit.clear();
+    }
+    #[inline]
+    fn is_rejected(
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        to_be_completed_items: &AHashSet<ToBeCompletedItem<TI, TSP>>,
+    ) -> bool {
+        earley_sets.view::<1, 1>([earley_sets.len() - 1]).is_empty()
+            && to_be_completed_items.is_empty()
+    }
+    /// Compact the Earley sets by removing the Earley sets that are not reachable from the last Earley set,
+    /// except the ones before `first_removable_set`.
+    fn compact(
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        column_to_postdot_nonterminals: &mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
+        first_removable_set: usize,
+    ) {
+        let earley_set_index = earley_sets.len() - 1;
+        let mut view = earley_sets.view_mut::<1, 1>([earley_set_index]);
+        let earley_set = view.as_slice_mut();
+        let mut max_start_position = 0;
+        for item in earley_set.iter_mut() {
+            let mut start_position = item.start_position.as_();
+            if let Some(leo_item) = leo_items
+                .get(&Dotted {
+                    postdot_nonterminal_id: item.nonterminal_id,
+                    column: item.start_position,
+                })
+                .copied()
+            {
+                // the chain of leo items allows us to fold the start position
+                item.start_position = leo_item.start_position;
+                if item.nonterminal_id != leo_item.nonterminal_id {
+                    leo_items.insert(
+                        Dotted {
+                            postdot_nonterminal_id: item.nonterminal_id,
+                            column: item.start_position,
+                        },
+                        leo_item,
+                    );
+                }
+                start_position = leo_item.start_position.as_();
+            }
+            if start_position > max_start_position {
+                max_start_position = start_position;
+            }
+        }
+        let first_removed_set = (max_start_position + 1).max(first_removable_set);
+        if first_removed_set >= earley_set_index {
+            return;
+        }
+        earley_sets.remove_rows(first_removed_set..earley_set_index);
+        for index in first_removed_set..earley_set_index {
+            if let Some(nonterminals) = column_to_postdot_nonterminals.remove(&index.as_()) {
+                for nonterminal in nonterminals.into_iter() {
+                    let dotted: Dotted<TI, TSP> = Dotted {
+                        postdot_nonterminal_id: nonterminal,
+                        column: index.as_(),
+                    };
+                    postdot_items.remove(&dotted);
+                    leo_items.remove(&dotted);
+                }
+            }
+        }
+    }
+
+    /// Removes the Earley items of the intersections from the last Earley set
+    /// once the DFAs of their trackers or their operands can no longer match.
+    ///
+    /// Each instance of an intersection is identified by the column where its operand and its tracker start,
+    /// so overlapping instances of the same intersection are checked separately.
+    /// An item of an operand, or of a nonterminal reachable from it, is kept only if the tracker of an instance it belongs to
+    /// is alive, and a tracker is kept only if an item of its instance is alive.
+    fn prune_intersections(
+        grammar: &Grammar<TI>,
+        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
+        postdot_items: &AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+    ) {
+        let earley_set_index = earley_sets.len() - 1;
+        let mut view = earley_sets.view_mut::<1, 1>([earley_set_index]);
+        let earley_set = view.as_slice_mut();
+        // The instances whose trackers are alive, and the instances each nonterminal of an operand belongs to
+        let mut trackers = AHashSet::<(usize, usize)>::default();
+        let mut instances = AHashMap::<Dotted<TI, TSP>, Vec<usize>>::default();
+        for item in earley_set.iter() {
+            match grammar.intersection_role(item.nonterminal_id) {
+                Some((i, IntersectionRole::Tracker)) => {
+                    trackers.insert((i, item.start_position.as_()));
+                }
+                Some((_, IntersectionRole::Operand | IntersectionRole::Member)) => {
+                    let dotted = Dotted {
+                        postdot_nonterminal_id: item.nonterminal_id,
+                        column: item.start_position,
+                    };
+                    instances.entry(dotted).or_insert_with(|| {
+                        Self::intersection_instances(grammar, postdot_items, leo_items, dotted)
+                    });
+                }
+                _ => {}
+            }
+        }
+        if trackers.is_empty() && instances.is_empty() {
+            return;
+        }
+        // The instances whose trackers and operands are both alive
+        let mut alive = AHashSet::<(usize, usize)>::default();
+        for (dotted, columns) in instances.iter() {
+            let (i, _) = grammar
+                .intersection_role(dotted.postdot_nonterminal_id)
+                .unwrap();
+            alive.extend(
+                columns
+                    .iter()
+                    .map(|&column| (i, column))
+                    .filter(|x| trackers.contains(x)),
+            );
+        }
+        let mut len = 0;
+        for index in 0..earley_set.len() {
+            let item = earley_set[index];
+            let keep = match grammar.intersection_role(item.nonterminal_id) {
+                Some((i, role)) => match role {
+                    IntersectionRole::Root => true,
+                    IntersectionRole::Tracker => alive.contains(&(i, item.start_position.as_())),
+                    IntersectionRole::Operand | IntersectionRole::Member => instances[&Dotted {
+                        postdot_nonterminal_id: item.nonterminal_id,
+                        column: item.start_position,
+                    }]
+                        .iter()
+                        .any(|&column| alive.contains(&(i, column))),
+                },
+                None => true,
+            };
+            if keep {
+                earley_set[len] = item;
+                len += 1;
+            }
+        }
+        let removed = earley_set.len() - len;
+        for _ in 0..removed {
+            earley_sets.pop_from_last_row();
+        }
+    }
+
+    /// Get the columns of the instances of an intersection that the nonterminal of its operand starting at the column belongs to.
+    ///
+    /// The items waiting for the nonterminal are followed through the postdot items up to the operands,
+    /// each of which starts at the column of its instance.
+    fn intersection_instances(
+        grammar: &Grammar<TI>,
+        postdot_items: &AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        dotted: Dotted<TI, TSP>,
+    ) -> Vec<usize> {
+        let mut columns = Vec::new();
+        let mut visited = AHashSet::default();
+        let mut stack = vec![dotted];
+        while let Some(dotted) = stack.pop() {
+            // The compaction may have moved the start position of an item to that of its topmost Leo item.
+            let (nonterminal_id, start_position) =
+                Self::resolve_leo_item(leo_items, dotted.postdot_nonterminal_id, dotted.column);
+            let dotted = Dotted {
+                postdot_nonterminal_id: nonterminal_id,
+                column: start_position,
+            };
+            if !visited.insert(dotted) {
+                continue;
+            }
+            match grammar.intersection_role(nonterminal_id) {
+                Some((_, IntersectionRole::Operand)) => {
+                    columns.push(start_position.as_());
+                    continue;
+                }
+                Some((_, IntersectionRole::Member)) => {}
+                _ => continue,
+            }
+            let items = match postdot_items.get(&dotted) {
+                Some(PostDotItems::NormalItems(items)) => items.as_slice(),
+                Some(PostDotItems::LeoEligible(item)) => std::slice::from_ref(item),
+                None => &[],
+            };
+            stack.extend(items.iter().map(|item| Dotted {
+                postdot_nonterminal_id: item.nonterminal_id,
+                column: item.start_position,
+            }));
+        }
+        columns
+    }
+
+    /// Get the nonterminal and the start position of the topmost item
+    /// that is completed together with the nonterminal starting at the position, according to the Leo items.
+    fn resolve_leo_item(
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        nonterminal_id: NonterminalID<TI>,
+        start_position: TSP,
+    ) -> (NonterminalID<TI>, TSP) {
+        match leo_items.get(&Dotted {
+            postdot_nonterminal_id: nonterminal_id,
+            column: start_position,
+        }) {
+            Some(leo_item) => (leo_item.nonterminal_id, leo_item.start_position),
+            None => (nonterminal_id, start_position),
+        }
+    }
+
+    /// Get the fewest bytes needed to finish the grammar after the nonterminal completes at the start position.
+    fn length_after_completion(
+        grammar: &Grammar<TI>,
+        lengths: &ColumnLengths<TI>,
+        nonterminal_id: NonterminalID<TI>,
+        start_position: TSP,
+    ) -> usize {
+        if grammar.get_start_nonterminal_id() == nonterminal_id && start_position == TSP::ZERO {
+            return 0;
+        }
+        lengths
+            .get(start_position.as_())
+            .and_then(Option::as_ref)
+            .and_then(|lengths| lengths.get(&nonterminal_id))
+            .copied()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Computes the fewest bytes needed to finish the grammar after each nonterminal completes at each Earley set
+    /// the last Earley set depends on, reusing the lengths already in `lengths`.
+    ///
+    /// The Earley sets are visited from the first to the last, since the items of an Earley set only depend on
+    /// the earlier Earley sets and the nonterminals completed at the same Earley set.
+    /// The latter is resolved by Dijkstra's algorithm.
+    fn lengths_after_completion(
+        grammar: &Grammar<TI>,
+        table: &CompletionTable,
+        earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        lengths: &mut ColumnLengths<TI>,
+    ) {
+        let earley_set_index = earley_sets.len() - 1;
+        let mut columns = Vec::new();
+        let mut visited = AHashSet::new();
+        let mut stack: Vec<usize> = earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .iter()
+            .map(|item| {
+                Self::resolve_leo_item(leo_items, item.nonterminal_id, item.start_position)
+                    .1
+                    .as_()
+            })
+            .collect();
+        while let Some(column) = stack.pop() {
+            if lengths.get(column).is_some_and(Option::is_some) || !visited.insert(column) {
+                continue;
+            }
+            columns.push(column);
+            for item in earley_sets.view::<1, 1>([column]).as_slice().iter() {
+                if let HIRNode::Nonterminal(_) = grammar.node(
+                    item.nonterminal_id,
+                    item.dot_position,
+                    item.production_index,
+                ) {
+                    let (_, start_position) =
+                        Self::resolve_leo_item(leo_items, item.nonterminal_id, item.start_position);
+                    stack.push(start_position.as_());
+                }
+            }
+        }
+        columns.sort_unstable();
+        if let Some(&column) = columns.last() {
+            if lengths.len() <= column {
+                lengths.resize(column + 1, None);
+            }
+        }
+        for column in columns {
+            let mut column_lengths = AHashMap::<NonterminalID<TI>, usize>::default();
+            // The postdot nonterminals of the items that start at the same Earley set, keyed by the items' nonterminals
+            let mut dependents =
+                AHashMap::<NonterminalID<TI>, Vec<(NonterminalID<TI>, usize)>>::default();
+            for item in earley_sets.view::<1, 1>([column]).as_slice().iter() {
+                let &HIRNode::Nonterminal(postdot) = grammar.node(
+                    item.nonterminal_id,
+                    item.dot_position,
+                    item.production_index,
+                ) else {
+                    continue;
+                };
+                let suffix = table.suffix(
+                    item.nonterminal_id.0.as_(),
+                    item.production_index.as_(),
+                    item.dot_position.as_(),
+                );
+                let (nonterminal_id, start_position) =
+                    Self::resolve_leo_item(leo_items, item.nonterminal_id, item.start_position);
+                let length = if start_position.as_() == column {
+                    if grammar.get_start_nonterminal_id() != nonterminal_id || column != 0 {
+                        dependents
+                            .entry(nonterminal_id)
+                            .or_default()
+                            .push((postdot, suffix));
+                        continue;
+                    }
+                    suffix
                 } else {
-                    Self::earley_complete_one_item(
+                    suffix.saturating_add(Self::length_after_completion(
                         grammar,
-                        item,
-                        postdot_items,
-                        to_be_completed_items_buffer,
-                        deduplication_buffer,
-                        finished,
-                    );
+                        lengths,
+                        nonterminal_id,
+                        start_position,
+                    ))
+                };
+                let entry = column_lengths.entry(postdot).or_insert(usize::MAX);
+                *entry = (*entry).min(length);
+            }
+            let mut heap: BinaryHeap<_> = column_lengths
+                .iter()
+                .map(|(nonterminal_id, length)| Reverse((*length, nonterminal_id.0.as_())))
+                .collect();
+            while let Some(Reverse((length, nonterminal_id))) = heap.pop() {
+                let nonterminal_id = NonterminalID(nonterminal_id.as_());
+                if length > column_lengths[&nonterminal_id] {
+                    continue;
+                }
+                for &(postdot, suffix) in dependents.get(&nonterminal_id).into_iter().flatten() {
+                    let length = length.saturating_add(suffix);
+                    let entry = column_lengths.entry(postdot).or_insert(usize::MAX);
+                    if length < *entry {
+                        *entry = length;
+                        heap.push(Reverse((length, postdot.0.as_())));
+                    }
                 }
             }
-            std::mem::swap(to_be_completed_items, to_be_completed_items_buffer);
-        }
-        for item in deduplication_buffer.drain() {
-            earley_sets.push_to_last_row(item);
+            lengths[column] = Some(column_lengths);
         }
     }
 
-    fn revert_change(
-        earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
-        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
-        added_postdot_items: &mut AHashSet<Dotted<TI, TSP>>,
-        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
-        mut column_to_postdot_nonterminal_operation: impl FnMut(TSP),
-        earley_set_length: usize,
-        finished: &mut bool,
-    ) {
-        earley_sets.truncate::<0>(earley_set_length);
-        *finished = false;
-        for postdot in added_postdot_items.iter() {
-            // interestingly, this is faster than drain
-            postdot_items.remove(postdot);
-            leo_items.remove(postdot);
-            column_to_postdot_nonterminal_operation(postdot.column);
-        }
-        added_postdot_items.clear();
-    }
-    #[inline]
-    fn commit_change(postdot_items_since_last_commit: &mut AHashSet<Dotted<TI, TSP>>) {
-        postdot_items_since_last_commit.clear();
+    /// Get the fewest bytes needed to finish the grammar from the item,
+    /// given the fewest bytes needed to finish the node at its dot position.
+    ///
+    /// It is the sum of the fewest bytes to finish the item
+    /// and the fewest bytes to finish the grammar after the item's nonterminal completes.
+    fn length_to_finish(
+        grammar: &Grammar<TI>,
+        table: &CompletionTable,
+        lengths: &ColumnLengths<TI>,
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        item: EarleyItem<TI, TD, TP, TSP, TS>,
+        node_length: usize,
+    ) -> usize {
+        let (nonterminal_id, start_position) =
+            Self::resolve_leo_item(leo_items, item.nonterminal_id, item.start_position);
+        node_length
+            .saturating_add(table.suffix(
+                item.nonterminal_id.0.as_(),
+                item.production_index.as_(),
+                item.dot_position.as_(),
+            ))
+            .saturating_add(Self::length_after_completion(
+                grammar,
+                lengths,
+                nonterminal_id,
+                start_position,
+            ))
     }
-    #[inline]
-    fn is_rejected(
+
+    /// Get the fewest bytes needed to finish the grammar from the last Earley set.
+    fn fewest_bytes_to_finish(
+        grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
+        table: &CompletionTable,
         earley_sets: &EarleySets<TI, TD, TP, TSP, TS>,
-        to_be_completed_items: &AHashSet<ToBeCompletedItem<TI, TSP>>,
-    ) -> bool {
-        earley_sets.view::<1, 1>([earley_sets.len() - 1]).is_empty()
-            && to_be_completed_items.is_empty()
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        lengths: &mut ColumnLengths<TI>,
+    ) -> usize {
+        Self::lengths_after_completion(grammar, table, earley_sets, leo_items, lengths);
+        let earley_set_index = earley_sets.len() - 1;
+        earley_sets
+            .view::<1, 1>([earley_set_index])
+            .as_slice()
+            .iter()
+            .map(|item| {
+                let node_length =
+                    Self::fewest_bytes_to_finish_node(grammar, lazy_dfa_caches, table, item);
+                Self::length_to_finish(grammar, table, lengths, leo_items, *item, node_length)
+            })
+            .min()
+            .unwrap_or(usize::MAX)
     }
-    /// Compact the Earley sets by removing the Earley sets that are not reachable from the last Earley set
-    fn compact(
+
+    /// Get the fewest bytes needed to finish the postdot node of `item` from its state.
+    ///
+    /// Unlike [CompletionTable::node], the regexes compiled into lazy DFAs are searched for their exact lengths,
+    /// since the table only knows the lengths from their start states.
+    fn fewest_bytes_to_finish_node(
+        grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
+        table: &CompletionTable,
+        item: &EarleyItem<TI, TD, TP, TSP, TS>,
+    ) -> usize {
+        let node = *grammar.node(
+            item.nonterminal_id,
+            item.dot_position,
+            item.production_index,
+        );
+        match node {
+            HIRNode::RegexString(id) | HIRNode::EarlyEndRegexString(id) => {
+                match grammar.regex(id) {
+                    FiniteStateAutomaton::LazyDfa(dfa) => dfa.shortest_match_length(
+                        lazy_dfa_caches.get(id.0.as_(), dfa),
+                        Self::from_state_id_to_lazy_dfa_state_id(item.state_id),
+                    ),
+                    FiniteStateAutomaton::Dfa(_) => table.node(node, item.state_id.as_()),
+                }
+            }
+            _ => table.node(node, item.state_id.as_()),
+        }
+    }
+
+    /// Removes the Earley items from the last Earley set that cannot finish the grammar within `remaining_bytes`.
+    fn prune_by_length(
+        grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
+        table: &CompletionTable,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
-        leo_items: &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
-        postdot_items: &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
-        column_to_postdot_nonterminals: &mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
+        leo_items: &AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
+        lengths: &mut ColumnLengths<TI>,
+        remaining_bytes: usize,
     ) {
+        Self::lengths_after_completion(grammar, table, earley_sets, leo_items, lengths);
         let earley_set_index = earley_sets.len() - 1;
         let mut view = earley_sets.view_mut::<1, 1>([earley_set_index]);
         let earley_set = view.as_slice_mut();
-        let mut max_start_position = 0;
-        for item in earley_set.iter_mut() {
-            let mut start_position = item.start_position.as_();
-            if let Some(leo_item) = leo_items
-                .get(&Dotted {
-                    postdot_nonterminal_id: item.nonterminal_id,
-                    column: item.start_position,
-                })
-                .copied()
-            {
-                // the chain of leo items allows us to fold the start position
-                item.start_position = leo_item.start_position;
-                if item.nonterminal_id != leo_item.nonterminal_id {
-                    leo_items.insert(
-                        Dotted {
-                            postdot_nonterminal_id: item.nonterminal_id,
-                            column: item.start_position,
-                        },
-                        leo_item,
-                    );
-                }
-                start_position = leo_item.start_position.as_();
-            }
-            if start_position > max_start_position {
-                max_start_position = start_position;
+        let mut len = 0;
+        for index in 0..earley_set.len() {
+            let item = earley_set[index];
+            let node_length =
+                Self::fewest_bytes_to_finish_node(grammar, lazy_dfa_caches, table, &item);
+            let length =
+                Self::length_to_finish(grammar, table, lengths, leo_items, item, node_length);
+            if length <= remaining_bytes {
+                earley_set[len] = item;
+                len += 1;
             }
         }
-        if max_start_position + 1 == earley_set_index {
-            return;
-        }
-        earley_sets.remove_rows(max_start_position + 1..earley_set_index);
-        for index in max_start_position + 1..earley_set_index {
-            if let Some(nonterminals) = column_to_postdot_nonterminals.remove(&index.as_()) {
-                for nonterminal in nonterminals.into_iter() {
-                    let dotted: Dotted<TI, TSP> = Dotted {
-                        postdot_nonterminal_id: nonterminal,
-                        column: index.as_(),
-                    };
-                    postdot_items.remove(&dotted);
-                    leo_items.remove(&dotted);
-                }
-            }
+        let removed = earley_set.len() - len;
+        for _ in 0..removed {
+            earley_sets.pop_from_last_row();
         }
     }
 
     fn accept_byte(
         grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
         to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
         to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
@@ -1385,10 +2673,37 @@ where
             &mut AHashMap<Dotted<TI, TSP>, ToBeCompletedItem<TI, TSP>>,
             &mut AHashMap<Dotted<TI, TSP>, PostDotItems<TI, TD, TP, TSP, TS>>,
         ),
+        column_lengths: &mut ColumnLengths<TI>,
+        length_limit: Option<(&CompletionTable, usize)>,
         byte: u8,
     ) -> Result<(), crate::engine_like::AcceptTokenError> {
-        Self::scan(grammar, earley_sets, to_be_completed_items, byte); // scan the current Earley set and creates the next Earley set
-        if Self::is_rejected(earley_sets, to_be_completed_items) {
+        // scan the current Earley set and creates the next Earley set
+        if Self::scan(
+            grammar,
+            lazy_dfa_caches,
+            earley_sets,
+            to_be_completed_items,
+            byte,
+        )
+        .is_err()
+        {
+            to_be_completed_items.clear();
+            Self::revert_change(
+                earley_sets,
+                postdot_items,
+                added_postdot_items,
+                leo_items,
+                remove_column_to_postdot_nonterminal_operation,
+                previous_earley_set_length,
+                finished,
+            );
+            return Err(crate::engine_like::AcceptTokenError::LazyDfaCacheFull);
+        }
+        // The new Earley set may replace a reverted one.
+        column_lengths.truncate(earley_sets.len() - 1);
+        if Self::is_rejected(earley_sets, to_be_completed_items)
+            || length_limit.is_some_and(|(_, remaining_bytes)| remaining_bytes == 0)
+        {
             Self::revert_change(
                 earley_sets,
                 postdot_items,
@@ -1411,7 +2726,38 @@ where
             deduplication_buffer,
             finished,
         ); // complete the next Earley set
+        if grammar.has_intersections() {
+            Self::prune_intersections(grammar, earley_sets, postdot_items, leo_items);
+        }
+        if let Some((table, remaining_bytes)) = length_limit.filter(|_| !*finished) {
+            Self::prune_by_length(
+                grammar,
+                lazy_dfa_caches,
+                table,
+                earley_sets,
+                leo_items,
+                column_lengths,
+                remaining_bytes - 1,
+            );
+        }
+        if (grammar.has_intersections() || length_limit.is_some())
+            && Self::is_rejected(earley_sets, to_be_completed_items)
+            && !*finished
+        {
+            Self::revert_change(
+                earley_sets,
+                postdot_items,
+                added_postdot_items,
+                leo_items,
+                remove_column_to_postdot_nonterminal_operation,
+                previous_earley_set_length,
+                finished,
+            );
+            return Err(crate::engine_like::AcceptTokenError::Rejected);
+        }
         compact(earley_sets, leo_items, postdot_items);
+        // The compaction moves the new Earley set in place of the removed ones.
+        column_lengths.truncate(earley_sets.len() - 1);
         Self::predict(grammar, earley_sets, already_predicted_nonterminals); // predict the next Earley set
         Self::update_postdot_items(
             grammar,
@@ -1423,8 +2769,288 @@ where
         Ok(())
     }
 
+    fn accept_new_token(
+        &mut self,
+        token_id: u32,
+    ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        if self.is_finished() {
+            return Err(crate::engine_like::AcceptTokenError::Finished);
+        }
+        let token = match self.vocabulary.token(token_id) {
+            Some(token) => token,
+            None => return Err(crate::engine_like::AcceptTokenError::UnknownTokenID),
+        };
+        if self
+            .config
+            .max_output_tokens
+            .is_some_and(|x| self.output_tokens >= x)
+        {
+            return Err(crate::engine_like::AcceptTokenError::OutputLimitReached);
+        }
+        let token_len = token.0.len();
+        let remaining_bytes = self.remaining_bytes();
+        let must_finish = self.is_last_token();
+        let token_iter = token.0.iter().copied();
+        let ptr = &mut self.column_to_postdot_nonterminals as *mut _;
+        let result = Self::accept_bytes(
+            &self.grammar,
+            &mut self.lazy_dfa_caches,
+            &mut self.earley_sets,
+            &mut self.to_be_completed_items,
+            &mut self.to_be_completed_items_buffer,
+            &mut self.leo_items,
+            &mut self.leo_items_buffer,
+            &mut self.postdot_items,
+            &mut self.postdot_items_since_last_commit,
+            &mut self.already_predicted_nonterminals,
+            &mut self.deduplication_buffer,
+            ptr,
+            &self.config,
+            &mut self.finished,
+            &mut self.column_lengths,
+            self.completion_table.as_deref().zip(remaining_bytes),
+            must_finish,
+            token_iter,
+        );
+        if result.is_ok() {
+            self.output_bytes += token_len;
+            self.output_tokens += 1;
+        }
+        result
+    }
+
+    fn accept_new_bytes(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<AcceptTokenResult, crate::engine_like::AcceptTokenError> {
+        if self.is_finished() {
+            return Err(crate::engine_like::AcceptTokenError::Finished);
+        }
+        let remaining_bytes = self.remaining_bytes();
+        let ptr = &mut self.column_to_postdot_nonterminals
+            as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
+        let result = Self::accept_bytes(
+            &self.grammar,
+            &mut self.lazy_dfa_caches,
+            &mut self.earley_sets,
+            &mut self.to_be_completed_items,
+            &mut self.to_be_completed_items_buffer,
+            &mut self.leo_items,
+            &mut self.leo_items_buffer,
+            &mut self.postdot_items,
+            &mut self.postdot_items_since_last_commit,
+            &mut self.already_predicted_nonterminals,
+            &mut self.deduplication_buffer,
+            ptr,
+            &self.config,
+            &mut self.finished,
+            &mut self.column_lengths,
+            self.completion_table.as_deref().zip(remaining_bytes),
+            false,
+            bytes.iter().copied(),
+        );
+        if result.is_ok() {
+            self.output_bytes += bytes.len();
+        }
+        result
+    }
+
+    /// Replaces the full lazy DFA caches with new caches that only hold the states the last Earley set refers to,
+    /// so the engine can go on after a transition does not fit in a cache.
+    ///
+    /// Only the items of the last Earley set scan bytes, so the states of the earlier items are never used again.
+    /// The allowed token IDs cached by the Earley sets are cleared since the state IDs change.
+    /// Nothing is changed if the states do not fit in the new caches.
+    ///
+    /// # Returns
+    ///
+    /// Whether the full caches are replaced.
+    fn rebuild_full_lazy_dfa_caches(&mut self) -> bool {
+        let regex_ids = self.lazy_dfa_caches.full_regex_ids();
+        if regex_ids.is_empty() {
+            return false;
+        }
+        let earley_set_index = self.earley_sets.len() - 1;
+        // The states of the full caches that the last Earley set refers to.
+        let mut states: AHashMap<usize, AHashSet<LazyStateID>> = regex_ids
+            .iter()
+            .map(|&regex_id| (regex_id, AHashSet::default()))
+            .collect();
+        for item in self.earley_sets.view::<1, 1>([earley_set_index]).as_slice() {
+            if let Some(set) =
+                Self::regex_id_of(&self.grammar, item).and_then(|x| states.get_mut(&x))
+            {
+                set.insert(Self::from_state_id_to_lazy_dfa_state_id(item.state_id));
+            }
+        }
+        // The new IDs of the states, keyed by the regex IDs and then by their old IDs.
+        let mut new_ids: AHashMap<usize, AHashMap<LazyStateID, LazyStateID>> = AHashMap::default();
+        let mut caches = Vec::with_capacity(regex_ids.len());
+        for (regex_id, states) in states {
+            let FiniteStateAutomaton::LazyDfa(dfa) = self.grammar.regex(RegexID(regex_id.as_()))
+            else {
+                unreachable!("Only the lazy DFAs have caches.")
+            };
+            let states: Vec<_> = states.into_iter().collect();
+            let Some((cache, new_states)) = self
+                .lazy_dfa_caches
+                .get_created(regex_id)
+                .and_then(|cache| dfa.rebuild_cache(cache, &states))
+            else {
+                return false;
+            };
+            new_ids.insert(regex_id, states.into_iter().zip(new_states).collect());
+            caches.push((regex_id, cache));
+        }
+        for (regex_id, cache) in caches {
+            self.lazy_dfa_caches.replace(regex_id, cache);
+        }
+        let mut view = self.earley_sets.view_mut::<1, 1>([earley_set_index]);
+        for item in view.as_slice_mut() {
+            if let Some(mapping) =
+                Self::regex_id_of(&self.grammar, item).and_then(|x| new_ids.get(&x))
+            {
+                item.state_id = Self::from_lazy_dfa_state_id_to_state_id(
+                    mapping[&Self::from_state_id_to_lazy_dfa_state_id(item.state_id)],
+                );
+            }
+        }
+        self.cache.clear();
+        true
+    }
+
+    /// Get the ID of the regex after the dot of the item, if any.
+    fn regex_id_of(grammar: &Grammar<TI>, item: &EarleyItem<TI, TD, TP, TSP, TS>) -> Option<usize> {
+        match grammar.node(
+            item.nonterminal_id,
+            item.dot_position,
+            item.production_index,
+        ) {
+            HIRNode::RegexString(id)
+            | HIRNode::EarlyEndRegexString(id)
+            | HIRNode::RegexComplement(id) => Some(id.0.as_()),
+            _ => None,
+        }
+    }
+
+    /// Get the number of bytes the engine can still accept, or `None` if the output length is not limited.
+    ///
+    /// Every remaining token is assumed to be as long as the longest token in the vocabulary.
+    fn remaining_bytes(&self) -> Option<usize> {
+        let bytes = self
+            .config
+            .max_output_bytes
+            .map(|x| x.saturating_sub(self.output_bytes));
+        let tokens = self.config.max_output_tokens.map(|x| {
+            x.saturating_sub(self.output_tokens)
+                .saturating_mul(self.max_token_length)
+        });
+        match (bytes, tokens) {
+            (Some(bytes), Some(tokens)) => Some(bytes.min(tokens)),
+            (bytes, tokens) => bytes.or(tokens),
+        }
+    }
+
+    /// Whether the next token is the last one allowed by [EngineConfig::max_output_tokens].
+    fn is_last_token(&self) -> bool {
+        self.config
+            .max_output_tokens
+            .is_some_and(|x| self.output_tokens + 1 >= x)
+    }
+
+    /// Clones the engine for a search over its states.
+    ///
+    /// The cache of allowed token IDs is only cloned if `with_cache` is true, since a short-lived copy may not need it.
+    pub(crate) fn fork(&self, with_cache: bool) -> Self {
+        Self {
+            vocabulary: self.vocabulary.clone(),
+            grammar: self.grammar.clone(),
+            allowed_first_bytes: self.allowed_first_bytes.clone(),
+            allowed_token_ids: self.allowed_token_ids.clone(),
+            allowed_token_ids_error: self.allowed_token_ids_error,
+            token_bonuses: self.token_bonuses.clone(),
+            earley_sets: self.earley_sets.clone(),
+            cache: if with_cache {
+                self.cache.clone()
+            } else {
+                AHashMap::default()
+            },
+            lazy_dfa_caches: self.lazy_dfa_caches.clone(),
+            to_be_completed_items: self.to_be_completed_items.clone(),
+            to_be_completed_items_buffer: AHashSet::default(),
+            deduplication_buffer: AHashSet::default(),
+            postdot_items: self.postdot_items.clone(),
+            postdot_items_since_last_commit: self.postdot_items_since_last_commit.clone(),
+            column_to_postdot_nonterminals: self.column_to_postdot_nonterminals.clone(),
+            leo_items: self.leo_items.clone(),
+            leo_items_buffer: Vec::new(),
+            already_predicted_nonterminals: self.already_predicted_nonterminals.clone(),
+            finished: self.finished,
+            config: self.config,
+            completion_table: self.completion_table.clone(),
+            column_lengths: self.column_lengths.clone(),
+            output_bytes: self.output_bytes,
+            output_tokens: self.output_tokens,
+            max_token_length: self.max_token_length,
+        }
+    }
+
+    /// Whether the engine accepts the same token sequences as the other engine forked from the same engine.
+    ///
+    /// With compaction enabled, the Earley sets that no item refers to are removed,
+    /// so the engine returns to equal Earley sets whenever it returns to an equivalent state,
+    /// e.g. after every repetition of a left recursion. Without compaction, the Earley sets only grow and are never equal.
+    /// The remaining output budgets are compared as well since they also limit the accepted token sequences.
+    pub(crate) fn has_same_state(&self, other: &Self) -> bool {
+        self.finished == other.finished
+            && self.earley_sets == other.earley_sets
+            && (self.config.max_output_bytes.is_none() || self.output_bytes == other.output_bytes)
+            && (self.config.max_output_tokens.is_none()
+                || self.output_tokens == other.output_tokens)
+    }
+
+    /// Moves the cache of allowed token IDs to the other engine forked from the same engine.
+    pub(crate) fn move_cache_to(&mut self, other: &mut Self) {
+        other.cache = std::mem::take(&mut self.cache);
+    }
+
+    /// Whether the grammar can still be finished from the current state according to the table,
+    /// which is from [EngineBase::completion_table] of the same grammar.
+    pub(crate) fn can_finish(&mut self, table: &CompletionTable) -> bool {
+        if self.finished {
+            return true;
+        }
+        Self::fewest_bytes_to_finish(
+            &self.grammar,
+            &mut self.lazy_dfa_caches,
+            table,
+            &self.earley_sets,
+            &self.leo_items,
+            &mut self.column_lengths,
+        ) != usize::MAX
+    }
+
+    /// Returns an error if no token is allowed while the engine is not finished.
+    #[inline]
+    fn check_dead_end(&self) -> Result<(), ComputeAllowedTokenIdsError> {
+        if self.allowed_token_ids.count_ones(..) == 0 && !self.is_finished() {
+            Err(ComputeAllowedTokenIdsError::DeadEnd)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether accepting a byte failed, recording in `cache_full` if it failed because a lazy DFA cache is full.
+    #[inline]
+    fn is_rejected_by(
+        result: Result<(), crate::engine_like::AcceptTokenError>,
+        cache_full: &mut bool,
+    ) -> bool {
+        *cache_full |= result == Err(crate::engine_like::AcceptTokenError::LazyDfaCacheFull);
+        result.is_err()
+    }
+
     fn add_tokens_from_eager_regex_cache(&mut self) -> bool {
-        let cache = &self.grammar.regex_to_token_ids;
         let last_earley_set_index = self.earley_sets.len() - 1;
         let last_earley_set = self
             .earley_sets
@@ -1432,6 +3058,13 @@ where
             .as_slice();
         let mut changed = false;
         for item in last_earley_set.iter().copied() {
+            // The tokens accepted by the regexes of intersections may still be rejected by their counterparts.
+            if matches!(
+                self.grammar.intersection_role(item.nonterminal_id),
+                Some((_, role)) if role != IntersectionRole::Root
+            ) {
+                continue;
+            }
             let node = *self.grammar.node(
                 item.nonterminal_id,
                 item.dot_position,
@@ -1457,9 +3090,14 @@ where
             let dfa = self.grammar.regex(regex_id);
             let stride2 = match dfa {
                 FiniteStateAutomaton::Dfa(dfa) => dfa.stride2(),
+                // The eager regex cache is never built for lazy DFAs.
+                FiniteStateAutomaton::LazyDfa(_) => continue,
             };
             let state_id = Self::from_state_id_to_dfa_state_id(item.state_id, stride2);
-            if let Some(token_ids) = cache.get(&(regex_id, state_id, regex_type)) {
+            if let Some(token_ids) = self
+                .grammar
+                .token_ids_from_regex(regex_id, state_id, regex_type)
+            {
                 self.allowed_token_ids.union_with(token_ids);
                 changed = true;
             }
@@ -1469,6 +3107,7 @@ where
 
     fn accept_bytes(
         grammar: &Grammar<TI>,
+        lazy_dfa_caches: &mut LazyDfaCaches,
         earley_sets: &mut EarleySets<TI, TD, TP, TSP, TS>,
         to_be_completed_items: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
         to_be_completed_items_buffer: &mut AHashSet<ToBeCompletedItem<TI, TSP>>,
@@ -1481,13 +3120,28 @@ where
         column_to_postdot_nonterminals: *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>,
         config: &EngineConfig,
         finished: &mut bool,
+        column_lengths: &mut ColumnLengths<TI>,
+        length_limit: Option<(&CompletionTable, usize)>,
+        must_finish: bool,
         bytes: impl Iterator<Item = u8>,
     ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
         let len = earley_sets.len();
+        let length_limit = |i: usize| {
+            length_limit.map(|(table, remaining_bytes)| (table, remaining_bytes.saturating_sub(i)))
+        };
         if config.compaction_enabled {
-            for byte in bytes {
+            let mut bytes = bytes.enumerate().peekable();
+            while let Some((i, byte)) = bytes.next() {
+                // Reverting a rejection cannot restore the committed Earley sets, so they are only removed
+                // by the last byte, unless the input may still be rejected for not finishing.
+                let first_removable_set = if bytes.peek().is_none() && !must_finish {
+                    0
+                } else {
+                    len
+                };
                 Self::accept_byte(
                     grammar,
+                    lazy_dfa_caches,
                     earley_sets,
                     to_be_completed_items,
                     to_be_completed_items_buffer,
@@ -1519,17 +3173,24 @@ where
                     |earley_sets, leo_items, postdot_items| {
                         // SAFETY: this closure will only be called in `accept_byte`
                         // and never run simultaneously with the closures above
-                        Self::compact(earley_sets, leo_items, postdot_items, unsafe {
-                            &mut *column_to_postdot_nonterminals
-                        })
+                        Self::compact(
+                            earley_sets,
+                            leo_items,
+                            postdot_items,
+                            unsafe { &mut *column_to_postdot_nonterminals },
+                            first_removable_set,
+                        )
                     },
+                    column_lengths,
+                    length_limit(i),
                     byte,
                 )?;
             }
         } else {
-            for byte in bytes {
+            for (i, byte) in bytes.enumerate() {
                 Self::accept_byte(
                     grammar,
+                    lazy_dfa_caches,
                     earley_sets,
                     to_be_completed_items,
                     to_be_completed_items_buffer,
@@ -1544,10 +3205,26 @@ where
                     len,
                     finished,
                     |_, _, _| {},
+                    column_lengths,
+                    length_limit(i),
                     byte,
                 )?;
             }
         }
+        if must_finish && !*finished {
+            Self::revert_change(
+                earley_sets,
+                postdot_items,
+                added_postdot_items,
+                leo_items,
+                |column| {
+                    unsafe { &mut *column_to_postdot_nonterminals }.remove(&column);
+                },
+                len,
+                finished,
+            );
+            return Err(crate::engine_like::AcceptTokenError::Rejected);
+        }
         Self::commit_change(added_postdot_items);
         if *finished {
             Ok(crate::engine_like::AcceptTokenResult::Finished)
@@ -1613,267 +3290,79 @@ where
         &mut self,
         token_id: u32,
     ) -> Result<crate::engine_like::AcceptTokenResult, crate::engine_like::AcceptTokenError> {
-        if self.is_finished() {
-            return Err(crate::engine_like::AcceptTokenError::Finished);
+        let result = self.accept_new_token(token_id);
+        if result == Err(crate::engine_like::AcceptTokenError::LazyDfaCacheFull)
+            && self.rebuild_full_lazy_dfa_caches()
+        {
+            return self.accept_new_token(token_id);
         }
-        let token = match self.vocabulary.token(token_id) {
-            Some(token) => token,
-            None => return Err(crate::engine_like::AcceptTokenError::UnknownTokenID),
-        };
-        let token_iter = token.0.iter().copied();
-        let ptr = &mut self.column_to_postdot_nonterminals as *mut _;
-        Self::accept_bytes(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.to_be_completed_items,
-            &mut self.to_be_completed_items_buffer,
-            &mut self.leo_items,
-            &mut self.leo_items_buffer,
-            &mut self.postdot_items,
-            &mut self.postdot_items_since_last_commit,
-            &mut self.already_predicted_nonterminals,
-            &mut self.deduplication_buffer,
-            ptr,
-            &self.config,
-            &mut self.finished,
-            token_iter,
-        )
+        result
     }
 
     fn try_accept_new_bytes(
         &mut self,
         bytes: &[u8],
     ) -> Result<AcceptTokenResult, crate::engine_like::AcceptTokenError> {
-        if self.is_finished() {
-            return Err(crate::engine_like::AcceptTokenError::Finished);
+        let result = self.accept_new_bytes(bytes);
+        if result == Err(crate::engine_like::AcceptTokenError::LazyDfaCacheFull)
+            && self.rebuild_full_lazy_dfa_caches()
+        {
+            return self.accept_new_bytes(bytes);
         }
-        let ptr = &mut self.column_to_postdot_nonterminals
-            as *mut AHashMap<TSP, AHashSet<NonterminalID<TI>>>;
-        Self::accept_bytes(
-            &self.grammar,
-            &mut self.earley_sets,
-            &mut self.to_be_completed_items,
-            &mut self.to_be_completed_items_buffer,
-            &mut self.leo_items,
-            &mut self.leo_items_buffer,
-            &mut self.postdot_items,
-            &mut self.postdot_items_since_last_commit,
-            &mut self.already_predicted_nonterminals,
-            &mut self.deduplication_buffer,
-            ptr,
-            &self.config,
-            &mut self.finished,
-            bytes.iter().copied(),
-        )
+        result
     }
 
     fn compute_allowed_token_ids(&mut self) {
-        self.allowed_token_ids.clear();
-        if self.is_finished() {
-            return;
-        }
-        if self.config.cache_enabled {
-            if let Some(allowed_ids) = self.cache.get(&self.earley_sets) {
-                self.allowed_token_ids.union_with(allowed_ids);
-                return;
-            }
-        }
-        let mut eager_cache = false;
-        if !self.grammar.regex_to_token_ids.is_empty() {
-            eager_cache = self.add_tokens_from_eager_regex_cache();
-        }
-        let original_earley_set_len = self.earley_sets.len();
-        self.update_allowed_first_bytes();
-        let mut invalid_next_bytes = ByteSet::with_capacity(256);
-        for byte in self.allowed_first_bytes.ones() {
-            invalid_next_bytes.clear();
-            Self::accept_byte(
-                &self.grammar,
-                &mut self.earley_sets,
-                &mut self.to_be_completed_items,
-                &mut self.to_be_completed_items_buffer,
-                &mut self.leo_items,
-                &mut self.leo_items_buffer,
-                &mut self.postdot_items,
-                &mut self.postdot_items_since_last_commit,
-                |_| {},
-                |_| {},
-                &mut self.already_predicted_nonterminals,
-                &mut self.deduplication_buffer,
-                original_earley_set_len,
-                &mut self.finished,
-                |_, _, _| {},
-                byte as u8,
-            )
-            .unwrap();
-            let mut staged_changes = StagedChanges {
-                earley_sets_len_since_last_commit: original_earley_set_len,
-                postdot_items_since_last_commit: self.postdot_items_since_last_commit.clone(),
-            };
-            let len = self.earley_sets.len();
-            Self::commit_change(&mut self.postdot_items_since_last_commit);
-            let mut current_token_id: usize = usize::MAX;
-            let mut token_iter = self.vocabulary.normal_tokens_from_first_byte(byte as u8);
-            let mut rejected = true;
-            let mut accepted = false;
-            let mut second_byte_unseen = false;
-            while let Some(token_byte) = token_iter.next() {
-                match token_byte {
-                    TokenIterItem::TokenByte(token_byte) => {
-                        let token_byte = token_byte.get();
-                        if second_byte_unseen
-                        // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
-                            && unsafe { invalid_next_bytes.contains_unchecked(token_byte.into()) }
-                        {
-                            rejected = true;
-                            token_iter.next_token();
-                            continue;
-                        }
-                        if Self::accept_byte(
-                            &self.grammar,
-                            &mut self.earley_sets,
-                            &mut self.to_be_completed_items,
-                            &mut self.to_be_completed_items_buffer,
-                            &mut self.leo_items,
-                            &mut self.leo_items_buffer,
-                            &mut self.postdot_items,
-                            &mut self.postdot_items_since_last_commit,
-                            |_| {},
-                            |_| {},
-                            &mut self.already_predicted_nonterminals,
-                            &mut self.deduplication_buffer,
-                            len,
-                            &mut self.finished,
-                            |_, _, _| {},
-                            token_byte,
-                        )
-                        .is_err()
-                        // The token is rejected
-                        {
-                            if second_byte_unseen {
-                                // SAFETY: invalid_next_bytes preallocates 256 bytes on the stack
-                                unsafe { invalid_next_bytes.insert_unchecked(token_byte.into()) };
-                            }
-                            rejected = true;
-                            token_iter.next_token();
-                        }
-                        second_byte_unseen = false;
-                    }
-                    TokenIterItem::NewToken => {
-                        // The token is accepted
-                        second_byte_unseen = true;
-                        if !accepted && !rejected {
-                            Self::revert_change(
-                                &mut self.earley_sets,
-                                &mut self.postdot_items,
-                                &mut self.postdot_items_since_last_commit,
-                                &mut self.leo_items,
-                                |_| {},
-                                len,
-                                &mut self.finished,
-                            );
-                            self.allowed_token_ids.insert(current_token_id);
-                        }
-                        current_token_id = token_iter.current_token_id();
-                        rejected = false;
-                        accepted = eager_cache && self.allowed_token_ids.contains(current_token_id);
-                        if accepted {
-                            token_iter.next_token();
-                        }
-                    }
-                }
-            }
-            // reach the end of the token iterator, revert the last token's change
-            Self::revert_change(
-                &mut self.earley_sets,
-                &mut self.postdot_items,
-                &mut self.postdot_items_since_last_commit,
-                &mut self.leo_items,
-                |_| {},
-                len,
-                &mut self.finished,
-            );
-            if !rejected && !accepted {
-                self.allowed_token_ids.insert(current_token_id);
-            }
-            Self::revert_change(
-                &mut self.earley_sets,
-                &mut self.postdot_items,
-                &mut staged_changes.postdot_items_since_last_commit,
-                &mut self.leo_items,
-                |_| {},
-                staged_changes.earley_sets_len_since_last_commit,
-                &mut self.finished,
-            )
-        }
-        for (token_id, token) in self.vocabulary.tokens_containing_separators() {
-            let mut accepted = true;
-            for byte in token.0.iter().copied() {
-                if Self::accept_byte(
-                    &self.grammar,
-                    &mut self.earley_sets,
-                    &mut self.to_be_completed_items,
-                    &mut self.to_be_completed_items_buffer,
-                    &mut self.leo_items,
-                    &mut self.leo_items_buffer,
-                    &mut self.postdot_items,
-                    &mut self.postdot_items_since_last_commit,
-                    |_| {},
-                    |_| {},
-                    &mut self.already_predicted_nonterminals,
-                    &mut self.deduplication_buffer,
-                    original_earley_set_len,
-                    &mut self.finished,
-                    |_, _, _| {},
-                    byte,
-                )
-                .is_err()
-                // The token is rejected
-                {
-                    accepted = false;
-                    break;
-                }
-            }
-            if accepted {
-                self.allowed_token_ids.insert(token_id as usize);
-                Self::revert_change(
-                    &mut self.earley_sets,
-                    &mut self.postdot_items,
-                    &mut self.postdot_items_since_last_commit,
-                    &mut self.leo_items,
-                    |_| {},
-                    original_earley_set_len,
-                    &mut self.finished,
-                );
-            }
-        }
-        Self::commit_change(&mut self.postdot_items_since_last_commit);
-        if self.config.cache_enabled {
-            self.cache
-                .insert(self.earley_sets.clone(), self.allowed_token_ids.clone());
+        let mut result = self.compute_allowed_token_ids_without_bonuses();
+        if result == Err(ComputeAllowedTokenIdsError::LazyDfaCacheFull)
+            && self.rebuild_full_lazy_dfa_caches()
+        {
+            result = self.compute_allowed_token_ids_without_bonuses();
         }
+        self.update_token_bonuses();
+        self.allowed_token_ids_error = result.err();
     }
 
     fn mask_logits(&self, logits: &mut [f32]) -> Result<(), crate::engine_like::MaskLogitsError> {
-        let vocab_size = self.vocabulary.vocab_size();
-        let logits_len = logits.len();
-        if logits_len < vocab_size {
-            return Err(crate::engine_like::MaskLogitsError::InvalidLogitsLength);
-        }
-        if self.allowed_token_ids.count_zeroes(..) > logits_len / 2 {
-            let mut mask = vec![f32::NEG_INFINITY; logits_len];
-            for token_id in self.allowed_token_ids.ones() {
-                // SAFETY: the capacity of self.allowed_token_ids == vocab_size and we have checked logits_len >= vocab_size
-                unsafe { *mask.get_unchecked_mut(token_id) = *logits.get_unchecked(token_id) };
-            }
-            logits.copy_from_slice(&mask);
-        } else {
-            for token_id in self.allowed_token_ids.zeroes() {
-                // SAFETY: the capacity of self.allowed_token_ids == vocab_size and we have checked logits_len >= vocab_size
-                unsafe { *logits.get_unchecked_mut(token_id) = f32::NEG_INFINITY };
-            }
-        }
-        Ok(())
+        self.mask_logits_rows(logits, logits.len())
+    }
+
+    fn mask_logits_f16(
+        &self,
+        logits: &mut [half::f16],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        self.mask_logits_rows(logits, logits.len())
+    }
+
+    fn mask_logits_bf16(
+        &self,
+        logits: &mut [half::bf16],
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        self.mask_logits_rows(logits, logits.len())
+    }
+
+    fn mask_logits_2d(
+        &self,
+        logits: &mut [f32],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        self.mask_logits_rows(logits, row_stride)
+    }
+
+    fn mask_logits_2d_f16(
+        &self,
+        logits: &mut [half::f16],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        self.mask_logits_rows(logits, row_stride)
+    }
+
+    fn mask_logits_2d_bf16(
+        &self,
+        logits: &mut [half::bf16],
+        row_stride: usize,
+    ) -> Result<(), crate::engine_like::MaskLogitsError> {
+        self.mask_logits_rows(logits, row_stride)
     }
 
     fn update_logits(
@@ -1891,6 +3380,12 @@ where
             crate::engine_like::AcceptTokenError::Rejected => {
                 crate::engine_like::UpdateLogitsError::Rejected
             }
+            crate::engine_like::AcceptTokenError::LazyDfaCacheFull => {
+                crate::engine_like::UpdateLogitsError::LazyDfaCacheFull
+            }
+            crate::engine_like::AcceptTokenError::OutputLimitReached => {
+                crate::engine_like::UpdateLogitsError::OutputLimitReached
+            }
         })?;
         if AcceptTokenResult::Finished == result {
             return Ok(crate::engine_like::AcceptTokenResult::Finished);
@@ -1908,6 +3403,18 @@ where
         &self.allowed_token_ids
     }
 
+    fn allowed_token_ids_error_from_last_computation(&self) -> Option<ComputeAllowedTokenIdsError> {
+        self.allowed_token_ids_error
+    }
+
+    fn token_bonuses_from_last_computation(&self) -> &[(usize, f32)] {
+        &self.token_bonuses
+    }
+
+    fn has_positive_bonuses(&self) -> bool {
+        self.grammar.has_positive_bonuses()
+    }
+
     fn write_disallowed_token_ids_to_buffer(
         &self,
         buffer: &mut [usize],
@@ -1934,12 +3441,25 @@ where
         Ok(())
     }
 
+    fn write_allowed_bitmask(&self, buffer: &mut [u32]) -> Result<(), WriteBufferError> {
+        utils::write_bitmask(&self.allowed_token_ids, buffer)
+    }
+
+    fn write_allowed_bitmask_u64(&self, buffer: &mut [u64]) -> Result<(), WriteBufferError> {
+        utils::write_bitmask(&self.allowed_token_ids, buffer)
+    }
+
     fn is_finished(&self) -> bool {
         self.finished
     }
 
     fn reset(&mut self) {
         self.earley_sets.clear();
+        // No Earley item refers to a state of the lazy DFAs now, so their full caches can be cleared.
+        // The allowed token IDs cached by their states are cleared as well.
+        if self.lazy_dfa_caches.clear_full() {
+            self.cache.clear();
+        }
         self.to_be_completed_items.clear();
         self.to_be_completed_items_buffer.clear();
         self.leo_items.clear();
@@ -1948,9 +3468,14 @@ where
         self.postdot_items_since_last_commit.clear();
         self.deduplication_buffer.clear();
         self.column_to_postdot_nonterminals.clear();
+        self.column_lengths.clear();
         self.already_predicted_nonterminals.clear();
         self.finished = false;
+        self.output_bytes = 0;
+        self.output_tokens = 0;
         self.allowed_token_ids.clear();
+        self.allowed_token_ids_error = None;
+        self.token_bonuses.clear();
         self.allowed_first_bytes.clear();
         self.earley_sets.new_row::<0>();
         Self::predict_nonterminal(
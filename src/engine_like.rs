@@ -4,6 +4,7 @@ use std::sync::Arc;
 
 use displaydoc::Display;
 use fixedbitset_stack::FixedBitSet;
+use half::{bf16, f16};
 #[cfg(feature = "python")]
 use pyo3::pyclass;
 #[cfg(feature = "wasm")]
@@ -21,6 +22,14 @@ pub enum AcceptTokenError {
     Rejected,
     /// The [`EngineLike`] is finished, as defined by its grammar. No more tokens can be accepted.
     Finished,
+    /// A transition of a lazy DFA does not fit in its cache, so the token cannot be checked
+    /// and the [`EngineLike`]'s internal states are not updated.
+    /// It is only returned when the full cache cannot be rebuilt with the states the [`EngineLike`] refers to
+    /// and the transitions of the token, so a larger [`RegexConfig::cache_capacity`](crate::config::RegexConfig::cache_capacity) is needed.
+    LazyDfaCacheFull,
+    /// The [`EngineLike`] has accepted [`EngineConfig::max_output_tokens`](crate::engine::EngineConfig::max_output_tokens) tokens,
+    /// so no more tokens can be accepted regardless of the grammar.
+    OutputLimitReached,
 }
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -35,9 +44,24 @@ pub enum AcceptTokenResult {
 #[cfg_attr(feature = "python", pyclass(eq, eq_int))]
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
+/// Represents the error when an [`EngineLike`] computes the allowed token IDs.
+pub enum ComputeAllowedTokenIdsError {
+    /// No token is allowed while the [`EngineLike`] is not finished, so the grammar can never be completed with the vocabulary.
+    DeadEnd,
+    /// A transition of a lazy DFA does not fit in its cache, so some tokens may be missing from the allowed token IDs.
+    /// It is only returned when the full cache cannot be rebuilt with the states the [`EngineLike`] refers to
+    /// and the transitions of every token, so a larger [`RegexConfig::cache_capacity`](crate::config::RegexConfig::cache_capacity) is needed.
+    LazyDfaCacheFull,
+    /// The [`EngineLike`] has output [`EngineConfig::max_output_tokens`](crate::engine::EngineConfig::max_output_tokens) tokens without finishing,
+    /// so no more tokens are allowed.
+    OutputLimitReached,
+}
+#[cfg_attr(feature = "python", pyclass(eq, eq_int))]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash)]
 /// Represents the error when an [`EngineLike`] tries to mask logits.
 pub enum MaskLogitsError {
-    /// The input logits array is not equal to the vocabulary size.
+    /// The input logits array is not of the expected length according to the vocabulary size.
     InvalidLogitsLength,
 }
 
@@ -63,6 +87,14 @@ pub enum UpdateLogitsError {
     Finished,
     /// The input logits array is not of the expected length according to the vocabulary.
     InvalidLogitsLength,
+    /// A transition of a lazy DFA does not fit in its cache, so the token cannot be checked
+    /// and the [`EngineLike`]'s internal states are not updated.
+    /// It is only returned when the full cache cannot be rebuilt to fit the transitions of the token,
+    /// as described in [`AcceptTokenError::LazyDfaCacheFull`].
+    LazyDfaCacheFull,
+    /// The [`EngineLike`] has accepted [`EngineConfig::max_output_tokens`](crate::engine::EngineConfig::max_output_tokens) tokens,
+    /// so no more tokens can be accepted regardless of the grammar.
+    OutputLimitReached,
 }
 pub(crate) mod sealed {
     pub trait Sealed {}
@@ -106,8 +138,29 @@ pub trait EngineLike: sealed::Sealed {
         -> Result<AcceptTokenResult, AcceptTokenError>;
 
     /// Computes the allowed token IDs based on current states.
+    ///
+    /// The computation may fail, e.g. when no token is allowed while the [`EngineLike`] is not finished.
+    /// Check [`EngineLike::allowed_token_ids_error_from_last_computation`] for such failures,
+    /// or use [`EngineLike::try_compute_allowed_token_ids`] instead.
     fn compute_allowed_token_ids(&mut self);
 
+    /// Computes the allowed token IDs based on current states like [`EngineLike::compute_allowed_token_ids`],
+    /// and returns the failure of the computation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ComputeAllowedTokenIdsError::DeadEnd`] when no token is allowed while the [`EngineLike`] is not finished.
+    /// Returns [`ComputeAllowedTokenIdsError::LazyDfaCacheFull`] when a lazy DFA cache is too small to check every token.
+    /// Returns [`ComputeAllowedTokenIdsError::OutputLimitReached`] when the output limit is reached while the [`EngineLike`] is not finished.
+    /// The allowed token IDs are still updated in these cases.
+    fn try_compute_allowed_token_ids(&mut self) -> Result<(), ComputeAllowedTokenIdsError> {
+        self.compute_allowed_token_ids();
+        match self.allowed_token_ids_error_from_last_computation() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
     /// Masks the logits based on last computed token IDs.
     /// These token IDs can also be obtained from [`EngineLike::allowed_token_ids_from_last_computation`].
     ///
@@ -124,6 +177,73 @@ pub trait EngineLike: sealed::Sealed {
     /// The logits array is not updated in this case.
     fn mask_logits(&self, logits: &mut [f32]) -> Result<(), MaskLogitsError>;
 
+    /// Masks the [`half::f16`] logits based on last computed token IDs.
+    ///
+    /// This is the same as [`EngineLike::mask_logits`], except for the element type of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    fn mask_logits_f16(&self, logits: &mut [f16]) -> Result<(), MaskLogitsError>;
+
+    /// Masks the [`half::bf16`] logits based on last computed token IDs.
+    ///
+    /// This is the same as [`EngineLike::mask_logits`], except for the element type of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    fn mask_logits_bf16(&self, logits: &mut [bf16]) -> Result<(), MaskLogitsError>;
+
+    /// Masks every row of a row-major 2D logits array based on last computed token IDs.
+    ///
+    /// Every row is masked as [`EngineLike::mask_logits`] masks a single logits array.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - A mutable reference to the row-major logits array to be masked.
+    /// * `row_stride` - The distance in elements between the starts of two consecutive rows.
+    ///   It can be larger than the vocabulary size, in which case the padding of each row is masked as well.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MaskLogitsError::InvalidLogitsLength`] when `row_stride` is smaller than the vocabulary size
+    /// or the length of the logits array is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    fn mask_logits_2d(&self, logits: &mut [f32], row_stride: usize) -> Result<(), MaskLogitsError>;
+
+    /// Masks every row of a row-major 2D [`half::f16`] logits array based on last computed token IDs.
+    ///
+    /// This is the same as [`EngineLike::mask_logits_2d`], except for the element type of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MaskLogitsError::InvalidLogitsLength`] when `row_stride` is smaller than the vocabulary size
+    /// or the length of the logits array is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    fn mask_logits_2d_f16(
+        &self,
+        logits: &mut [f16],
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError>;
+
+    /// Masks every row of a row-major 2D [`half::bf16`] logits array based on last computed token IDs.
+    ///
+    /// This is the same as [`EngineLike::mask_logits_2d`], except for the element type of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`MaskLogitsError::InvalidLogitsLength`] when `row_stride` is smaller than the vocabulary size
+    /// or the length of the logits array is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    fn mask_logits_2d_bf16(
+        &self,
+        logits: &mut [bf16],
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError>;
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Arguments
@@ -140,6 +260,10 @@ pub trait EngineLike: sealed::Sealed {
     /// Returns an [`UpdateLogitsError`] when the logits is not updated. Check the error type docs for more details.
     /// The [`EngineLike`] internal states are not updated in this case.
     /// The logits array is not updated as well.
+    ///
+    /// Once the token is accepted, the allowed token IDs are computed and `Ok` is returned
+    /// even if the computation fails, e.g. because of a dead end.
+    /// Check [`EngineLike::allowed_token_ids_error_from_last_computation`] for such failures.
     fn update_logits(
         &mut self,
         token_id: u32,
@@ -151,6 +275,21 @@ pub trait EngineLike: sealed::Sealed {
     ///
     /// In other words, [`EngineLike::try_accept_new_token`] DOES NOT compute the allowed token IDs and hence DOES NOT affect its result!
     fn allowed_token_ids_from_last_computation(&self) -> &FixedBitSet;
+    /// Gets the error of the last computation, or `None` if it succeeded.
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    ///
+    /// It is how the callers of [`EngineLike::compute_allowed_token_ids`] and [`EngineLike::update_logits`]
+    /// learn that no token is allowed or some allowed tokens are missing.
+    fn allowed_token_ids_error_from_last_computation(&self) -> Option<ComputeAllowedTokenIdsError>;
+    /// Gets the bonuses of the allowed token IDs from last computation, as pairs of token IDs and bonuses
+    /// in ascending order of token IDs.
+    ///
+    /// Only the tokens with nonzero bonuses are listed. The bonuses come from the `bonus` statements
+    /// and the weighted alternatives of the grammar, and are the ones added by [`EngineLike::mask_logits`].
+    fn token_bonuses_from_last_computation(&self) -> &[(usize, f32)];
+    /// Whether any token may receive a positive bonus from the grammar,
+    /// in which case accepting a token may increase the log-probability of the output.
+    fn has_positive_bonuses(&self) -> bool;
     /// Write the disallowed token IDs to the given buffer.
     fn write_disallowed_token_ids_to_buffer(
         &self,
@@ -162,6 +301,28 @@ pub trait EngineLike: sealed::Sealed {
         &self,
         buffer: &mut [usize],
     ) -> Result<(), WriteBufferError>;
+
+    /// Write the allowed token IDs to the given buffer as a packed bitmask.
+    ///
+    /// Token ID `i` is allowed if and only if bit `i % 32` of `buffer[i / 32]` is set,
+    /// which matches the layout of [`FixedBitSet`].
+    /// Bits beyond the vocabulary size are cleared, and only the first `vocab_size.div_ceil(32)` words are written.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`WriteBufferError::BufferTooSmall`] when the buffer has fewer than `vocab_size.div_ceil(32)` words.
+    /// The buffer is not updated in this case.
+    fn write_allowed_bitmask(&self, buffer: &mut [u32]) -> Result<(), WriteBufferError>;
+
+    /// Write the allowed token IDs to the given buffer as a packed bitmask of 64-bit words.
+    ///
+    /// This is the same as [`EngineLike::write_allowed_bitmask`], except that each word holds 64 token IDs.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`WriteBufferError::BufferTooSmall`] when the buffer has fewer than `vocab_size.div_ceil(64)` words.
+    /// The buffer is not updated in this case.
+    fn write_allowed_bitmask_u64(&self, buffer: &mut [u64]) -> Result<(), WriteBufferError>;
     /// Checks if the engine is finished.
     fn is_finished(&self) -> bool;
     /// Resets the engine to its initial state. Notably, the cache is preserved.
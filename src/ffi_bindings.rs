@@ -1,9 +1,21 @@
 #[cfg(any(feature = "python", feature = "wasm"))]
+use crate::completion::Completion;
+#[cfg(any(feature = "python", feature = "wasm"))]
+use crate::completion::EnumerateCompletionsError;
+#[cfg(any(feature = "python", feature = "wasm"))]
+use crate::completion::ShortestCompletionError;
+#[cfg(any(feature = "python", feature = "wasm"))]
+use crate::diagnostic::Diagnostic;
+#[cfg(any(feature = "python", feature = "wasm"))]
 use crate::engine::CreateEngineError;
 #[cfg(any(feature = "python", feature = "wasm"))]
 use crate::engine_like::WriteBufferError;
 #[cfg(any(feature = "python", feature = "wasm"))]
-use crate::engine_like::{AcceptTokenError, MaskLogitsError, UpdateLogitsError};
+use crate::engine_like::{
+    AcceptTokenError, ComputeAllowedTokenIdsError, MaskLogitsError, UpdateLogitsError,
+};
+#[cfg(any(feature = "python", feature = "wasm"))]
+use crate::grammar::SetDynamicError;
 #[cfg(any(feature = "python", feature = "wasm"))]
 use crate::vocabulary::{CreateVocabularyError, Vocabulary};
 #[cfg(any(feature = "python", feature = "wasm"))]
@@ -13,9 +25,9 @@ use pyo3::exceptions::PyValueError;
 #[cfg(feature = "python")]
 use pyo3::types::PyDict;
 #[cfg(feature = "python")]
-use pyo3::Python;
-#[cfg(feature = "python")]
 use pyo3::{pymethods, PyErr};
+#[cfg(feature = "python")]
+use pyo3::{types::PyAnyMethods, IntoPy, Python};
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
@@ -24,6 +36,36 @@ use wasm_bindgen::prelude::*;
 #[cfg(feature = "wasm")]
 impl From<CreateEngineError> for JsValue {
     fn from(error: CreateEngineError) -> Self {
+        match error.diagnostic() {
+            Some(diagnostic) => {
+                let js_error = js_sys::Error::new(error.to_string().as_str());
+                // Setting a property on a freshly created Error object never fails.
+                let _ = js_sys::Reflect::set(
+                    &js_error,
+                    &JsValue::from_str("diagnostic"),
+                    &JsValue::from(diagnostic.clone()),
+                );
+                js_error.into()
+            }
+            None => JsValue::from_str(error.to_string().as_str()),
+        }
+    }
+}
+#[cfg(feature = "wasm")]
+impl From<SetDynamicError> for JsValue {
+    fn from(error: SetDynamicError) -> Self {
+        JsValue::from_str(error.to_string().as_str())
+    }
+}
+#[cfg(feature = "wasm")]
+impl From<EnumerateCompletionsError> for JsValue {
+    fn from(error: EnumerateCompletionsError) -> Self {
+        JsValue::from_str(error.to_string().as_str())
+    }
+}
+#[cfg(feature = "wasm")]
+impl From<ShortestCompletionError> for JsValue {
+    fn from(error: ShortestCompletionError) -> Self {
         JsValue::from_str(error.to_string().as_str())
     }
 }
@@ -36,7 +78,16 @@ impl From<CreateVocabularyError> for PyErr {
 #[cfg(feature = "python")]
 impl From<CreateEngineError> for PyErr {
     fn from(error: CreateEngineError) -> Self {
-        PyErr::new::<PyValueError, _>(error.to_string())
+        let py_error = PyErr::new::<PyValueError, _>(error.to_string());
+        if let Some(diagnostic) = error.diagnostic() {
+            Python::with_gil(|py| {
+                // Setting an attribute on a freshly created ValueError never fails.
+                let _ = py_error
+                    .value_bound(py)
+                    .setattr("diagnostic", diagnostic.clone().into_py(py));
+            });
+        }
+        py_error
     }
 }
 #[cfg(feature = "python")]
@@ -46,6 +97,30 @@ impl From<AcceptTokenError> for PyErr {
     }
 }
 #[cfg(feature = "python")]
+impl From<ComputeAllowedTokenIdsError> for PyErr {
+    fn from(error: ComputeAllowedTokenIdsError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
+impl From<SetDynamicError> for PyErr {
+    fn from(error: SetDynamicError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
+impl From<EnumerateCompletionsError> for PyErr {
+    fn from(error: EnumerateCompletionsError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
+impl From<ShortestCompletionError> for PyErr {
+    fn from(error: ShortestCompletionError) -> Self {
+        PyErr::new::<PyValueError, _>(error.to_string())
+    }
+}
+#[cfg(feature = "python")]
 impl From<MaskLogitsError> for PyErr {
     fn from(error: MaskLogitsError) -> Self {
         PyErr::new::<PyValueError, _>(error.to_string())
@@ -351,6 +426,59 @@ impl Engine {
         EngineLike::compute_allowed_token_ids(self)
     }
 
+    /// Computes the allowed token IDs based on current states and returns the failure of the computation.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ComputeAllowedTokenIdsError::DeadEnd`] when no token is allowed while the engine is not finished.
+    /// Returns [`ComputeAllowedTokenIdsError::LazyDfaCacheFull`] when a lazy DFA cache is too small to check every token.
+    /// Returns [`ComputeAllowedTokenIdsError::OutputLimitReached`] when the output limit is reached while the engine is not finished.
+    #[wasm_bindgen(js_name = tryComputeAllowedTokenIds)]
+    pub fn try_compute_allowed_token_ids_js(&mut self) -> Result<(), ComputeAllowedTokenIdsError> {
+        EngineLike::try_compute_allowed_token_ids(self)
+    }
+
+    /// Replaces the strings matched by the dynamic alternative `#dynamic"name"` and resets the engine.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dynamic alternative does not exist, if any of the strings is empty,
+    /// or if the strings cannot be compiled into a regex supported by the engine.
+    #[wasm_bindgen(js_name = setDynamic)]
+    pub fn set_dynamic_js(
+        &mut self,
+        name: &str,
+        strings: Vec<String>,
+    ) -> Result<(), SetDynamicError> {
+        let strings: Vec<&str> = strings.iter().map(String::as_str).collect();
+        self.set_dynamic(name, &strings)
+    }
+
+    /// Finds the shortest bytes that finish the grammar from the current state, along with the fewest tokens that spell them.
+    ///
+    /// Throws an error if the grammar can never be finished or the search gives up.
+    #[wasm_bindgen(js_name = shortestCompletion)]
+    pub fn shortest_completion_js(&self) -> Result<Completion, ShortestCompletionError> {
+        self.shortest_completion()
+    }
+
+    /// Enumerates every token sequence that finishes the grammar from the current state, as an Array of Uint32Arrays.
+    ///
+    /// Throws if there are more than `maxCompletions` sequences, infinitely many sequences are found,
+    /// a sequence can be longer than `maxTokens` tokens, or a lazy DFA cache is full.
+    #[wasm_bindgen(js_name = enumerateCompletions)]
+    pub fn enumerate_completions_js(
+        &self,
+        max_completions: usize,
+        max_tokens: usize,
+    ) -> Result<js_sys::Array, EnumerateCompletionsError> {
+        Ok(self
+            .enumerate_completions(max_completions, max_tokens)?
+            .iter()
+            .map(|x| js_sys::Uint32Array::from(x.as_slice()))
+            .collect())
+    }
+
     /// Gets the allowed token IDs since last computation.
     /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
     ///
@@ -361,6 +489,25 @@ impl Engine {
             .ones()
             .collect()
     }
+    /// Gets the error of the last computation, or `undefined` if it succeeded.
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    #[wasm_bindgen(js_name = getAllowedTokenIdsErrorFromLastComputation)]
+    pub fn allowed_token_ids_error_from_last_computation_js(
+        &self,
+    ) -> Option<ComputeAllowedTokenIdsError> {
+        EngineLike::allowed_token_ids_error_from_last_computation(self)
+    }
+    /// Gets the bonuses of the allowed token IDs from last computation, as a Map<number, number> from token IDs to bonuses.
+    ///
+    /// Only the tokens with nonzero bonuses are listed.
+    #[wasm_bindgen(js_name = getTokenBonusesFromLastComputation)]
+    pub fn token_bonuses_from_last_computation_js(&self) -> js_sys::Map {
+        let bonuses = js_sys::Map::new();
+        for &(token_id, bonus) in EngineLike::token_bonuses_from_last_computation(self) {
+            bonuses.set(&JsValue::from(token_id), &JsValue::from(bonus));
+        }
+        bonuses
+    }
     /// Checks if the engine is finished.
     #[wasm_bindgen(js_name = isFinished)]
     pub fn is_finished_js(&self) -> bool {
@@ -395,6 +542,29 @@ impl Engine {
         EngineLike::mask_logits(self, logits)
     }
 
+    /// Masks every row of a row-major 2D logits array based on last computed token IDs.
+    ///
+    /// Every row is masked as [`EngineLike::mask_logits`] masks a single logits array.
+    ///
+    /// # Arguments
+    ///
+    /// * `logits` - A mutable reference to the row-major logits array to be masked.
+    /// * `row_stride` - The distance in elements between the starts of two consecutive rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when `row_stride` is smaller than the vocabulary size
+    /// or the length of the logits array is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    #[wasm_bindgen(js_name = maskLogits2d)]
+    pub fn mask_logits_2d_js(
+        &self,
+        logits: &mut [f32],
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError> {
+        EngineLike::mask_logits_2d(self, logits, row_stride)
+    }
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Arguments
@@ -411,6 +581,7 @@ impl Engine {
     /// Returns an [`UpdateLogitsError`] when the logits is not updated. Check the error type docs for more details.
     /// The [`EngineLike`] internal states are not updated in this case.
     /// The logits array is not updated as well.
+    /// Once the token is accepted, check [`EngineLike::allowed_token_ids_error_from_last_computation`] for the failures of the computation.
     #[wasm_bindgen(js_name = updateLogits)]
     pub fn update_logits_js(
         &mut self,
@@ -419,6 +590,34 @@ impl Engine {
     ) -> Result<AcceptTokenResult, UpdateLogitsError> {
         EngineLike::update_logits(self, token_id, logits)
     }
+
+    /// Writes the allowed token IDs to the given buffer as a packed bitmask of 32-bit words.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to be written. It should have at least `ceil(vocab_size / 32)` words.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is too small.
+    #[wasm_bindgen(js_name = writeAllowedBitmask)]
+    pub fn write_allowed_bitmask_js(&self, buffer: &mut [u32]) -> Result<(), WriteBufferError> {
+        EngineLike::write_allowed_bitmask(self, buffer)
+    }
+
+    /// Writes the allowed token IDs to the given buffer as a packed bitmask of 64-bit words.
+    ///
+    /// # Arguments
+    ///
+    /// * `buffer` - The buffer to be written. It should have at least `ceil(vocab_size / 64)` words.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is too small.
+    #[wasm_bindgen(js_name = writeAllowedBitmaskU64)]
+    pub fn write_allowed_bitmask_u64_js(&self, buffer: &mut [u64]) -> Result<(), WriteBufferError> {
+        EngineLike::write_allowed_bitmask_u64(self, buffer)
+    }
 }
 
 #[cfg(feature = "python")]
@@ -515,6 +714,83 @@ impl Engine {
         py.allow_threads(|| EngineLike::compute_allowed_token_ids(self));
     }
 
+    /// Computes the allowed token IDs based on current states and raises the failure of the computation.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> None
+    ///
+    /// # Errors
+    ///
+    /// Raises a `ValueError` when no token is allowed while the engine is not finished,
+    /// when a lazy DFA cache is too small to check every token, or when the output limit is reached.
+    #[pyo3(name = "try_compute_allowed_token_ids")]
+    pub fn try_compute_allowed_token_ids_py(
+        &mut self,
+        py: Python<'_>,
+    ) -> Result<(), ComputeAllowedTokenIdsError> {
+        py.allow_threads(|| EngineLike::try_compute_allowed_token_ids(self))
+    }
+
+    /// Replaces the strings matched by the dynamic alternative `#dynamic"name"` and resets the engine.
+    ///
+    /// # Signature
+    ///
+    /// (self, name: str, strings: list[str]) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the dynamic alternative.
+    /// * `strings` - The strings to be matched. An empty list makes the dynamic alternative match nothing again.
+    ///
+    /// # Errors
+    ///
+    /// Raises a `ValueError` if the dynamic alternative does not exist, if any of the strings is empty,
+    /// or if the strings cannot be compiled into a regex supported by the engine.
+    #[pyo3(name = "set_dynamic")]
+    pub fn set_dynamic_py(
+        &mut self,
+        py: Python<'_>,
+        name: &str,
+        strings: Vec<String>,
+    ) -> Result<(), SetDynamicError> {
+        let strings: Vec<&str> = strings.iter().map(String::as_str).collect();
+        py.allow_threads(|| self.set_dynamic(name, &strings))
+    }
+
+    /// Finds the shortest bytes that finish the grammar from the current state, along with the fewest tokens that spell them.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Completion
+    ///
+    /// Raises `ValueError` if the grammar can never be finished or the search gives up.
+    #[pyo3(name = "shortest_completion")]
+    pub fn shortest_completion_py(
+        &self,
+        py: Python<'_>,
+    ) -> Result<Completion, ShortestCompletionError> {
+        py.allow_threads(|| self.shortest_completion())
+    }
+
+    /// Enumerates every token sequence that finishes the grammar from the current state.
+    ///
+    /// # Signature
+    ///
+    /// (self, max_completions: int, max_tokens: int) -> List[List[int]]
+    ///
+    /// Raises `ValueError` if there are more than `max_completions` sequences, infinitely many sequences are found,
+    /// a sequence can be longer than `max_tokens` tokens, or a lazy DFA cache is full.
+    #[pyo3(name = "enumerate_completions")]
+    pub fn enumerate_completions_py(
+        &self,
+        py: Python<'_>,
+        max_completions: usize,
+        max_tokens: usize,
+    ) -> Result<Vec<Vec<u32>>, EnumerateCompletionsError> {
+        py.allow_threads(|| self.enumerate_completions(max_completions, max_tokens))
+    }
+
     /// Gets the allowed token IDs since last computation.
     /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
     ///
@@ -529,6 +805,40 @@ impl Engine {
             .ones()
             .collect()
     }
+    /// Gets the error of the last computation, or `None` if it succeeded.
+    /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Optional[ComputeAllowedTokenIdsError]
+    #[pyo3(name = "get_allowed_token_ids_error_from_last_computation")]
+    pub fn allowed_token_ids_error_from_last_computation_py(
+        &self,
+    ) -> Option<ComputeAllowedTokenIdsError> {
+        EngineLike::allowed_token_ids_error_from_last_computation(self)
+    }
+    /// Gets the bonuses of the allowed token IDs from last computation, as pairs of token IDs and bonuses.
+    ///
+    /// Only the tokens with nonzero bonuses are listed.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> List[Tuple[int, float]]
+    #[pyo3(name = "get_token_bonuses_from_last_computation")]
+    pub fn token_bonuses_from_last_computation_py(&self) -> Vec<(usize, f32)> {
+        EngineLike::token_bonuses_from_last_computation(self).to_vec()
+    }
+    /// Gets the penalty added to the logits of the disallowed tokens.
+    ///
+    /// `None` means the logits of the disallowed tokens are set to negative infinity.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> Optional[float]
+    #[pyo3(name = "get_disallowed_token_penalty")]
+    pub fn disallowed_token_penalty_py(&self) -> Option<f32> {
+        self.disallowed_token_penalty()
+    }
     /// Gets the disallowed token IDs since last computation.
     /// Last computation is the last [`EngineLike::compute_allowed_token_ids`] or [`EngineLike::update_logits`] called.
     ///
@@ -691,6 +1001,106 @@ impl Engine {
         EngineLike::write_allowed_token_ids_to_buffer(self, buffer)
     }
 
+    /// Writes the allowed token IDs to the given buffer as a packed bitmask of 32-bit words.
+    ///
+    /// # Signature
+    ///
+    /// (self, ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - The pointer to the buffer of `u32` words.
+    /// * `length` - The number of words in the buffer. It should be at least `ceil(vocab_size / 32)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is too small.
+    ///
+    ///
+    /// # Safety
+    ///
+    /// Behavior is undefined if any of the following conditions are violated:
+    ///
+    /// * `ptr` must be [valid] for both reads and writes for `len * mem::size_of::<T>()` many bytes,
+    ///   and it must be properly aligned. This means in particular:
+    ///
+    ///     * The entire memory range of this slice must be contained within a single allocated object!
+    ///       Slices can never span across multiple allocated objects.
+    ///     * `ptr` must be non-null and aligned even for zero-length slices. One
+    ///       reason for this is that enum layout optimizations may rely on references
+    ///       (including slices of any length) being aligned and non-null to distinguish
+    ///       them from other data. You can obtain a pointer that is usable as `ptr`
+    ///       for zero-length slices using [`NonNull::dangling()`].
+    ///
+    /// * `ptr` must point to `len` consecutive properly initialized values of type `T`.
+    ///
+    /// * The memory referenced by the returned slice must not be accessed through any other pointer
+    ///   (not derived from the return value) for the duration of lifetime `'a`.
+    ///   Both read and write accesses are forbidden.
+    ///
+    /// * The total size `len * mem::size_of::<T>()` of the slice must be no larger than `isize::MAX`,
+    ///   and adding that size to `data` must not "wrap around" the address space.
+    ///   See the safety documentation of [`pointer::offset`].
+    #[pyo3(name = "write_allowed_bitmask")]
+    pub unsafe fn write_allowed_bitmask_py(
+        &self,
+        ptr: usize,
+        length: usize,
+    ) -> Result<(), WriteBufferError> {
+        let buffer = std::slice::from_raw_parts_mut(ptr as *mut u32, length);
+        EngineLike::write_allowed_bitmask(self, buffer)
+    }
+
+    /// Writes the allowed token IDs to the given buffer as a packed bitmask of 64-bit words.
+    ///
+    /// # Signature
+    ///
+    /// (self, ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `ptr` - The pointer to the buffer of `u64` words.
+    /// * `length` - The number of words in the buffer. It should be at least `ceil(vocab_size / 64)`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`WriteBufferError`] when the buffer is too small.
+    ///
+    ///
+    /// # Safety
+    ///
+    /// Behavior is undefined if any of the following conditions are violated:
+    ///
+    /// * `ptr` must be [valid] for both reads and writes for `len * mem::size_of::<T>()` many bytes,
+    ///   and it must be properly aligned. This means in particular:
+    ///
+    ///     * The entire memory range of this slice must be contained within a single allocated object!
+    ///       Slices can never span across multiple allocated objects.
+    ///     * `ptr` must be non-null and aligned even for zero-length slices. One
+    ///       reason for this is that enum layout optimizations may rely on references
+    ///       (including slices of any length) being aligned and non-null to distinguish
+    ///       them from other data. You can obtain a pointer that is usable as `ptr`
+    ///       for zero-length slices using [`NonNull::dangling()`].
+    ///
+    /// * `ptr` must point to `len` consecutive properly initialized values of type `T`.
+    ///
+    /// * The memory referenced by the returned slice must not be accessed through any other pointer
+    ///   (not derived from the return value) for the duration of lifetime `'a`.
+    ///   Both read and write accesses are forbidden.
+    ///
+    /// * The total size `len * mem::size_of::<T>()` of the slice must be no larger than `isize::MAX`,
+    ///   and adding that size to `data` must not "wrap around" the address space.
+    ///   See the safety documentation of [`pointer::offset`].
+    #[pyo3(name = "write_allowed_bitmask_u64")]
+    pub unsafe fn write_allowed_bitmask_u64_py(
+        &self,
+        ptr: usize,
+        length: usize,
+    ) -> Result<(), WriteBufferError> {
+        let buffer = std::slice::from_raw_parts_mut(ptr as *mut u64, length);
+        EngineLike::write_allowed_bitmask_u64(self, buffer)
+    }
+
     /// Checks if the engine is finished.
     /// # Signature
     ///
@@ -750,6 +1160,170 @@ impl Engine {
         EngineLike::mask_logits(self, logits)
     }
 
+    /// Masks the float16 logits based on last computed token IDs.
+    ///
+    /// This is the same as `mask_logits`, except for the element type of the logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The length of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains float16 and the length is correct.
+    #[pyo3(name = "mask_logits_f16")]
+    pub unsafe fn mask_logits_f16_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut half::f16, length);
+        EngineLike::mask_logits_f16(self, logits)
+    }
+
+    /// Masks the bfloat16 logits based on last computed token IDs.
+    ///
+    /// This is the same as `mask_logits`, except for the element type of the logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The length of the logits array.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when the input logits array is not of the expected length according to the vocabulary.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains bfloat16 and the length is correct.
+    #[pyo3(name = "mask_logits_bf16")]
+    pub unsafe fn mask_logits_bf16_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut half::bf16, length);
+        EngineLike::mask_logits_bf16(self, logits)
+    }
+
+    /// Masks every row of a row-major 2D logits array based on last computed token IDs.
+    ///
+    /// Every row is masked as `mask_logits` masks a single logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int, row_stride: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The total length of the logits array.
+    /// * `row_stride` - The distance in elements between the starts of two consecutive rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when `row_stride` is smaller than the vocabulary size
+    /// or `length` is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains float32 and the length is correct.
+    #[pyo3(name = "mask_logits_2d")]
+    pub unsafe fn mask_logits_2d_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut f32, length);
+        EngineLike::mask_logits_2d(self, logits, row_stride)
+    }
+
+    /// Masks every row of a row-major 2D float16 logits array based on last computed token IDs.
+    ///
+    /// Every row is masked as `mask_logits` masks a single logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int, row_stride: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The total length of the logits array.
+    /// * `row_stride` - The distance in elements between the starts of two consecutive rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when `row_stride` is smaller than the vocabulary size
+    /// or `length` is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains float16 and the length is correct.
+    #[pyo3(name = "mask_logits_2d_f16")]
+    pub unsafe fn mask_logits_2d_f16_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut half::f16, length);
+        EngineLike::mask_logits_2d_f16(self, logits, row_stride)
+    }
+
+    /// Masks every row of a row-major 2D bfloat16 logits array based on last computed token IDs.
+    ///
+    /// Every row is masked as `mask_logits` masks a single logits array.
+    ///
+    /// # Signature
+    ///
+    /// (self, logits_ptr: int, length: int, row_stride: int) -> None
+    ///
+    /// # Arguments
+    ///
+    /// * `logits_ptr` - The pointer to the logits array.
+    /// * `length` - The total length of the logits array.
+    /// * `row_stride` - The distance in elements between the starts of two consecutive rows.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`MaskLogitsError`] when `row_stride` is smaller than the vocabulary size
+    /// or `length` is not a multiple of `row_stride`.
+    /// The logits array is not updated in this case.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that the pointer is on CPU, points to readable,aligned memory that contains bfloat16 and the length is correct.
+    #[pyo3(name = "mask_logits_2d_bf16")]
+    pub unsafe fn mask_logits_2d_bf16_py(
+        &self,
+        logits_ptr: usize,
+        length: usize,
+        row_stride: usize,
+    ) -> Result<(), MaskLogitsError> {
+        let logits = std::slice::from_raw_parts_mut(logits_ptr as *mut half::bf16, length);
+        EngineLike::mask_logits_2d_bf16(self, logits, row_stride)
+    }
+
     /// Try to accept the token ID and if succeeds, update the given logits array.
     ///
     /// # Signature
@@ -771,6 +1345,7 @@ impl Engine {
     /// Returns an [`UpdateLogitsError`] when the logits is not updated. Check the error type docs for more details.
     /// The [`EngineLike`] internal states are not updated in this case.
     /// The logits array is not updated as well.
+    /// Once the token is accepted, check [`EngineLike::allowed_token_ids_error_from_last_computation`] for the failures of the computation.
     ///
     /// # Safety
     ///
@@ -810,6 +1385,12 @@ impl Config {
     pub fn new_js() -> Config {
         Config::default()
     }
+
+    /// Binds the documents to the `#documents"name"` symbols with the given name.
+    #[wasm_bindgen(js_name = setDocuments)]
+    pub fn set_documents_js(&mut self, name: String, documents: Vec<String>) {
+        self.documents.insert(name, documents);
+    }
 }
 
 #[cfg(feature = "python")]
@@ -821,3 +1402,35 @@ impl Config {
         Config::default()
     }
 }
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl Diagnostic {
+    /// Render the diagnostic into a human-readable string, with the offending source underlined by carets.
+    #[wasm_bindgen(js_name = render)]
+    pub fn render_js(&self) -> String {
+        self.render()
+    }
+}
+
+#[cfg(feature = "python")]
+#[pymethods]
+impl Diagnostic {
+    /// Render the diagnostic into a human-readable string, with the offending source underlined by carets.
+    ///
+    /// # Signature
+    ///
+    /// (self) -> str
+    #[pyo3(name = "render")]
+    pub fn render_py(&self) -> String {
+        self.render()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self)
+    }
+
+    fn __str__(&self) -> String {
+        self.render()
+    }
+}
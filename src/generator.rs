@@ -0,0 +1,714 @@
+//! The generator module that samples random strings from a grammar and random token sequences from an engine.
+use std::borrow::Cow;
+use std::hash::Hash;
+
+use ahash::AHashMap;
+use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::util::start;
+use kbnf_regex_automata::Anchored;
+use num::traits::{NumAssign, NumOps};
+use num::{
+    cast::AsPrimitive,
+    traits::{ConstOne, ConstZero},
+    Num,
+};
+
+use crate::config::Config;
+use crate::engine_like::{
+    AcceptTokenError, AcceptTokenResult, ComputeAllowedTokenIdsError, EngineLike,
+};
+use crate::grammar::{
+    CreateGrammarError, Grammar, HIRNode, IntersectionRole, NonterminalID, RegexID,
+};
+use crate::regex::FiniteStateAutomaton;
+use crate::utils::{self, FsaStateStatus};
+use crate::vocabulary::Vocabulary;
+
+/// The configuration of a [Generator].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorConfig {
+    /// The seed of the random number generator. The same seed always produces the same strings from the same grammar.
+    pub seed: u64,
+    /// The depth of nonterminal expansion beyond which the generator only picks the productions
+    /// that terminate the derivation the fastest.
+    /// The default is 32.
+    pub max_depth: usize,
+    /// The maximum length of a generated string in bytes.
+    /// Attempts producing longer strings are discarded.
+    /// The default is 4096.
+    pub max_length: usize,
+    /// The maximum number of attempts to generate one string before giving up.
+    /// The default is 64.
+    pub max_attempts: usize,
+    /// The probability to end a regex whenever its next byte can end it, instead of generating more bytes.
+    /// The default is 0.5.
+    pub regex_stop_probability: f64,
+    /// The relative weights of the productions of the nonterminals, keyed by the nonterminal names.
+    /// The weights are indexed by the production ids in the simplified grammar,
+    /// i.e. in the order shown by [Grammar::to_kbnf_string].
+    /// The productions of the nonterminals not listed here are chosen uniformly.
+    pub production_weights: AHashMap<String, Vec<f64>>,
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            max_depth: 32,
+            max_length: 4096,
+            max_attempts: 64,
+            regex_stop_probability: 0.5,
+            production_weights: AHashMap::default(),
+        }
+    }
+}
+
+/// The error type for errors in [Generator] creation and string generation.
+#[derive(Debug, thiserror::Error, Clone, PartialEq)]
+pub enum GenerateError {
+    #[error("The start nonterminal `{0}` cannot derive any string accepted by the engine.")]
+    /// The start nonterminal cannot derive any finite string the engine accepts.
+    /// Regex complements never end in the engine, so the productions containing them are never generated.
+    NoFiniteString(String),
+    #[error("The weights of `{0}` are invalid: {1}")]
+    /// The production weights of a nonterminal are of the wrong length, negative or not finite.
+    InvalidWeights(String, String),
+    #[error("Failed to generate a string within {0} attempts.")]
+    /// Every attempt exceeded [GeneratorConfig::max_length] or ran into a regex that cannot be completed.
+    AttemptsExhausted(usize),
+}
+
+/// The error type for errors in [Generator::from_kbnf].
+#[derive(Debug, thiserror::Error)]
+pub enum CreateGeneratorError {
+    #[error("{0}")] // inherits the error message from the wrapped GrammarError
+    /// A wrapper for the [CreateGrammarError] error type.
+    GrammarError(#[from] CreateGrammarError),
+    #[error("The grammar after simplification is empty.")]
+    /// The grammar is empty.
+    EmptyGrammarError,
+    #[error("{0}")] // inherits the error message from the wrapped GenerateError
+    /// A wrapper for the [GenerateError] error type.
+    GenerateError(#[from] GenerateError),
+}
+
+/// The configuration of a [TokenSampler].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenSamplerConfig {
+    /// The seed of the random number generator.
+    /// The same seed always produces the same token sequences from engines in the same state.
+    pub seed: u64,
+    /// The maximum number of tokens sampled in one sequence.
+    /// The default is 1024.
+    pub max_tokens: usize,
+    /// The relative prior weights of the tokens, indexed by token id.
+    /// Its length must equal [Vocabulary::vocab_size](crate::vocabulary::Vocabulary::vocab_size).
+    /// `None` means the allowed tokens are chosen uniformly.
+    /// If all the allowed tokens have zero weights, they are chosen uniformly as well.
+    pub prior: Option<Vec<f64>>,
+}
+
+impl Default for TokenSamplerConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            max_tokens: 1024,
+            prior: None,
+        }
+    }
+}
+
+/// The error type for errors in token sequence sampling.
+#[derive(Debug, thiserror::Error, Clone, PartialEq)]
+pub enum SampleTokensError {
+    #[error("The engine allows no token before the grammar finishes, after accepting {0:?}.")]
+    /// No token is allowed while the engine is not finished.
+    /// Contains the token ids accepted before the dead end.
+    DeadEnd(Vec<u32>),
+    #[error(
+        "The engine reaches its output limit before the grammar finishes, after accepting {0:?}."
+    )]
+    /// The engine has output [EngineConfig::max_output_tokens](crate::engine::EngineConfig::max_output_tokens) tokens without finishing.
+    /// Contains the token ids accepted so far.
+    OutputLimitReached(Vec<u32>),
+    #[error("A lazy DFA cache of the engine is too small to compute the allowed tokens, after accepting {0:?}.")]
+    /// A transition of a lazy DFA does not fit in its cache, so the allowed tokens cannot be computed.
+    /// Contains the token ids accepted so far.
+    LazyDfaCacheFull(Vec<u32>),
+    #[error("The engine does not finish within {} tokens.", .0.len())]
+    /// The engine is not finished after accepting [TokenSamplerConfig::max_tokens] tokens.
+    /// Contains the token ids accepted so far.
+    MaxTokensExceeded(Vec<u32>),
+    #[error("The engine fails to accept the allowed token {1} after accepting {0:?}: {2}")]
+    /// The engine fails to accept a token it allows, which indicates a bug in the engine.
+    /// Contains the token ids accepted before, the failed token id and the error.
+    AcceptTokenError(Vec<u32>, u32, AcceptTokenError),
+    #[error("The prior is invalid: {0}")]
+    /// [TokenSamplerConfig::prior] is of the wrong length, negative or not finite.
+    InvalidPrior(String),
+}
+
+/// A sampler that drives an [EngineLike] without a model by accepting random allowed tokens until it finishes.
+///
+/// This is useful for fuzzing a grammar against a real vocabulary,
+/// in particular to find dead ends where no token is allowed before the grammar can finish.
+#[derive(Debug, Clone)]
+pub struct TokenSampler {
+    config: TokenSamplerConfig,
+    rng: Rng,
+}
+
+impl TokenSampler {
+    /// Create a new [TokenSampler].
+    pub fn new(config: TokenSamplerConfig) -> Self {
+        Self {
+            rng: Rng::new(config.seed),
+            config,
+        }
+    }
+
+    /// Sample a token sequence from the current state of the engine.
+    ///
+    /// At each step, the allowed token ids are computed and one of them is chosen randomly
+    /// according to [TokenSamplerConfig::prior] and accepted, until the engine is finished.
+    /// The engine is not reset before or after sampling.
+    ///
+    /// # Arguments
+    ///
+    /// * `engine` - The engine to drive.
+    ///
+    /// # Returns
+    ///
+    /// The accepted token ids.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [SampleTokensError] if the engine runs into a dead end, does not finish in time
+    /// or the prior is invalid. The engine keeps the tokens accepted so far in this case.
+    pub fn sample(&mut self, engine: &mut impl EngineLike) -> Result<Vec<u32>, SampleTokensError> {
+        if let Some(prior) = &self.config.prior {
+            let vocab_size = engine.vocab().vocab_size();
+            if prior.len() != vocab_size {
+                return Err(SampleTokensError::InvalidPrior(format!(
+                    "expected {vocab_size} weights but got {}",
+                    prior.len()
+                )));
+            }
+            if prior.iter().any(|x| !x.is_finite() || *x < 0.0) {
+                return Err(SampleTokensError::InvalidPrior(
+                    "the weights must be finite and non-negative".to_string(),
+                ));
+            }
+        }
+        let mut token_ids = Vec::new();
+        while !engine.is_finished() {
+            if token_ids.len() == self.config.max_tokens {
+                return Err(SampleTokensError::MaxTokensExceeded(token_ids));
+            }
+            if let Err(e) = engine.try_compute_allowed_token_ids() {
+                return Err(match e {
+                    ComputeAllowedTokenIdsError::DeadEnd => SampleTokensError::DeadEnd(token_ids),
+                    ComputeAllowedTokenIdsError::OutputLimitReached => {
+                        SampleTokensError::OutputLimitReached(token_ids)
+                    }
+                    ComputeAllowedTokenIdsError::LazyDfaCacheFull => {
+                        SampleTokensError::LazyDfaCacheFull(token_ids)
+                    }
+                });
+            }
+            let allowed: Vec<usize> = engine
+                .allowed_token_ids_from_last_computation()
+                .ones()
+                .collect();
+            let token_id = self.choose(&allowed) as u32;
+            match engine.try_accept_new_token(token_id) {
+                Ok(AcceptTokenResult::Ongoing) => token_ids.push(token_id),
+                Ok(AcceptTokenResult::Finished) => {
+                    token_ids.push(token_id);
+                    break;
+                }
+                Err(e) => return Err(SampleTokensError::AcceptTokenError(token_ids, token_id, e)),
+            }
+        }
+        Ok(token_ids)
+    }
+
+    fn choose(&mut self, allowed: &[usize]) -> usize {
+        let Some(prior) = &self.config.prior else {
+            return allowed[self.rng.below(allowed.len())];
+        };
+        let total: f64 = allowed.iter().map(|&x| prior[x]).sum();
+        if total <= 0.0 {
+            return allowed[self.rng.below(allowed.len())];
+        }
+        let mut target = self.rng.unit() * total;
+        for &token_id in allowed.iter() {
+            if target < prior[token_id] {
+                return token_id;
+            }
+            target -= prior[token_id];
+        }
+        // Only reachable due to rounding errors.
+        *allowed.last().unwrap()
+    }
+}
+
+/// A small seedable pseudo-random number generator based on SplitMix64.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number in `0..n`.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// Returns a number in `[0, 1)`.
+    pub(crate) fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The signal that the current attempt cannot be completed and should be discarded.
+struct AttemptFailed;
+
+/// A generator that samples random strings from a [Grammar].
+///
+/// The generator walks the productions of the simplified grammar from the start nonterminal,
+/// samples regexes by walking their DFAs and substrings from their source strings.
+/// Every generated string is accepted by an [Engine](crate::engine::Engine) built from the same grammar,
+/// in the sense that passing the whole string to [EngineLike::try_accept_new_bytes]
+/// returns [AcceptTokenResult::Finished].
+#[derive(Clone)]
+pub struct Generator<'a, TI>
+where
+    TI: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+{
+    /// The grammar, borrowed by [Generator::new] or owned by [Generator::from_kbnf].
+    grammar: Cow<'a, Grammar<TI>>,
+    config: GeneratorConfig,
+    rng: Rng,
+    productions: Vec<Vec<Vec<HIRNode<TI>>>>,
+    /// The minimum derivation height of every nonterminal, `None` if it cannot derive any string.
+    heights: Vec<Option<usize>>,
+    /// The production weights indexed by nonterminal id.
+    weights: Vec<Option<Vec<f64>>>,
+}
+
+impl<'a, TI> Generator<'a, TI>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    /// Create a new [Generator] from the grammar.
+    ///
+    /// # Arguments
+    ///
+    /// * `grammar` - The grammar to generate strings from.
+    /// * `config` - The configuration of the generator.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [GenerateError] if the start nonterminal cannot derive any string
+    /// or the production weights are invalid.
+    pub fn new(grammar: &'a Grammar<TI>, config: GeneratorConfig) -> Result<Self, GenerateError> {
+        Self::with_grammar(Cow::Borrowed(grammar), config)
+    }
+
+    fn with_grammar(
+        grammar: Cow<'a, Grammar<TI>>,
+        config: GeneratorConfig,
+    ) -> Result<Self, GenerateError> {
+        let mut productions = grammar.productions();
+        // Strings of an intersection are sampled from its operand and discarded unless its regex matches them.
+        for alternations in productions.iter_mut() {
+            alternations.retain(|production| {
+                !matches!(production.as_slice(), [HIRNode::Nonterminal(x)]
+                    if matches!(grammar.intersection_role(*x), Some((_, IntersectionRole::Tracker))))
+            });
+        }
+        let mut weights = vec![None; productions.len()];
+        for (name, production_weights) in config.production_weights.iter() {
+            let Some(nonterminal_id) = grammar.interned_strings().nonterminals.get(name) else {
+                return Err(GenerateError::InvalidWeights(
+                    name.clone(),
+                    "the nonterminal does not exist in the simplified grammar".to_string(),
+                ));
+            };
+            let nonterminal_id = string_interner::Symbol::to_usize(nonterminal_id);
+            let expected = productions[nonterminal_id].len();
+            if production_weights.len() != expected {
+                return Err(GenerateError::InvalidWeights(
+                    name.clone(),
+                    format!(
+                        "expected {expected} weights but got {}",
+                        production_weights.len()
+                    ),
+                ));
+            }
+            if production_weights
+                .iter()
+                .any(|x| !x.is_finite() || *x < 0.0)
+            {
+                return Err(GenerateError::InvalidWeights(
+                    name.clone(),
+                    "the weights must be finite and non-negative".to_string(),
+                ));
+            }
+            weights[nonterminal_id] = Some(production_weights.clone());
+        }
+        let heights = Self::heights(&productions);
+        let start = grammar.get_start_nonterminal_id();
+        if heights[start.0.as_()].is_none() {
+            return Err(GenerateError::NoFiniteString(
+                grammar
+                    .nonterminal_str(start)
+                    .unwrap_or_default()
+                    .to_string(),
+            ));
+        }
+        Ok(Self {
+            grammar,
+            rng: Rng::new(config.seed),
+            config,
+            productions,
+            heights,
+            weights,
+        })
+    }
+
+    /// Computes the minimum derivation height of every nonterminal by fixpoint iteration.
+    fn heights(productions: &[Vec<Vec<HIRNode<TI>>>]) -> Vec<Option<usize>> {
+        let mut heights: Vec<Option<usize>> = vec![None; productions.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (nonterminal_id, alternations) in productions.iter().enumerate() {
+                let height = alternations
+                    .iter()
+                    .filter_map(|production| Self::production_height(&heights, production))
+                    .min();
+                if height.is_some()
+                    && (heights[nonterminal_id].is_none() || height < heights[nonterminal_id])
+                {
+                    heights[nonterminal_id] = height;
+                    changed = true;
+                }
+            }
+        }
+        heights
+    }
+
+    /// Returns the derivation height of the production, `None` if it cannot derive any string yet.
+    fn production_height(heights: &[Option<usize>], production: &[HIRNode<TI>]) -> Option<usize> {
+        let mut height = 0;
+        for node in production.iter() {
+            match node {
+                HIRNode::Nonterminal(x) => height = height.max(heights[x.0.as_()]?),
+                // The engine never completes a regex complement.
+                HIRNode::RegexComplement(_) => return None,
+                _ => {}
+            }
+        }
+        Some(height + 1)
+    }
+
+    /// Generate a random string from the grammar.
+    ///
+    /// # Errors
+    ///
+    /// Returns [GenerateError::AttemptsExhausted] if no string is generated within [GeneratorConfig::max_attempts] attempts.
+    pub fn generate(&mut self) -> Result<Vec<u8>, GenerateError> {
+        let start = self.grammar.get_start_nonterminal_id();
+        let mut output = Vec::new();
+        for _ in 0..self.config.max_attempts {
+            output.clear();
+            if self.expand(start, 0, &mut output).is_ok() {
+                return Ok(output);
+            }
+        }
+        Err(GenerateError::AttemptsExhausted(self.config.max_attempts))
+    }
+
+    fn expand(
+        &mut self,
+        nonterminal_id: NonterminalID<TI>,
+        depth: usize,
+        output: &mut Vec<u8>,
+    ) -> Result<(), AttemptFailed> {
+        let production_id = self.choose_production(nonterminal_id.0.as_(), depth);
+        // Cloning the production is cheap compared to generating it and frees `self` for the recursion.
+        let production = self.productions[nonterminal_id.0.as_()][production_id].clone();
+        let start = output.len();
+        for node in production {
+            match node {
+                HIRNode::Terminal(x) => output.extend_from_slice(self.grammar.terminal(x)),
+                HIRNode::RegexString(x) => self.sample_regex(x, false, output)?,
+                HIRNode::EarlyEndRegexString(x) => self.sample_regex(x, true, output)?,
+                HIRNode::Substrings(x) => {
+                    // A substring never spans documents, so it is taken from a single document.
+                    let documents: Vec<&str> = self
+                        .grammar
+                        .suffix_automata_documents(x)
+                        .unwrap()
+                        .into_iter()
+                        .filter(|x| !x.is_empty())
+                        .collect();
+                    if documents.is_empty() {
+                        return Err(AttemptFailed);
+                    }
+                    let source = documents[self.rng.below(documents.len())].as_bytes();
+                    let start = self.rng.below(source.len());
+                    let end = start + 1 + self.rng.below(source.len() - start);
+                    output.extend_from_slice(&source[start..end]);
+                }
+                HIRNode::Nonterminal(x) => self.expand(x, depth + 1, output)?,
+                HIRNode::RegexComplement(_) => {
+                    unreachable!("productions with regex complements are never chosen")
+                }
+            }
+            if output.len() > self.config.max_length {
+                return Err(AttemptFailed);
+            }
+        }
+        if let Some((i, IntersectionRole::Root)) = self.grammar.intersection_role(nonterminal_id) {
+            let tracker = self.grammar.intersection(i).tracker;
+            let HIRNode::RegexString(regex_id) = self.productions[tracker.0.as_()][0][0] else {
+                unreachable!("the only production of a tracker is a regex")
+            };
+            if !self.regex_matches(regex_id, &output[start..]) {
+                return Err(AttemptFailed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the regex matches the whole `bytes`.
+    fn regex_matches(&self, regex_id: RegexID<TI>, bytes: &[u8]) -> bool {
+        match self.grammar.regex(regex_id) {
+            FiniteStateAutomaton::Dfa(dfa) => {
+                let Ok(mut state) = dfa.start_state(&start::Config::new().anchored(Anchored::Yes))
+                else {
+                    return false;
+                };
+                for &byte in bytes {
+                    state = dfa.next_state(state, byte);
+                }
+                utils::check_dfa_state_status(state, dfa) == FsaStateStatus::Accept
+            }
+            FiniteStateAutomaton::LazyDfa(dfa) => {
+                let mut cache = dfa.create_cache();
+                let mut state = dfa.anchored_start_state();
+                let mut status = dfa.state_status(&mut cache, state);
+                for &byte in bytes {
+                    let Ok(next) = dfa.next_state(&mut cache, state, byte) else {
+                        return false;
+                    };
+                    (state, status) = next;
+                }
+                status == FsaStateStatus::Accept
+            }
+        }
+    }
+
+    /// Chooses a production of the nonterminal that can derive a string.
+    ///
+    /// Beyond [GeneratorConfig::max_depth], only the productions with the minimum height are considered.
+    fn choose_production(&mut self, nonterminal_id: usize, depth: usize) -> usize {
+        let minimum = self.heights[nonterminal_id];
+        let candidates: Vec<usize> = self.productions[nonterminal_id]
+            .iter()
+            .enumerate()
+            .filter_map(|(production_id, production)| {
+                let height = Self::production_height(&self.heights, production)?;
+                (depth < self.config.max_depth || Some(height) == minimum).then_some(production_id)
+            })
+            .collect();
+        let weights: Vec<f64> = match &self.weights[nonterminal_id] {
+            Some(weights) => candidates.iter().map(|&x| weights[x]).collect(),
+            None => vec![1.0; candidates.len()],
+        };
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return candidates[self.rng.below(candidates.len())];
+        }
+        let mut target = self.rng.unit() * total;
+        for (&production_id, weight) in candidates.iter().zip(weights) {
+            if target < weight {
+                return production_id;
+            }
+            target -= weight;
+        }
+        // Only reachable due to rounding errors.
+        *candidates.last().unwrap()
+    }
+
+    fn sample_regex(
+        &mut self,
+        regex_id: RegexID<TI>,
+        early_end: bool,
+        output: &mut Vec<u8>,
+    ) -> Result<(), AttemptFailed> {
+        let budget = self.config.max_length.saturating_sub(output.len());
+        let stop_probability = self.config.regex_stop_probability;
+        let bytes = match self.grammar.regex(regex_id) {
+            FiniteStateAutomaton::Dfa(dfa) => {
+                let start = dfa
+                    .start_state(&start::Config::new().anchored(Anchored::Yes))
+                    .map_err(|_| AttemptFailed)?;
+                Self::walk(
+                    &mut self.rng,
+                    start,
+                    |state, byte| {
+                        let next = dfa.next_state(state, byte);
+                        (next, utils::check_dfa_state_status(next, dfa))
+                    },
+                    early_end,
+                    budget,
+                    stop_probability,
+                )
+            }
+            FiniteStateAutomaton::LazyDfa(dfa) => {
+                let mut cache = dfa.create_cache();
+                Self::walk(
+                    &mut self.rng,
+                    dfa.anchored_start_state(),
+                    |state, byte| {
+                        dfa.next_state(&mut cache, state, byte)
+                            .unwrap_or((state, FsaStateStatus::Reject))
+                    },
+                    early_end,
+                    budget,
+                    stop_probability,
+                )
+            }
+        }?;
+        output.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Walks the automaton randomly from `start` until it stops in a match state.
+    ///
+    /// Whenever a byte can lead to a match state, the walk ends with that byte with the probability `stop_probability`.
+    /// An early end regex always ends at its first match state, since the engine ends it there as well.
+    fn walk<S: Copy>(
+        rng: &mut Rng,
+        start: S,
+        mut next: impl FnMut(S, u8) -> (S, FsaStateStatus),
+        early_end: bool,
+        budget: usize,
+        stop_probability: f64,
+    ) -> Result<Vec<u8>, AttemptFailed> {
+        let mut bytes = Vec::new();
+        let mut state = start;
+        let mut accepted = false;
+        while bytes.len() < budget {
+            let candidates: Vec<(u8, S, FsaStateStatus)> = (0..=u8::MAX)
+                .filter_map(|byte| {
+                    let (next, status) = next(state, byte);
+                    (status != FsaStateStatus::Reject).then_some((byte, next, status))
+                })
+                .collect();
+            if candidates.is_empty() {
+                break;
+            }
+            let endings: Vec<u8> = candidates
+                .iter()
+                .filter(|x| x.2 == FsaStateStatus::Accept)
+                .map(|x| x.0)
+                .collect();
+            if !endings.is_empty() && (bytes.len() + 1 == budget || rng.unit() < stop_probability) {
+                bytes.push(endings[rng.below(endings.len())]);
+                return Ok(bytes);
+            }
+            let (byte, next, status) = candidates[rng.below(candidates.len())];
+            bytes.push(byte);
+            state = next;
+            accepted = status == FsaStateStatus::Accept;
+            if accepted && early_end {
+                return Ok(bytes);
+            }
+        }
+        if accepted {
+            Ok(bytes)
+        } else {
+            Err(AttemptFailed)
+        }
+    }
+}
+
+impl<TI> Generator<'static, TI>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    /// Create a new [Generator] that owns the grammar built from a KBNF grammar string.
+    ///
+    /// The grammar is built the same way as by [Engine::with_config](crate::engine::Engine::with_config),
+    /// except that it is not matched against any vocabulary.
+    /// `TI` must be large enough for the ids of the grammar; [u16] is large enough for every grammar the engine supports.
+    ///
+    /// # Arguments
+    ///
+    /// * `kbnf_syntax_grammar_str` - The KBNF grammar string.
+    /// * `config` - The configuration of the grammar, e.g. its start nonterminal and regex type.
+    /// * `generator_config` - The configuration of the generator.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [CreateGeneratorError] if the grammar is invalid or empty,
+    /// or if the [Generator] cannot be created from it.
+    pub fn from_kbnf(
+        kbnf_syntax_grammar_str: &str,
+        config: Config,
+        generator_config: GeneratorConfig,
+    ) -> Result<Self, CreateGeneratorError> {
+        let mut regex_config = config.regex_config;
+        // No token is ever matched against the grammar.
+        regex_config.min_tokens_required_for_eager_regex_cache = None;
+        let (grammar, metadata) = utils::construct_kbnf_syntax_grammar_with_metadata(
+            kbnf_syntax_grammar_str,
+            config.internal_config(),
+        )?;
+        if grammar.is_empty() {
+            return Err(CreateGeneratorError::EmptyGrammarError);
+        }
+        let grammar =
+            Grammar::new_with_metadata(grammar, metadata, &Vocabulary::empty(), regex_config)?;
+        Ok(Self::with_grammar(Cow::Owned(grammar), generator_config)?)
+    }
+}
@@ -1,8 +1,13 @@
 //! The grammar module that contains the grammar struct in HIR form and its related functions and structs.
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::sync::Arc;
 
 use crate::config::RegexConfig;
+use crate::diagnostic::Diagnostic;
+use crate::lint::{self, LintWarning};
+use crate::preprocessor::{self, RegexPlaceholder, SubstringsDocuments};
+use crate::regex::FiniteStateAutomaton;
 use crate::utils::{self, dispatch_by_dfa_state_status, ByteSet};
 use crate::Vocabulary;
 use ahash::AHashMap;
@@ -16,7 +21,7 @@ use kbnf_syntax::node::{OperatorFlattenedNode, Rhs};
 use kbnf_syntax::simplified_grammar::SimplifiedGrammar;
 use kbnf_syntax::suffix_automaton::SuffixAutomaton;
 use kbnf_syntax::InternedStrings;
-use kbnf_syntax::{self, regex::FiniteStateAutomaton};
+use kbnf_syntax::{self};
 use num::traits::{NumAssign, NumOps};
 use num::{
     cast::AsPrimitive,
@@ -137,7 +142,10 @@ where
     pub fn to_display_form(&self, grammar: &Grammar<T>) -> String {
         format!(
             "#\"{}\"[{}]",
-            grammar.suffix_automata_str(*self).unwrap(),
+            grammar
+                .suffix_automata_documents(*self)
+                .unwrap()
+                .join("\", \""),
             self.0.as_()
         )
     }
@@ -187,13 +195,7 @@ where
             HIRNode::EarlyEndRegexString(x) => {
                 format!("#e\"{}\"[{}]", grammar.regex_str(*x).unwrap(), x.0.as_())
             }
-            HIRNode::Substrings(x) => {
-                format!(
-                    "#\"{}\"[{}]",
-                    grammar.suffix_automata_str(*x).unwrap(),
-                    x.0.as_()
-                )
-            }
+            HIRNode::Substrings(x) => x.to_display_form(grammar),
             HIRNode::RegexComplement(x) => {
                 format!("#ex\"{}\"[{}]", grammar.regex_str(*x).unwrap(), x.0.as_())
             }
@@ -201,7 +203,87 @@ where
     }
 }
 
+/// An intersection `X & #"R"` in the grammar.
+///
+/// The root nonterminal has two productions, the operand and the tracker.
+/// The only production of the operand is a copy of `X`, whose reachable nonterminals are copied as well
+/// so they are not shared with the rest of the grammar, and only the root refers to the operand.
+/// The only production of the tracker is `#"R"`, whose Earley items run the DFA of `R` alongside the Earley items of the operand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Intersection<TI>
+where
+    TI: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+{
+    pub(crate) root: NonterminalID<TI>,
+    /// `None` if `X` only matches the empty string, which the simplification has moved to the parents of the root.
+    pub(crate) operand: Option<NonterminalID<TI>>,
+    pub(crate) tracker: NonterminalID<TI>,
+}
+
+/// The role of a nonterminal in an [Intersection].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IntersectionRole {
+    /// The root, which behaves like any other nonterminal outside the intersection.
+    Root,
+    /// The operand, which can only be completed where the tracker matches.
+    Operand,
+    /// A nonterminal reachable from the operand other than the operand itself.
+    Member,
+    /// The tracker, whose completion never advances the root.
+    Tracker,
+}
+
+/// The intersection each nonterminal belongs to and its role there, indexed by the nonterminal IDs.
+type IntersectionRoles = Vec<Option<(usize, IntersectionRole)>>;
+
+/// The names of the nonterminals of an [Intersection],
+/// recorded by [construct_intersections](crate::utils::construct_kbnf_syntax_grammar_with_metadata) when it splits the intersection.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct IntersectionNonterminals {
+    pub(crate) root: String,
+    /// `None` if `X` only matches the empty string.
+    pub(crate) operand: Option<String>,
+    /// The copies of the nonterminals reachable from `X`.
+    pub(crate) members: Vec<String>,
+    pub(crate) tracker: String,
+}
+
+/// The metadata of a grammar that the simplified KBNF grammar cannot carry,
+/// collected by [construct_kbnf_syntax_grammar_with_metadata](crate::utils::construct_kbnf_syntax_grammar_with_metadata).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GrammarMetadata {
+    /// The bonus of each nonterminal with one, keyed by the nonterminal name.
+    /// The bonuses come from the `bonus X = value;` statements and the weighted alternatives.
+    pub nonterminal_bonuses: AHashMap<String, f32>,
+    /// The nonterminals defined in the grammar that cannot be reached from the start nonterminal,
+    /// in the order of their definitions. The simplification removes them, so they are only known here.
+    pub unreachable_nonterminals: Vec<String>,
+    /// The syntax extensions that the regexes of the grammar stand for, keyed by the regexes.
+    pub(crate) regex_placeholders: AHashMap<String, RegexPlaceholder>,
+    /// The documents of the substrings symbols with multiple documents, keyed by their substrings strings.
+    pub(crate) substrings_documents: AHashMap<String, SubstringsDocuments>,
+    /// The nonterminals of the intersections, in the order of their roots.
+    pub(crate) intersections: Vec<IntersectionNonterminals>,
+}
+
+/// The bytes that can be accepted first from each state of each regex.
+type RegexFirstBytes<TI> = AHashMap<(TI, StateID), ByteSet>;
+
+/// The caches computed from a regex of the grammar.
+#[derive(Clone, Default)]
+struct RegexCaches {
+    /// The bytes that can be accepted first from each state.
+    first_bytes: AHashMap<StateID, ByteSet>,
+    /// The bytes that can be accepted first from each state when the regex is complemented.
+    complement_first_bytes: AHashMap<StateID, ByteSet>,
+    /// The tokens that can be accepted from each state. Empty if the eager regex cache is disabled.
+    token_ids: AHashMap<(StateID, RegexType), FixedBitSet>,
+}
+
 /// The grammar struct that stores the grammar in HIR.
+///
+/// The regexes, their caches and the suffix automata are shared between the clones of a grammar,
+/// so cloning a grammar is cheap and replacing a regex only copies the regex itself.
 #[derive(Clone)]
 pub struct Grammar<TI>
 where
@@ -211,13 +293,26 @@ where
     // Maybe storing the nonterminal id with the node is better. Profiling is needed.
     rules: JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
     interned_strings: InternedStrings,
-    id_to_regexes: Vec<FiniteStateAutomaton>,
-    pub(crate) regex_to_token_ids: AHashMap<(RegexID<TI>, StateID, RegexType), FixedBitSet>,
-    id_to_regex_first_bytes: AHashMap<(TI, StateID), ByteSet>,
-    id_to_regex_complement_first_bytes: AHashMap<(TI, StateID), ByteSet>,
+    id_to_regexes: Vec<Arc<FiniteStateAutomaton>>,
+    id_to_regex_caches: Vec<Arc<RegexCaches>>,
+    /// Whether any regex has tokens in [RegexCaches::token_ids].
+    has_eager_regex_cache: bool,
     id_to_terminals: JaggedArray<u8, Vec<usize>, 2>,
-    id_to_suffix_automata: Vec<SuffixAutomaton>,
-    id_to_suffix_automata_first_bytes: AHashMap<(usize, GeneralSamNodeID), ByteSet>,
+    id_to_suffix_automata: Arc<[SuffixAutomaton]>,
+    id_to_suffix_automata_first_bytes: Arc<AHashMap<(usize, GeneralSamNodeID), ByteSet>>,
+    regex_config: RegexConfig,
+    dynamic_alternatives: AHashMap<String, RegexID<TI>>,
+    intersections: Vec<Intersection<TI>>,
+    /// The intersection each nonterminal belongs to and its role there. Empty if the grammar has no intersections.
+    nonterminal_to_intersection: IntersectionRoles,
+    /// The bonus of each nonterminal from [GrammarMetadata::nonterminal_bonuses]. Empty if the grammar has no bonuses.
+    nonterminal_bonuses: Vec<f32>,
+    /// The nonterminals from [GrammarMetadata::unreachable_nonterminals], which are reported by [Grammar::lint].
+    pub(crate) unreachable_nonterminals: Vec<String>,
+    /// The syntax extensions from [GrammarMetadata::regex_placeholders], keyed by the regexes.
+    regex_placeholders: AHashMap<String, RegexPlaceholder>,
+    /// The documents from [GrammarMetadata::substrings_documents], keyed by the substrings strings.
+    substrings_documents: Arc<AHashMap<String, SubstringsDocuments>>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -241,6 +336,58 @@ pub enum CreateGrammarError {
     #[error("Regex initialization error: {0}")]
     /// Error due to inefficient cache usage in a lazy DFA.
     LazyDfaCacheError(#[from] kbnf_regex_automata::hybrid::CacheError),
+    #[error("KBNF preprocessing error: {0}")]
+    /// Error when expanding the syntax extensions or resolving the imports in the KBNF grammar.
+    PreprocessingError(String),
+    #[error("Parameterized rule error: {0}")]
+    /// Error when instantiating the parameterized rules in the KBNF grammar.
+    ParameterizedRuleError(String),
+    #[error("Regex initialization error: {0}")]
+    /// Error when building the NFA of a difference `A - B`.
+    NfaBuildError(#[from] Box<kbnf_regex_automata::nfa::thompson::BuildError>),
+    #[error("{diagnostic}")]
+    /// A [ParsingError](CreateGrammarError::ParsingError), [SemanticError](CreateGrammarError::SemanticError),
+    /// [PreprocessingError](CreateGrammarError::PreprocessingError) or
+    /// [ParameterizedRuleError](CreateGrammarError::ParameterizedRuleError) located in the KBNF source.
+    Located {
+        /// The located error.
+        #[source]
+        error: Box<CreateGrammarError>,
+        /// The diagnostic that points to the source of the error.
+        diagnostic: Box<Diagnostic>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+/// The error type for errors when replacing the strings of a dynamic alternative.
+pub enum SetDynamicError {
+    #[error("The dynamic alternative `{0}` does not exist in the grammar.")]
+    /// Error due to the grammar not containing `#dynamic"name"` with the given name.
+    UnknownDynamicAlternative(String),
+    #[error("The dynamic alternative cannot contain the empty string.")]
+    /// Error due to an empty string, which would change the nullability the grammar is simplified with.
+    EmptyString,
+    #[error("Regex initialization error: {0}")]
+    /// Error when compiling the strings into a DFA.
+    DfaBuildError(#[from] Box<kbnf_regex_automata::dfa::dense::BuildError>),
+    #[error("{0}")]
+    /// Error when compiling the strings into a lazy DFA.
+    LazyDfaBuildError(#[from] Box<CreateGrammarError>),
+    #[error(
+        "Regex length {0} exceeds {1}, the maximum regex length allowed by current size of StateID(TS)."
+    )]
+    /// The compiled strings exceed the maximum regex length allowed by the current size of StateID(TS).
+    RegexTooLarge(usize, usize),
+}
+
+impl CreateGrammarError {
+    /// Get the diagnostic that points to the source of the error, if the error is located in the KBNF source.
+    pub fn diagnostic(&self) -> Option<&Diagnostic> {
+        match self {
+            CreateGrammarError::Located { diagnostic, .. } => Some(diagnostic),
+            _ => None,
+        }
+    }
 }
 impl<TI> Debug for Grammar<TI>
 where
@@ -258,6 +405,17 @@ where
     usize: num::traits::AsPrimitive<TI>,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let regex_first_bytes = |get: fn(&RegexCaches) -> &AHashMap<StateID, ByteSet>| {
+            self.id_to_regex_caches
+                .iter()
+                .enumerate()
+                .flat_map(|(i, caches)| {
+                    get(caches)
+                        .iter()
+                        .map(move |(state_id, set)| ((i.as_(), *state_id), set.clone()))
+                })
+                .collect::<RegexFirstBytes<TI>>()
+        };
         f.debug_struct("Grammar")
             .field(
                 "start_nonterminal",
@@ -317,7 +475,7 @@ where
             .field(
                 "id_to_regex_first_bytes",
                 &utils::get_deterministic_display_form_from_hash_map(
-                    &self.id_to_regex_first_bytes,
+                    &regex_first_bytes(|x| &x.first_bytes),
                     |(x, y)| (*x, utils::get_display_form_from_bitset_on_stack(y)),
                 )
                 .iter()
@@ -327,7 +485,7 @@ where
             .field(
                 "id_to_regex_complement_first_bytes",
                 &utils::get_deterministic_display_form_from_hash_map(
-                    &self.id_to_regex_complement_first_bytes,
+                    &regex_first_bytes(|x| &x.complement_first_bytes),
                     |(x, y)| (*x, utils::get_display_form_from_bitset_on_stack(y)),
                 )
                 .iter()
@@ -374,10 +532,15 @@ where
 {
     /// Create a new grammar from a simplified KBNF grammar and configuration.
     ///
+    /// The grammar has no metadata, so it has no bonuses, and the syntax extensions the metadata describes,
+    /// e.g. the dynamic alternatives, the intersections and the substrings of multiple documents, do not work.
+    /// Use [Grammar::new_with_metadata] to add it.
+    ///
     /// # Arguments
     ///
     /// * `grammar` - The simplified KBNF grammar.
-    /// * `config` - The configuration of the engine.
+    /// * `vocabulary` - The vocabulary of the engine.
+    /// * `regex_config` - The configuration of the regexes.
     ///
     /// # Returns
     ///
@@ -391,6 +554,36 @@ where
         grammar: SimplifiedGrammar,
         vocabulary: &Vocabulary,
         regex_config: RegexConfig,
+    ) -> Result<Self, CreateGrammarError> {
+        Self::new_with_metadata(
+            grammar,
+            GrammarMetadata::default(),
+            vocabulary,
+            regex_config,
+        )
+    }
+    /// Create a new grammar from a simplified KBNF grammar, its metadata and configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `grammar` - The simplified KBNF grammar.
+    /// * `metadata` - The metadata of the grammar from [construct_kbnf_syntax_grammar_with_metadata](crate::utils::construct_kbnf_syntax_grammar_with_metadata).
+    /// * `vocabulary` - The vocabulary of the engine.
+    /// * `regex_config` - The configuration of the regexes.
+    ///
+    /// # Returns
+    ///
+    /// The grammar struct.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversion from [usize] to the generic parameter fails, or if the regex initialization fails.
+    /// More information about the error can be found in the [GrammarError] enum docs.
+    pub fn new_with_metadata(
+        grammar: SimplifiedGrammar,
+        metadata: GrammarMetadata,
+        vocabulary: &Vocabulary,
+        regex_config: RegexConfig,
     ) -> Result<Self, CreateGrammarError> {
         let mut id_to_terminals = JaggedArray::<u8, Vec<usize>, 2>::new();
         for (id, terminal) in grammar.interned_strings.terminals.iter() {
@@ -473,17 +666,74 @@ where
                 }
             }
         }
-        let id_to_regexes = grammar.id_to_regex;
-        let id_to_suffix_automata = grammar.id_to_suffix_automaton;
+        // The simplification may leave references to nonterminals whose rules were all removed.
+        // They get an empty dot position 0 so predicting them adds no items.
+        while rules.len() < grammar.interned_strings.nonterminals.len() {
+            rules.new_row::<0>();
+            rules.new_row::<1>();
+        }
+        let id_to_regexes = match regex_config.lazy_dfa_config() {
+            None => grammar
+                .id_to_regex
+                .into_iter()
+                .map(|fsa| match fsa {
+                    kbnf_syntax::regex::FiniteStateAutomaton::Dfa(dfa) => {
+                        Arc::new(FiniteStateAutomaton::Dfa(dfa))
+                    }
+                })
+                .collect(),
+            Some(config) => {
+                let mut id_to_regexes = Vec::with_capacity(grammar.id_to_regex.len());
+                for (id, regex_string) in grammar.interned_strings.regex_strings.iter() {
+                    let dfa = config
+                        .build_with_placeholders(regex_string, &metadata.regex_placeholders)?;
+                    id_to_regexes.push(Arc::new(FiniteStateAutomaton::LazyDfa(dfa)));
+                    assert!(id_to_regexes.len() - 1 == id.to_usize());
+                }
+                id_to_regexes
+            }
+        };
+        let id_to_suffix_automata: Arc<[SuffixAutomaton]> = grammar.id_to_suffix_automaton.into();
         let (id_to_regex_first_bytes, id_to_regex_complement_first_bytes) =
-            Self::construct_regex_first_bytes(&rules, &id_to_regexes);
+            Self::construct_regex_first_bytes(&rules, &id_to_regexes, None);
         let id_to_suffix_automata_first_bytes =
             Self::construct_suffix_automata_first_bytes(&id_to_suffix_automata);
         let mut regex_to_token_ids = AHashMap::default();
         if let Some(limit) = regex_config.min_tokens_required_for_eager_regex_cache {
             regex_to_token_ids =
-                Self::construct_regex_to_token_ids(vocabulary, &rules, &id_to_regexes, limit);
+                Self::construct_regex_to_token_ids(vocabulary, &rules, &id_to_regexes, limit, None);
         }
+        let has_eager_regex_cache = !regex_to_token_ids.is_empty();
+        let id_to_regex_caches = Self::split_regex_caches(
+            id_to_regexes.len(),
+            id_to_regex_first_bytes,
+            id_to_regex_complement_first_bytes,
+            regex_to_token_ids,
+        )
+        .into_iter()
+        .map(Arc::new)
+        .collect();
+        let mut dynamic_alternatives = AHashMap::default();
+        for (id, regex_string) in grammar.interned_strings.regex_strings.iter() {
+            if let Some(RegexPlaceholder::Dynamic(name)) =
+                metadata.regex_placeholders.get(regex_string)
+            {
+                dynamic_alternatives.insert(
+                    name.to_string(),
+                    RegexID(id.to_usize().try_into().map_err(|_| {
+                        CreateGrammarError::IntConversionError(
+                            "regex".to_string(),
+                            id.to_usize(),
+                            TI::max_value().as_(),
+                        )
+                    })?),
+                );
+            }
+        }
+        let (intersections, nonterminal_to_intersection) =
+            Self::find_intersections(&metadata, rules.len(), &grammar.interned_strings);
+        let nonterminal_bonuses =
+            Self::find_bonuses(&metadata, rules.len(), &grammar.interned_strings);
         Ok(Self {
             start_nonterminal_id: NonterminalID(
                 grammar.start_symbol.to_usize().try_into().map_err(|_| {
@@ -497,20 +747,90 @@ where
             rules,
             interned_strings: grammar.interned_strings,
             id_to_regexes,
+            id_to_regex_caches,
+            has_eager_regex_cache,
             id_to_terminals,
-            id_to_regex_first_bytes,
-            id_to_regex_complement_first_bytes,
             id_to_suffix_automata,
-            id_to_suffix_automata_first_bytes,
-            regex_to_token_ids,
+            id_to_suffix_automata_first_bytes: Arc::new(id_to_suffix_automata_first_bytes),
+            regex_config,
+            dynamic_alternatives,
+            intersections,
+            nonterminal_to_intersection,
+            nonterminal_bonuses,
+            unreachable_nonterminals: metadata.unreachable_nonterminals,
+            regex_placeholders: metadata.regex_placeholders,
+            substrings_documents: Arc::new(metadata.substrings_documents),
         })
     }
 
+    /// Finds the bonus of each nonterminal from the metadata.
+    fn find_bonuses(
+        metadata: &GrammarMetadata,
+        nonterminals: usize,
+        interned_strings: &InternedStrings,
+    ) -> Vec<f32> {
+        let mut bonuses = vec![0.0; nonterminals];
+        let mut found = false;
+        for (name, &bonus) in metadata.nonterminal_bonuses.iter() {
+            if let Some(id) = interned_strings.nonterminals.get(name) {
+                bonuses[id.to_usize()] = bonus;
+                found = true;
+            }
+        }
+        if !found {
+            bonuses.clear();
+        }
+        bonuses
+    }
+
+    /// Finds the nonterminals of the intersections recorded in the metadata and their roles.
+    ///
+    /// The nonterminals that do not exist anymore are skipped.
+    fn find_intersections(
+        metadata: &GrammarMetadata,
+        nonterminals: usize,
+        interned_strings: &InternedStrings,
+    ) -> (Vec<Intersection<TI>>, IntersectionRoles) {
+        let find = |name: &str| {
+            interned_strings
+                .nonterminals
+                .get(name)
+                .map(|id| NonterminalID(id.to_usize().as_()))
+        };
+        let mut intersections = Vec::new();
+        let mut roles = vec![None; nonterminals];
+        for names in metadata.intersections.iter() {
+            let (Some(root), Some(tracker)) = (find(&names.root), find(&names.tracker)) else {
+                continue;
+            };
+            let i = intersections.len();
+            let operand = names.operand.as_deref().and_then(find);
+            roles[root.0.as_()] = Some((i, IntersectionRole::Root));
+            roles[tracker.0.as_()] = Some((i, IntersectionRole::Tracker));
+            for member in names.members.iter().filter_map(|x| find(x)) {
+                roles[member.0.as_()] = Some((i, IntersectionRole::Member));
+            }
+            if let Some(operand) = operand {
+                roles[operand.0.as_()] = Some((i, IntersectionRole::Operand));
+            }
+            intersections.push(Intersection {
+                root,
+                operand,
+                tracker,
+            });
+        }
+        if intersections.is_empty() {
+            roles.clear();
+        }
+        (intersections, roles)
+    }
+
     fn construct_regex_to_token_ids(
         vocabulary: &Vocabulary,
         rules: &JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
-        id_to_regexes: &[FiniteStateAutomaton],
+        id_to_regexes: &[Arc<FiniteStateAutomaton>],
         limit: usize,
+        only: Option<RegexID<TI>>,
     ) -> AHashMap<(RegexID<TI>, StateID, RegexType), FixedBitSet> {
         let mut regex_to_token_ids = AHashMap::default();
         for i in 0..rules.len() {
@@ -534,8 +854,11 @@ where
                         }
                         _ => continue,
                     };
+                    if only.is_some_and(|x| x != regex_id) {
+                        continue;
+                    }
                     let regex = &id_to_regexes[regex_id.0.as_()];
-                    match regex {
+                    match regex.as_ref() {
                         FiniteStateAutomaton::Dfa(dfa) => {
                             for state in dfa.states() {
                                 let mut set = FixedBitSet::with_capacity(vocabulary.vocab_size());
@@ -584,6 +907,8 @@ where
                                 regex_to_token_ids.insert((regex_id, start_state, regex_type), set);
                             }
                         }
+                        // The states of a lazy DFA are not known in advance.
+                        FiniteStateAutomaton::LazyDfa(_) => {}
                     }
                 }
             }
@@ -593,11 +918,9 @@ where
 
     fn construct_regex_first_bytes(
         rules: &JaggedArray<HIRNode<TI>, Vec<usize>, 3>,
-        id_to_regexes: &[FiniteStateAutomaton],
-    ) -> (
-        AHashMap<(TI, StateID), ByteSet>,
-        AHashMap<(TI, StateID), ByteSet>,
-    ) {
+        id_to_regexes: &[Arc<FiniteStateAutomaton>],
+        only: Option<RegexID<TI>>,
+    ) -> (RegexFirstBytes<TI>, RegexFirstBytes<TI>) {
         let mut id_to_regex_first_bytes = AHashMap::default();
         let mut id_to_regex_complement_first_bytes = AHashMap::default();
         for i in 0..rules.len() {
@@ -621,8 +944,11 @@ where
                         }
                         _ => continue,
                     };
+                    if only.is_some_and(|x| x != regex_id) {
+                        continue;
+                    }
                     let regex = &id_to_regexes[regex_id.0.as_()];
-                    match regex {
+                    match regex.as_ref() {
                         FiniteStateAutomaton::Dfa(dfa) => {
                             for state in dfa.states() {
                                 let mut set = ByteSet::with_capacity(256);
@@ -659,6 +985,8 @@ where
                                 }
                             }
                         }
+                        // The first bytes of a lazy DFA are computed on demand.
+                        FiniteStateAutomaton::LazyDfa(_) => {}
                     }
                 }
             }
@@ -666,6 +994,32 @@ where
         (id_to_regex_first_bytes, id_to_regex_complement_first_bytes)
     }
 
+    /// Split the caches of all the regexes into the caches of each regex.
+    fn split_regex_caches(
+        regexes: usize,
+        first_bytes: RegexFirstBytes<TI>,
+        complement_first_bytes: RegexFirstBytes<TI>,
+        token_ids: AHashMap<(RegexID<TI>, StateID, RegexType), FixedBitSet>,
+    ) -> Vec<RegexCaches> {
+        let mut id_to_regex_caches = vec![RegexCaches::default(); regexes];
+        for ((regex_id, state_id), set) in first_bytes {
+            id_to_regex_caches[regex_id.as_()]
+                .first_bytes
+                .insert(state_id, set);
+        }
+        for ((regex_id, state_id), set) in complement_first_bytes {
+            id_to_regex_caches[regex_id.as_()]
+                .complement_first_bytes
+                .insert(state_id, set);
+        }
+        for ((regex_id, state_id, regex_type), set) in token_ids {
+            id_to_regex_caches[regex_id.0.as_()]
+                .token_ids
+                .insert((state_id, regex_type), set);
+        }
+        id_to_regex_caches
+    }
+
     fn construct_suffix_automata_first_bytes(
         id_to_suffix_automata: &[SuffixAutomaton],
     ) -> AHashMap<(usize, GeneralSamNodeID), ByteSet> {
@@ -686,6 +1040,284 @@ where
         }
         id_to_suffix_automata_first_bytes
     }
+    /// Replace the strings matched by the dynamic alternative `#dynamic"name"`.
+    ///
+    /// Only the regex standing for the dynamic alternative is recompiled, along with its caches.
+    /// The dynamic alternative matches nothing until its strings are set.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the dynamic alternative.
+    /// * `strings` - The strings to be matched. An empty slice makes the dynamic alternative match nothing again.
+    /// * `vocabulary` - The vocabulary the grammar is created with.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the dynamic alternative does not exist, if any of the strings is empty,
+    /// or if the strings cannot be compiled into a regex.
+    pub fn set_dynamic(
+        &mut self,
+        name: &str,
+        strings: &[&str],
+        vocabulary: &Vocabulary,
+    ) -> Result<(), SetDynamicError> {
+        let (regex_id, fsa) = self.compile_dynamic(name, strings)?;
+        self.replace_regex(regex_id, fsa, vocabulary);
+        Ok(())
+    }
+
+    /// Compile the strings of the dynamic alternative `name` into a regex without modifying the grammar.
+    pub(crate) fn compile_dynamic(
+        &self,
+        name: &str,
+        strings: &[&str],
+    ) -> Result<(RegexID<TI>, FiniteStateAutomaton), SetDynamicError> {
+        let regex_id = *self
+            .dynamic_alternatives
+            .get(name)
+            .ok_or_else(|| SetDynamicError::UnknownDynamicAlternative(name.to_string()))?;
+        if strings.iter().any(|x| x.is_empty()) {
+            return Err(SetDynamicError::EmptyString);
+        }
+        let pattern = if strings.is_empty() {
+            r"[^\s\S]".to_string()
+        } else {
+            strings
+                .iter()
+                .map(|x| preprocessor::escape_regex(x))
+                .collect::<Vec<_>>()
+                .join("|")
+        };
+        let pattern = format!(r"\A(?:{pattern})\z");
+        let fsa = match self.regex_config.lazy_dfa_config() {
+            None => FiniteStateAutomaton::Dfa(
+                kbnf_regex_automata::dfa::dense::Builder::new()
+                    .configure(
+                        kbnf_regex_automata::dfa::dense::Config::new()
+                            .dfa_size_limit(self.regex_config.max_memory_usage)
+                            .start_kind(kbnf_regex_automata::dfa::StartKind::Both),
+                    )
+                    .build(&pattern)
+                    .map_err(Box::new)?,
+            ),
+            Some(config) => {
+                FiniteStateAutomaton::LazyDfa(config.build(&pattern).map_err(Box::new)?)
+            }
+        };
+        Ok((regex_id, fsa))
+    }
+
+    /// Replace the regex `regex_id` with `fsa` and recompute the caches of the regex.
+    pub(crate) fn replace_regex(
+        &mut self,
+        regex_id: RegexID<TI>,
+        fsa: FiniteStateAutomaton,
+        vocabulary: &Vocabulary,
+    ) {
+        self.id_to_regexes[regex_id.0.as_()] = Arc::new(fsa);
+        let (first_bytes, complement_first_bytes) =
+            Self::construct_regex_first_bytes(&self.rules, &self.id_to_regexes, Some(regex_id));
+        let mut token_ids = AHashMap::default();
+        if let Some(limit) = self.regex_config.min_tokens_required_for_eager_regex_cache {
+            token_ids = Self::construct_regex_to_token_ids(
+                vocabulary,
+                &self.rules,
+                &self.id_to_regexes,
+                limit,
+                Some(regex_id),
+            );
+        }
+        let caches = Self::split_regex_caches(
+            self.id_to_regexes.len(),
+            first_bytes,
+            complement_first_bytes,
+            token_ids,
+        )
+        .swap_remove(regex_id.0.as_());
+        self.has_eager_regex_cache = !caches.token_ids.is_empty()
+            || self
+                .id_to_regex_caches
+                .iter()
+                .enumerate()
+                .any(|(i, x)| i != regex_id.0.as_() && !x.token_ids.is_empty());
+        self.id_to_regex_caches[regex_id.0.as_()] = Arc::new(caches);
+    }
+
+    /// Get the names of the dynamic alternatives in the grammar.
+    pub fn dynamic_alternatives(&self) -> impl Iterator<Item = &str> {
+        self.dynamic_alternatives.keys().map(String::as_str)
+    }
+    /// Convert the grammar into a KBNF string.
+    ///
+    /// Unlike the [Debug] form, the result is valid KBNF which can be fed back into [Engine::new](crate::engine::Engine::new)
+    /// to create an engine accepting the same language, provided the start nonterminal is unchanged.
+    /// The start nonterminal's rule comes first, followed by the others in the order of their ids, one rule per line.
+    /// The bonuses of the nonterminals follow the rules as `bonus X = value;` statements.
+    /// Since the grammar is simplified, this shows what the simplification and the terminal compression did.
+    pub fn to_kbnf_string(&self) -> String {
+        let start = self.start_nonterminal_id.0.as_();
+        let productions = self.productions();
+        let mut output = String::new();
+        for nonterminal_id in
+            std::iter::once(start).chain((0..productions.len()).filter(|&x| x != start))
+        {
+            let role = self.intersection_role(NonterminalID(nonterminal_id.as_()));
+            if matches!(role, Some((_, IntersectionRole::Tracker))) {
+                continue;
+            }
+            output.push_str(
+                self.nonterminal_str(NonterminalID(nonterminal_id.as_()))
+                    .unwrap(),
+            );
+            output.push_str(" ::= ");
+            if let Some((i, IntersectionRole::Root)) = role {
+                let intersection = &self.intersections[i];
+                let regex = productions[intersection.tracker.0.as_()][0][0];
+                // An intersection without operand matches nothing but the empty string, which its parents already handle.
+                match intersection.operand {
+                    Some(operand) => output.push_str(&format!(
+                        "{} & {}",
+                        self.node_to_kbnf_string(HIRNode::Nonterminal(operand)),
+                        self.node_to_kbnf_string(regex)
+                    )),
+                    None => output.push_str(r#"#"[^\\s\\S]""#),
+                }
+                output.push_str(";\n");
+                continue;
+            }
+            output.push_str(
+                &productions[nonterminal_id]
+                    .iter()
+                    .map(|production| {
+                        production
+                            .iter()
+                            .map(|&node| self.node_to_kbnf_string(node))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" | "),
+            );
+            output.push_str(";\n");
+        }
+        for nonterminal_id in
+            std::iter::once(start).chain((0..productions.len()).filter(|&x| x != start))
+        {
+            let nonterminal_id = NonterminalID(nonterminal_id.as_());
+            let bonus = self.nonterminal_bonus(nonterminal_id);
+            if bonus != 0.0 {
+                output.push_str(&format!(
+                    "bonus {} = {bonus};\n",
+                    self.nonterminal_str(nonterminal_id).unwrap()
+                ));
+            }
+        }
+        output
+    }
+
+    fn node_to_kbnf_string<'a>(&'a self, node: HIRNode<TI>) -> String {
+        // The parser wraps every regex as `\A(?:...)\z`, and additionally every regex complement as `(?:...)\z`.
+        // They are added back when the string is parsed again.
+        let unwrap = |regex: &'a str, prefix: &str| {
+            regex
+                .strip_prefix(prefix)
+                .and_then(|x| x.strip_suffix(r")\z"))
+                .unwrap_or(regex)
+        };
+        let regex = |x: RegexID<TI>| unwrap(self.regex_str(x).unwrap(), r"\A(?:");
+        match node {
+            HIRNode::Terminal(x) => utils::escape_kbnf_string(self.terminal_str(x).unwrap()),
+            HIRNode::RegexString(x) => self.regex_to_kbnf_string(self.regex_str(x).unwrap()),
+            HIRNode::EarlyEndRegexString(x) => {
+                format!("#e{}", utils::escape_kbnf_string(regex(x)))
+            }
+            HIRNode::RegexComplement(x) => {
+                format!("#ex{}", utils::escape_kbnf_string(unwrap(regex(x), "(?:")))
+            }
+            HIRNode::Substrings(x) => match (
+                self.suffix_automata_documents_name(x),
+                &self.suffix_automata_documents(x).unwrap()[..],
+            ) {
+                (Some(name), _) => format!("#documents{}", utils::escape_kbnf_string(name)),
+                (None, [document]) => format!("#substrs{}", utils::escape_kbnf_string(document)),
+                (None, documents) => format!(
+                    "#substrs[{}]",
+                    documents
+                        .iter()
+                        .map(|x| utils::escape_kbnf_string(x))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            },
+            HIRNode::Nonterminal(x) => self.nonterminal_str(x).unwrap().to_string(),
+        }
+    }
+    /// Converts the anchored regex of a [HIRNode::RegexString] into KBNF, with the syntax extension it stands for if any.
+    fn regex_to_kbnf_string(&self, regex_str: &str) -> String {
+        match self.regex_placeholders.get(regex_str) {
+            Some(RegexPlaceholder::Dynamic(name)) => {
+                format!("#dynamic{}", utils::escape_kbnf_string(name))
+            }
+            Some(RegexPlaceholder::Difference(minuend, subtrahend)) => format!(
+                "#{} - #{}",
+                utils::escape_kbnf_string(minuend),
+                utils::escape_kbnf_string(subtrahend)
+            ),
+            Some(RegexPlaceholder::NumericRange(range)) => range.to_string(),
+            Some(RegexPlaceholder::Intersection(regex)) => self.regex_to_kbnf_string(regex),
+            None => format!(
+                "#{}",
+                utils::escape_kbnf_string(
+                    regex_str
+                        .strip_prefix(r"\A(?:")
+                        .and_then(|x| x.strip_suffix(r")\z"))
+                        .unwrap_or(regex_str)
+                )
+            ),
+        }
+    }
+
+    /// Returns the syntax extension the regex stands for, if it is a placeholder.
+    pub(crate) fn regex_placeholder(&self, regex_id: RegexID<TI>) -> Option<&RegexPlaceholder> {
+        self.regex_placeholders.get(self.regex_str(regex_id)?)
+    }
+
+    /// Lint the grammar for patterns that are likely to be mistakes or to slow down the engine.
+    ///
+    /// The following patterns are reported:
+    /// - shift-reduce and reduce-reduce conflicts, approximated by SLR(1) with the first bytes of terminals as lookaheads
+    /// - cycles of nonterminals that derive each other without consuming any input
+    /// - nonterminals that are unreachable from the start nonterminal or cannot derive any finite string
+    /// - right recursions that could be rewritten as left recursions
+    /// - regexes whose DFAs are larger than [LARGE_REGEX_MEMORY_USAGE](crate::lint::LARGE_REGEX_MEMORY_USAGE)
+    ///
+    /// None of them prevents the engine from working correctly. Conflicts only indicate possible ambiguity,
+    /// which slows down the engine, rather than proving it.
+    ///
+    /// # Returns
+    ///
+    /// The sorted warnings.
+    pub fn lint(&self) -> Vec<LintWarning> {
+        lint::lint(self)
+    }
+    /// Lint the grammar against the bytes of a vocabulary for terminals, regexes and substrings
+    /// that need a byte which no token in it contains.
+    ///
+    /// This is a necessary-but-not-sufficient check rather than a reachability analysis over token sequences:
+    /// a terminal is reported if it contains a byte that no token contains,
+    /// and a regex is reported if it cannot match any string consisting of the bytes in the vocabulary.
+    /// The token boundaries are ignored since a token may end in the middle of a node,
+    /// so a node may still be unproducible without being reported,
+    /// e.g. `'ab'` with the tokens `a` and `cb` only.
+    /// Reported nodes cause dead ends, i.e. no token is allowed before the grammar finishes,
+    /// once the engine needs to produce them.
+    ///
+    /// # Returns
+    ///
+    /// The sorted warnings, all of kind [LintKind::MissingVocabularyBytes](crate::lint::LintKind::MissingVocabularyBytes).
+    pub fn lint_vocabulary_bytes(&self, vocabulary: &Vocabulary) -> Vec<LintWarning> {
+        lint::lint_vocabulary_bytes(self, vocabulary)
+    }
     #[inline]
     /// Get the start nonterminal id.
     pub fn get_start_nonterminal_id(&self) -> NonterminalID<TI> {
@@ -763,11 +1395,38 @@ where
     }
     #[inline]
     /// Get the suffix automata string from the grammar.
+    ///
+    /// A substrings symbol with multiple documents has a placeholder string,
+    /// so [Grammar::suffix_automata_documents] should be used to get the documents themselves.
     pub fn suffix_automata_str(&self, suffix_automata_id: SuffixAutomataID<TI>) -> Option<&str> {
         self.interned_strings
             .sub_strings
             .resolve(SymbolU32::try_from_usize(suffix_automata_id.0.as_()).unwrap())
     }
+    /// Get the documents of the suffix automata from the grammar.
+    ///
+    /// A substrings symbol with a single document, e.g. `#substrs"abc"`, has that document as its only one.
+    pub fn suffix_automata_documents(
+        &self,
+        suffix_automata_id: SuffixAutomataID<TI>,
+    ) -> Option<Vec<&str>> {
+        let string = self.suffix_automata_str(suffix_automata_id)?;
+        Some(match self.substrings_documents.get(string) {
+            Some(x) => x.documents.iter().map(|x| x.as_str()).collect(),
+            None => vec![string],
+        })
+    }
+    /// Get the name of the documents bound in [Config::documents](crate::config::Config::documents)
+    /// that the suffix automata is built from, i.e. the name of a `#documents"name"` symbol.
+    pub fn suffix_automata_documents_name(
+        &self,
+        suffix_automata_id: SuffixAutomataID<TI>,
+    ) -> Option<&str> {
+        self.substrings_documents
+            .get(self.suffix_automata_str(suffix_automata_id)?)?
+            .name
+            .as_deref()
+    }
     #[inline]
     /// Get the regex from the grammar.
     pub fn regex(&self, regex_id: RegexID<TI>) -> &FiniteStateAutomaton {
@@ -823,7 +1482,7 @@ where
     }
     #[inline]
     /// Get the regexes from the grammar.
-    pub fn id_to_regexes(&self) -> &[FiniteStateAutomaton] {
+    pub fn id_to_regexes(&self) -> &[Arc<FiniteStateAutomaton>] {
         &self.id_to_regexes
     }
     #[inline]
@@ -842,7 +1501,9 @@ where
         regex_id: RegexID<TI>,
         state_id: StateID,
     ) -> Option<&ByteSet> {
-        self.id_to_regex_first_bytes.get(&(regex_id.0, state_id))
+        self.id_to_regex_caches[regex_id.0.as_()]
+            .first_bytes
+            .get(&state_id)
     }
     #[inline]
     pub(crate) fn complement_first_bytes_from_regex(
@@ -850,8 +1511,26 @@ where
         regex_id: RegexID<TI>,
         state_id: StateID,
     ) -> Option<&ByteSet> {
-        self.id_to_regex_complement_first_bytes
-            .get(&(regex_id.0, state_id))
+        self.id_to_regex_caches[regex_id.0.as_()]
+            .complement_first_bytes
+            .get(&state_id)
+    }
+    #[inline]
+    /// Get the tokens that can be accepted from the state of the regex, if they are in the eager regex cache.
+    pub(crate) fn token_ids_from_regex(
+        &self,
+        regex_id: RegexID<TI>,
+        state_id: StateID,
+        regex_type: RegexType,
+    ) -> Option<&FixedBitSet> {
+        self.id_to_regex_caches[regex_id.0.as_()]
+            .token_ids
+            .get(&(state_id, regex_type))
+    }
+    #[inline]
+    /// Whether the eager regex cache has any tokens.
+    pub(crate) fn has_eager_regex_cache(&self) -> bool {
+        self.has_eager_regex_cache
     }
 
     #[inline]
@@ -859,6 +1538,41 @@ where
         &self.id_to_suffix_automata_first_bytes[&(0, state_id)]
     }
     #[inline]
+    pub(crate) fn has_intersections(&self) -> bool {
+        !self.intersections.is_empty()
+    }
+    #[inline]
+    pub(crate) fn intersection(&self, index: usize) -> &Intersection<TI> {
+        &self.intersections[index]
+    }
+    #[inline]
+    pub(crate) fn has_bonuses(&self) -> bool {
+        !self.nonterminal_bonuses.is_empty()
+    }
+    #[inline]
+    pub(crate) fn has_positive_bonuses(&self) -> bool {
+        self.nonterminal_bonuses.iter().any(|&x| x > 0.0)
+    }
+    /// Get the bonus of the nonterminal, which is zero if it has none.
+    #[inline]
+    pub(crate) fn nonterminal_bonus(&self, nonterminal_id: NonterminalID<TI>) -> f32 {
+        self.nonterminal_bonuses
+            .get(nonterminal_id.0.as_())
+            .copied()
+            .unwrap_or(0.0)
+    }
+    /// Get the intersection the nonterminal belongs to and its role there.
+    #[inline]
+    pub(crate) fn intersection_role(
+        &self,
+        nonterminal_id: NonterminalID<TI>,
+    ) -> Option<(usize, IntersectionRole)> {
+        self.nonterminal_to_intersection
+            .get(nonterminal_id.0.as_())
+            .copied()
+            .flatten()
+    }
+    #[inline]
     pub(crate) unsafe fn dotted_productions(
         &self,
         nonterminal_id: NonterminalID<TI>,
@@ -869,4 +1583,26 @@ where
     pub(crate) fn rules(&self) -> &JaggedArray<HIRNode<TI>, Vec<usize>, 3> {
         &self.rules
     }
+    /// Get the productions of every nonterminal, indexed by nonterminal id and then production id.
+    ///
+    /// The rules are stored by dot position first, so this transposes them into whole productions.
+    pub(crate) fn productions(&self) -> Vec<Vec<Vec<HIRNode<TI>>>> {
+        let mut productions = Vec::with_capacity(self.rules.len());
+        for nonterminal_id in 0..self.rules.len() {
+            let view = self.rules.view::<1, 2>([nonterminal_id]);
+            let mut alternations: Vec<Vec<HIRNode<TI>>> = if view.len() == 0 {
+                vec![]
+            } else {
+                vec![vec![]; view.view::<1, 1>([0]).len()]
+            };
+            for dot_position in 0..view.len() {
+                let view = view.view::<1, 1>([dot_position]);
+                for production_id in 0..view.len() {
+                    alternations[production_id].push(view[[production_id]]);
+                }
+            }
+            productions.push(alternations);
+        }
+        productions
+    }
 }
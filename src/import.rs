@@ -0,0 +1,78 @@
+//! The import module that loads the KBNF grammars imported by `import "path" as alias;` statements.
+use std::fmt::Debug;
+use std::path::PathBuf;
+
+use ahash::AHashMap;
+
+/// A source of KBNF grammars that can be imported by other grammars.
+pub trait GrammarLoader: Debug + Send + Sync {
+    /// Load the KBNF source of the grammar at `path`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path in the import statement.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The KBNF source, or `None` if the grammar cannot be found.
+    fn load(&self, path: &str) -> Option<String>;
+}
+
+/// The standard library of KBNF grammars, which is the default [`InternalConfig::loader`](crate::config::InternalConfig::loader).
+///
+/// | Path                | Nonterminals                                                     |
+/// |---------------------|------------------------------------------------------------------|
+/// | `std/json.kbnf`     | `value`, `object`, `array`, `string`, `element`, `ws`            |
+/// | `std/number.kbnf`   | `unsigned_integer`, `integer`, `decimal`, `json`, `hexadecimal`  |
+/// | `std/datetime.kbnf` | `date`, `time`, `offset`, `date_time`                            |
+/// | `std/uuid.kbnf`     | `uuid`                                                           |
+/// | `std/email.kbnf`    | `email`                                                          |
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardLibrary;
+
+impl StandardLibrary {
+    /// The paths and sources of the grammars in the standard library.
+    pub const GRAMMARS: [(&'static str, &'static str); 5] = [
+        ("std/json.kbnf", include_str!("stdlib/json.kbnf")),
+        ("std/number.kbnf", include_str!("stdlib/number.kbnf")),
+        ("std/datetime.kbnf", include_str!("stdlib/datetime.kbnf")),
+        ("std/uuid.kbnf", include_str!("stdlib/uuid.kbnf")),
+        ("std/email.kbnf", include_str!("stdlib/email.kbnf")),
+    ];
+}
+
+impl GrammarLoader for StandardLibrary {
+    fn load(&self, path: &str) -> Option<String> {
+        Self::GRAMMARS
+            .iter()
+            .find(|(x, _)| *x == path)
+            .map(|(_, source)| source.to_string())
+    }
+}
+
+/// A [`GrammarLoader`] that reads the grammars from files relative to a root directory.
+#[derive(Debug, Clone)]
+pub struct FileSystemLoader {
+    /// The directory that the paths are relative to.
+    pub root: PathBuf,
+}
+
+impl GrammarLoader for FileSystemLoader {
+    fn load(&self, path: &str) -> Option<String> {
+        std::fs::read_to_string(self.root.join(path)).ok()
+    }
+}
+
+/// Maps the paths to the KBNF sources.
+impl GrammarLoader for AHashMap<String, String> {
+    fn load(&self, path: &str) -> Option<String> {
+        self.get(path).cloned()
+    }
+}
+
+/// Tries the first loader and falls back to the second one.
+impl<A: GrammarLoader, B: GrammarLoader> GrammarLoader for (A, B) {
+    fn load(&self, path: &str) -> Option<String> {
+        self.0.load(path).or_else(|| self.1.load(path))
+    }
+}
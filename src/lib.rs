@@ -82,6 +82,23 @@ while [Engine] is a concrete implementation of [EngineLike]. The most important
 - [Engine::update_logits]: This method tries to accept a new token and then updates the logits accordingly.
 - [Engine::reset]: This method resets the engine to its initial state. Notably, the cache is preserved.
 
+[generator::Generator] samples random strings accepted by the engine from a [Grammar],
+or from a KBNF grammar string with [generator::Generator::from_kbnf],
+which is useful for testing downstream parsers or building few-shot examples.
+[generator::TokenSampler] drives an engine with random allowed tokens instead of a model,
+which is useful for fuzzing a grammar against a real vocabulary.
+
+With the opt-in `sampling` feature, [sampling::Sampler] samples a token from the raw logits
+of a language model and accepts it into the engine, so the masking and sampling loop does not have to be reimplemented.
+
+[beam_search::BeamSearch] finds the best token sequences accepted by the engine according to a scoring callback,
+which is useful for short structured outputs like classification labels.
+
+[Engine::shortest_completion] finds the shortest bytes that finish the grammar from the current state,
+which can be appended for a graceful truncation when the output budget runs out.
+[Engine::enumerate_completions] enumerates every token sequence that finishes the grammar from the current state,
+so the completions of a classification-style grammar can be scored all at once.
+
 This crate-level documentation is organized as follows:
 
 - [Examples](#examples): This section contains some examples of how to use the crate.
@@ -243,6 +260,20 @@ A terminal is a sequence of UTF-8 characters enclosed in double quotes or single
 All [Javascript escaped characters](https://developer.mozilla.org/en-US/docs/Web/JavaScript/Reference/Regular_expressions/Character_escape)
  are supported.
 
+A terminal prefixed with `#i`, like `#i"true"`, is case-insensitive.
+It is compiled into an equivalent regular expression, while other terminals are matched byte by byte.
+
+```ebnf
+start ::= #i"true" | #i"false";
+(*The engine will accept "true", "True", "TRUE" and so on.*)
+```
+
+If [Config::nfc_normalization](config::Config::nfc_normalization) is enabled,
+terminals are normalized into Unicode Normalization Form C, so `"e\u0301"` and `"\u00e9"` are the same terminal.
+Only the terminals are normalized. Regular expressions, the documents of substrings symbols,
+the strings of dynamic alternatives and the tokens of the vocabulary are matched byte by byte as they are given,
+so they should be normalized beforehand if the output is expected to be in NFC.
+
 ## Concatenation
 
 Two or more symbols in a sequence are concatenated.
@@ -358,6 +389,36 @@ start ::= ("A"|"B")+ "C";
 a nonempty sequence of "A"s and "B"s followed by exactly one "C".*)
 ```
 
+A symbol followed by `{m}`, `{m,n}` or `{m,}`, where `m` and `n` are non-negative integers,
+can be repeated exactly `m` times, between `m` and `n` times, or at least `m` times respectively.
+```ebnf
+start ::= digit{3} ("," digit{1,8})* "."{2,};
+digit ::= #"[0-9]";
+(*The engine will constrain the output to exactly three digits,
+followed by any number of comma separated groups of one to eight digits,
+ended with at least two dots.*)
+```
+
+Counted repetitions are expanded into nonterminals prefixed with `__kbnf_` that double the repeated symbol,
+so the size of the expanded grammar grows logarithmically with the bounds.
+
+## Parameterized rule
+
+A nonterminal followed by parameters in angle brackets defines a parameterized rule,
+which is instantiated by giving the same number of arguments.
+
+```ebnf
+start ::= list<"A" | "B"> ";" list<digit>;
+list<X> ::= X ("," X)*;
+digit ::= #"[0-9]";
+(*The engine will constrain the output to a comma separated list of "A"s and "B"s,
+followed by a semicolon and a comma separated list of digits.*)
+```
+
+Each distinct instantiation is expanded into a nonterminal prefixed with `__kbnf_`.
+Parameterized rules can instantiate other parameterized rules and themselves,
+but an instantiation can be nested at most 32 times, which prevents instantiating infinitely many rules.
+
 ## Regular expression
 
 A UTF-8 string enclosed in `#""` or `#e""` is a regular expression. The escaped characters supported is the same as [Terminal](##terminal).
@@ -387,7 +448,63 @@ which means [the syntax supported](https://docs.rs/regex/latest/regex/index.html
 Notably, the regex crate does not support arbitrary lookarounds. In exchange, linear time matching is guaranteed.
 **WARNING: the regular expression is compiled into a DFA which, by its nature, has worst case exponential time and space complexity.**
 If you are dealing with untrusted regular expressions,
-you should set a memory limit in [Config::regex_config] to prevent DoS attacks.
+you should set a memory limit in [Config::regex_config] to prevent DoS attacks,
+or switch to [Fsa::LazyDfa](config::Fsa::LazyDfa), which builds DFA states on demand instead.
+
+## Numeric range
+
+`#int[min,max]` matches the integers from `min` to `max`, and `#float[min,max]` matches the decimal numbers from `min` to `max`.
+Both bounds are inclusive and can be negative.
+
+```ebnf
+start ::= "port=" #int[0,65535] ";" "weight=" #float[-1,1] ";";
+(*
+The engine will accept "port=8080;weight=-0.25;", but not "port=65536;weight=0;" or "port=80;weight=1.5;".
+*)
+```
+
+The numbers are matched in their plain decimal forms: an optional minus sign, an integer part without leading zeros
+and, for floats, an optional fraction like `.25`. Exponents and `-0` are not matched.
+A numeric range is compiled into a minimized DFA, so it is as fast as a handwritten regular expression.
+
+## Difference
+
+`A - B` matches the strings matched by `A` but not by `B`.
+Both operands must be regular: they can only contain terminals, regular expressions and nonterminals that do not recurse.
+The difference is compiled into a single DFA, which is the product of the DFAs of both operands.
+
+```ebnf
+start ::= identifier - keyword ";";
+identifier ::= #"[a-z]+";
+keyword ::= "if" | "else" | "while";
+(*
+The engine will accept "iff;" and "elsewhere;", but not "if;".
+*)
+```
+
+`-` binds tighter than concatenation, so use parentheses for operands made of several symbols.
+`A - B - C` is equivalent to `A - (B | C)`, while other nested differences are not supported.
+
+## Intersection
+
+`X & #"R"` matches the strings matched by both the nonterminal `X` and the regular expression `R`.
+Unlike a difference, `X` does not need to be regular, so the intersection can constrain
+recursive structures, for example by limiting their length. The right operand can be a regular expression
+or a difference of regular sub-grammars.
+
+```ebnf
+start ::= expr & #".{0,7}" ";";
+expr ::= term | expr "+" term;
+term ::= "1" | "(" expr ")";
+(*
+The engine will accept "(1)+1+1;", but not "(1+1)+1+1;" since the expression is longer than 7 bytes.
+*)
+```
+
+`&` binds tighter than concatenation. Intersections cannot be chained or nested:
+the left operand cannot reach another intersection. Each intersection is expanded into nonterminals prefixed with `__kbnf_intersection_`.
+The engine tracks `R` alongside `X` with its DFA. When several instances of the same intersection overlap in one output,
+e.g. because the output is still ambiguous, each instance is tracked with its own DFA state.
 
 ## Substrings
 
@@ -402,6 +519,106 @@ since empty string is a substring of any string.
 *)
 ```
 
+Multiple documents can be given as a list in `#substrs[]`, in which case the output is constrained to be a substring of any of them.
+A substring never spans two documents.
+
+```ebnf
+start ::= #substrs["hello world", "world peace"] '\n';
+(*
+The engine will accept "lo wor\n" and "d peace\n", but not "hello world peace\n".
+*)
+```
+
+The documents can also be bound at engine-creation time with [Config::documents](config::Config::documents),
+so that they do not need to be escaped into the grammar string.
+A name enclosed in `#documents""` is a substrings symbol of the documents bound to that name.
+
+```ebnf
+start ::= #documents"passage" '\n';
+(*
+With config.documents = {"passage": ["the quick brown fox", "a lazy dog"]},
+the engine will constrain the output to be a substring of either document ended with a newline.
+*)
+```
+
+## Dynamic alternative
+
+A name enclosed in `#dynamic""` is a dynamic alternative, whose set of strings can be replaced on an existing engine
+with [Engine::set_dynamic](engine::Engine::set_dynamic) without recompiling the rest of the grammar.
+The name can only contain ASCII alphanumeric characters and underscores.
+
+```ebnf
+start ::= #dynamic"tool" "(" args ")";
+(*
+After engine.set_dynamic("tool", &["search", "calc"]),
+the engine will constrain the output to start with either "search" or "calc".
+*)
+```
+
+A dynamic alternative matches nothing until its strings are set, and it can never match the empty string.
+
+## Import
+
+`import "path" as alias;` imports the grammar at `path`, whose nonterminals can then be referred to as `alias.name`.
+Imported grammars can import other grammars as well.
+An imported grammar must define every nonterminal it uses, so it never refers to the nonterminals of the importing grammar.
+
+```ebnf
+import "std/json.kbnf" as json;
+start ::= json.value "\n";
+```
+
+The grammars are loaded by the [GrammarLoader](import::GrammarLoader) passed to [Engine::with_loader](engine::Engine::with_loader).
+By default, only the [StandardLibrary](import::StandardLibrary) is available,
+which provides common rules for JSON, numbers, ISO 8601 dates, UUIDs and e-mail addresses.
+The imported nonterminals are renamed into nonterminals prefixed with `__kbnf_`,
+and those not reachable from the importing grammar are removed.
+
+## Bonus
+
+`bonus X = value;` adds `value` to the logits of the tokens that start to match `X`,
+where `X` is either a nonterminal or a literal and `value` is a finite number, possibly negative.
+The bonuses are applied by the `mask_logits` family of methods on top of the masking.
+
+```ebnf
+start ::= value ";";
+value ::= "null" | "true" | num;
+num ::= #"[0-9]+";
+bonus "null" = 2.5;
+bonus num = -1;
+(*
+When masking the logits of the first token, "null" will receive a bonus of 2.5 and the tokens starting with a digit a bonus of -1.
+*)
+```
+
+Only the first token of `X` receives the bonus. The tokens continuing `X`, like "ll" after "nu" above, receive none.
+When the nonterminals with bonuses are nested, the innermost one started by the token decides the bonus.
+A token receives the largest bonus among the nonterminals that the grammar can accept the whole token through,
+i.e. the nonterminals of the symbols that match its first byte. The symbols after them must accept the rest of the token.
+
+By default, the disallowed tokens are still masked with negative infinity.
+Setting [EngineConfig::disallowed_token_penalty](engine::EngineConfig::disallowed_token_penalty)
+adds the penalty to their logits instead, turning the grammar into a soft constraint.
+The engine still rejects the disallowed tokens when they are accepted, leaving its state unchanged,
+so a disallowed token that is sampled anyway must be handled by the caller, e.g. by sampling again without it.
+
+## Weighted alternative
+
+An alternative followed by `@weight`, where `weight` is a positive number, is a weighted alternative.
+The tokens that start a weighted alternative receive the bonus `ln(weight)` as if it were declared by [`bonus`](#bonus),
+and an alternative without a weight has a weight of 1.
+The tokens continuing the alternative receive no bonus, so the weight is applied once per alternative.
+
+```ebnf
+value ::= "null" @0.8 | object @0.2;
+(*
+The engine will add ln(0.8) to the logit of "null" and ln(0.2) to the logits of the tokens that start an object.
+*)
+```
+
+Like the bonuses, the innermost weighted alternative decides the bonus of a token,
+and a token accepted by several weighted alternatives receives the largest bonus among them.
+
 # Performance
 
 ## Reducing ambuguity
@@ -413,7 +630,13 @@ for [ambiguity](https://en.wikipedia.org/wiki/Ambiguous_grammar). Unfortunately,
 There does exist some heuristics to detect ambiguity like
 [Shift-Reduce Conflict](https://www.gnu.org/software/bison/manual/html_node/Shift_002fReduce.html) and
 [Reduce-Reduce Conflict](https://www.gnu.org/software/bison/manual/html_node/Reduce_002fReduce.html#:~:text=A%20reduce/reduce%20conflict%20occurs,zero%20or%20more%20word%20groupings).
-They may be implemented in this crate in the future. Some locally disambiguation methods may be implemented in the future as well.
+[Grammar::lint] approximates them with SLR(1) conflicts, and also reports nullable cycles, unreachable or unproductive nonterminals,
+right recursions and large regexes. [Grammar::lint_vocabulary_bytes] reports terminals that need a byte missing from the vocabulary,
+which lead to dead ends where [EngineLike::try_compute_allowed_token_ids] fails.
+It only checks the bytes, so a terminal that is not reported may still be unproducible with the vocabulary.
+With [Config::lint](config::Config::lint) enabled, the warnings of both are logged when an [Engine] is created
+and can be retrieved with [Engine::lint_warnings].
+Some locally disambiguation methods may be implemented in the future as well.
 
 ## Reuse an engine for multiple generations with cache enabled
 
@@ -432,12 +655,22 @@ it still introduces a constant factor overhead.
 */
 #![warn(missing_docs)]
 #![warn(rustdoc::broken_intra_doc_links)]
+pub mod beam_search;
+pub mod completion;
 pub mod config;
+pub mod diagnostic;
 pub mod engine;
 pub mod engine_base;
 pub mod engine_like;
 mod ffi_bindings;
+pub mod generator;
 pub mod grammar;
+pub mod import;
+pub mod lint;
+mod preprocessor;
+pub mod regex;
+#[cfg(feature = "sampling")]
+pub mod sampling;
 pub mod utils;
 pub mod vocabulary;
 mod zero;
@@ -467,9 +700,14 @@ fn kbnf(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<config::Fsa>()?;
     m.add_class::<config::RegexConfig>()?;
     m.add_class::<engine::EngineConfig>()?;
+    m.add_class::<diagnostic::Diagnostic>()?;
+    m.add_class::<diagnostic::Span>()?;
+    m.add_class::<diagnostic::Location>()?;
+    m.add_class::<completion::Completion>()?;
     m.add_class::<Engine>()?;
     m.add_class::<AcceptTokenResult>()?;
     m.add_class::<engine_like::AcceptTokenError>()?;
+    m.add_class::<engine_like::ComputeAllowedTokenIdsError>()?;
     m.add_class::<engine_like::MaskLogitsError>()?;
     m.add_class::<engine_like::UpdateLogitsError>()?;
     m.add_class::<Vocabulary>()?;
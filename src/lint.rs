@@ -0,0 +1,788 @@
+//! The lint module that detects grammar patterns which are likely to be mistakes or to slow down the engine.
+use std::collections::BTreeSet;
+use std::fmt::Display;
+use std::hash::Hash;
+
+use ahash::{AHashMap, AHashSet};
+use fixedbitset_stack::FixedBitSet;
+use kbnf_regex_automata::dfa::Automaton;
+use kbnf_regex_automata::util::start;
+use kbnf_regex_automata::Anchored;
+use num::traits::{NumAssign, NumOps};
+use num::{
+    cast::AsPrimitive,
+    traits::{ConstOne, ConstZero},
+    Num,
+};
+
+use crate::grammar::{Grammar, HIRNode, NonterminalID, RegexID};
+use crate::preprocessor::RegexPlaceholder;
+use crate::regex::FiniteStateAutomaton;
+use crate::utils::{self, ByteSet, FsaStateStatus};
+use crate::vocabulary::Vocabulary;
+
+/// The memory usage in bytes above which a regex DFA is reported as [LintKind::LargeRegex].
+pub const LARGE_REGEX_MEMORY_USAGE: usize = 1 << 20;
+/// The maximum number of LR(0) states built when looking for conflicts, and of regex states explored
+/// when checking them against the vocabulary.
+/// Nothing is reported for grammars or regexes whose automaton is larger than this.
+const MAX_LR_STATES: usize = 1 << 14;
+
+/// The kind of a [LintWarning].
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub enum LintKind {
+    /// A production can be completed while another production can still scan a terminal starting with the same byte.
+    ShiftReduceConflict,
+    /// Two productions can be completed at the same position and be followed by the same byte.
+    ReduceReduceConflict,
+    /// A set of nonterminals can derive each other without consuming any input.
+    NullableCycle,
+    /// A nonterminal cannot be reached from the start nonterminal.
+    UnreachableNonterminal,
+    /// A nonterminal cannot derive any finite string.
+    UnproductiveNonterminal,
+    /// A production is right recursive and can be rewritten as left recursion.
+    RightRecursion,
+    /// A regex compiles into a DFA larger than [LARGE_REGEX_MEMORY_USAGE].
+    LargeRegex,
+    /// A terminal, regex or substrings needs a byte that no token in the vocabulary contains,
+    /// so no token sequence in the vocabulary can produce it.
+    MissingVocabularyBytes,
+}
+
+/// A warning reported by [Grammar::lint].
+#[derive(Debug, Clone, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct LintWarning {
+    /// The kind of the warning.
+    pub kind: LintKind,
+    /// The human-readable description of the warning.
+    pub message: String,
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+/// The bytes that may follow a nonterminal, plus whether the input may end after it.
+#[derive(Debug, Clone, Default)]
+struct Lookahead {
+    bytes: ByteSet,
+    end: bool,
+}
+
+impl Lookahead {
+    fn union_with(&mut self, other: &Lookahead) -> bool {
+        let old = (self.bytes.count_ones(..), self.end);
+        self.bytes.union_with(&other.bytes);
+        self.end |= other.end;
+        old != (self.bytes.count_ones(..), self.end)
+    }
+}
+
+/// An LR(0) item represented as (nonterminal, production, dot position).
+type Item = (usize, usize, usize);
+
+struct Linter<'a, TI>
+where
+    TI: Num + AsPrimitive<usize> + ConstOne + ConstZero,
+{
+    grammar: &'a Grammar<TI>,
+    productions: Vec<Vec<Vec<HIRNode<TI>>>>,
+    nullable: Vec<bool>,
+    warnings: BTreeSet<LintWarning>,
+}
+
+/// Lints the grammar against the bytes of the vocabulary. See [Grammar::lint_vocabulary_bytes] for details.
+pub(crate) fn lint_vocabulary_bytes<TI>(
+    grammar: &Grammar<TI>,
+    vocabulary: &Vocabulary,
+) -> Vec<LintWarning>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    let mut linter = Linter::new(grammar);
+    linter.check_missing_vocabulary_bytes(vocabulary);
+    linter.warnings.into_iter().collect()
+}
+
+/// Lints the grammar. See [Grammar::lint] for details.
+pub(crate) fn lint<TI>(grammar: &Grammar<TI>) -> Vec<LintWarning>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    let mut linter = Linter::new(grammar);
+    linter.check_unreachable_nonterminals();
+    linter.check_unproductive_nonterminals();
+    linter.check_nullable_cycles();
+    linter.check_right_recursions();
+    linter.check_large_regexes();
+    linter.check_conflicts();
+    linter.warnings.into_iter().collect()
+}
+
+impl<'a, TI> Linter<'a, TI>
+where
+    TI: Num
+        + AsPrimitive<usize>
+        + ConstOne
+        + ConstZero
+        + NumOps
+        + NumAssign
+        + std::cmp::PartialOrd
+        + std::convert::TryFrom<usize>
+        + num::Bounded
+        + Hash
+        + Eq,
+    usize: num::traits::AsPrimitive<TI>,
+{
+    fn new(grammar: &'a Grammar<TI>) -> Self {
+        let mut productions = grammar.productions();
+        // Simplification may leave references to nonterminals whose rules were all removed,
+        // so every nonterminal gets an entry and one without rules is neither nullable nor productive.
+        productions.resize(grammar.nonterminals_size().max(productions.len()), vec![]);
+        let mut nullable = vec![false; productions.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (nonterminal_id, alternations) in productions.iter().enumerate() {
+                if !nullable[nonterminal_id]
+                    && alternations.iter().any(|production| {
+                        production.iter().all(|node| match node {
+                            HIRNode::Nonterminal(x) => nullable[x.0.as_()],
+                            _ => false,
+                        })
+                    })
+                {
+                    nullable[nonterminal_id] = true;
+                    changed = true;
+                }
+            }
+        }
+        Self {
+            grammar,
+            productions,
+            nullable,
+            warnings: BTreeSet::new(),
+        }
+    }
+
+    fn warn(&mut self, kind: LintKind, message: String) {
+        self.warnings.insert(LintWarning { kind, message });
+    }
+
+    fn nonterminal_display_form(&self, nonterminal_id: usize) -> String {
+        NonterminalID(nonterminal_id.as_()).to_display_form(self.grammar)
+    }
+
+    fn production_display_form(&self, nonterminal_id: usize, production_id: usize) -> String {
+        let mut result = format!("{} ::=", self.nonterminal_display_form(nonterminal_id));
+        for node in self.productions[nonterminal_id][production_id].iter() {
+            result.push(' ');
+            result.push_str(&node.to_display_form(self.grammar));
+        }
+        result
+    }
+
+    fn is_nullable(&self, node: &HIRNode<TI>) -> bool {
+        match node {
+            HIRNode::Nonterminal(x) => self.nullable[x.0.as_()],
+            _ => false,
+        }
+    }
+
+    fn check_unreachable_nonterminals(&mut self) {
+        let start = self
+            .grammar
+            .nonterminal_str(self.grammar.get_start_nonterminal_id())
+            .unwrap();
+        for nonterminal in self.grammar.unreachable_nonterminals.iter() {
+            self.warnings.insert(LintWarning {
+                kind: LintKind::UnreachableNonterminal,
+                message: format!(
+                    "`{nonterminal}` cannot be reached from the start nonterminal `{start}`."
+                ),
+            });
+        }
+    }
+
+    fn check_unproductive_nonterminals(&mut self) {
+        let mut productive = vec![false; self.productions.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (nonterminal_id, alternations) in self.productions.iter().enumerate() {
+                if !productive[nonterminal_id]
+                    && alternations.iter().any(|production| {
+                        production.iter().all(|node| match node {
+                            HIRNode::Nonterminal(x) => productive[x.0.as_()],
+                            _ => true,
+                        })
+                    })
+                {
+                    productive[nonterminal_id] = true;
+                    changed = true;
+                }
+            }
+        }
+        for (nonterminal_id, productive) in productive.into_iter().enumerate() {
+            if !productive {
+                self.warn(
+                    LintKind::UnproductiveNonterminal,
+                    format!(
+                        "`{}` cannot derive any finite string.",
+                        self.nonterminal_display_form(nonterminal_id)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_nullable_cycles(&mut self) {
+        let len = self.productions.len();
+        // An edge A -> B means A can derive B alone without consuming any input.
+        let mut edges = vec![vec![]; len];
+        for (nonterminal_id, alternations) in self.productions.iter().enumerate() {
+            for production in alternations.iter() {
+                for (i, node) in production.iter().enumerate() {
+                    if let HIRNode::Nonterminal(x) = node {
+                        let others_nullable = production
+                            .iter()
+                            .enumerate()
+                            .all(|(j, node)| i == j || self.is_nullable(node));
+                        if others_nullable {
+                            edges[nonterminal_id].push(x.0.as_());
+                        }
+                    }
+                }
+            }
+        }
+        let mut reachable: Vec<FixedBitSet> = Vec::with_capacity(len);
+        for nonterminal_id in 0..len {
+            let mut visited = FixedBitSet::with_capacity(len);
+            let mut stack = edges[nonterminal_id].clone();
+            while let Some(next) = stack.pop() {
+                if !visited.put(next) {
+                    stack.extend(edges[next].iter().copied());
+                }
+            }
+            reachable.push(visited);
+        }
+        let mut reported = FixedBitSet::with_capacity(len);
+        for nonterminal_id in 0..len {
+            if reported.contains(nonterminal_id)
+                || !reachable[nonterminal_id].contains(nonterminal_id)
+            {
+                continue;
+            }
+            let cycle: Vec<usize> = reachable[nonterminal_id]
+                .ones()
+                .filter(|&x| reachable[x].contains(nonterminal_id))
+                .collect();
+            for &x in cycle.iter() {
+                reported.insert(x);
+            }
+            let names = cycle
+                .iter()
+                .map(|&x| format!("`{}`", self.nonterminal_display_form(x)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            self.warn(
+                LintKind::NullableCycle,
+                format!(
+                    "{names} can derive themselves without consuming any input, \
+                    which makes the grammar infinitely ambiguous."
+                ),
+            );
+        }
+    }
+
+    fn check_right_recursions(&mut self) {
+        for nonterminal_id in 0..self.productions.len() {
+            for production_id in 0..self.productions[nonterminal_id].len() {
+                let production = &self.productions[nonterminal_id][production_id];
+                let Some((HIRNode::Nonterminal(last), prefix)) = production.split_last() else {
+                    continue;
+                };
+                let is_self = |node: &HIRNode<TI>| matches!(node, HIRNode::Nonterminal(x) if x.0.as_() == nonterminal_id);
+                // Only tail recursions whose prefix does not recurse can be mechanically turned into left recursions.
+                if last.0.as_() != nonterminal_id || prefix.is_empty() || prefix.iter().any(is_self)
+                {
+                    continue;
+                }
+                let name = self.nonterminal_display_form(nonterminal_id);
+                let prefix = prefix
+                    .iter()
+                    .map(|node| node.to_display_form(self.grammar))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.warn(
+                    LintKind::RightRecursion,
+                    format!(
+                        "`{}` is right recursive. Consider rewriting it as left recursion like `{name} ::= {name} {prefix}`, \
+                        which the engine recognizes with fewer Earley items.",
+                        self.production_display_form(nonterminal_id, production_id)
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_large_regexes(&mut self) {
+        for (regex_id, fsa) in self.grammar.id_to_regexes().iter().enumerate() {
+            // The size of a lazy DFA is bounded by its cache capacity instead.
+            if let FiniteStateAutomaton::Dfa(dfa) = fsa.as_ref() {
+                let memory_usage = dfa.memory_usage();
+                if memory_usage > LARGE_REGEX_MEMORY_USAGE {
+                    self.warn(
+                        LintKind::LargeRegex,
+                        format!(
+                            "`{}` compiles into a DFA of {memory_usage} bytes. \
+                            Consider simplifying it or using `Fsa::LazyDfa`.",
+                            RegexID(regex_id.as_()).to_display_form(self.grammar)
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reports the terminal-like nodes that need a byte which no token in the vocabulary contains.
+    ///
+    /// Since a token may end in the middle of a node, the nodes are only checked byte by byte.
+    /// This is necessary but not sufficient for a node to be producible, since the token boundaries are ignored.
+    fn check_missing_vocabulary_bytes(&mut self, vocabulary: &Vocabulary) {
+        let mut vocabulary_bytes = ByteSet::with_capacity(256);
+        for token in vocabulary.id_to_token.values() {
+            for &byte in token.0.iter() {
+                vocabulary_bytes.insert(byte as usize);
+            }
+        }
+        let mut nodes: Vec<HIRNode<TI>> = vec![];
+        let mut visited = AHashSet::default();
+        for production in self.productions.iter().flatten() {
+            for &node in production.iter() {
+                if !matches!(node, HIRNode::Nonterminal(_)) && visited.insert(node) {
+                    nodes.push(node);
+                }
+            }
+        }
+        for node in nodes {
+            let message = match node {
+                HIRNode::Terminal(x) => {
+                    let mut missing = ByteSet::with_capacity(256);
+                    for &byte in self.grammar.terminal(x) {
+                        if !vocabulary_bytes.contains(byte as usize) {
+                            missing.insert(byte as usize);
+                        }
+                    }
+                    if missing.is_clear() {
+                        continue;
+                    }
+                    let missing = missing
+                        .ones()
+                        .map(Self::byte_display_form)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    format!(
+                        "`{}` contains {missing}, which no token in the vocabulary contains.",
+                        node.to_display_form(self.grammar)
+                    )
+                }
+                HIRNode::RegexString(x) | HIRNode::EarlyEndRegexString(x) => {
+                    // A dynamic alternative matches nothing until its strings are set.
+                    if self.regex_matches_bytes(x, false, &vocabulary_bytes)
+                        || matches!(
+                            self.grammar.regex_placeholder(x),
+                            Some(RegexPlaceholder::Dynamic(_))
+                        )
+                    {
+                        continue;
+                    }
+                    format!(
+                        "`{}` cannot be matched with the bytes in the vocabulary.",
+                        node.to_display_form(self.grammar)
+                    )
+                }
+                HIRNode::RegexComplement(x) => {
+                    if self.regex_matches_bytes(x, true, &vocabulary_bytes) {
+                        continue;
+                    }
+                    format!(
+                        "`{}` cannot accept any byte in the vocabulary.",
+                        node.to_display_form(self.grammar)
+                    )
+                }
+                HIRNode::Substrings(x) => {
+                    let documents = self.grammar.suffix_automata_documents(x).unwrap();
+                    if documents
+                        .iter()
+                        .flat_map(|x| x.bytes())
+                        .any(|byte| vocabulary_bytes.contains(byte as usize))
+                    {
+                        continue;
+                    }
+                    format!(
+                        "`{}` has no byte that any token in the vocabulary contains.",
+                        node.to_display_form(self.grammar)
+                    )
+                }
+                HIRNode::Nonterminal(_) => continue,
+            };
+            self.warn(LintKind::MissingVocabularyBytes, message);
+        }
+    }
+
+    /// Returns whether the regex can reach a match state with the given bytes,
+    /// or for a complement, whether it can accept any of them since the engine never ends a complement.
+    fn regex_matches_bytes(
+        &self,
+        regex_id: RegexID<TI>,
+        complement: bool,
+        bytes: &ByteSet,
+    ) -> bool {
+        let anchored = if complement {
+            Anchored::No
+        } else {
+            Anchored::Yes
+        };
+        match self.grammar.regex(regex_id) {
+            FiniteStateAutomaton::Dfa(dfa) => {
+                let Ok(start) = dfa.start_state(&start::Config::new().anchored(anchored)) else {
+                    return true;
+                };
+                Self::automaton_matches_bytes(
+                    start,
+                    |state, byte| {
+                        let next = dfa.next_state(state, byte);
+                        (next, utils::check_dfa_state_status(next, dfa))
+                    },
+                    complement,
+                    bytes,
+                )
+            }
+            FiniteStateAutomaton::LazyDfa(dfa) => {
+                let start = if complement {
+                    dfa.unanchored_start_state()
+                } else {
+                    dfa.anchored_start_state()
+                };
+                let mut cache = dfa.create_cache();
+                Self::automaton_matches_bytes(
+                    start,
+                    |state, byte| {
+                        dfa.next_state(&mut cache, state, byte)
+                            .unwrap_or((state, FsaStateStatus::Reject))
+                    },
+                    complement,
+                    bytes,
+                )
+            }
+        }
+    }
+
+    fn automaton_matches_bytes<S: Copy + Eq + Hash>(
+        start: S,
+        mut next: impl FnMut(S, u8) -> (S, FsaStateStatus),
+        complement: bool,
+        bytes: &ByteSet,
+    ) -> bool {
+        let mut visited = AHashSet::default();
+        let mut stack = vec![start];
+        visited.insert(start);
+        while let Some(state) = stack.pop() {
+            for byte in bytes.ones() {
+                let (next, status) = next(state, byte as u8);
+                match status {
+                    FsaStateStatus::Accept if !complement => return true,
+                    FsaStateStatus::InProgress if complement => return true,
+                    FsaStateStatus::InProgress if visited.insert(next) => {
+                        // Give up on automata too large to explore and assume they are fine.
+                        if visited.len() > MAX_LR_STATES {
+                            return true;
+                        }
+                        stack.push(next);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the bytes that can start the given terminal-like node.
+    fn node_first_bytes(&self, node: &HIRNode<TI>) -> ByteSet {
+        let mut set = ByteSet::with_capacity(256);
+        match *node {
+            HIRNode::Terminal(x) => {
+                if let Some(&byte) = self.grammar.terminal(x).first() {
+                    set.insert(byte as usize);
+                }
+            }
+            HIRNode::RegexString(x) | HIRNode::EarlyEndRegexString(x) => {
+                match self.grammar.regex(x) {
+                    FiniteStateAutomaton::Dfa(dfa) => {
+                        if let Ok(state) =
+                            dfa.start_state(&start::Config::new().anchored(Anchored::Yes))
+                        {
+                            if let Some(bytes) = self.grammar.first_bytes_from_regex(x, state) {
+                                set = bytes.clone();
+                            }
+                        }
+                    }
+                    FiniteStateAutomaton::LazyDfa(dfa) => {
+                        set = dfa.first_bytes(
+                            &mut dfa.create_cache(),
+                            dfa.anchored_start_state(),
+                            false,
+                        );
+                    }
+                }
+            }
+            HIRNode::RegexComplement(x) => match self.grammar.regex(x) {
+                FiniteStateAutomaton::Dfa(dfa) => {
+                    if let Ok(state) = dfa.start_state(&start::Config::new().anchored(Anchored::No))
+                    {
+                        if let Some(bytes) =
+                            self.grammar.complement_first_bytes_from_regex(x, state)
+                        {
+                            set = bytes.clone();
+                        }
+                    }
+                }
+                FiniteStateAutomaton::LazyDfa(dfa) => {
+                    set = dfa.first_bytes(
+                        &mut dfa.create_cache(),
+                        dfa.unanchored_start_state(),
+                        true,
+                    );
+                }
+            },
+            HIRNode::Substrings(x) => {
+                let suffix_automaton = self.grammar.suffix_automata(x);
+                for byte in 0..=u8::MAX {
+                    let mut state = suffix_automaton.get_state(general_sam::SAM_ROOT_NODE_ID);
+                    state.feed([byte]);
+                    if !state.is_nil() {
+                        set.insert(byte as usize);
+                    }
+                }
+            }
+            HIRNode::Nonterminal(_) => {}
+        }
+        set
+    }
+
+    /// Reports SLR(1) conflicts, using the first bytes of the terminals as lookaheads
+    /// since the engine scans the input byte by byte.
+    fn check_conflicts(&mut self) {
+        let len = self.productions.len();
+        let mut terminal_first_bytes: AHashMap<HIRNode<TI>, ByteSet> = AHashMap::default();
+        for production in self.productions.iter().flatten() {
+            for node in production.iter() {
+                if !matches!(node, HIRNode::Nonterminal(_))
+                    && !terminal_first_bytes.contains_key(node)
+                {
+                    terminal_first_bytes.insert(*node, self.node_first_bytes(node));
+                }
+            }
+        }
+        let mut first = vec![ByteSet::with_capacity(256); len];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for nonterminal_id in 0..len {
+                let mut set = first[nonterminal_id].clone();
+                for production in self.productions[nonterminal_id].iter() {
+                    for node in production.iter() {
+                        match node {
+                            HIRNode::Nonterminal(x) => set.union_with(&first[x.0.as_()]),
+                            _ => set.union_with(&terminal_first_bytes[node]),
+                        }
+                        if !self.is_nullable(node) {
+                            break;
+                        }
+                    }
+                }
+                if set != first[nonterminal_id] {
+                    first[nonterminal_id] = set;
+                    changed = true;
+                }
+            }
+        }
+        let mut follow = vec![Lookahead::default(); len];
+        follow[self.grammar.get_start_nonterminal_id().0.as_()].end = true;
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for nonterminal_id in 0..len {
+                for production in self.productions[nonterminal_id].iter() {
+                    // The lookahead after the current position, built from right to left.
+                    let mut lookahead = follow[nonterminal_id].clone();
+                    for node in production.iter().rev() {
+                        match node {
+                            HIRNode::Nonterminal(x) => {
+                                changed |= follow[x.0.as_()].union_with(&lookahead);
+                                if !self.nullable[x.0.as_()] {
+                                    lookahead = Lookahead::default();
+                                }
+                                lookahead.bytes.union_with(&first[x.0.as_()]);
+                            }
+                            _ => {
+                                lookahead = Lookahead {
+                                    bytes: terminal_first_bytes[node].clone(),
+                                    end: false,
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let Some(states) = self.lr0_states() else {
+            log::debug!("The LR(0) automaton is too large, so conflicts are not checked.");
+            return;
+        };
+        for items in states {
+            let completed: Vec<(usize, usize)> = items
+                .iter()
+                .filter(|&&(n, p, d)| n < len && d == self.productions[n][p].len())
+                .map(|&(n, p, _)| (n, p))
+                .collect();
+            if completed.is_empty() {
+                continue;
+            }
+            let mut shifted: Vec<HIRNode<TI>> = vec![];
+            for &(n, p, d) in items.iter().filter(|&&(n, _, _)| n < len) {
+                match self.productions[n][p].get(d) {
+                    Some(node)
+                        if !matches!(node, HIRNode::Nonterminal(_)) && !shifted.contains(node) =>
+                    {
+                        shifted.push(*node);
+                    }
+                    _ => {}
+                }
+            }
+            for &(n, p) in completed.iter() {
+                for node in shifted.iter() {
+                    let mut overlap = follow[n].bytes.clone();
+                    overlap.intersect_with(&terminal_first_bytes[node]);
+                    if let Some(byte) = overlap.minimum() {
+                        let message = format!(
+                            "Shift-reduce conflict between completing `{}` and scanning `{}`, \
+                            both of which can be followed by {}.",
+                            self.production_display_form(n, p),
+                            node.to_display_form(self.grammar),
+                            Self::byte_display_form(byte)
+                        );
+                        self.warn(LintKind::ShiftReduceConflict, message);
+                    }
+                }
+            }
+            for (i, &(n1, p1)) in completed.iter().enumerate() {
+                for &(n2, p2) in completed[i + 1..].iter() {
+                    let mut overlap = follow[n1].bytes.clone();
+                    overlap.intersect_with(&follow[n2].bytes);
+                    let lookahead = match overlap.minimum() {
+                        Some(byte) => Self::byte_display_form(byte),
+                        None if follow[n1].end && follow[n2].end => "the end of input".to_string(),
+                        None => continue,
+                    };
+                    let message = format!(
+                        "Reduce-reduce conflict between completing `{}` and `{}`, \
+                        both of which can be followed by {lookahead}.",
+                        self.production_display_form(n1, p1),
+                        self.production_display_form(n2, p2),
+                    );
+                    self.warn(LintKind::ReduceReduceConflict, message);
+                }
+            }
+        }
+    }
+
+    /// Builds the closed item sets of the LR(0) automaton, or returns `None` if there are more than [MAX_LR_STATES] states.
+    ///
+    /// The augmented start production is represented by the nonterminal id `self.productions.len()`.
+    fn lr0_states(&self) -> Option<Vec<Vec<Item>>> {
+        let len = self.productions.len();
+        let start = self.grammar.get_start_nonterminal_id();
+        let augmented = [HIRNode::Nonterminal(start)];
+        let production = |n: usize, p: usize| -> &[HIRNode<TI>] {
+            if n == len {
+                &augmented
+            } else {
+                &self.productions[n][p]
+            }
+        };
+        let closure = |kernel: &[Item]| -> Vec<Item> {
+            let mut items = kernel.to_vec();
+            let mut predicted = FixedBitSet::with_capacity(len);
+            let mut i = 0;
+            while i < items.len() {
+                let (n, p, d) = items[i];
+                if let Some(HIRNode::Nonterminal(x)) = production(n, p).get(d) {
+                    let x = x.0.as_();
+                    if !predicted.put(x) {
+                        items.extend((0..self.productions[x].len()).map(|p| (x, p, 0)));
+                    }
+                }
+                i += 1;
+            }
+            items
+        };
+        let mut states = vec![];
+        let mut queue = vec![vec![(len, 0, 0)]];
+        let mut visited: AHashSet<Vec<Item>> = AHashSet::default();
+        visited.insert(queue[0].clone());
+        while let Some(kernel) = queue.pop() {
+            let items = closure(&kernel);
+            let mut gotos: AHashMap<HIRNode<TI>, Vec<Item>> = AHashMap::default();
+            for &(n, p, d) in items.iter() {
+                if let Some(node) = production(n, p).get(d) {
+                    gotos.entry(*node).or_default().push((n, p, d + 1));
+                }
+            }
+            for (_, mut next) in gotos {
+                next.sort_unstable();
+                if visited.insert(next.clone()) {
+                    if visited.len() > MAX_LR_STATES {
+                        return None;
+                    }
+                    queue.push(next);
+                }
+            }
+            states.push(items);
+        }
+        Some(states)
+    }
+
+    fn byte_display_form(byte: usize) -> String {
+        match u8::try_from(byte) {
+            Ok(byte) if byte.is_ascii_graphic() || byte == b' ' => format!("'{}'", byte as char),
+            _ => format!("byte {byte:#04x}"),
+        }
+    }
+}
@@ -0,0 +1,1835 @@
+//! The preprocessor that rewrites KBNF syntax extensions into plain KBNF accepted by [kbnf_syntax].
+//!
+//! The source is split into tokens, the tokens are rewritten by a series of passes,
+//! and the result is rendered back into a string.
+//! Untouched parts of the source, including comments and whitespace, are copied verbatim.
+use std::borrow::Cow;
+
+use std::collections::VecDeque;
+
+use ahash::{AHashMap, AHashSet};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::config::InternalConfig;
+use crate::diagnostic::Diagnostic;
+use crate::grammar::CreateGrammarError;
+use crate::regex::NumericRange;
+use crate::utils::escape_kbnf_string;
+
+/// The prefix of the nonterminals generated by the preprocessor.
+pub(crate) const GENERATED_NONTERMINAL_PREFIX: &str = "__kbnf_";
+/// The maximum depth of nested instantiations of parameterized rules.
+pub(crate) const MAX_INSTANTIATION_DEPTH: usize = 32;
+/// The tag of the placeholder regexes, which stand for the syntax extensions that [kbnf_syntax] cannot express.
+///
+/// A placeholder regex is the empty class, which matches nothing, followed by the tag and an index.
+/// What it stands for is recorded in [Preprocessed::placeholders] instead of the regex itself.
+/// Underscores are appended to the tag until no literal in the grammar contains it,
+/// so a user regex is never taken for a placeholder.
+const PLACEHOLDER_TAG: &str = "__kbnf_placeholder_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Identifier,
+    /// A terminal, regex, early-end regex, regex complement or substrings literal, quotes and prefix included.
+    Literal,
+    Number,
+    Punctuation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Origin {
+    start: usize,
+    end: usize,
+    index: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub(crate) kind: TokenKind,
+    pub(crate) text: String,
+    /// The position of the token in the source. `None` for generated tokens.
+    origin: Option<Origin>,
+}
+
+impl Token {
+    pub(crate) fn identifier(text: impl Into<String>) -> Self {
+        Self {
+            kind: TokenKind::Identifier,
+            text: text.into(),
+            origin: None,
+        }
+    }
+    pub(crate) fn punctuation(text: &str) -> Self {
+        Self {
+            kind: TokenKind::Punctuation,
+            text: text.to_string(),
+            origin: None,
+        }
+    }
+    pub(crate) fn literal(text: impl Into<String>) -> Self {
+        Self {
+            kind: TokenKind::Literal,
+            text: text.into(),
+            origin: None,
+        }
+    }
+    /// Returns the byte range of the token in the source, or `None` if the token is generated.
+    pub(crate) fn span(&self) -> Option<std::ops::Range<usize>> {
+        self.origin.map(|x| x.start..x.end)
+    }
+    #[inline]
+    pub(crate) fn is(&self, kind: TokenKind, text: &str) -> bool {
+        self.kind == kind && self.text == text
+    }
+    #[inline]
+    pub(crate) fn is_punctuation(&self, text: &str) -> bool {
+        self.is(TokenKind::Punctuation, text)
+    }
+}
+
+/// The result of [preprocess].
+#[derive(Debug, Clone)]
+pub(crate) struct Preprocessed<'a> {
+    /// The plain KBNF.
+    pub(crate) text: Cow<'a, str>,
+    /// Pairs of offsets in `text` where a token starts and the offset of that token in the source.
+    /// `None` for generated tokens. Empty if `text` is the source itself.
+    source_map: Vec<(usize, Option<usize>)>,
+    /// The syntax extensions the placeholder regexes stand for, keyed by the regexes as [kbnf_syntax] interns them.
+    pub(crate) placeholders: AHashMap<String, RegexPlaceholder>,
+    /// The documents the placeholder substrings strings stand for, keyed by the strings.
+    ///
+    /// A placeholder substrings string is the tag of the placeholder regexes followed by `documents` and an index.
+    pub(crate) documents: AHashMap<String, SubstringsDocuments>,
+    /// The bonuses of the `bonus X = value;` statements and the weighted alternatives, keyed by the nonterminal names.
+    pub(crate) nonterminal_bonuses: AHashMap<String, f32>,
+    /// The nonterminals inlined into the operands of the differences.
+    ///
+    /// Their rules stay in the grammar but may no longer be referenced, so they are not reported as unreachable.
+    pub(crate) difference_operands: AHashSet<String>,
+}
+
+/// A syntax extension that a placeholder regex stands for, see [PLACEHOLDER_TAG].
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RegexPlaceholder {
+    /// The dynamic alternative `#dynamic"name"` with its name, which matches nothing until its strings are set.
+    Dynamic(String),
+    /// The difference `A - B` with the regexes of its operands.
+    Difference(String, String),
+    /// The numeric range `#int[min,max]` or `#float[min,max]`.
+    NumericRange(NumericRange),
+    /// The regex operand `R` of an intersection `X & #"R"` with the anchored regex of `R`,
+    /// which may be a placeholder itself.
+    Intersection(String),
+}
+
+/// The documents of a substrings symbol with multiple documents, `#substrs["a", "b"]` or `#documents"name"`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SubstringsDocuments {
+    /// The name the documents are bound to in [Config::documents](crate::config::Config::documents),
+    /// or `None` for a list of documents.
+    pub(crate) name: Option<String>,
+    pub(crate) documents: Vec<String>,
+}
+
+/// The language a [RegexPlaceholder] matches.
+pub(crate) enum PlaceholderLanguage<'a> {
+    /// The strings matched by an anchored regex.
+    Regex(&'a str),
+    /// The strings matched by the first regex but not by the second, neither of them anchored.
+    Difference(Cow<'a, str>, Cow<'a, str>),
+}
+
+impl RegexPlaceholder {
+    /// Returns the language the placeholder matches, or `None` if it matches nothing.
+    ///
+    /// The operand of an intersection is looked up in `placeholders`, since it can be a difference or a numeric range.
+    pub(crate) fn language<'a>(
+        &'a self,
+        placeholders: &'a AHashMap<String, RegexPlaceholder>,
+    ) -> Option<PlaceholderLanguage<'a>> {
+        match self {
+            Self::Dynamic(_) => None,
+            Self::Difference(minuend, subtrahend) => Some(PlaceholderLanguage::Difference(
+                Cow::Borrowed(minuend),
+                Cow::Borrowed(subtrahend),
+            )),
+            Self::NumericRange(range) => {
+                let (minuend, subtrahend) = range.difference();
+                Some(PlaceholderLanguage::Difference(
+                    Cow::Owned(minuend),
+                    Cow::Owned(subtrahend),
+                ))
+            }
+            Self::Intersection(regex) => match placeholders.get(regex) {
+                Some(operand) => operand.language(placeholders),
+                None => Some(PlaceholderLanguage::Regex(regex)),
+            },
+        }
+    }
+}
+
+impl Preprocessed<'_> {
+    /// Maps an offset in the preprocessed text back to the closest offset in the source.
+    pub(crate) fn source_offset(&self, offset: usize) -> usize {
+        let end = self.source_map.partition_point(|&(x, _)| x <= offset);
+        let mut last_generated = None;
+        for &(text_offset, source_offset) in self.source_map[..end].iter().rev() {
+            match source_offset {
+                // Errors inside generated tokens are reported at the closest preceding source token.
+                Some(source_offset) if last_generated.is_some() => return source_offset,
+                Some(source_offset) => return source_offset + (offset - text_offset),
+                None => last_generated = Some(text_offset),
+            }
+        }
+        offset
+    }
+}
+
+/// Rewrites the KBNF syntax extensions in `source` into plain KBNF.
+///
+/// Returns `source` unchanged if it does not use any extension.
+/// If `source` cannot be tokenized, it is returned unchanged as well so that [kbnf_syntax] reports the error.
+pub(crate) fn preprocess<'a>(
+    source: &'a str,
+    config: &InternalConfig,
+) -> Result<Preprocessed<'a>, CreateGrammarError> {
+    let unchanged = Preprocessed {
+        text: Cow::Borrowed(source),
+        source_map: Vec::new(),
+        placeholders: AHashMap::default(),
+        documents: AHashMap::default(),
+        nonterminal_bonuses: AHashMap::default(),
+        difference_operands: AHashSet::default(),
+    };
+    let Some(tokens) = tokenize(source) else {
+        return Ok(unchanged);
+    };
+    let mut preprocessor = Preprocessor::new(source, config);
+    let tokens = preprocessor.resolve_imports(tokens)?;
+    preprocessor.placeholder_tag = placeholder_tag(&tokens);
+    let tokens = preprocessor.instantiate_parameterized_rules(tokens)?;
+    let tokens = preprocessor.collect_bonuses(tokens)?;
+    let tokens = preprocessor.rewrite_weights(tokens)?;
+    let tokens = preprocessor.rewrite_terminals(tokens)?;
+    let tokens = preprocessor.rewrite_differences(tokens)?;
+    let tokens = preprocessor.rewrite_intersections(tokens)?;
+    let tokens = preprocessor.apply_bonuses(tokens)?;
+    let tokens = preprocessor.expand_counted_repetitions(tokens)?;
+    if !preprocessor.changed {
+        return Ok(unchanged);
+    }
+    let mut output = String::new();
+    let mut source_map = Vec::new();
+    render(source, &tokens, &mut output, &mut source_map);
+    for rule in preprocessor.generated_rules.iter() {
+        output.push('\n');
+        render(source, rule, &mut output, &mut source_map);
+    }
+    Ok(Preprocessed {
+        text: Cow::Owned(output),
+        source_map,
+        placeholders: preprocessor
+            .placeholders
+            .into_iter()
+            .map(|(regex, placeholder)| (anchored_regex(&regex), placeholder))
+            .collect(),
+        documents: preprocessor.documents,
+        nonterminal_bonuses: preprocessor.nonterminal_bonuses,
+        difference_operands: preprocessor.difference_operands,
+    })
+}
+
+/// Splits `source` into tokens, skipping whitespace and comments.
+///
+/// Returns `None` if `source` contains an unterminated literal or comment, or an unknown literal prefix.
+pub(crate) fn tokenize(source: &str) -> Option<Vec<Token>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        let kind = match bytes[i] {
+            b if b.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'(' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2 + source[i + 2..].find("*)")? + 2;
+                continue;
+            }
+            b'"' | b'\'' => {
+                i = skip_quoted(bytes, i)?;
+                TokenKind::Literal
+            }
+            b'#' => {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_alphabetic() {
+                    i += 1;
+                }
+                i = match bytes.get(i) {
+                    Some(b'"' | b'\'') => skip_quoted(bytes, i)?,
+                    Some(b'[') if &source[start..i] == "#substrs" => skip_quoted_list(bytes, i)?,
+                    Some(b'[') if ["#int", "#float"].contains(&&source[start..i]) => {
+                        i + source[i..].find(']')? + 1
+                    }
+                    _ => return None,
+                };
+                TokenKind::Literal
+            }
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                TokenKind::Identifier
+            }
+            b if b.is_ascii_digit() => {
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if bytes.get(i) == Some(&b'.')
+                    && bytes.get(i + 1).is_some_and(|b| b.is_ascii_digit())
+                {
+                    i += 1;
+                    while i < bytes.len() && bytes[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                TokenKind::Number
+            }
+            _ => {
+                i += if source[i..].starts_with("::=") {
+                    3
+                } else {
+                    source[i..].chars().next()?.len_utf8()
+                };
+                TokenKind::Punctuation
+            }
+        };
+        tokens.push(Token {
+            kind,
+            text: source[start..i].to_string(),
+            origin: Some(Origin {
+                start,
+                end: i,
+                index: tokens.len(),
+            }),
+        });
+    }
+    Some(tokens)
+}
+
+/// Returns [PLACEHOLDER_TAG], extended with underscores until no literal in the tokens contains it.
+fn placeholder_tag(tokens: &[Token]) -> String {
+    let literals: Vec<String> = tokens
+        .iter()
+        .filter(|x| x.kind == TokenKind::Literal)
+        .map(|x| unescaper::unescape(&x.text).unwrap_or_else(|_| x.text.clone()))
+        .collect();
+    let mut tag = PLACEHOLDER_TAG.to_string();
+    while literals.iter().any(|x| x.contains(&tag)) {
+        tag.push('_');
+    }
+    tag
+}
+
+/// Returns the index right after the closing bracket of the list of quoted strings starting at `start`,
+/// e.g. `["a", "b"]`.
+fn skip_quoted_list(bytes: &[u8], start: usize) -> Option<usize> {
+    let skip_whitespace = |mut i: usize| {
+        while bytes.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        i
+    };
+    let mut i = skip_whitespace(start + 1);
+    if bytes.get(i) == Some(&b']') {
+        return Some(i + 1);
+    }
+    loop {
+        if !matches!(bytes.get(i), Some(b'"' | b'\'')) {
+            return None;
+        }
+        i = skip_whitespace(skip_quoted(bytes, i)?);
+        match bytes.get(i)? {
+            b',' => i = skip_whitespace(i + 1),
+            b']' => return Some(i + 1),
+            _ => return None,
+        }
+    }
+}
+
+/// Returns the index right after the closing quote of the quoted string starting at `start`.
+fn skip_quoted(bytes: &[u8], start: usize) -> Option<usize> {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    loop {
+        match *bytes.get(i)? {
+            b'\\' => i += 2,
+            b if b == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+}
+
+/// Renders the tokens back into KBNF and appends them to `output`, recording where each token comes from.
+///
+/// The text between two tokens that were adjacent in the source is copied verbatim,
+/// otherwise the tokens are separated by a single space.
+fn render(
+    source: &str,
+    tokens: &[Token],
+    output: &mut String,
+    source_map: &mut Vec<(usize, Option<usize>)>,
+) {
+    let mut previous: Option<Origin> = None;
+    for (i, token) in tokens.iter().enumerate() {
+        match (previous, token.origin) {
+            (None, Some(origin)) if i == 0 && origin.index == 0 => {
+                output.push_str(&source[..origin.start]);
+            }
+            (Some(previous), Some(origin)) if previous.index + 1 == origin.index => {
+                output.push_str(&source[previous.end..origin.start]);
+            }
+            _ if i != 0 => output.push(' '),
+            _ => {}
+        }
+        source_map.push((output.len(), token.origin.map(|x| x.start)));
+        output.push_str(&token.text);
+        previous = token.origin;
+    }
+}
+
+/// Returns the index of the first token of the alternative that ends `tokens`.
+///
+/// The alternative starts after the nearest `|`, `::=`, `=`, `;` or unclosed bracket outside brackets.
+fn alternative_start(tokens: &[Token]) -> usize {
+    let mut depth = 0usize;
+    for i in (0..tokens.len()).rev() {
+        let token = &tokens[i];
+        if token.kind != TokenKind::Punctuation {
+            continue;
+        }
+        match token.text.as_str() {
+            ")" | "]" | "}" => depth += 1,
+            "(" | "[" | "{" if depth == 0 => return i + 1,
+            "(" | "[" | "{" => depth -= 1,
+            "|" | "::=" | "=" | ";" if depth == 0 => return i + 1,
+            _ => {}
+        }
+    }
+    0
+}
+
+/// Returns the index of the first token of the operand that ends at the end of `tokens`.
+///
+/// The operand is an identifier, a literal or a bracketed group, optionally followed by postfix operators.
+fn operand_start(tokens: &[Token]) -> Option<usize> {
+    let mut i = tokens.len();
+    while i > 0
+        && ["*", "+", "?"]
+            .iter()
+            .any(|x| tokens[i - 1].is_punctuation(x))
+    {
+        i -= 1;
+    }
+    let last = tokens.get(i.checked_sub(1)?)?;
+    match last.kind {
+        TokenKind::Identifier | TokenKind::Literal => Some(i - 1),
+        TokenKind::Punctuation if [")", "]", "}"].contains(&last.text.as_str()) => {
+            let mut depth = 0usize;
+            for j in (0..i).rev() {
+                let token = &tokens[j];
+                if token.kind != TokenKind::Punctuation {
+                    continue;
+                }
+                match token.text.as_str() {
+                    ")" | "]" | "}" => depth += 1,
+                    "(" | "[" | "{" => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(j);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
+/// Returns the index of the bracket that closes the one at `open`.
+fn closing_bracket(tokens: &[Token], open: usize) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.kind != TokenKind::Punctuation {
+            continue;
+        }
+        match token.text.as_str() {
+            "(" | "[" | "{" => depth += 1,
+            ")" | "]" | "}" => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Returns the index right after the operand that starts at `start`, postfix operators included.
+fn operand_end(tokens: &[Token], start: usize) -> Option<usize> {
+    let first = tokens.get(start)?;
+    let mut i = match first.kind {
+        TokenKind::Identifier | TokenKind::Literal => start + 1,
+        TokenKind::Punctuation if ["(", "[", "{"].contains(&first.text.as_str()) => {
+            closing_bracket(tokens, start)? + 1
+        }
+        _ => return None,
+    };
+    loop {
+        match tokens.get(i) {
+            Some(x) if ["*", "+", "?"].iter().any(|y| x.is_punctuation(y)) => i += 1,
+            Some(x) if x.is_punctuation("{") => {
+                match Preprocessor::parse_repetition_bounds(&tokens[i + 1..]) {
+                    Some((_, _, len)) => i += 1 + len,
+                    None => return Some(i),
+                }
+            }
+            _ => return Some(i),
+        }
+    }
+}
+
+/// Returns the start of the operand ending at the end of `tokens`, counted repetitions included.
+fn repeated_operand_start(tokens: &[Token]) -> Option<usize> {
+    let mut start = operand_start(tokens)?;
+    while tokens[start].is_punctuation("{")
+        && Preprocessor::parse_repetition_bounds(&tokens[start + 1..]).is_some()
+    {
+        start = operand_start(&tokens[..start])?;
+    }
+    Some(start)
+}
+
+/// Returns the bodies of the rules defined in `tokens`, keyed by their nonterminals.
+fn rule_bodies(tokens: &[Token]) -> AHashMap<&str, Vec<&[Token]>> {
+    let mut rules: AHashMap<&str, Vec<&[Token]>> = AHashMap::default();
+    for statement in tokens.split_inclusive(|x| x.is_punctuation(";")) {
+        if let [name, definition, body @ .., _] = statement {
+            if name.kind == TokenKind::Identifier
+                && (definition.is_punctuation("::=") || definition.is_punctuation("="))
+            {
+                rules.entry(name.text.as_str()).or_default().push(body);
+            }
+        }
+    }
+    rules
+}
+
+/// Returns the names of the nonterminals defined in `tokens`, including the parameterized ones.
+fn defined_nonterminals(tokens: &[Token]) -> impl Iterator<Item = &str> {
+    tokens.iter().enumerate().filter_map(|(i, x)| {
+        (x.kind == TokenKind::Identifier
+            && (i == 0 || tokens[i - 1].is_punctuation(";"))
+            && tokens
+                .get(i + 1)
+                .is_some_and(|x| x.is_punctuation("::=") || x.is_punctuation("<")))
+        .then_some(x.text.as_str())
+    })
+}
+
+/// Parses a parameterized rule `name<P, ...> ::= body;`, where `statement` is the whole rule including the `;`.
+///
+/// Returns the name, the parameters and the body.
+fn parse_parameterized_rule(statement: &[Token]) -> Option<(&Token, Vec<&Token>, &[Token])> {
+    let (name, rest) = statement.split_first()?;
+    if name.kind != TokenKind::Identifier || !rest.first()?.is_punctuation("<") {
+        return None;
+    }
+    let mut parameters = Vec::new();
+    let mut i = 1;
+    loop {
+        parameters.push(rest.get(i).filter(|x| x.kind == TokenKind::Identifier)?);
+        match rest.get(i + 1)? {
+            x if x.is_punctuation(",") => i += 2,
+            x if x.is_punctuation(">") => break,
+            _ => return None,
+        }
+    }
+    let definition = rest.get(i + 2)?;
+    if !(definition.is_punctuation("::=") || definition.is_punctuation("=")) {
+        return None;
+    }
+    Some((name, parameters, &rest[i + 3..rest.len() - 1]))
+}
+
+/// Strips the brackets enclosing the whole `tokens`, so that `("a")` and `"a"` are the same argument.
+fn strip_parentheses(mut tokens: &[Token]) -> &[Token] {
+    while tokens.len() >= 2
+        && tokens[0].is_punctuation("(")
+        && operand_start(tokens) == Some(0)
+        && tokens[tokens.len() - 1].is_punctuation(")")
+    {
+        tokens = &tokens[1..tokens.len() - 1];
+    }
+    tokens
+}
+
+/// Returns `regex` anchored at both ends, as [kbnf_syntax] interns it.
+fn anchored_regex(regex: &str) -> String {
+    format!(r"\A(?:{regex})\z")
+}
+
+/// Returns the prefix and the unescaped value of a literal, so that `"a"` and `'a'` are the same literal.
+fn literal_key(text: &str) -> (&str, String) {
+    let quote = text.find(['"', '\'']).unwrap_or(0);
+    let value = &text[(quote + 1).min(text.len())..text.len().saturating_sub(1).max(quote)];
+    (
+        &text[..quote],
+        unescaper::unescape(value).unwrap_or_else(|_| value.to_string()),
+    )
+}
+
+/// Escapes the characters of `value` that have special meanings in a regex.
+pub(crate) fn escape_regex(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for c in value.chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            output.push('\\');
+        }
+        output.push(c);
+    }
+    output
+}
+
+fn render_tokens(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|x| x.text.as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The parameterized rules and their instantiations.
+#[derive(Default)]
+struct Instantiations {
+    /// The parameters and the body of each parameterized rule.
+    templates: AHashMap<String, (Vec<String>, Vec<Token>)>,
+    /// The nonterminal of each instantiation, keyed by the rule name and the arguments.
+    instances: AHashMap<String, String>,
+    /// The nonterminal of each argument that consists of more than one token, so arguments never grow.
+    arguments: AHashMap<String, String>,
+    /// The instantiations whose rules are not generated yet, along with their bodies and depths.
+    pending: VecDeque<(String, Vec<Token>, usize)>,
+}
+
+struct Preprocessor<'a> {
+    source: &'a str,
+    config: &'a InternalConfig,
+    changed: bool,
+    generated_rules: Vec<Vec<Token>>,
+    /// The targets of the `bonus X = value;` statements, along with their bonuses.
+    bonuses: Vec<(Token, f32)>,
+    /// The bonuses of the nonterminals, see [Preprocessed::nonterminal_bonuses].
+    nonterminal_bonuses: AHashMap<String, f32>,
+    /// The tag of the placeholder regexes, see [PLACEHOLDER_TAG].
+    placeholder_tag: String,
+    /// The syntax extensions the placeholder regexes stand for, keyed by the regexes.
+    placeholders: AHashMap<String, RegexPlaceholder>,
+    /// The documents the placeholder substrings strings stand for, see [Preprocessed::documents].
+    documents: AHashMap<String, SubstringsDocuments>,
+    /// The nonterminals inlined into the differences, see [Preprocessed::difference_operands].
+    difference_operands: AHashSet<String>,
+    /// The operands of counted repetitions, along with the number of their doubling rules.
+    repeated_operands: AHashMap<String, (usize, u32)>,
+    /// The generated rules that match from zero to the given number of copies of an operand.
+    repetition_upto_rules: AHashMap<(usize, u32), String>,
+}
+
+impl<'a> Preprocessor<'a> {
+    fn new(source: &'a str, config: &'a InternalConfig) -> Self {
+        Self {
+            source,
+            config,
+            changed: false,
+            generated_rules: Vec::new(),
+            bonuses: Vec::new(),
+            nonterminal_bonuses: AHashMap::default(),
+            placeholder_tag: PLACEHOLDER_TAG.to_string(),
+            placeholders: AHashMap::default(),
+            documents: AHashMap::default(),
+            difference_operands: AHashSet::default(),
+            repeated_operands: AHashMap::default(),
+            repetition_upto_rules: AHashMap::default(),
+        }
+    }
+
+    /// Returns the regex literal of the placeholder that stands for `placeholder`.
+    ///
+    /// Equal placeholders share their regex, so e.g. the occurrences of a dynamic alternative are replaced together.
+    fn placeholder(&mut self, placeholder: RegexPlaceholder) -> Token {
+        let regex = match self.placeholders.iter().find(|(_, x)| **x == placeholder) {
+            Some((regex, _)) => regex.clone(),
+            None => self.new_placeholder(placeholder),
+        };
+        Token::literal(format!("#{}", escape_kbnf_string(&regex)))
+    }
+
+    /// Returns a new placeholder regex that stands for `placeholder`, which is never shared.
+    fn new_placeholder(&mut self, placeholder: RegexPlaceholder) -> String {
+        let regex = format!(
+            r"[^\s\S]{}{}",
+            self.placeholder_tag,
+            self.placeholders.len()
+        );
+        self.placeholders.insert(regex.clone(), placeholder);
+        regex
+    }
+
+    /// Returns the substrings literal that stands for `documents`.
+    ///
+    /// A list of a single document is kept as an ordinary substrings symbol.
+    /// Equal documents share their placeholder, like [Preprocessor::placeholder].
+    fn substrings_placeholder(&mut self, documents: SubstringsDocuments) -> Token {
+        if let (None, [document]) = (&documents.name, &documents.documents[..]) {
+            return Token::literal(format!("#substrs{}", escape_kbnf_string(document)));
+        }
+        let string = match self.documents.iter().find(|(_, x)| **x == documents) {
+            Some((string, _)) => string.clone(),
+            None => {
+                let string = format!("{}documents{}", self.placeholder_tag, self.documents.len());
+                self.documents.insert(string.clone(), documents);
+                string
+            }
+        };
+        Token::literal(format!("#substrs{}", escape_kbnf_string(&string)))
+    }
+
+    fn add_rule(&mut self, name: &str, body: Vec<Token>) {
+        let mut rule = vec![Token::identifier(name), Token::punctuation("::=")];
+        rule.extend(body);
+        rule.push(Token::punctuation(";"));
+        self.generated_rules.push(rule);
+    }
+
+    /// Resolves `import "path" as alias;` statements through [InternalConfig::loader].
+    ///
+    /// The nonterminals of an imported grammar are referred to as `alias.name`
+    /// and renamed into `__kbnf_alias__name` so they cannot clash with the importing grammar.
+    /// Only the imported rules reachable from the source are kept.
+    fn resolve_imports(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut imported = Vec::new();
+        let source = self.source;
+        let mut tokens =
+            self.resolve_unit(tokens, source, "", None, &mut Vec::new(), &mut imported)?;
+        let mut rules: AHashMap<&str, Vec<&[Token]>> = AHashMap::default();
+        for rule in imported.split_inclusive(|x| x.is_punctuation(";")) {
+            rules.entry(rule[0].text.as_str()).or_default().push(rule);
+        }
+        let mut reachable = AHashSet::default();
+        let mut stack: Vec<&str> = tokens
+            .iter()
+            .filter(|x| x.kind == TokenKind::Identifier && rules.contains_key(x.text.as_str()))
+            .map(|x| x.text.as_str())
+            .collect();
+        while let Some(name) = stack.pop() {
+            if !reachable.insert(name) {
+                continue;
+            }
+            for rule in rules[name].iter() {
+                stack.extend(
+                    rule.iter()
+                        .filter(|x| {
+                            x.kind == TokenKind::Identifier && rules.contains_key(x.text.as_str())
+                        })
+                        .map(|x| x.text.as_str()),
+                );
+            }
+        }
+        let reachable_rules = imported
+            .split_inclusive(|x| x.is_punctuation(";"))
+            .filter(|rule| reachable.contains(rule[0].text.as_str()))
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        tokens.extend(reachable_rules);
+        Ok(tokens)
+    }
+
+    /// Resolves the imports of a grammar and qualifies the nonterminals it defines with `namespace`.
+    ///
+    /// The rules of the imported grammars are appended to `imported`.
+    /// `site` is the import statement in the source that imports the grammar directly or indirectly,
+    /// where the errors in the import statements of the grammar are reported.
+    /// A name the grammar uses but does not define is reported in `source`, the text of the grammar.
+    /// `paths` are the grammars being imported, which are used to detect circular imports.
+    fn resolve_unit(
+        &mut self,
+        tokens: Vec<Token>,
+        source: &str,
+        namespace: &str,
+        site: Option<&Token>,
+        paths: &mut Vec<String>,
+        imported: &mut Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut body = Vec::with_capacity(tokens.len());
+        let mut aliases: AHashMap<String, (String, AHashSet<String>)> = AHashMap::default();
+        let mut i = 0;
+        while i < tokens.len() {
+            let statement = &tokens[i..(i + 5).min(tokens.len())];
+            let is_import = (i == 0 || tokens[i - 1].is_punctuation(";"))
+                && matches!(statement, [import, path, r#as, alias, end]
+                    if import.is(TokenKind::Identifier, "import")
+                        && path.kind == TokenKind::Literal
+                        && path.text.starts_with(['"', '\''])
+                        && r#as.is(TokenKind::Identifier, "as")
+                        && alias.kind == TokenKind::Identifier
+                        && end.is_punctuation(";"));
+            if !is_import {
+                body.push(tokens[i].clone());
+                i += 1;
+                continue;
+            }
+            let site = site.unwrap_or(&statement[1]);
+            let alias = &statement[3].text;
+            let path = unescaper::unescape(&statement[1].text[1..statement[1].text.len() - 1])
+                .map_err(|_| self.preprocessing_error(site, "Invalid escape sequence"))?;
+            if aliases.contains_key(alias) {
+                return Err(self.preprocessing_error(site, "Duplicate import alias"));
+            }
+            if paths.contains(&path) {
+                return Err(self.preprocessing_error(site, "Circular import"));
+            }
+            let unit_source = self.config.loader.load(&path).ok_or_else(|| {
+                self.preprocessing_error(site, "Cannot find the imported grammar")
+            })?;
+            let unit = tokenize(&unit_source)
+                .ok_or_else(|| self.preprocessing_error(site, "Invalid imported grammar"))?;
+            let unit_namespace = if namespace.is_empty() {
+                format!("{GENERATED_NONTERMINAL_PREFIX}{alias}__")
+            } else {
+                format!("{namespace}{alias}__")
+            };
+            paths.push(path.clone());
+            let mut unit = self.resolve_unit(
+                unit,
+                &unit_source,
+                &unit_namespace,
+                Some(site),
+                paths,
+                imported,
+            )?;
+            paths.pop();
+            // The origins point into the imported grammar rather than the source.
+            for token in unit.iter_mut() {
+                token.origin = None;
+            }
+            let defined = defined_nonterminals(&unit).map(str::to_string).collect();
+            imported.extend(unit);
+            aliases.insert(alias.clone(), (unit_namespace, defined));
+            self.changed = true;
+            i += 5;
+        }
+        let defined: AHashSet<String> = if namespace.is_empty() {
+            AHashSet::default()
+        } else {
+            defined_nonterminals(&body).map(str::to_string).collect()
+        };
+        let mut output = Vec::with_capacity(body.len());
+        let mut parameters = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+            let next = body.get(i + 1);
+            let starts_statement = i == 0 || body[i - 1].is_punctuation(";");
+            if starts_statement {
+                let end = body[i..]
+                    .iter()
+                    .position(|x| x.is_punctuation(";"))
+                    .map_or(body.len(), |x| i + x + 1);
+                parameters = parse_parameterized_rule(&body[i..end])
+                    .map(|(_, parameters, _)| parameters)
+                    .unwrap_or_default();
+            }
+            if token.kind != TokenKind::Identifier || next.is_some_and(|x| x.is_punctuation("!")) {
+                output.push(token.clone());
+                i += 1;
+                continue;
+            }
+            if let (Some((unit_namespace, unit_defined)), true, Some(name)) = (
+                aliases.get(&token.text),
+                next.is_some_and(|x| x.is_punctuation(".")),
+                body.get(i + 2).filter(|x| x.kind == TokenKind::Identifier),
+            ) {
+                let qualified = format!("{unit_namespace}{}", name.text);
+                if !unit_defined.contains(&qualified) {
+                    return Err(self.preprocessing_error(
+                        site.unwrap_or(name),
+                        "Undefined nonterminal in the imported grammar",
+                    ));
+                }
+                output.push(Token {
+                    text: qualified,
+                    ..token.clone()
+                });
+                i += 3;
+                continue;
+            }
+            let mut token = token.clone();
+            if defined.contains(&token.text) {
+                token.text = format!("{namespace}{}", token.text);
+            } else if !namespace.is_empty()
+                && !starts_statement
+                && !parameters.iter().any(|x| x.text == token.text)
+            {
+                // The name would otherwise refer to a nonterminal of the importing grammar.
+                let path = paths.last().map_or("", String::as_str);
+                return Err(Self::located(
+                    source,
+                    &token,
+                    CreateGrammarError::PreprocessingError(format!(
+                        "`{}` is not defined in the imported grammar `{path}`",
+                        token.text
+                    )),
+                ));
+            }
+            output.push(token);
+            i += 1;
+        }
+        Ok(output)
+    }
+
+    fn preprocessing_error(&self, token: &Token, message: &str) -> CreateGrammarError {
+        Self::located(
+            self.source,
+            token,
+            CreateGrammarError::PreprocessingError(message.to_string()),
+        )
+    }
+
+    fn parameterized_rule_error(&self, token: &Token, message: String) -> CreateGrammarError {
+        Self::located(
+            self.source,
+            token,
+            CreateGrammarError::ParameterizedRuleError(message),
+        )
+    }
+
+    /// Locates `error` at `token` in `source`, the text `token` is taken from.
+    fn located(source: &str, token: &Token, error: CreateGrammarError) -> CreateGrammarError {
+        CreateGrammarError::Located {
+            diagnostic: Box::new(Diagnostic::new(error.to_string(), source, token.span())),
+            error: Box::new(error),
+        }
+    }
+
+    /// Instantiates the parameterized rules `name<P, ...> ::= body;`.
+    ///
+    /// Every distinct `name<A, ...>` becomes a nonterminal prefixed with `__kbnf_`,
+    /// defined as `body` with each parameter replaced by the corresponding argument.
+    /// Arguments of more than one token are replaced by nonterminals as well.
+    /// The arguments are instantiated first, and the instantiations in the body
+    /// are nested one level deeper, up to [MAX_INSTANTIATION_DEPTH].
+    fn instantiate_parameterized_rules(
+        &mut self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        if !tokens
+            .windows(2)
+            .any(|x| x[0].kind == TokenKind::Identifier && x[1].is_punctuation("<"))
+        {
+            return Ok(tokens);
+        }
+        self.changed = true;
+        let mut rules = Instantiations::default();
+        let mut rest = Vec::with_capacity(tokens.len());
+        for statement in tokens.split_inclusive(|x| x.is_punctuation(";")) {
+            let Some((name, parameters, body)) = parse_parameterized_rule(statement) else {
+                rest.extend_from_slice(statement);
+                continue;
+            };
+            if rules.templates.contains_key(&name.text) {
+                return Err(self.parameterized_rule_error(
+                    name,
+                    format!("`{}` is defined more than once.", name.text),
+                ));
+            }
+            for (i, parameter) in parameters.iter().enumerate() {
+                if parameters[..i].iter().any(|x| x.text == parameter.text) {
+                    return Err(self.parameterized_rule_error(
+                        parameter,
+                        format!(
+                            "`{}` has duplicate parameter `{}`.",
+                            name.text, parameter.text
+                        ),
+                    ));
+                }
+            }
+            rules.templates.insert(
+                name.text.clone(),
+                (
+                    parameters.into_iter().map(|x| x.text.clone()).collect(),
+                    body.to_vec(),
+                ),
+            );
+        }
+        let mut output = self.replace_instantiations(&rest, 0, &mut rules)?;
+        while let Some((name, body, depth)) = rules.pending.pop_front() {
+            let body = self.replace_instantiations(&body, depth, &mut rules)?;
+            output.push(Token::identifier(name));
+            output.push(Token::punctuation("::="));
+            output.extend(body);
+            output.push(Token::punctuation(";"));
+        }
+        Ok(output)
+    }
+
+    /// Replaces every `name<A, ...>` in `tokens` by the nonterminal of the instantiation,
+    /// queueing the rules of new instantiations in `rules`.
+    fn replace_instantiations(
+        &self,
+        tokens: &[Token],
+        depth: usize,
+        rules: &mut Instantiations,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut output = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            let name = &tokens[i];
+            if name.kind != TokenKind::Identifier
+                || !tokens.get(i + 1).is_some_and(|x| x.is_punctuation("<"))
+            {
+                output.push(name.clone());
+                i += 1;
+                continue;
+            }
+            let mut arguments = vec![Vec::new()];
+            let mut nesting = 0usize;
+            let mut j = i + 2;
+            loop {
+                let Some(token) = tokens.get(j) else {
+                    return Err(self.parameterized_rule_error(
+                        name,
+                        format!("The arguments of `{}` are not closed.", name.text),
+                    ));
+                };
+                j += 1;
+                if token.kind == TokenKind::Punctuation {
+                    match token.text.as_str() {
+                        ">" if nesting == 0 => break,
+                        "," if nesting == 0 => {
+                            arguments.push(Vec::new());
+                            continue;
+                        }
+                        "<" | "(" | "[" | "{" => nesting += 1,
+                        ">" | ")" | "]" | "}" => nesting = nesting.saturating_sub(1),
+                        ";" => {
+                            return Err(self.parameterized_rule_error(
+                                name,
+                                format!("The arguments of `{}` are not closed.", name.text),
+                            ))
+                        }
+                        _ => {}
+                    }
+                }
+                arguments.last_mut().unwrap().push(token.clone());
+            }
+            i = j;
+            let Some(parameters) = rules.templates.get(&name.text).map(|x| x.0.clone()) else {
+                return Err(self.parameterized_rule_error(
+                    name,
+                    format!("`{}` is not a parameterized rule.", name.text),
+                ));
+            };
+            if parameters.len() != arguments.len() {
+                return Err(self.parameterized_rule_error(
+                    name,
+                    format!(
+                        "`{}` expects {} argument(s) but {} are given.",
+                        name.text,
+                        parameters.len(),
+                        arguments.len()
+                    ),
+                ));
+            }
+            if arguments.iter().any(|x| x.is_empty()) {
+                return Err(self.parameterized_rule_error(
+                    name,
+                    format!("`{}` is given an empty argument.", name.text),
+                ));
+            }
+            let mut replacements = AHashMap::default();
+            let mut key = name.text.clone();
+            for (parameter, argument) in parameters.into_iter().zip(arguments) {
+                let argument = self.replace_instantiations(&argument, depth, rules)?;
+                let argument = match strip_parentheses(&argument) {
+                    [token] => token.clone(),
+                    argument => {
+                        let text = render_tokens(argument);
+                        let name = match rules.arguments.get(&text) {
+                            Some(name) => name.clone(),
+                            None => {
+                                let name = format!(
+                                    "{GENERATED_NONTERMINAL_PREFIX}argument_{}",
+                                    rules.arguments.len()
+                                );
+                                rules.arguments.insert(text, name.clone());
+                                rules
+                                    .pending
+                                    .push_back((name.clone(), argument.to_vec(), depth));
+                                name
+                            }
+                        };
+                        Token::identifier(name)
+                    }
+                };
+                key.push('\0');
+                key.push_str(&argument.text);
+                replacements.insert(parameter, argument);
+            }
+            let instance = match rules.instances.get(&key) {
+                Some(instance) => instance.clone(),
+                None => {
+                    if depth >= MAX_INSTANTIATION_DEPTH {
+                        return Err(self.parameterized_rule_error(
+                            name,
+                            format!(
+                                "Instantiating `{}` exceeds the maximum depth {MAX_INSTANTIATION_DEPTH}.",
+                                name.text
+                            ),
+                        ));
+                    }
+                    let instance = format!(
+                        "{GENERATED_NONTERMINAL_PREFIX}{}_{}",
+                        name.text.trim_start_matches(GENERATED_NONTERMINAL_PREFIX),
+                        rules.instances.len()
+                    );
+                    let (_, body) = &rules.templates[&name.text];
+                    let mut instance_body = Vec::with_capacity(body.len());
+                    for token in body.iter() {
+                        match replacements.get(&token.text) {
+                            Some(argument) if token.kind == TokenKind::Identifier => {
+                                instance_body.push(argument.clone())
+                            }
+                            _ => instance_body.push(token.clone()),
+                        }
+                    }
+                    rules.instances.insert(key, instance.clone());
+                    rules
+                        .pending
+                        .push_back((instance.clone(), instance_body, depth + 1));
+                    instance
+                }
+            };
+            output.push(Token {
+                kind: TokenKind::Identifier,
+                text: instance,
+                origin: name.origin,
+            });
+        }
+        Ok(output)
+    }
+
+    /// Rewrites the case-insensitive terminals `#i"..."` into regexes and normalizes terminals if requested.
+    ///
+    /// The numeric ranges `#int[min,max]` and `#float[min,max]` and the dynamic alternatives `#dynamic"name"`
+    /// are rewritten into placeholders.
+    /// The substrings of multiple documents, `#substrs["a", "b"]` or the bound `#documents"name"`,
+    /// are rewritten into placeholder substrings strings, see [Preprocessed::documents].
+    ///
+    /// Plain terminals are only rewritten when normalization changes them,
+    /// so they still match by exact bytes.
+    /// Only terminals are normalized; the regexes and the documents are left as they are.
+    fn rewrite_terminals(
+        &mut self,
+        mut tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        for token in tokens.iter_mut() {
+            if token.kind != TokenKind::Literal {
+                continue;
+            }
+            let range = match token.text.strip_suffix(']') {
+                Some(x) if x.starts_with("#int[") => Some((false, &x[5..])),
+                Some(x) if x.starts_with("#float[") => Some((true, &x[7..])),
+                _ => None,
+            };
+            if let Some((float, bounds)) = range {
+                let range = NumericRange::parse(float, bounds)
+                    .ok_or_else(|| self.preprocessing_error(token, "Invalid numeric range"))?;
+                token.text = self.placeholder(RegexPlaceholder::NumericRange(range)).text;
+                self.changed = true;
+                continue;
+            }
+            if let Some(list) = token.text.strip_prefix("#substrs[") {
+                let mut documents = Vec::new();
+                let mut i = 0;
+                while let Some(start) = list[i..].find(['"', '\'']).map(|x| x + i) {
+                    i = skip_quoted(list.as_bytes(), start).unwrap();
+                    documents.push(
+                        unescaper::unescape(&list[start + 1..i - 1]).map_err(|_| {
+                            self.preprocessing_error(token, "Invalid escape sequence")
+                        })?,
+                    );
+                }
+                token.text = self
+                    .substrings_placeholder(SubstringsDocuments {
+                        name: None,
+                        documents,
+                    })
+                    .text;
+                self.changed = true;
+                continue;
+            }
+            let quote = token.text.find(['"', '\'']).unwrap_or(0);
+            let prefix = &token.text[..quote];
+            if !(prefix == "#i"
+                || prefix == "#dynamic"
+                || prefix == "#documents"
+                || prefix.is_empty() && self.config.nfc_normalization)
+            {
+                continue;
+            }
+            let value = unescaper::unescape(&token.text[quote + 1..token.text.len() - 1])
+                .map_err(|_| self.preprocessing_error(token, "Invalid escape sequence"))?;
+            if prefix == "#dynamic" {
+                if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+                {
+                    return Err(self.preprocessing_error(token, "Invalid dynamic alternative name"));
+                }
+                token.text = self.placeholder(RegexPlaceholder::Dynamic(value)).text;
+                self.changed = true;
+                continue;
+            }
+            if prefix == "#documents" {
+                let Some(documents) = self.config.documents.get(&value) else {
+                    return Err(self.preprocessing_error(token, "Unbound documents"));
+                };
+                let documents = SubstringsDocuments {
+                    name: Some(value),
+                    documents: documents.clone(),
+                };
+                token.text = self.substrings_placeholder(documents).text;
+                self.changed = true;
+                continue;
+            }
+            let normalized: String = if self.config.nfc_normalization {
+                value.nfc().collect()
+            } else {
+                value.clone()
+            };
+            token.text = if prefix == "#i" {
+                let regex = format!("(?i:{})", escape_regex(&normalized));
+                format!("#{}", escape_kbnf_string(&regex))
+            } else if normalized != value {
+                escape_kbnf_string(&normalized)
+            } else {
+                continue;
+            };
+            self.changed = true;
+        }
+        Ok(tokens)
+    }
+
+    /// Rewrites the differences `A - B` into placeholders.
+    ///
+    /// `-` binds tighter than concatenation and `A - B - C` means `A - (B | C)`.
+    /// Both operands must be regular: their nonterminals are inlined into a regex,
+    /// so they cannot be recursive and can only contain terminals and regexes.
+    fn rewrite_differences(
+        &mut self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        if !tokens.iter().any(|x| x.is_punctuation("-")) {
+            return Ok(tokens);
+        }
+        let rules = rule_bodies(&tokens);
+        let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+        // The operands of the difference that was rewritten last, along with its index in the output.
+        let mut last: Option<(usize, String, String)> = None;
+        let mut inlined = AHashSet::default();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if !token.is_punctuation("-") {
+                output.push(token.clone());
+                i += 1;
+                continue;
+            }
+            let start = repeated_operand_start(&output)
+                .ok_or_else(|| self.preprocessing_error(token, "Expected an operand before `-`"))?;
+            let end = operand_end(&tokens, i + 1)
+                .ok_or_else(|| self.preprocessing_error(token, "Expected an operand after `-`"))?;
+            let subtrahend = self.regular_expression(
+                &tokens[i + 1..end],
+                &rules,
+                &mut Vec::new(),
+                &mut inlined,
+            )?;
+            let (minuend, subtrahend) = match last.take() {
+                Some((index, minuend, previous)) if index == start && output.len() == start + 1 => {
+                    (minuend, format!("{previous}|{subtrahend}"))
+                }
+                _ => (
+                    self.regular_expression(
+                        &output[start..],
+                        &rules,
+                        &mut Vec::new(),
+                        &mut inlined,
+                    )?,
+                    subtrahend,
+                ),
+            };
+            output.truncate(start);
+            output.push(self.placeholder(RegexPlaceholder::Difference(
+                minuend.clone(),
+                subtrahend.clone(),
+            )));
+            last = Some((start, minuend, subtrahend));
+            self.changed = true;
+            i = end;
+        }
+        self.difference_operands.extend(inlined);
+        Ok(output)
+    }
+
+    /// Converts the regular sub-grammar `tokens` into an equivalent regex by inlining its nonterminals.
+    ///
+    /// `visiting` holds the nonterminals being inlined, which detects recursion,
+    /// and `inlined` collects every inlined nonterminal.
+    fn regular_expression(
+        &self,
+        tokens: &[Token],
+        rules: &AHashMap<&str, Vec<&[Token]>>,
+        visiting: &mut Vec<String>,
+        inlined: &mut AHashSet<String>,
+    ) -> Result<String, CreateGrammarError> {
+        let mut alternatives = Vec::new();
+        let mut concatenation = String::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            let atom = match token.kind {
+                TokenKind::Punctuation if token.text == "|" => {
+                    alternatives.push(std::mem::take(&mut concatenation));
+                    i += 1;
+                    continue;
+                }
+                TokenKind::Punctuation if token.text == "-" => {
+                    return Err(self.preprocessing_error(
+                        token,
+                        "Difference cannot be an operand of another difference",
+                    ));
+                }
+                TokenKind::Punctuation if ["(", "[", "{"].contains(&token.text.as_str()) => {
+                    let close = closing_bracket(tokens, i)
+                        .ok_or_else(|| self.preprocessing_error(token, "Unclosed bracket"))?;
+                    let inner =
+                        self.regular_expression(&tokens[i + 1..close], rules, visiting, inlined)?;
+                    i = close;
+                    match token.text.as_str() {
+                        "(" => inner,
+                        "[" => format!("(?:{inner})?"),
+                        _ => format!("(?:{inner})*"),
+                    }
+                }
+                TokenKind::Literal => self.literal_regex(token)?,
+                TokenKind::Identifier => {
+                    if visiting.contains(&token.text) {
+                        return Err(self.preprocessing_error(
+                            token,
+                            "Recursive nonterminal cannot be an operand of a difference",
+                        ));
+                    }
+                    let bodies = rules
+                        .get(token.text.as_str())
+                        .ok_or_else(|| self.preprocessing_error(token, "Undefined nonterminal"))?;
+                    visiting.push(token.text.clone());
+                    inlined.insert(token.text.clone());
+                    let bodies = bodies
+                        .iter()
+                        .map(|body| self.regular_expression(body, rules, visiting, inlined))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    visiting.pop();
+                    bodies.join("|")
+                }
+                _ => {
+                    return Err(self.preprocessing_error(
+                        token,
+                        "Unexpected symbol in the operand of a difference",
+                    ))
+                }
+            };
+            concatenation.push_str(&format!("(?:{atom})"));
+            i += 1;
+            loop {
+                match tokens.get(i) {
+                    Some(x) if ["*", "+", "?"].iter().any(|y| x.is_punctuation(y)) => {
+                        concatenation.push_str(&x.text);
+                        i += 1;
+                    }
+                    Some(x) if x.is_punctuation("{") => {
+                        let Some((min, max, len)) = Self::parse_repetition_bounds(&tokens[i + 1..])
+                        else {
+                            break;
+                        };
+                        let max = max.map_or("", |x| x.text.as_str());
+                        concatenation.push_str(&format!("{{{},{max}}}", min.text));
+                        i += 1 + len;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        alternatives.push(concatenation);
+        Ok(alternatives.join("|"))
+    }
+
+    /// Converts a terminal or regex literal into a regex.
+    fn literal_regex(&self, token: &Token) -> Result<String, CreateGrammarError> {
+        let quote = token.text.find(['"', '\'']).unwrap_or(0);
+        let prefix = &token.text[..quote];
+        let value = unescaper::unescape(&token.text[quote + 1..token.text.len() - 1])
+            .map_err(|_| self.preprocessing_error(token, "Invalid escape sequence"))?;
+        match (prefix, self.placeholders.get(&value)) {
+            ("", _) => Ok(escape_regex(&value)),
+            ("#", Some(RegexPlaceholder::Dynamic(_))) => Err(self.preprocessing_error(
+                token,
+                "Dynamic alternative cannot be an operand of a difference",
+            )),
+            ("#", Some(RegexPlaceholder::NumericRange(_))) => Err(self
+                .preprocessing_error(token, "Numeric range cannot be an operand of a difference")),
+            ("#", Some(RegexPlaceholder::Difference(..))) => Err(self.preprocessing_error(
+                token,
+                "Difference cannot be an operand of another difference",
+            )),
+            ("#", _) => Ok(value),
+            _ => Err(self.preprocessing_error(
+                token,
+                "Only terminals and regexes can be operands of a difference",
+            )),
+        }
+    }
+
+    /// Rewrites the intersections `X & #"R"` into generated nonterminals `I ::= X #"M";`,
+    /// where `M` is the placeholder of the regex operand.
+    ///
+    /// `&` binds tighter than concatenation. The left operand must be a nonterminal and the right operand a regex,
+    /// possibly a difference. Every nonterminal reachable from `X` is copied for the intersection later,
+    /// so none of them can contain another intersection.
+    fn rewrite_intersections(
+        &mut self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        if !tokens.iter().any(|x| x.is_punctuation("&")) {
+            return Ok(tokens);
+        }
+        let rules = rule_bodies(&tokens);
+        let mut intersections: AHashMap<(String, String), String> = AHashMap::default();
+        let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if !token.is_punctuation("&") {
+                output.push(token.clone());
+                i += 1;
+                continue;
+            }
+            let operand = match output.last() {
+                Some(x) if x.kind == TokenKind::Identifier => x.clone(),
+                _ => {
+                    return Err(self.preprocessing_error(token, "Expected a nonterminal before `&`"))
+                }
+            };
+            if operand.origin.is_none() && intersections.values().any(|x| *x == operand.text) {
+                return Err(self.preprocessing_error(
+                    token,
+                    "Intersection cannot be an operand of another intersection",
+                ));
+            }
+            let regex = match tokens.get(i + 1) {
+                Some(x)
+                    if x.kind == TokenKind::Literal
+                        && matches!(x.text.as_bytes()[..2], [b'#', b'"' | b'\'']) =>
+                {
+                    x
+                }
+                _ => return Err(self.preprocessing_error(token, "Expected a regex after `&`")),
+            };
+            let value = unescaper::unescape(&regex.text[2..regex.text.len() - 1])
+                .map_err(|_| self.preprocessing_error(regex, "Invalid escape sequence"))?;
+            if matches!(
+                self.placeholders.get(&value),
+                Some(RegexPlaceholder::Dynamic(_))
+            ) {
+                return Err(self.preprocessing_error(
+                    regex,
+                    "Dynamic alternative cannot be an operand of an intersection",
+                ));
+            }
+            let mut reachable = AHashSet::default();
+            let mut stack = vec![operand.text.as_str()];
+            while let Some(name) = stack.pop() {
+                if !reachable.insert(name) {
+                    continue;
+                }
+                for body in rules.get(name).into_iter().flatten() {
+                    if body.iter().any(|x| x.is_punctuation("&")) {
+                        return Err(self.preprocessing_error(
+                            &operand,
+                            "Nonterminal reaching another intersection cannot be an operand of an intersection",
+                        ));
+                    }
+                    stack.extend(
+                        body.iter()
+                            .filter(|x| x.kind == TokenKind::Identifier)
+                            .map(|x| x.text.as_str()),
+                    );
+                }
+            }
+            let count = intersections.len();
+            let name = intersections
+                .entry((operand.text.clone(), value))
+                .or_insert_with_key(|(operand_name, value)| {
+                    let name = format!(
+                        "{GENERATED_NONTERMINAL_PREFIX}intersection_{count}_{}",
+                        operand_name.trim_start_matches(GENERATED_NONTERMINAL_PREFIX)
+                    );
+                    let marker =
+                        self.placeholder(RegexPlaceholder::Intersection(anchored_regex(value)));
+                    let mut rule =
+                        vec![Token::identifier(name.as_str()), Token::punctuation("::=")];
+                    rule.push(operand.clone());
+                    rule.push(marker);
+                    rule.push(Token::punctuation(";"));
+                    self.generated_rules.push(rule);
+                    name
+                })
+                .clone();
+            output.pop();
+            output.push(Token::identifier(name));
+            self.changed = true;
+            i += 2;
+        }
+        Ok(output)
+    }
+
+    /// Removes the `bonus X = value;` statements and records their targets and bonuses.
+    ///
+    /// The target is either a nonterminal or a literal, and the bonus is a finite number, possibly negative.
+    /// The literal targets are rewritten as [rewrite_terminals](Self::rewrite_terminals) rewrites the rules,
+    /// so they still match the literals in the rules afterwards.
+    fn collect_bonuses(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut output = Vec::with_capacity(tokens.len());
+        for statement in tokens.split_inclusive(|x| x.is_punctuation(";")) {
+            let [keyword, target, equal, rest @ ..] = statement else {
+                output.extend_from_slice(statement);
+                continue;
+            };
+            if !keyword.is(TokenKind::Identifier, "bonus")
+                || !matches!(target.kind, TokenKind::Identifier | TokenKind::Literal)
+                || !equal.is_punctuation("=")
+            {
+                output.extend_from_slice(statement);
+                continue;
+            }
+            let value = match rest {
+                [value, end] if value.kind == TokenKind::Number && end.is_punctuation(";") => {
+                    value.text.parse::<f32>().ok()
+                }
+                [minus, value, end]
+                    if minus.is_punctuation("-")
+                        && value.kind == TokenKind::Number
+                        && end.is_punctuation(";") =>
+                {
+                    value.text.parse::<f32>().ok().map(|x| -x)
+                }
+                _ => None,
+            };
+            let value = value
+                .filter(|x| x.is_finite())
+                .ok_or_else(|| self.preprocessing_error(equal, "Invalid bonus"))?;
+            let target = match target.kind {
+                TokenKind::Literal => self.rewrite_terminals(vec![target.clone()])?.pop().unwrap(),
+                _ => target.clone(),
+            };
+            if self.bonuses.iter().any(|(x, _)| {
+                x.kind == target.kind
+                    && match target.kind {
+                        TokenKind::Literal => literal_key(&x.text) == literal_key(&target.text),
+                        _ => x.text == target.text,
+                    }
+            }) {
+                return Err(self.preprocessing_error(keyword, "Duplicate bonus"));
+            }
+            self.bonuses.push((target, value));
+            self.changed = true;
+        }
+        Ok(output)
+    }
+
+    /// Records the bonuses of the targets of the `bonus X = value;` statements.
+    ///
+    /// A nonterminal target `X` must be defined in the grammar.
+    /// Every occurrence of a literal target `L` is replaced by a generated nonterminal `B ::= L;`,
+    /// which gets the bonus instead.
+    fn apply_bonuses(&mut self, mut tokens: Vec<Token>) -> Result<Vec<Token>, CreateGrammarError> {
+        for (i, (target, value)) in std::mem::take(&mut self.bonuses).into_iter().enumerate() {
+            if target.kind == TokenKind::Literal {
+                let name = format!("{GENERATED_NONTERMINAL_PREFIX}bonus_{i}");
+                let key = literal_key(&target.text);
+                for token in tokens.iter_mut() {
+                    if token.kind == TokenKind::Literal && literal_key(&token.text) == key {
+                        *token = Token {
+                            kind: TokenKind::Identifier,
+                            text: name.clone(),
+                            origin: token.origin,
+                        };
+                    }
+                }
+                self.add_rule(&name, vec![target]);
+                self.nonterminal_bonuses.insert(name, value);
+                continue;
+            }
+            let defined = (0..tokens.len()).any(|i| {
+                tokens[i].is(TokenKind::Identifier, &target.text)
+                    && (i == 0 || tokens[i - 1].is_punctuation(";"))
+                    && tokens
+                        .get(i + 1)
+                        .is_some_and(|x| x.is_punctuation("::=") || x.is_punctuation("="))
+            });
+            if !defined {
+                return Err(self.preprocessing_error(&target, "Undefined nonterminal in bonus"));
+            }
+            self.nonterminal_bonuses.insert(target.text, value);
+        }
+        Ok(tokens)
+    }
+
+    /// Rewrites the weighted alternatives `A @w`, where `w` is a positive number.
+    ///
+    /// Each weighted alternative is moved into a generated nonterminal `W ::= A;`,
+    /// which gets the bonus `ln(w)` as [apply_bonuses](Self::apply_bonuses) does.
+    /// The generated rules are appended to the tokens so the later passes rewrite `A` as well.
+    /// An alternative weighted `1` is left unchanged, since its bonus is zero.
+    fn rewrite_weights(&mut self, tokens: Vec<Token>) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+        let mut rules = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            if !token.is_punctuation("@") {
+                output.push(token.clone());
+                i += 1;
+                continue;
+            }
+            let weight = tokens
+                .get(i + 1)
+                .filter(|x| x.kind == TokenKind::Number)
+                .and_then(|x| x.text.parse::<f32>().ok())
+                .filter(|x| x.is_finite() && *x > 0.0)
+                .ok_or_else(|| self.preprocessing_error(token, "Invalid weight"))?;
+            if !tokens.get(i + 2).is_some_and(|x| {
+                [";", "|", ")", "]", "}"]
+                    .iter()
+                    .any(|end| x.is_punctuation(end))
+            }) {
+                return Err(self.preprocessing_error(token, "Weight must end an alternative"));
+            }
+            let start = alternative_start(&output);
+            if start == output.len() {
+                return Err(self.preprocessing_error(token, "Weight must follow an alternative"));
+            }
+            i += 2;
+            self.changed = true;
+            let bonus = weight.ln();
+            if bonus == 0.0 {
+                continue;
+            }
+            let name = format!("{GENERATED_NONTERMINAL_PREFIX}weighted_{}", rules.len());
+            let mut rule = vec![Token::identifier(&name), Token::punctuation("::=")];
+            rule.extend(output.drain(start..));
+            rule.push(Token::punctuation(";"));
+            rules.push(rule);
+            self.nonterminal_bonuses.insert(name.clone(), bonus);
+            output.push(Token::identifier(name));
+        }
+        output.extend(rules.into_iter().flatten());
+        Ok(output)
+    }
+
+    /// Expands `X{m}`, `X{m,n}` and `X{m,}`.
+    ///
+    /// Rather than copying `X` up to `n` times, the expansion introduces doubling rules
+    /// `R0 ::= X; R1 ::= R0 R0; ...` so `X{m}` becomes the `Rk` for every bit `k` set in `m`,
+    /// and the optional tail `X{0,c}` is encoded in binary as well.
+    /// The generated grammar is unambiguous and its size is logarithmic in the bounds.
+    fn expand_counted_repetitions(
+        &mut self,
+        tokens: Vec<Token>,
+    ) -> Result<Vec<Token>, CreateGrammarError> {
+        let mut output: Vec<Token> = Vec::with_capacity(tokens.len());
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            let bounds = if token.is_punctuation("{") {
+                Self::parse_repetition_bounds(&tokens[i + 1..])
+            } else {
+                None
+            };
+            let (Some((min, max, len)), Some(start)) = (bounds, operand_start(&output)) else {
+                output.push(token.clone());
+                i += 1;
+                continue;
+            };
+            let parse = |x: &Token| {
+                x.text
+                    .parse::<u32>()
+                    .map_err(|_| self.preprocessing_error(x, "Invalid repetition bound"))
+            };
+            let min_value = parse(min)?;
+            let max_value = max.map(parse).transpose()?;
+            if max_value.is_some_and(|max| max < min_value) {
+                return Err(self.preprocessing_error(
+                    token,
+                    "Repetition upper bound is smaller than lower bound",
+                ));
+            }
+            let operand: Vec<Token> = output.drain(start..).collect();
+            let replacement = self.counted_repetition(operand, min_value, max_value);
+            output.extend(replacement);
+            self.changed = true;
+            i += 1 + len;
+        }
+        Ok(output)
+    }
+
+    /// Parses the `m}`, `m,}` or `m,n}` following a `{`.
+    ///
+    /// Returns the lower bound, the optional upper bound and the number of tokens consumed.
+    fn parse_repetition_bounds(tokens: &[Token]) -> Option<(&Token, Option<&Token>, usize)> {
+        let min = tokens.first().filter(|x| x.kind == TokenKind::Number)?;
+        match tokens.get(1..) {
+            Some([close, ..]) if close.is_punctuation("}") => Some((min, Some(min), 2)),
+            Some([comma, close, ..]) if comma.is_punctuation(",") && close.is_punctuation("}") => {
+                Some((min, None, 3))
+            }
+            Some([comma, max, close, ..])
+                if comma.is_punctuation(",")
+                    && max.kind == TokenKind::Number
+                    && close.is_punctuation("}") =>
+            {
+                Some((min, Some(max), 4))
+            }
+            _ => None,
+        }
+    }
+
+    fn counted_repetition(
+        &mut self,
+        operand: Vec<Token>,
+        min: u32,
+        max: Option<u32>,
+    ) -> Vec<Token> {
+        let key = render_tokens(&operand);
+        let id = match self.repeated_operands.get(&key) {
+            Some(&(id, _)) => id,
+            None => {
+                let id = self.repeated_operands.len();
+                self.repeated_operands.insert(key.clone(), (id, 0));
+                self.add_rule(&Self::doubling_rule_name(id, 0), operand);
+                id
+            }
+        };
+        let mut sequence: Vec<Token> = (0..u32::BITS)
+            .rev()
+            .filter(|k| min & (1 << k) != 0)
+            .map(|k| Token::identifier(self.doubling_rule(&key, k)))
+            .collect();
+        match max {
+            Some(max) if max > min => {
+                let upto = self.repetition_upto_rule(&key, id, max - min);
+                sequence.push(Token::identifier(upto));
+            }
+            Some(_) => {}
+            None => {
+                sequence.push(Token::identifier(Self::doubling_rule_name(id, 0)));
+                sequence.push(Token::punctuation("*"));
+            }
+        }
+        match sequence.len() {
+            0 => vec![Token::literal("''")],
+            1 => sequence,
+            _ => {
+                sequence.insert(0, Token::punctuation("("));
+                sequence.push(Token::punctuation(")"));
+                sequence
+            }
+        }
+    }
+
+    fn doubling_rule_name(id: usize, k: u32) -> String {
+        format!("{GENERATED_NONTERMINAL_PREFIX}repeat_{id}_{k}")
+    }
+
+    /// Returns the name of the rule that matches exactly `2^k` copies of the operand, generating it if necessary.
+    fn doubling_rule(&mut self, key: &str, k: u32) -> String {
+        let (id, generated) = self.repeated_operands[key];
+        for j in generated + 1..=k {
+            let previous = Self::doubling_rule_name(id, j - 1);
+            self.add_rule(
+                &Self::doubling_rule_name(id, j),
+                vec![
+                    Token::identifier(previous.clone()),
+                    Token::identifier(previous),
+                ],
+            );
+        }
+        if k > generated {
+            self.repeated_operands.insert(key.to_string(), (id, k));
+        }
+        Self::doubling_rule_name(id, k)
+    }
+
+    /// Returns the name of the rule that matches from zero to `count` copies of the operand, generating it if necessary.
+    fn repetition_upto_rule(&mut self, key: &str, id: usize, count: u32) -> String {
+        if let Some(name) = self.repetition_upto_rules.get(&(id, count)) {
+            return name.clone();
+        }
+        let name = format!("{GENERATED_NONTERMINAL_PREFIX}repeat_{id}_upto_{count}");
+        self.repetition_upto_rules.insert((id, count), name.clone());
+        // 2^k is the largest power of two not exceeding count.
+        let k = u32::BITS - 1 - count.leading_zeros();
+        if k == 0 {
+            let body = vec![
+                Token::punctuation("["),
+                Token::identifier(self.doubling_rule(key, 0)),
+                Token::punctuation("]"),
+            ];
+            self.add_rule(&name, body);
+            return name;
+        }
+        // Matches from zero to 2^k - 1 copies, one optional doubling rule per bit.
+        let mut body: Vec<Token> = Vec::new();
+        for j in (0..k).rev() {
+            body.push(Token::punctuation("["));
+            body.push(Token::identifier(self.doubling_rule(key, j)));
+            body.push(Token::punctuation("]"));
+        }
+        // Matches from 2^k to count copies.
+        body.push(Token::punctuation("|"));
+        body.push(Token::identifier(self.doubling_rule(key, k)));
+        let rest = count - (1 << k);
+        if rest > 0 {
+            let rest = self.repetition_upto_rule(key, id, rest);
+            body.push(Token::identifier(rest));
+        }
+        self.add_rule(&name, body);
+        name
+    }
+}
@@ -0,0 +1,801 @@
+//! The regex module that contains the finite state automata used to match regular expressions in the grammar.
+use std::collections::VecDeque;
+
+use ahash::{AHashMap, AHashSet};
+use kbnf_regex_automata::dfa::{dense, Automaton, StartKind};
+use kbnf_regex_automata::hybrid::dfa::{Builder, Cache, DFA};
+use kbnf_regex_automata::hybrid::{CacheError, LazyStateID};
+use kbnf_regex_automata::nfa::thompson::{self, Transition, NFA};
+use kbnf_regex_automata::util::look::Look;
+use kbnf_regex_automata::util::start;
+use kbnf_regex_automata::Anchored;
+use kbnf_syntax::semantic_error::SemanticError;
+
+use crate::grammar::CreateGrammarError;
+use crate::preprocessor::{PlaceholderLanguage, RegexPlaceholder};
+use crate::utils::{ByteSet, FsaStateStatus};
+
+/// The largest raw value a [LazyStateID] can take, tag bits included.
+pub(crate) const LAZY_STATE_ID_UPPER_BOUND: usize = (1 << (LazyStateID::MAX_BIT + 1)) - 1;
+
+/// The finite state automaton compiled from a regular expression in the grammar.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum FiniteStateAutomaton {
+    /// The dense DFA, whose states are all computed when the grammar is created.
+    Dfa(dense::DFA<Vec<u32>>),
+    /// The lazy DFA, whose states are computed on demand and stored in a cache.
+    LazyDfa(LazyDfa),
+}
+
+/// The configuration used to build a [LazyDfa].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LazyDfaConfig {
+    /// The capacity of the transition cache in bytes. `None` means the default capacity of the underlying lazy DFA.
+    pub cache_capacity: Option<usize>,
+    /// The maximum memory usage in bytes of the NFA compiled from the regex. `None` means no limit.
+    pub nfa_size_limit: Option<usize>,
+}
+
+impl LazyDfaConfig {
+    /// Returns the builder of the lazy DFAs with this configuration.
+    fn builder(&self) -> Builder {
+        // The cache is never cleared while it is in use, otherwise the state IDs stored in the engines would be invalidated.
+        // A full cache is instead rebuilt with the states the Earley items refer to, or removed by LazyDfaCaches::clear_full once no Earley item refers to its states.
+        let mut config = DFA::config().minimum_cache_clear_count(Some(0));
+        if let Some(capacity) = self.cache_capacity {
+            config = config.cache_capacity(capacity);
+        }
+        let mut builder = DFA::builder();
+        builder
+            .configure(config)
+            .thompson(thompson::Config::new().nfa_size_limit(self.nfa_size_limit));
+        builder
+    }
+
+    /// Builds a lazy DFA from the given regex pattern.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the regex is invalid, exceeds the configured limits
+    /// or its start states do not fit in the cache.
+    pub fn build(&self, pattern: &str) -> Result<LazyDfa, CreateGrammarError> {
+        let dfa = self.builder().build(pattern).map_err(|e| {
+            CreateGrammarError::SemanticError(Box::new(SemanticError::LazyDfaRegexBuildError(e)))
+        })?;
+        LazyDfa::new(dfa)
+    }
+
+    /// Builds a lazy DFA from the given regex pattern, which matches the language of its placeholder if it is one.
+    pub(crate) fn build_with_placeholders(
+        &self,
+        pattern: &str,
+        placeholders: &AHashMap<String, RegexPlaceholder>,
+    ) -> Result<LazyDfa, CreateGrammarError> {
+        let language = placeholders
+            .get(pattern)
+            .and_then(|x| x.language(placeholders));
+        let dfa = match language {
+            Some(PlaceholderLanguage::Difference(minuend, subtrahend)) => self
+                .builder()
+                .build_from_nfa(difference_nfa(&minuend, &subtrahend, self.nfa_size_limit)?),
+            Some(PlaceholderLanguage::Regex(regex)) => return self.build(regex),
+            None => return self.build(pattern),
+        }
+        .map_err(|e| {
+            CreateGrammarError::SemanticError(Box::new(SemanticError::LazyDfaRegexBuildError(e)))
+        })?;
+        LazyDfa::new(dfa)
+    }
+}
+
+/// Builds an NFA that matches the strings matched by the regex `minuend` but not by the regex `subtrahend`.
+///
+/// Both regexes are compiled into dense DFAs, and the reachable states of their product become the NFA states.
+/// Only the product states from which an accepting state is reachable are kept,
+/// so the NFA never leads into a dead end.
+///
+/// # Errors
+///
+/// Returns an error if either regex cannot be compiled or the NFA exceeds `size_limit`.
+pub(crate) fn difference_nfa(
+    minuend: &str,
+    subtrahend: &str,
+    size_limit: Option<usize>,
+) -> Result<NFA, CreateGrammarError> {
+    let build = |pattern: &str| {
+        dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .start_kind(StartKind::Anchored)
+                    .dfa_size_limit(size_limit),
+            )
+            .build(&format!(r"\A(?:{pattern})\z"))
+            .map_err(|e| CreateGrammarError::SemanticError(Box::new(e.into())))
+    };
+    let (a, b) = (build(minuend)?, build(subtrahend)?);
+    let start_config = start::Config::new().anchored(Anchored::Yes);
+    let start = (a.start_state(&start_config)?, b.start_state(&start_config)?);
+    let mut states = vec![start];
+    let mut ids = AHashMap::default();
+    ids.insert(start, 0);
+    let mut transitions: Vec<Vec<(u8, usize)>> = Vec::new();
+    while let Some(&(state_a, state_b)) = states.get(transitions.len()) {
+        let mut row = Vec::new();
+        for byte in 0..=u8::MAX {
+            let next_a = a.next_state(state_a, byte);
+            if a.is_dead_state(next_a) || a.is_quit_state(next_a) {
+                continue;
+            }
+            let next = (next_a, b.next_state(state_b, byte));
+            let id = *ids.entry(next).or_insert_with(|| {
+                states.push(next);
+                states.len() - 1
+            });
+            row.push((byte, id));
+        }
+        transitions.push(row);
+    }
+    let accepting: Vec<bool> = states
+        .iter()
+        .map(|&(state_a, state_b)| {
+            a.is_match_state(a.next_eoi_state(state_a))
+                && !b.is_match_state(b.next_eoi_state(state_b))
+        })
+        .collect();
+    let mut predecessors = vec![Vec::new(); states.len()];
+    for (id, row) in transitions.iter().enumerate() {
+        for &(_, next) in row {
+            predecessors[next].push(id);
+        }
+    }
+    let mut live = accepting.clone();
+    let mut stack: Vec<usize> = (0..states.len()).filter(|&x| accepting[x]).collect();
+    while let Some(id) = stack.pop() {
+        for &previous in &predecessors[id] {
+            if !live[previous] {
+                live[previous] = true;
+                stack.push(previous);
+            }
+        }
+    }
+    product_nfa(&transitions, &accepting, &live, size_limit)
+        .map_err(CreateGrammarError::NfaBuildError)
+}
+
+/// The regex that matches any fraction of a decimal number, including none.
+const ANY_FRACTION: &str = r"(?:\.[0-9]+)?";
+
+/// A decimal number in its canonical form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Decimal {
+    /// Whether the number is negative. Zero is never negative.
+    negative: bool,
+    /// The integer digits without leading zeros, `"0"` for zero.
+    integer: String,
+    /// The fraction digits without trailing zeros.
+    fraction: String,
+}
+
+impl Decimal {
+    /// Parses a decimal number with an optional minus sign. Fractions are only allowed if `float` is true.
+    fn parse(value: &str, float: bool) -> Option<Self> {
+        let (negative, magnitude) = match value.strip_prefix('-') {
+            Some(magnitude) => (true, magnitude),
+            None => (false, value),
+        };
+        let (integer, fraction) = match magnitude.split_once('.') {
+            Some((integer, fraction)) if float && !fraction.is_empty() => (integer, fraction),
+            Some(_) => return None,
+            None => (magnitude, ""),
+        };
+        if integer.is_empty()
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|x| x.is_ascii_digit())
+        {
+            return None;
+        }
+        let integer = match integer.trim_start_matches('0') {
+            "" => "0",
+            x => x,
+        };
+        let fraction = fraction.trim_end_matches('0');
+        Some(Self {
+            negative: negative && (integer != "0" || !fraction.is_empty()),
+            integer: integer.to_string(),
+            fraction: fraction.to_string(),
+        })
+    }
+
+    fn is_zero(&self) -> bool {
+        self.integer == "0" && self.fraction.is_empty()
+    }
+
+    fn cmp_magnitude(&self, other: &Self) -> std::cmp::Ordering {
+        self.integer
+            .len()
+            .cmp(&other.integer.len())
+            .then_with(|| self.integer.cmp(&other.integer))
+            .then_with(|| self.fraction.cmp(&other.fraction))
+    }
+
+    /// Builds the regex that matches the magnitudes not greater than this number's.
+    fn at_most(&self, float: bool) -> String {
+        let fraction = if float { ANY_FRACTION } else { "" };
+        let mut alternatives: Vec<String> = integers_less_than(&self.integer)
+            .into_iter()
+            .map(|x| format!("{x}{fraction}"))
+            .collect();
+        alternatives.push(if float {
+            format!(
+                r"{}(?:\.{})?",
+                self.integer,
+                fractions_at_most(&self.fraction)
+            )
+        } else {
+            self.integer.clone()
+        });
+        alternation(&alternatives)
+    }
+
+    /// Builds the regex that matches the magnitudes less than this number's.
+    fn less_than(&self, float: bool) -> String {
+        let fraction = if float { ANY_FRACTION } else { "" };
+        let mut alternatives: Vec<String> = integers_less_than(&self.integer)
+            .into_iter()
+            .map(|x| format!("{x}{fraction}"))
+            .collect();
+        if let Some(fractions) = fractions_less_than(&self.fraction) {
+            alternatives.push(format!(r"{}(?:\.{fractions})?", self.integer));
+        }
+        alternation(&alternatives)
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.cmp_magnitude(other),
+            (true, true) => other.cmp_magnitude(self),
+            (negative, _) => other.negative.cmp(&negative),
+        }
+    }
+}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.integer)?;
+        if !self.fraction.is_empty() {
+            write!(f, ".{}", self.fraction)?;
+        }
+        Ok(())
+    }
+}
+
+/// A numeric range symbol `#int[min,max]` or `#float[min,max]`, both bounds inclusive.
+///
+/// It matches the numbers within the bounds in their plain decimal forms:
+/// an optional minus sign, an integer part without leading zeros and, for floats, an optional fraction.
+/// Zero is never signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NumericRange {
+    /// Whether fractions are allowed.
+    pub(crate) float: bool,
+    pub(crate) min: Decimal,
+    pub(crate) max: Decimal,
+}
+
+impl NumericRange {
+    /// Parses the bounds of a numeric range, e.g. `0,65535` for `#int[0,65535]`.
+    ///
+    /// Returns `None` if a bound is invalid or `min` is greater than `max`.
+    pub(crate) fn parse(float: bool, bounds: &str) -> Option<Self> {
+        let (min, max) = bounds.split_once(',')?;
+        let min = Decimal::parse(min.trim(), float)?;
+        let max = Decimal::parse(max.trim(), float)?;
+        (min <= max).then_some(Self { float, min, max })
+    }
+
+    /// Get the regexes whose difference matches the numbers within the range.
+    ///
+    /// A single regex would need the intersection of an upper bound and a lower bound,
+    /// so the range is the numbers up to `max` minus the numbers below `min` instead.
+    pub(crate) fn difference(&self) -> (String, String) {
+        let zero = if self.float { r"0(?:\.0+)?" } else { "0" };
+        let mut minuend = Vec::new();
+        let mut subtrahend = Vec::new();
+        if !self.max.negative {
+            minuend.push(self.max.at_most(self.float));
+            if !self.min.negative && !self.min.is_zero() {
+                subtrahend.push(self.min.less_than(self.float));
+            }
+        }
+        if self.min.negative {
+            minuend.push(format!("-{}", self.min.at_most(self.float)));
+            subtrahend.push(format!("-{zero}"));
+            if self.max.negative {
+                subtrahend.push(format!("-{}", self.max.less_than(self.float)));
+            }
+        }
+        (alternation(&minuend), alternation(&subtrahend))
+    }
+}
+
+impl std::fmt::Display for NumericRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = if self.float { "float" } else { "int" };
+        write!(f, "#{kind}[{},{}]", self.min, self.max)
+    }
+}
+
+/// Joins the regexes into an alternation that matches nothing if there is no regex.
+fn alternation(regexes: &[String]) -> String {
+    if regexes.is_empty() {
+        r"[^\s\S]".to_string()
+    } else {
+        format!("(?:{})", regexes.join("|"))
+    }
+}
+
+/// Builds the regex of the digits from `min` to `max`.
+fn digit_class(min: u8, max: u8) -> String {
+    if min == max {
+        (min as char).to_string()
+    } else {
+        format!("[{}-{}]", min as char, max as char)
+    }
+}
+
+/// Builds the regexes that together match the integers without leading zeros less than `integer`.
+fn integers_less_than(integer: &str) -> Vec<String> {
+    let digits = integer.as_bytes();
+    let len = digits.len();
+    let mut alternatives = Vec::new();
+    if len >= 2 {
+        alternatives.push("0".to_string());
+        alternatives.push(format!("[1-9][0-9]{{0,{}}}", len - 2));
+    }
+    for (i, &digit) in digits.iter().enumerate() {
+        let min = if i == 0 && len >= 2 { b'1' } else { b'0' };
+        if digit > min {
+            let rest = len - i - 1;
+            let rest = if rest == 0 {
+                String::new()
+            } else {
+                format!("[0-9]{{{rest}}}")
+            };
+            alternatives.push(format!(
+                "{}{}{rest}",
+                &integer[..i],
+                digit_class(min, digit - 1)
+            ));
+        }
+    }
+    alternatives
+}
+
+/// Builds the regex that matches the fraction digits not greater than `fraction` when both follow a decimal point.
+fn fractions_at_most(fraction: &str) -> String {
+    let Some(&first) = fraction.as_bytes().first() else {
+        return "0+".to_string();
+    };
+    let mut alternatives = Vec::new();
+    if first > b'0' {
+        alternatives.push(format!("{}[0-9]*", digit_class(b'0', first - 1)));
+    }
+    alternatives.push(format!(
+        "{}(?:{})?",
+        first as char,
+        fractions_at_most(&fraction[1..])
+    ));
+    alternation(&alternatives)
+}
+
+/// Builds the regex that matches the fraction digits less than `fraction` when both follow a decimal point.
+///
+/// Returns `None` if `fraction` is zero, which no fraction is less than.
+fn fractions_less_than(fraction: &str) -> Option<String> {
+    let &first = fraction.as_bytes().first()?;
+    let mut alternatives = Vec::new();
+    if first > b'0' {
+        alternatives.push(format!("{}[0-9]*", digit_class(b'0', first - 1)));
+    }
+    if let Some(rest) = fractions_less_than(&fraction[1..]) {
+        alternatives.push(format!("{}(?:{rest})?", first as char));
+    }
+    Some(alternation(&alternatives))
+}
+
+/// Builds an NFA whose states are the live product states, each with a transition for every byte.
+fn product_nfa(
+    transitions: &[Vec<(u8, usize)>],
+    accepting: &[bool],
+    live: &[bool],
+    size_limit: Option<usize>,
+) -> Result<NFA, Box<thompson::BuildError>> {
+    let mut builder = thompson::Builder::new();
+    builder.set_size_limit(size_limit)?;
+    builder.start_pattern()?;
+    let mut entries = Vec::with_capacity(transitions.len());
+    for _ in transitions {
+        entries.push(builder.add_union(Vec::new())?);
+    }
+    let matched = builder.add_match()?;
+    let end = builder.add_look(matched, Look::End)?;
+    for (id, row) in transitions.iter().enumerate() {
+        if !live[id] {
+            continue;
+        }
+        let mut ranges: Vec<Transition> = Vec::new();
+        for &(byte, next) in row.iter().filter(|&&(_, next)| live[next]) {
+            match ranges.last_mut() {
+                Some(last)
+                    if last.end as usize + 1 == byte as usize && last.next == entries[next] =>
+                {
+                    last.end = byte;
+                }
+                _ => ranges.push(Transition {
+                    start: byte,
+                    end: byte,
+                    next: entries[next],
+                }),
+            }
+        }
+        if !ranges.is_empty() {
+            let sparse = builder.add_sparse(ranges)?;
+            builder.patch(entries[id], sparse)?;
+        }
+        if accepting[id] {
+            builder.patch(entries[id], end)?;
+        }
+    }
+    builder.finish_pattern(entries[0])?;
+    Ok(builder.build(entries[0], entries[0])?)
+}
+
+/// A lazy DFA, whose states are computed on demand in a transition cache.
+///
+/// The lazy DFA itself is never modified, so it is shared by all the engines created from the same grammar,
+/// while every engine computes the states it visits in caches of its own.
+/// The start states are computed first in every cache, so they get the same IDs in all of them.
+#[derive(Debug, Clone)]
+pub struct LazyDfa {
+    dfa: DFA,
+    anchored_start: LazyStateID,
+    unanchored_start: LazyStateID,
+}
+
+impl LazyDfa {
+    /// Creates a new lazy DFA and computes its start states along with their transitions.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the start states cannot be computed or the cache is too small to hold them.
+    pub fn new(dfa: DFA) -> Result<Self, CreateGrammarError> {
+        let mut cache = dfa.create_cache();
+        let (anchored_start, unanchored_start) = Self::cache_start_states(&dfa, &mut cache)?;
+        Ok(Self {
+            dfa,
+            anchored_start,
+            unanchored_start,
+        })
+    }
+    /// Computes the anchored and unanchored start states along with their transitions.
+    ///
+    /// The states are added to an empty cache in the same order every time, so they always get the same IDs.
+    fn cache_start_states(
+        dfa: &DFA,
+        cache: &mut Cache,
+    ) -> Result<(LazyStateID, LazyStateID), CreateGrammarError> {
+        let anchored_start =
+            dfa.start_state(cache, &start::Config::new().anchored(Anchored::Yes))?;
+        let unanchored_start =
+            dfa.start_state(cache, &start::Config::new().anchored(Anchored::No))?;
+        for start in [anchored_start, unanchored_start] {
+            for byte in 0..=u8::MAX {
+                let next = dfa.next_state(cache, start, byte)?;
+                dfa.next_eoi_state(cache, next)?;
+            }
+        }
+        Ok((anchored_start, unanchored_start))
+    }
+    /// Creates a new transition cache that holds the start states.
+    pub(crate) fn create_cache(&self) -> LazyDfaCache {
+        let mut cache = self.dfa.create_cache();
+        // The start states fitted in a cache when the lazy DFA was created, so they fit again.
+        let start_states = Self::cache_start_states(&self.dfa, &mut cache).ok();
+        debug_assert_eq!(
+            start_states,
+            Some((self.anchored_start, self.unanchored_start))
+        );
+        LazyDfaCache {
+            cache,
+            full: false,
+            shortest_match_lengths: AHashMap::default(),
+        }
+    }
+    /// Creates a new transition cache that holds the start states and `states` of `cache`,
+    /// which is a cache of this lazy DFA.
+    ///
+    /// Every state is computed again from a start state through the fewest transitions `cache` holds,
+    /// so the new cache only holds the states on these paths.
+    ///
+    /// # Returns
+    ///
+    /// The new cache along with the IDs of `states` in it, in the same order,
+    /// or `None` if a state cannot be reached or the states do not fit in the new cache.
+    pub(crate) fn rebuild_cache(
+        &self,
+        cache: &LazyDfaCache,
+        states: &[LazyStateID],
+    ) -> Option<(LazyDfaCache, Vec<LazyStateID>)> {
+        // The transitions are followed in a copy since a transition that is not cached may still add a state.
+        let mut old_cache = cache.cache.clone();
+        let mut remaining: AHashSet<LazyStateID> = states.iter().copied().collect();
+        // The state and the byte each visited state is reached from.
+        let mut parents: AHashMap<LazyStateID, Option<(LazyStateID, u8)>> = AHashMap::default();
+        let mut queue = VecDeque::new();
+        for start in [self.anchored_start, self.unanchored_start] {
+            parents.insert(start, None);
+            remaining.remove(&start);
+            queue.push_back(start);
+        }
+        while let Some(state) = queue.pop_front() {
+            if remaining.is_empty() {
+                break;
+            }
+            if state.is_dead() || state.is_quit() {
+                continue;
+            }
+            for byte in 0..=u8::MAX {
+                let Ok(next) = self.dfa.next_state(&mut old_cache, state, byte) else {
+                    continue;
+                };
+                if next.is_unknown() || parents.contains_key(&next) {
+                    continue;
+                }
+                parents.insert(next, Some((state, byte)));
+                remaining.remove(&next);
+                queue.push_back(next);
+            }
+        }
+        if !remaining.is_empty() {
+            return None;
+        }
+        let mut new_cache = self.create_cache();
+        let mut new_states = Vec::with_capacity(states.len());
+        let mut path = Vec::new();
+        for &state in states {
+            let mut previous = state;
+            path.clear();
+            while let Some((parent, byte)) = parents[&previous] {
+                path.push(byte);
+                previous = parent;
+            }
+            for &byte in path.iter().rev() {
+                previous = self.next_state(&mut new_cache, previous, byte).ok()?.0;
+            }
+            new_states.push(previous);
+        }
+        Some((new_cache, new_states))
+    }
+    /// Get the underlying lazy DFA.
+    pub fn dfa(&self) -> &DFA {
+        &self.dfa
+    }
+    #[inline]
+    pub(crate) fn anchored_start_state(&self) -> LazyStateID {
+        self.anchored_start
+    }
+    #[inline]
+    pub(crate) fn unanchored_start_state(&self) -> LazyStateID {
+        self.unanchored_start
+    }
+    /// Transitions from `state` with `byte` and returns the next state along with its status.
+    ///
+    /// `cache` must be a cache of this lazy DFA that holds `state`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [CacheError] if the next state is not cached and the cache is full.
+    #[inline]
+    pub(crate) fn next_state(
+        &self,
+        cache: &mut LazyDfaCache,
+        state: LazyStateID,
+        byte: u8,
+    ) -> Result<(LazyStateID, FsaStateStatus), CacheError> {
+        let next = self
+            .dfa
+            .next_state(&mut cache.cache, state, byte)
+            .inspect_err(|_| cache.full = true)?;
+        Ok((next, self.status(cache, next)?))
+    }
+    /// Returns the status of `state`, which is rejected if it cannot be determined because the cache is full.
+    pub(crate) fn state_status(
+        &self,
+        cache: &mut LazyDfaCache,
+        state: LazyStateID,
+    ) -> FsaStateStatus {
+        self.status(cache, state).unwrap_or(FsaStateStatus::Reject)
+    }
+    /// Returns the bytes that can be accepted from `state`.
+    ///
+    /// For a complement regex, bytes leading to a match state are excluded.
+    /// The bytes whose transitions do not fit in the cache are included,
+    /// so that scanning them reports the full cache rather than silently rejecting them.
+    pub(crate) fn first_bytes(
+        &self,
+        cache: &mut LazyDfaCache,
+        state: LazyStateID,
+        complement: bool,
+    ) -> ByteSet {
+        let mut set = ByteSet::with_capacity(256);
+        for byte in 0..=u8::MAX {
+            match self.next_state(cache, state, byte) {
+                Ok((_, FsaStateStatus::Accept)) if !complement => set.insert(byte as usize),
+                Ok((_, FsaStateStatus::InProgress)) | Err(_) => set.insert(byte as usize),
+                _ => {}
+            }
+        }
+        set
+    }
+    /// Returns the fewest bytes needed to reach a match state from `state`, which is at least one byte.
+    ///
+    /// Returns [usize::MAX] if no match state is reachable.
+    /// Every state visited by the search is added to the cache, and the result is memoized in the cache.
+    /// A transition that does not fit in the cache is assumed to reach a match state,
+    /// so the result never exceeds the true length.
+    pub(crate) fn shortest_match_length(
+        &self,
+        cache: &mut LazyDfaCache,
+        state: LazyStateID,
+    ) -> usize {
+        if let Some(&length) = cache.shortest_match_lengths.get(&state) {
+            return length;
+        }
+        let length = self.search_shortest_match_length(cache, state);
+        cache.shortest_match_lengths.insert(state, length);
+        length
+    }
+    fn search_shortest_match_length(&self, cache: &mut LazyDfaCache, state: LazyStateID) -> usize {
+        let representatives: Vec<u8> = self
+            .dfa
+            .byte_classes()
+            .representatives(..)
+            .filter_map(|x| x.as_u8())
+            .collect();
+        let mut visited = AHashSet::default();
+        visited.insert(state);
+        let mut queue = VecDeque::from([(state, 0usize)]);
+        while let Some((state, length)) = queue.pop_front() {
+            for byte in representatives.iter().copied() {
+                match self.next_state(cache, state, byte) {
+                    Ok((_, FsaStateStatus::Accept)) | Err(_) => return length + 1,
+                    Ok((next, FsaStateStatus::InProgress)) if visited.insert(next) => {
+                        queue.push_back((next, length + 1));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        usize::MAX
+    }
+    /// Get the raw value of `state`, tag bits included.
+    #[inline]
+    pub(crate) fn raw_state_id(state: LazyStateID) -> usize {
+        // SAFETY: LazyStateID is a u32 due to #[repr(transparent)] attribute
+        let id: u32 = unsafe { std::mem::transmute(state) };
+        id as usize
+    }
+    #[inline]
+    fn status(
+        &self,
+        cache: &mut LazyDfaCache,
+        state: LazyStateID,
+    ) -> Result<FsaStateStatus, CacheError> {
+        if state.is_dead() || state.is_quit() {
+            return Ok(FsaStateStatus::Reject);
+        }
+        let eoi = self
+            .dfa
+            .next_eoi_state(&mut cache.cache, state)
+            .inspect_err(|_| cache.full = true)?;
+        Ok(if eoi.is_match() {
+            FsaStateStatus::Accept
+        } else {
+            FsaStateStatus::InProgress
+        })
+    }
+}
+
+/// The transition cache of a [LazyDfa].
+///
+/// When the cache is full, transitions into states that are not cached yet fail with a [CacheError].
+#[derive(Debug, Clone)]
+pub(crate) struct LazyDfaCache {
+    cache: Cache,
+    /// Whether a transition has failed because the cache is full since the cache was created.
+    full: bool,
+    /// The memoized results of [LazyDfa::shortest_match_length], keyed by state.
+    shortest_match_lengths: AHashMap<LazyStateID, usize>,
+}
+
+/// The transition caches of the lazy DFAs of a grammar, owned by an engine and indexed by regex ID.
+///
+/// A cache is created when its lazy DFA is first used.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LazyDfaCaches {
+    caches: Vec<Option<LazyDfaCache>>,
+}
+
+impl LazyDfaCaches {
+    /// Get the cache of `dfa`, which is the lazy DFA of the regex `regex_id`.
+    #[inline]
+    pub(crate) fn get(&mut self, regex_id: usize, dfa: &LazyDfa) -> &mut LazyDfaCache {
+        if regex_id >= self.caches.len() {
+            self.caches.resize_with(regex_id + 1, || None);
+        }
+        self.caches[regex_id].get_or_insert_with(|| dfa.create_cache())
+    }
+    /// Get a copy of the cache of `dfa`, which is the lazy DFA of the regex `regex_id`, without creating it in place.
+    pub(crate) fn get_cloned(&self, regex_id: usize, dfa: &LazyDfa) -> LazyDfaCache {
+        match self.caches.get(regex_id) {
+            Some(Some(cache)) => cache.clone(),
+            _ => dfa.create_cache(),
+        }
+    }
+    /// Removes the cache of the regex `regex_id`, e.g. when its lazy DFA is replaced.
+    pub(crate) fn remove(&mut self, regex_id: usize) {
+        if let Some(cache) = self.caches.get_mut(regex_id) {
+            *cache = None;
+        }
+    }
+    /// Get the IDs of the regexes whose caches have failed a transition because they are full.
+    pub(crate) fn full_regex_ids(&self) -> Vec<usize> {
+        self.caches
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.as_ref().is_some_and(|x| x.full))
+            .map(|(regex_id, _)| regex_id)
+            .collect()
+    }
+    /// Get the cache of the regex `regex_id` if it is created.
+    pub(crate) fn get_created(&self, regex_id: usize) -> Option<&LazyDfaCache> {
+        self.caches.get(regex_id).and_then(Option::as_ref)
+    }
+    /// Replaces the cache of the regex `regex_id`.
+    pub(crate) fn replace(&mut self, regex_id: usize, cache: LazyDfaCache) {
+        self.caches[regex_id] = Some(cache);
+    }
+    /// Removes the caches in which a transition has failed because they are full.
+    ///
+    /// They are created again with only the start states, which keep their IDs,
+    /// so every other state ID of the removed caches is invalidated.
+    /// It must only be called when no Earley item refers to one.
+    ///
+    /// # Returns
+    ///
+    /// Whether any cache is removed.
+    pub(crate) fn clear_full(&mut self) -> bool {
+        let mut cleared = false;
+        for cache in self.caches.iter_mut() {
+            if cache.as_ref().is_some_and(|x| x.full) {
+                *cache = None;
+                cleared = true;
+            }
+        }
+        cleared
+    }
+}
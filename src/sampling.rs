@@ -0,0 +1,220 @@
+//! The sampling module that samples tokens from the logits of a language model under the constraints of an engine.
+use crate::engine_like::{
+    AcceptTokenError, AcceptTokenResult, ComputeAllowedTokenIdsError, EngineLike,
+};
+use crate::generator::Rng;
+
+/// The configuration of a [Sampler].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SamplerConfig {
+    /// The seed of the random number generator.
+    /// The same seed always samples the same tokens from the same logits and engines in the same state.
+    pub seed: u64,
+    /// The temperature that divides the logits before the softmax.
+    /// `0.0` means greedy decoding, i.e. the allowed token with the largest logit is always chosen.
+    /// The default is 1.0.
+    pub temperature: f32,
+    /// Only the `top_k` allowed tokens with the largest logits are sampled.
+    /// `None` means no limit.
+    /// The default is `None`.
+    pub top_k: Option<usize>,
+    /// Only the smallest set of allowed tokens with the largest logits whose cumulative probability
+    /// reaches `top_p` is sampled.
+    /// The default is 1.0, which means no limit.
+    pub top_p: f32,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            temperature: 1.0,
+            top_k: None,
+            top_p: 1.0,
+        }
+    }
+}
+
+/// The error type for errors in token sampling.
+#[derive(Debug, thiserror::Error, Clone, PartialEq)]
+pub enum SampleError {
+    #[error("The engine is finished, so no more tokens can be sampled.")]
+    /// The engine is finished, as defined by its grammar.
+    Finished,
+    #[error("The engine allows no token before the grammar finishes.")]
+    /// No token is allowed while the engine is not finished.
+    DeadEnd,
+    #[error("The engine reaches its output limit before the grammar finishes.")]
+    /// The engine has output [EngineConfig::max_output_tokens](crate::engine::EngineConfig::max_output_tokens) tokens without finishing.
+    OutputLimitReached,
+    #[error("A lazy DFA cache of the engine is too small to compute the allowed tokens.")]
+    /// A transition of a lazy DFA does not fit in its cache, so the allowed tokens cannot be computed.
+    LazyDfaCacheFull,
+    #[error("Every allowed token has a logit that is not finite.")]
+    /// Every allowed token has a logit of negative infinity, positive infinity or NaN.
+    NoCandidate,
+    #[error("The logits are of length {0}, which is smaller than the vocabulary size {1}.")]
+    /// The logits array is shorter than the vocabulary size.
+    InvalidLogitsLength(usize, usize),
+    #[error("The configuration is invalid: {0}")]
+    /// The temperature is negative or not finite, `top_k` is zero or `top_p` is not in `(0, 1]`.
+    InvalidConfig(String),
+    #[error("The engine fails to accept the sampled token {0}: {1}")]
+    /// The engine fails to accept a token it allows, which indicates a bug in the engine.
+    /// Contains the sampled token id and the error.
+    AcceptTokenError(u32, AcceptTokenError),
+}
+
+/// A seedable sampler that chooses a token from the logits of a language model and accepts it into an [EngineLike].
+///
+/// The sampler replaces the usual "mask, softmax, temperature, top-k/top-p, sample, accept" loop
+/// around [EngineLike::update_logits]. It never modifies the logits and only visits the allowed token ids,
+/// so its cost does not depend on the vocabulary size when few tokens are allowed.
+/// The bonuses of the grammar are added to the logits of the allowed tokens as [EngineLike::mask_logits] does.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    config: SamplerConfig,
+    rng: Rng,
+    /// The allowed token ids along with their logits, reused across samplings.
+    candidates: Vec<(u32, f64)>,
+}
+
+impl Sampler {
+    /// Create a new [Sampler].
+    pub fn new(config: SamplerConfig) -> Self {
+        Self {
+            rng: Rng::new(config.seed),
+            config,
+            candidates: Vec::new(),
+        }
+    }
+
+    /// Sample a token from the logits and accept it into the engine.
+    ///
+    /// The allowed token ids are computed from the current state of the engine,
+    /// so the engine should already contain the prompt and the previously sampled tokens.
+    /// Allowed tokens whose logits are not finite are never sampled.
+    ///
+    /// # Arguments
+    ///
+    /// * `engine` - The engine that constrains the sampling.
+    /// * `logits` - The raw logits of the language model, indexed by token id.
+    ///   It may be longer than the vocabulary size, in which case the extra logits are ignored.
+    ///
+    /// # Returns
+    ///
+    /// The sampled token id and the result of accepting it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [SampleError] if the engine is finished, runs into a dead end or reaches its output limit, no allowed token can be sampled,
+    /// the logits are too short or the configuration is invalid. The engine is not updated in these cases.
+    pub fn sample(
+        &mut self,
+        engine: &mut impl EngineLike,
+        logits: &[f32],
+    ) -> Result<(u32, AcceptTokenResult), SampleError> {
+        self.validate_config()?;
+        if engine.is_finished() {
+            return Err(SampleError::Finished);
+        }
+        let vocab_size = engine.vocab().vocab_size();
+        if logits.len() < vocab_size {
+            return Err(SampleError::InvalidLogitsLength(logits.len(), vocab_size));
+        }
+        engine
+            .try_compute_allowed_token_ids()
+            .map_err(|e| match e {
+                ComputeAllowedTokenIdsError::DeadEnd => SampleError::DeadEnd,
+                ComputeAllowedTokenIdsError::OutputLimitReached => SampleError::OutputLimitReached,
+                ComputeAllowedTokenIdsError::LazyDfaCacheFull => SampleError::LazyDfaCacheFull,
+            })?;
+        let token_id = self.choose(&*engine, logits)?;
+        let result = engine
+            .try_accept_new_token(token_id)
+            .map_err(|e| SampleError::AcceptTokenError(token_id, e))?;
+        Ok((token_id, result))
+    }
+
+    fn validate_config(&self) -> Result<(), SampleError> {
+        let config = &self.config;
+        if !config.temperature.is_finite() || config.temperature < 0.0 {
+            return Err(SampleError::InvalidConfig(
+                "the temperature must be finite and non-negative".to_string(),
+            ));
+        }
+        if config.top_k == Some(0) {
+            return Err(SampleError::InvalidConfig(
+                "top_k must be positive".to_string(),
+            ));
+        }
+        if !(config.top_p > 0.0 && config.top_p <= 1.0) {
+            return Err(SampleError::InvalidConfig(
+                "top_p must be in (0, 1]".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn choose(&mut self, engine: &impl EngineLike, logits: &[f32]) -> Result<u32, SampleError> {
+        let candidates = &mut self.candidates;
+        candidates.clear();
+        let mut bonuses = engine
+            .token_bonuses_from_last_computation()
+            .iter()
+            .peekable();
+        for token_id in engine.allowed_token_ids_from_last_computation().ones() {
+            let mut logit = logits[token_id];
+            if let Some(&(_, bonus)) = bonuses.next_if(|(x, _)| *x == token_id) {
+                logit += bonus;
+            }
+            if logit.is_finite() {
+                candidates.push((token_id as u32, logit as f64));
+            }
+        }
+        if candidates.is_empty() {
+            return Err(SampleError::NoCandidate);
+        }
+        // The ties are broken by the smallest token id.
+        let descending = |a: &(u32, f64), b: &(u32, f64)| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0));
+        if self.config.temperature == 0.0 {
+            return Ok(candidates.iter().min_by(|a, b| descending(a, b)).unwrap().0);
+        }
+        if let Some(top_k) = self.config.top_k.filter(|x| *x < candidates.len()) {
+            candidates.select_nth_unstable_by(top_k - 1, descending);
+            candidates.truncate(top_k);
+        }
+        let max = candidates
+            .iter()
+            .map(|x| x.1)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let temperature = self.config.temperature as f64;
+        for candidate in candidates.iter_mut() {
+            candidate.1 = ((candidate.1 - max) / temperature).exp();
+        }
+        let mut total: f64 = candidates.iter().map(|x| x.1).sum();
+        if self.config.top_p < 1.0 {
+            candidates.sort_unstable_by(descending);
+            let threshold = self.config.top_p as f64 * total;
+            let mut cumulative = 0.0;
+            let kept = candidates
+                .iter()
+                .position(|x| {
+                    cumulative += x.1;
+                    cumulative >= threshold
+                })
+                .map_or(candidates.len(), |x| x + 1);
+            candidates.truncate(kept);
+            total = candidates.iter().map(|x| x.1).sum();
+        }
+        let mut target = self.rng.unit() * total;
+        for &(token_id, weight) in candidates.iter() {
+            if target < weight {
+                return Ok(token_id);
+            }
+            target -= weight;
+        }
+        // Only reachable due to rounding errors.
+        Ok(candidates.last().unwrap().0)
+    }
+}
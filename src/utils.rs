@@ -1,14 +1,27 @@
 //! Utility functions for the library.
 use ahash::{AHashMap, AHashSet};
 use fixedbitset_stack::on_stack::{get_nblock, FixedBitSet};
+use general_sam::{BTreeTransTable, GeneralSam, Trie};
 use kbnf_regex_automata::dfa::Automaton;
 use kbnf_regex_automata::util::primitives::StateID;
-use kbnf_syntax::regex::FiniteStateAutomaton;
+use kbnf_syntax::node::{Alternation, NodeWithID, OperatorFlattenedNode, Rhs};
+use kbnf_syntax::regex::{FiniteStateAutomaton, FiniteStateAutomatonConfig};
 use kbnf_syntax::simplified_grammar::SimplifiedGrammar;
+use kbnf_syntax::validated_grammar::ValidatedGrammar;
 use nom::error::VerboseError;
+use num::traits::AsPrimitive;
+use num::PrimInt;
+use string_interner::backend::StringBackend;
+use string_interner::symbol::SymbolU32;
+use string_interner::{StringInterner, Symbol};
 
 use crate::config::InternalConfig;
-use crate::grammar::CreateGrammarError;
+use crate::diagnostic;
+use crate::engine_like::{MaskLogitsError, WriteBufferError};
+use crate::grammar::{CreateGrammarError, GrammarMetadata, IntersectionNonterminals};
+use crate::preprocessor::{self, PlaceholderLanguage, RegexPlaceholder, SubstringsDocuments};
+use crate::regex;
+use crate::regex::LazyDfaConfig;
 
 pub(crate) type ByteSet = FixedBitSet<{ get_nblock(u8::MAX as usize) }>;
 #[derive(Debug, Clone, Copy, PartialEq, Eq, strum::Display)]
@@ -18,11 +31,26 @@ pub(crate) enum FsaStateStatus {
     InProgress,
 }
 /// Helper function to construct a simplified grammar from an KBNF grammar string.
+///
+/// The metadata of the grammar, e.g. the bonuses, is discarded.
+/// Use [construct_kbnf_syntax_grammar_with_metadata] to keep it.
 pub fn construct_kbnf_syntax_grammar(
     input: &str,
     config: InternalConfig,
 ) -> Result<SimplifiedGrammar, CreateGrammarError> {
-    let grammar = kbnf_syntax::get_grammar(input).map_err(|e| match e {
+    construct_kbnf_syntax_grammar_with_metadata(input, config).map(|(grammar, _)| grammar)
+}
+/// Helper function to construct a simplified grammar and its metadata from an KBNF grammar string.
+///
+/// The metadata should be passed to [Grammar::new_with_metadata](crate::grammar::Grammar::new_with_metadata).
+pub fn construct_kbnf_syntax_grammar_with_metadata(
+    input: &str,
+    config: InternalConfig,
+) -> Result<(SimplifiedGrammar, GrammarMetadata), CreateGrammarError> {
+    let source = input;
+    let input = preprocessor::preprocess(source, &config)
+        .map_err(|e| diagnostic::locate_error(e, source, None))?;
+    let grammar = kbnf_syntax::get_grammar(&input.text).map_err(|e| match e {
         nom::Err::Error(e) => nom::Err::Error(VerboseError {
             errors: e
                 .errors
@@ -38,15 +66,541 @@ pub fn construct_kbnf_syntax_grammar(
                 .collect::<Vec<_>>(),
         }),
         nom::Err::Incomplete(e) => nom::Err::Incomplete(e),
-    })?;
-    let grammar = grammar.validate_grammar(&config.start_nonterminal, config.regex_config)?;
-    let grammar = grammar.simplify_grammar(
+    });
+    let grammar = grammar.map_err(|e| diagnostic::locate_error(e.into(), source, Some(&input)))?;
+    let grammar = match config.lazy_dfa_config {
+        None => grammar
+            .validate_grammar(&config.start_nonterminal, config.regex_config.clone())
+            .map_err(CreateGrammarError::from)
+            .and_then(|mut grammar| {
+                construct_placeholders(&mut grammar, config.regex_config, &input.placeholders)?;
+                Ok(grammar)
+            }),
+        Some(lazy_dfa_config) => validate_grammar_with_lazy_dfa(
+            grammar,
+            &config.start_nonterminal,
+            lazy_dfa_config,
+            &input.placeholders,
+        ),
+    }
+    .map_err(|e| diagnostic::locate_error(e, source, None))?;
+    let unreachable_nonterminals =
+        find_unreachable_nonterminals(&grammar, &input.difference_operands);
+    let mut grammar = grammar;
+    let guards = add_bonus_guards(&mut grammar, &input.nonterminal_bonuses);
+    let mut grammar = grammar.simplify_grammar(
         config.compression_config,
         &kbnf_regex_automata::util::start::Config::new()
             .anchored(kbnf_regex_automata::Anchored::Yes),
     );
+    construct_generalized_suffix_automata(&mut grammar, &input.documents);
+    let intersections = construct_intersections(&mut grammar, &input.placeholders);
+    let metadata = GrammarMetadata {
+        nonterminal_bonuses: remove_bonus_guards(&mut grammar, &guards),
+        unreachable_nonterminals,
+        regex_placeholders: input.placeholders,
+        substrings_documents: input.documents,
+        intersections,
+    };
+    Ok((grammar, metadata))
+}
+/// Finds the nonterminals defined in the grammar that cannot be reached from the start nonterminal,
+/// in the order of their definitions.
+///
+/// The simplification removes these nonterminals, so they have to be found before it.
+/// The nonterminals generated by the preprocessor and those inlined into the differences are skipped.
+fn find_unreachable_nonterminals(
+    grammar: &ValidatedGrammar,
+    difference_operands: &AHashSet<String>,
+) -> Vec<String> {
+    let mut rules: AHashMap<SymbolU32, Vec<&NodeWithID>> = AHashMap::default();
+    for expression in grammar.expressions.iter() {
+        rules
+            .entry(expression.lhs)
+            .or_default()
+            .push(&expression.rhs);
+    }
+    let mut reachable = AHashSet::default();
+    reachable.insert(grammar.start_symbol);
+    let mut stack: Vec<&NodeWithID> = rules
+        .get(&grammar.start_symbol)
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+    while let Some(node) = stack.pop() {
+        match node {
+            NodeWithID::Nonterminal(x) => {
+                if reachable.insert(*x) {
+                    stack.extend(rules.get(x).into_iter().flatten().copied());
+                }
+            }
+            NodeWithID::Multiple(nodes) => stack.extend(nodes.iter()),
+            NodeWithID::RegexExt(node, _) | NodeWithID::Group(node) => stack.push(node),
+            NodeWithID::Symbol(lhs, _, rhs) => {
+                stack.push(lhs);
+                stack.push(rhs);
+            }
+            NodeWithID::Terminal(_)
+            | NodeWithID::RegexString(_)
+            | NodeWithID::EarlyEndRegexString(_)
+            | NodeWithID::Substrings(_)
+            | NodeWithID::RegexComplement(_)
+            | NodeWithID::Unknown => {}
+        }
+    }
+    let mut unreachable_nonterminals = Vec::new();
+    for expression in grammar.expressions.iter() {
+        if reachable.insert(expression.lhs) {
+            let name = grammar
+                .interned_strings
+                .nonterminals
+                .resolve(expression.lhs)
+                .unwrap();
+            if !name.starts_with(preprocessor::GENERATED_NONTERMINAL_PREFIX)
+                && !difference_operands.contains(name)
+            {
+                unreachable_nonterminals.push(name.to_string());
+            }
+        }
+    }
+    unreachable_nonterminals
+}
+/// Keeps the nonterminals with bonuses apart through the simplification,
+/// which would otherwise inline them into their parents or merge them with the nonterminals of the same productions.
+///
+/// Each nonterminal gets another rule `G G`, where `G` is a regex of its own that matches nothing.
+/// The regex is repeated so the production is never a unit production.
+/// Returns the bonuses keyed by the regexes, which [remove_bonus_guards] removes once the grammar is simplified.
+fn add_bonus_guards(
+    grammar: &mut ValidatedGrammar,
+    nonterminal_bonuses: &AHashMap<String, f32>,
+) -> AHashMap<String, f32> {
+    let mut guards = AHashMap::default();
+    let mut nonterminals: Vec<_> = nonterminal_bonuses.iter().collect();
+    nonterminals.sort_unstable_by(|x, y| x.0.cmp(y.0));
+    for (name, &bonus) in nonterminals {
+        let Some(lhs) = grammar.interned_strings.nonterminals.get(name) else {
+            continue;
+        };
+        // The expressions of kbnf_syntax cannot be constructed here, so a rule of the nonterminal is copied instead.
+        let Some(mut guard) = grammar.expressions.iter().find(|x| x.lhs == lhs).cloned() else {
+            continue;
+        };
+        let mut regex = format!(r"[^\s\S]{name}");
+        while grammar.interned_strings.regex_strings.get(&regex).is_some() {
+            regex.push('_');
+        }
+        let id = grammar.interned_strings.regex_strings.get_or_intern(&regex);
+        grammar.id_to_regex.insert(
+            id,
+            FiniteStateAutomaton::Dfa(kbnf_regex_automata::dfa::dense::DFA::never_match().unwrap()),
+        );
+        guard.rhs = NodeWithID::Multiple(vec![
+            NodeWithID::RegexString(id),
+            NodeWithID::RegexString(id),
+        ]);
+        grammar.expressions.push(guard);
+        guards.insert(regex, bonus);
+    }
+    guards
+}
+/// Removes the guards added by [add_bonus_guards] and returns the bonuses of the guarded nonterminals,
+/// keyed by the nonterminal names. The copies of a guarded nonterminal in an intersection share its guard and hence its bonus.
+///
+/// A nonterminal left without productions could only match the empty string,
+/// which the simplification has already removed from its parents.
+/// It is removed along with the productions that still refer to it, as the simplification would have done.
+fn remove_bonus_guards(
+    grammar: &mut SimplifiedGrammar,
+    guards: &AHashMap<String, f32>,
+) -> AHashMap<String, f32> {
+    let mut nonterminal_bonuses = AHashMap::default();
+    let guards: AHashMap<SymbolU32, f32> = guards
+        .iter()
+        .filter_map(|(regex, &bonus)| {
+            let id = grammar.interned_strings.regex_strings.get(regex)?;
+            Some((id, bonus))
+        })
+        .collect();
+    if guards.is_empty() {
+        return nonterminal_bonuses;
+    }
+    let guard = |alternation: &Alternation| match alternation.concatenations.as_slice() {
+        [OperatorFlattenedNode::RegexString(x), OperatorFlattenedNode::RegexString(y)]
+            if x == y =>
+        {
+            guards.get(x).copied()
+        }
+        _ => None,
+    };
+    for (nonterminal, rhs) in grammar.expressions.iter_mut().enumerate() {
+        let Some(bonus) = rhs.alternations.iter().find_map(guard) else {
+            continue;
+        };
+        rhs.alternations.retain(|x| guard(x).is_none());
+        let name = grammar
+            .interned_strings
+            .nonterminals
+            .resolve(SymbolU32::try_from_usize(nonterminal).unwrap())
+            .unwrap();
+        nonterminal_bonuses.insert(name.to_string(), bonus);
+    }
+    let mut removed = AHashSet::default();
+    loop {
+        let empty: Vec<_> = (0..grammar.expressions.len())
+            .filter(|&x| grammar.expressions[x].alternations.is_empty() && !removed.contains(&x))
+            .collect();
+        if empty.is_empty() {
+            break;
+        }
+        removed.extend(empty);
+        for rhs in grammar.expressions.iter_mut() {
+            rhs.alternations.retain(|x| {
+                !x.concatenations.iter().any(|x| {
+                    matches!(x, OperatorFlattenedNode::Nonterminal(x) if removed.contains(&x.to_usize()))
+                })
+            });
+        }
+    }
+    for &nonterminal in removed.iter() {
+        let name = grammar
+            .interned_strings
+            .nonterminals
+            .resolve(SymbolU32::try_from_usize(nonterminal).unwrap())
+            .unwrap();
+        nonterminal_bonuses.remove(name);
+    }
+    compact_interned(grammar, &removed);
+    nonterminal_bonuses
+}
+/// Re-interns the nonterminals except `removed` and the regexes still referred to by the productions,
+/// keeping their order.
+fn compact_interned(grammar: &mut SimplifiedGrammar, removed: &AHashSet<usize>) {
+    let mut used_regexes = AHashSet::default();
+    for rhs in grammar.expressions.iter() {
+        for alternation in rhs.alternations.iter() {
+            for node in alternation.concatenations.iter() {
+                if let OperatorFlattenedNode::RegexString(x)
+                | OperatorFlattenedNode::EarlyEndRegexString(x)
+                | OperatorFlattenedNode::RegexComplement(x) = node
+                {
+                    used_regexes.insert(*x);
+                }
+            }
+        }
+    }
+    let mut nonterminals = StringInterner::<StringBackend<SymbolU32>>::new();
+    let mut nonterminal_ids = Vec::with_capacity(grammar.expressions.len());
+    let mut expressions = Vec::with_capacity(grammar.expressions.len());
+    for (id, name) in grammar.interned_strings.nonterminals.iter() {
+        if removed.contains(&id.to_usize()) {
+            nonterminal_ids.push(None);
+            continue;
+        }
+        nonterminal_ids.push(Some(nonterminals.get_or_intern(name)));
+        expressions.push(std::mem::replace(
+            &mut grammar.expressions[id.to_usize()],
+            Rhs {
+                alternations: vec![],
+            },
+        ));
+    }
+    let mut regex_strings = StringInterner::<StringBackend<SymbolU32>>::new();
+    let mut regex_ids = Vec::with_capacity(grammar.id_to_regex.len());
+    let mut id_to_regex = Vec::with_capacity(grammar.id_to_regex.len());
+    for ((id, regex), fsa) in grammar
+        .interned_strings
+        .regex_strings
+        .iter()
+        .zip(std::mem::take(&mut grammar.id_to_regex))
+    {
+        if !used_regexes.contains(&id) {
+            regex_ids.push(None);
+            continue;
+        }
+        regex_ids.push(Some(regex_strings.get_or_intern(regex)));
+        id_to_regex.push(fsa);
+    }
+    for rhs in expressions.iter_mut() {
+        for alternation in rhs.alternations.iter_mut() {
+            for node in alternation.concatenations.iter_mut() {
+                match node {
+                    OperatorFlattenedNode::Nonterminal(x) => {
+                        *x = nonterminal_ids[x.to_usize()].unwrap();
+                    }
+                    OperatorFlattenedNode::RegexString(x)
+                    | OperatorFlattenedNode::EarlyEndRegexString(x)
+                    | OperatorFlattenedNode::RegexComplement(x) => {
+                        *x = regex_ids[x.to_usize()].unwrap();
+                    }
+                    OperatorFlattenedNode::Terminal(_) | OperatorFlattenedNode::Substrings(_) => {}
+                }
+            }
+        }
+    }
+    grammar.start_symbol = nonterminal_ids[grammar.start_symbol.to_usize()].unwrap();
+    grammar.expressions = expressions;
+    grammar.interned_strings.nonterminals = nonterminals;
+    grammar.interned_strings.regex_strings = regex_strings;
+    grammar.id_to_regex = id_to_regex;
+}
+/// Replaces the DFAs of the placeholders of the differences `A - B` and the numeric ranges `#int[min,max]`
+/// by the DFAs built from the products of their operands,
+/// and those of the regex operands of the intersections `X & #"R"` by the DFAs of `R`.
+///
+/// This happens before the simplification, which relies on whether the regexes match the empty string.
+fn construct_placeholders(
+    grammar: &mut ValidatedGrammar,
+    config: FiniteStateAutomatonConfig,
+    placeholders: &AHashMap<String, RegexPlaceholder>,
+) -> Result<(), CreateGrammarError> {
+    let FiniteStateAutomatonConfig::Dfa(config) = config;
+    for (id, regex_string) in &grammar.interned_strings.regex_strings {
+        let Some(placeholder) = placeholders.get(regex_string) else {
+            continue;
+        };
+        let Some(language) = placeholder.language(placeholders) else {
+            continue;
+        };
+        let mut builder = kbnf_regex_automata::dfa::dense::Builder::new();
+        // The DFAs of numeric ranges are small, so they are always minimized.
+        builder.configure(
+            config
+                .clone()
+                .minimize(matches!(placeholder, RegexPlaceholder::NumericRange(_))),
+        );
+        let dfa = match language {
+            PlaceholderLanguage::Difference(minuend, subtrahend) => builder.build_from_nfa(
+                &regex::difference_nfa(&minuend, &subtrahend, config.get_dfa_size_limit())?,
+            ),
+            PlaceholderLanguage::Regex(regex) => builder.build(regex),
+        }
+        .map_err(|e| CreateGrammarError::SemanticError(Box::new(e.into())))?;
+        grammar
+            .id_to_regex
+            .insert(id, FiniteStateAutomaton::Dfa(dfa));
+    }
+    Ok(())
+}
+/// Replaces the suffix automata of the substrings symbols with multiple documents
+/// by the generalized suffix automata of their documents.
+///
+/// The simplification only relies on whether a suffix automaton accepts nonempty strings,
+/// which holds for the placeholder string unless all the documents are empty.
+/// In that case the generalized suffix automaton accepts only the empty string, which is still correct.
+fn construct_generalized_suffix_automata(
+    grammar: &mut SimplifiedGrammar,
+    documents: &AHashMap<String, SubstringsDocuments>,
+) {
+    for (id, string) in grammar.interned_strings.sub_strings.iter() {
+        if let Some(documents) = documents.get(string) {
+            let mut trie = Trie::<BTreeTransTable<u8>>::default();
+            for document in documents.documents.iter() {
+                trie.insert_bytes(document);
+            }
+            grammar.id_to_suffix_automaton[id.to_usize()] =
+                GeneralSam::from_trie(trie.get_root_state());
+        }
+    }
+}
+/// Splits the generated nonterminal `I ::= X #"M"` of every intersection `X & #"R"`
+/// into `I ::= O | T; O ::= X'; T ::= #"M";`, where `X'` is a copy of `X` whose reachable nonterminals are copied as well.
+///
+/// The simplification has already decided whether `I` matches the empty string,
+/// since `M` matches the empty string if and only if `R` does, and removed the empty string from `X`.
+/// The copies keep the nonterminals of the intersection apart from the rest of the grammar,
+/// so the engine can discard their Earley items once the DFA of `R`, run by the Earley items of `T`, rejects the input.
+/// Returns the names of the nonterminals of each intersection.
+fn construct_intersections(
+    grammar: &mut SimplifiedGrammar,
+    placeholders: &AHashMap<String, RegexPlaceholder>,
+) -> Vec<IntersectionNonterminals> {
+    let is_marker = |grammar: &SimplifiedGrammar, node: &OperatorFlattenedNode| {
+        matches!(node, OperatorFlattenedNode::RegexString(x)
+        if matches!(
+            placeholders.get(grammar.interned_strings.regex_strings.resolve(*x).unwrap()),
+            Some(RegexPlaceholder::Intersection(_))
+        ))
+    };
+    let new_nonterminal = |grammar: &mut SimplifiedGrammar, name: String, rhs: Rhs| {
+        let id = grammar.interned_strings.nonterminals.get_or_intern(name);
+        debug_assert_eq!(id.to_usize(), grammar.expressions.len());
+        grammar.expressions.push(rhs);
+        id
+    };
+    let single = |node: OperatorFlattenedNode| Alternation {
+        concatenations: vec![node],
+    };
+    let mut intersections = Vec::new();
+    for root in 0..grammar.expressions.len() {
+        let alternations = &grammar.expressions[root].alternations;
+        let Some(marker) = alternations
+            .iter()
+            .flat_map(|x| x.concatenations.iter())
+            .find(|x| is_marker(grammar, x))
+            .cloned()
+        else {
+            continue;
+        };
+        let operand = alternations
+            .iter()
+            .find_map(|x| x.concatenations.first().filter(|&y| *y != marker))
+            .cloned();
+        let root_name = grammar
+            .interned_strings
+            .nonterminals
+            .resolve(SymbolU32::try_from_usize(root).unwrap())
+            .unwrap()
+            .to_string();
+        // The copy of each nonterminal, and the nonterminals whose copies are not filled yet.
+        let mut copies: AHashMap<SymbolU32, SymbolU32> = AHashMap::default();
+        let mut stack = Vec::new();
+        let mut copy = |grammar: &mut SimplifiedGrammar,
+                        stack: &mut Vec<(SymbolU32, SymbolU32)>,
+                        nonterminal: SymbolU32| {
+            *copies.entry(nonterminal).or_insert_with(|| {
+                let name = grammar
+                    .interned_strings
+                    .nonterminals
+                    .resolve(nonterminal)
+                    .unwrap();
+                let name = format!("{root_name}__{name}");
+                let id = new_nonterminal(
+                    grammar,
+                    name,
+                    Rhs {
+                        alternations: vec![],
+                    },
+                );
+                stack.push((nonterminal, id));
+                id
+            })
+        };
+        let mut productions = Vec::with_capacity(2);
+        let mut operand_name = None;
+        if let Some(mut operand) = operand {
+            if let OperatorFlattenedNode::Nonterminal(x) = operand {
+                operand = OperatorFlattenedNode::Nonterminal(copy(grammar, &mut stack, x));
+            }
+            // The operand is only referred to by the root, even if `X` is recursive.
+            let name = format!("{root_name}__operand");
+            operand_name = Some(name.clone());
+            let operand = new_nonterminal(
+                grammar,
+                name,
+                Rhs {
+                    alternations: vec![single(operand)],
+                },
+            );
+            productions.push(single(OperatorFlattenedNode::Nonterminal(operand)));
+        }
+        while let Some((nonterminal, id)) = stack.pop() {
+            let mut rhs = grammar.expressions[nonterminal.to_usize()].clone();
+            for node in rhs
+                .alternations
+                .iter_mut()
+                .flat_map(|x| x.concatenations.iter_mut())
+            {
+                if let OperatorFlattenedNode::Nonterminal(x) = node {
+                    *x = copy(grammar, &mut stack, *x);
+                }
+            }
+            grammar.expressions[id.to_usize()] = rhs;
+        }
+        let tracker_name = format!("{root_name}__regex");
+        let tracker = new_nonterminal(
+            grammar,
+            tracker_name.clone(),
+            Rhs {
+                alternations: vec![single(marker)],
+            },
+        );
+        productions.push(single(OperatorFlattenedNode::Nonterminal(tracker)));
+        grammar.expressions[root].alternations = productions;
+        let members = copies
+            .values()
+            .map(|&x| {
+                grammar
+                    .interned_strings
+                    .nonterminals
+                    .resolve(x)
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        intersections.push(IntersectionNonterminals {
+            root: root_name,
+            operand: operand_name,
+            members,
+            tracker: tracker_name,
+        });
+    }
+    intersections
+}
+/// Validates the grammar without compiling its regexes into dense DFAs.
+///
+/// Each regex is compiled into a lazy DFA only to check its validity and whether it matches the empty string.
+/// The simplification only relies on the latter, so a tiny dense DFA with the same property stands in for it.
+/// The actual lazy DFAs are built later in [Grammar::new](crate::grammar::Grammar::new).
+fn validate_grammar_with_lazy_dfa(
+    mut grammar: kbnf_syntax::Grammar,
+    start_nonterminal: &str,
+    config: LazyDfaConfig,
+    placeholders: &AHashMap<String, RegexPlaceholder>,
+) -> Result<ValidatedGrammar, CreateGrammarError> {
+    let regex_strings = std::mem::take(&mut grammar.interned_strings.regex_strings);
+    let dense_config = kbnf_regex_automata::dfa::dense::Config::new()
+        .start_kind(kbnf_regex_automata::dfa::StartKind::Both);
+    let mut grammar = grammar.validate_grammar(
+        start_nonterminal,
+        FiniteStateAutomatonConfig::Dfa(dense_config.clone()),
+    )?;
+    let build_stand_in = |pattern: &str| {
+        kbnf_regex_automata::dfa::dense::Builder::new()
+            .configure(dense_config.clone())
+            .build(pattern)
+            .map_err(|e| CreateGrammarError::SemanticError(Box::new(e.into())))
+    };
+    let only_empty = build_stand_in(r"\A\z")?;
+    let has_empty = build_stand_in(r"\A(?:|a)\z")?;
+    let no_empty = build_stand_in(r"\Aa\z")?;
+    for (id, regex_string) in &regex_strings {
+        let lazy_dfa = config.build_with_placeholders(regex_string, placeholders)?;
+        let start = lazy_dfa.anchored_start_state();
+        let mut cache = lazy_dfa.create_cache();
+        let stand_in = if lazy_dfa.state_status(&mut cache, start) != FsaStateStatus::Accept {
+            &no_empty
+        } else if lazy_dfa.first_bytes(&mut cache, start, false).is_clear() {
+            &only_empty
+        } else {
+            &has_empty
+        };
+        grammar
+            .id_to_regex
+            .insert(id, FiniteStateAutomaton::Dfa(stand_in.clone()));
+    }
+    grammar.interned_strings.regex_strings = regex_strings;
     Ok(grammar)
 }
+/// Quotes `value` as a KBNF string literal, escaping the characters that cannot appear in it verbatim.
+pub(crate) fn escape_kbnf_string(value: &str) -> String {
+    let mut output = String::with_capacity(value.len() + 2);
+    output.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if c.is_control() => output.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+    output
+}
 /// Helper function to find the maximum state ID from an KBNF grammar.
 /// This is useful for determining [EngineBase](crate::engine_base::EngineBase) and [Grammar](crate::grammar::Grammar)'s generic parameter(TS).
 pub fn find_max_state_id_from_kbnf_syntax_grammar(grammar: &SimplifiedGrammar) -> usize {
@@ -126,6 +680,124 @@ pub(crate) fn get_display_form_from_bitset(bitset: &fixedbitset_stack::FixedBitS
     bitset.ones().collect()
 }
 
+/// Writes `bitset` into `buffer` as packed words, filling each word from its least significant bit.
+///
+/// # Errors
+///
+/// Returns [`WriteBufferError::BufferTooSmall`] when `buffer` cannot hold every bit of `bitset`.
+pub(crate) fn write_bitmask<W>(
+    bitset: &fixedbitset_stack::FixedBitSet,
+    buffer: &mut [W],
+) -> Result<(), WriteBufferError>
+where
+    W: PrimInt + 'static,
+    usize: AsPrimitive<W>,
+{
+    let word_bits = W::zero().count_zeros() as usize;
+    let words = bitset.len().div_ceil(word_bits);
+    if words > buffer.len() {
+        return Err(WriteBufferError::BufferTooSmall);
+    }
+    let blocks = bitset.as_slice();
+    for (i, word) in buffer[..words].iter_mut().enumerate() {
+        *word = W::zero();
+        let mut filled = 0;
+        while filled < word_bits {
+            let bit = i * word_bits + filled;
+            let offset = bit % usize::BITS as usize;
+            let block = blocks.get(bit / usize::BITS as usize).copied().unwrap_or(0);
+            // Bits of the block that overflow the word are shifted out.
+            *word = *word | ((block >> offset).as_() << filled);
+            filled += usize::BITS as usize - offset;
+        }
+    }
+    Ok(())
+}
+
+/// A floating-point type of logits.
+pub(crate) trait Logit: Copy {
+    const NEG_INFINITY: Self;
+    /// Returns the logit shifted by `value`, rounded to the nearest representable logit.
+    fn shifted(self, value: f32) -> Self;
+}
+
+impl Logit for f32 {
+    const NEG_INFINITY: Self = f32::NEG_INFINITY;
+    #[inline]
+    fn shifted(self, value: f32) -> Self {
+        self + value
+    }
+}
+
+impl Logit for half::f16 {
+    const NEG_INFINITY: Self = half::f16::NEG_INFINITY;
+    #[inline]
+    fn shifted(self, value: f32) -> Self {
+        half::f16::from_f32(self.to_f32() + value)
+    }
+}
+
+impl Logit for half::bf16 {
+    const NEG_INFINITY: Self = half::bf16::NEG_INFINITY;
+    #[inline]
+    fn shifted(self, value: f32) -> Self {
+        half::bf16::from_f32(self.to_f32() + value)
+    }
+}
+
+/// Masks every row of the row-major `logits`, whose rows start `row_stride` elements apart,
+/// so that only the tokens in `allowed` remain.
+///
+/// The logits of the disallowed tokens are shifted by `penalty`, or set to negative infinity if it is `None`.
+/// In the latter case, when most tokens are disallowed, the allowed logits are copied into a buffer filled with negative infinity;
+/// otherwise the disallowed logits are overwritten in place.
+/// Either way, the logits beyond the vocabulary size are set to negative infinity.
+///
+/// # Errors
+///
+/// Returns [`MaskLogitsError::InvalidLogitsLength`] when `row_stride` is shorter than `allowed`
+/// or the length of `logits` is not a multiple of `row_stride`.
+pub(crate) fn mask_logits_2d<T: Logit>(
+    allowed: &fixedbitset_stack::FixedBitSet,
+    logits: &mut [T],
+    row_stride: usize,
+    penalty: Option<f32>,
+) -> Result<(), MaskLogitsError> {
+    if row_stride < allowed.len() || row_stride == 0 || !logits.len().is_multiple_of(row_stride) {
+        return Err(MaskLogitsError::InvalidLogitsLength);
+    }
+    if let Some(penalty) = penalty {
+        for row in logits.chunks_exact_mut(row_stride) {
+            for token_id in allowed.zeroes() {
+                // SAFETY: the capacity of allowed <= row_stride == row.len()
+                unsafe {
+                    let logit = row.get_unchecked_mut(token_id);
+                    *logit = logit.shifted(penalty);
+                }
+            }
+            row[allowed.len()..].fill(T::NEG_INFINITY);
+        }
+    } else if allowed.count_zeroes(..) > row_stride / 2 {
+        let mut mask = vec![T::NEG_INFINITY; row_stride];
+        for row in logits.chunks_exact_mut(row_stride) {
+            for token_id in allowed.ones() {
+                // SAFETY: the capacity of allowed <= row_stride == row.len()
+                unsafe { *mask.get_unchecked_mut(token_id) = *row.get_unchecked(token_id) };
+            }
+            row.copy_from_slice(&mask);
+        }
+    } else {
+        for row in logits.chunks_exact_mut(row_stride) {
+            for token_id in allowed.zeroes() {
+                // SAFETY: the capacity of allowed <= row_stride == row.len()
+                unsafe { *row.get_unchecked_mut(token_id) = T::NEG_INFINITY };
+            }
+            row[allowed.len()..].fill(T::NEG_INFINITY);
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn get_deterministic_display_form_from_hash_set<T, U: Ord>(
     set: &AHashSet<T>,
     process: impl FnMut(&T) -> U,
@@ -200,6 +200,21 @@ impl Vocabulary {
         })
     }
 
+    /// Creates a vocabulary without any token, for the grammars that are never matched against tokens.
+    pub(crate) fn empty() -> Self {
+        let mut first_byte_to_normal_tokens = JaggedArray::with_capacity([256, 0]);
+        for _ in 0..256 {
+            first_byte_to_normal_tokens.new_row::<0>();
+        }
+        Self {
+            token_to_id: AHashMap::default(),
+            id_to_token: AHashMap::default(),
+            id_to_token_string: AHashMap::default(),
+            first_byte_to_normal_tokens,
+            tokens_containing_separators: Vec::new(),
+        }
+    }
+
     fn check_vocabulary_utf8_support(token_to_id: &AHashMap<Token, u32>) {
         let mut not_existing_bytes = ByteSet::with_capacity(256);
         fn check_non_existing_byte_in_range(
@@ -313,6 +328,14 @@ impl Vocabulary {
     pub fn token_id(&self, token: &Token) -> Option<u32> {
         self.token_to_id.get(token).copied()
     }
+    /// Retrieves the length in bytes of the longest token.
+    pub(crate) fn max_token_length(&self) -> usize {
+        self.id_to_token
+            .values()
+            .map(|token| token.0.len())
+            .max()
+            .unwrap_or(0)
+    }
     /// Retrieves the size of the vocabulary.
     pub fn vocab_size(&self) -> usize {
         self.id_to_token
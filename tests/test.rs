@@ -14,6 +14,11 @@ mod tests {
     use kbnf::{
         engine::EngineConfig,
         engine_like::{AcceptTokenResult, EngineLike},
+        generator::{
+            CreateGeneratorError, GenerateError, Generator, GeneratorConfig, SampleTokensError,
+            TokenSampler, TokenSamplerConfig,
+        },
+        lint::{LintKind, LintWarning},
         vocabulary::{Token, Vocabulary},
     };
     #[derive(Debug, thiserror::Error)]
@@ -67,6 +72,14 @@ mod tests {
     fn get_token_id_from_str(vocab: &Vocabulary, token: &str) -> Option<u32> {
         vocab.token_id(&Token(token.as_bytes().to_vec().into_boxed_slice()))
     }
+
+    /// The default config with the lints run when an engine is created.
+    fn lint_config() -> kbnf::config::Config {
+        kbnf::config::Config {
+            lint: true,
+            ..Default::default()
+        }
+    }
     #[test]
     fn single_terminal() {
         let input = "start::='Hello, World!\n';";
@@ -141,10 +154,7 @@ mod tests {
         let input = "start::=#'[0-9]+''\\n';";
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
         let config = kbnf::config::Config {
-            engine_config: EngineConfig {
-                cache_enabled: true,
-                compaction_enabled: false,
-            },
+            engine_config: EngineConfig::new(true, false),
             ..Default::default()
         };
         let logits = vec![0.0; vocab.vocab_size()];
@@ -316,10 +326,7 @@ mod tests {
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
         let logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
-            engine_config: EngineConfig {
-                cache_enabled: true,
-                compaction_enabled: true,
-            },
+            engine_config: EngineConfig::new(true, true),
             ..Default::default()
         };
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
@@ -352,10 +359,7 @@ mod tests {
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
         let logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
-            engine_config: EngineConfig {
-                cache_enabled: true,
-                compaction_enabled: true,
-            },
+            engine_config: EngineConfig::new(true, true),
             ..Default::default()
         };
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
@@ -387,10 +391,7 @@ mod tests {
         let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
         let logits = vec![0.0; vocab.vocab_size()];
         let config = kbnf::config::Config {
-            engine_config: EngineConfig {
-                cache_enabled: true,
-                compaction_enabled: true,
-            },
+            engine_config: EngineConfig::new(true, true),
             ..Default::default()
         };
         let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
@@ -684,4 +685,1921 @@ __schema_json_1_next ::=
             "Should reject sequence containing invalid byte 'a'"
         );
     }
+
+    #[test]
+    fn lazy_dfa() {
+        let input = r#"start::=#"[0-9]+" #e"(a|b)+\n" #ex"c" '\n';"#;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut config = kbnf::config::Config::default();
+        config.regex_config.fsa_type = kbnf::config::Fsa::LazyDfa;
+        let mut lazy_engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for bytes in [&b"12"[..], b"3", b"ab", b"a\n", b"xyz", b"c", b"\n"] {
+            engine.compute_allowed_token_ids();
+            lazy_engine.compute_allowed_token_ids();
+            assert_eq!(
+                engine.allowed_token_ids_from_last_computation(),
+                lazy_engine.allowed_token_ids_from_last_computation()
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(bytes),
+                lazy_engine.try_accept_new_bytes(bytes)
+            );
+        }
+        assert_eq!(
+            lazy_engine.try_accept_new_bytes(b"c"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        let mut config = kbnf::config::Config::default();
+        config.regex_config.fsa_type = kbnf::config::Fsa::LazyDfa;
+        config.regex_config.cache_capacity = Some(0);
+        assert!(kbnf::engine::Engine::with_config(input, vocab, config).is_err());
+    }
+
+    #[test]
+    fn lazy_dfa_cache_full() {
+        let mut id_to_token = AHashMap::default();
+        let mut id_to_token_string = AHashMap::default();
+        for (id, token) in ('a'..='z').enumerate() {
+            id_to_token.insert(id as u32, Token(vec![token as u8].into_boxed_slice()));
+            id_to_token_string.insert(id as u32, token.to_string());
+        }
+        let vocab = Vocabulary::new(id_to_token, id_to_token_string).unwrap();
+        let input = r#"start::=#"[a-z]*(a[a-z]{6}|b[a-z]{6})";"#;
+        let mut config = kbnf::config::Config::default();
+        config.regex_config.fsa_type = kbnf::config::Fsa::LazyDfa;
+        config.regex_config.cache_capacity = Some(1000);
+        // The enumeration fails rather than returning the completions it could check.
+        let engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Err(kbnf::completion::EnumerateCompletionsError::LazyDfaCacheFull)
+        );
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab, config).unwrap();
+        engine.compute_allowed_token_ids();
+        assert_eq!(
+            engine.try_accept_new_token(0),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_compute_allowed_token_ids(),
+            Err(kbnf::engine_like::ComputeAllowedTokenIdsError::LazyDfaCacheFull)
+        );
+        assert_eq!(
+            engine.allowed_token_ids_error_from_last_computation(),
+            Some(kbnf::engine_like::ComputeAllowedTokenIdsError::LazyDfaCacheFull)
+        );
+        // The full cache is rebuilt with the states the engine refers to, so accepting the token succeeds.
+        assert_eq!(
+            engine.try_accept_new_token(1),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        // A token whose transitions do not fit in the rebuilt cache either still fails, leaving the engine unchanged.
+        let mut logits = vec![0.0; 26];
+        assert_eq!(
+            engine.update_logits(2, &mut logits),
+            Err(kbnf::engine_like::UpdateLogitsError::LazyDfaCacheFull)
+        );
+        assert_eq!(logits, vec![0.0; 26]);
+        assert_eq!(
+            engine.try_accept_new_token(2),
+            Err(kbnf::engine_like::AcceptTokenError::LazyDfaCacheFull)
+        );
+        // A long generation visits more states than the cache can hold,
+        // but the cache is rebuilt whenever it is full, so the generation never fails.
+        let input = r#"start::=#"[a-z]*(a[a-z]{6}|b[a-z]{6})" '.';"#;
+        let mut config = kbnf::config::Config::default();
+        config.regex_config.fsa_type = kbnf::config::Fsa::LazyDfa;
+        config.regex_config.cache_capacity = Some(4000);
+        let vocab = engine.vocab();
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, (*vocab).clone(), config).unwrap();
+        let bytes: Vec<u8> = (0..256)
+            .flat_map(|i| (0..8).map(move |j| if (i >> j) & 1 == 1 { b'a' } else { b'b' }))
+            .collect();
+        for byte in bytes.iter() {
+            assert_eq!(
+                engine.try_accept_new_bytes(&[*byte]),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+        }
+        // Every engine has caches of its own, so the clones keep working after the caches of an engine are rebuilt.
+        let mut other = engine.clone();
+        for byte in bytes.iter() {
+            assert_eq!(
+                engine.try_accept_new_bytes(&[*byte]),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+        }
+        for byte in bytes.iter() {
+            assert_eq!(
+                other.try_accept_new_bytes(&[*byte]),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+        }
+    }
+
+    #[test]
+    fn counted_repetition() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=digit{3}'\n';digit::=#'[0-9]';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"12\n"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"1234"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"123\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let input = "start::=(item ',' ){1,8}'\n';item::='a'|'b';";
+        for n in 1..=8 {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes("a,".repeat(n).as_bytes()),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            engine.compute_allowed_token_ids();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"\n"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes("b,".repeat(8).as_bytes()),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        let input = "start::='a'{2,}'\n';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a\n"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes("a".repeat(100).as_bytes()),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        let input = "start::='a'{0,100000}'\n';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let input = "start::='a'{3,1}'\n';";
+        assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+    }
+
+    #[test]
+    fn grammar_lint() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let kinds = |input: &str| {
+            let engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), lint_config()).unwrap();
+            engine
+                .lint_warnings()
+                .iter()
+                .map(|x| x.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kinds("start::=#'[0-9]+' '\\n';"), vec![]);
+        assert_eq!(
+            kinds("start::=E '\\n'; E::=E '+' E | 'a';"),
+            vec![LintKind::ShiftReduceConflict]
+        );
+        assert_eq!(
+            kinds("start::=A '\\n'; A::='a' A | 'a';"),
+            vec![LintKind::RightRecursion]
+        );
+        assert_eq!(
+            kinds("start::='a'|A; A::=A 'b';"),
+            vec![LintKind::UnproductiveNonterminal]
+        );
+        assert_eq!(
+            kinds("start::=A; A::=B|'x'; B::=A|'y';"),
+            vec![LintKind::ReduceReduceConflict, LintKind::NullableCycle]
+        );
+        assert_eq!(
+            kinds("start::=a \"x\"; a::=b | \"\"; b::=a;"),
+            vec![LintKind::UnproductiveNonterminal]
+        );
+        // The operands of a difference are inlined into its regex, so their rules are not reported.
+        assert_eq!(
+            kinds("start::=id - kw; id::=#\"[a-z]+\"; kw::=\"if\";"),
+            vec![]
+        );
+        let input = "start ::= \"a\"; u ::= \"q\";";
+        // The lints are only run on request.
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert!(engine.lint_warnings().is_empty());
+        let engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), lint_config()).unwrap();
+        assert_eq!(
+            engine.lint_warnings(),
+            [LintWarning {
+                kind: LintKind::UnreachableNonterminal,
+                message: "`u` cannot be reached from the start nonterminal `start`.".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn grammar_diagnostics() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start ::= 'a' B;";
+        let error = kbnf::engine::Engine::new(input, vocab.clone()).unwrap_err();
+        let diagnostic = error.diagnostic().unwrap();
+        let span = diagnostic.span.unwrap();
+        assert_eq!((span.start.line, span.start.column), (1, 15));
+        assert_eq!(diagnostic.snippet, "B");
+        assert_eq!(
+            error.to_string(),
+            "error: KBNF semantics error: the nonterminal `B` is not defined.
+ --> 1:15
+  |
+1 | start ::= 'a' B;
+  |               ^"
+        );
+        // Locations in the expanded counted repetitions are mapped back to the source.
+        let input = "start ::= 'a'{2} 'b';\nfoo ::= 'b' ) ;";
+        let error = kbnf::engine::Engine::new(input, vocab.clone()).unwrap_err();
+        let diagnostic = error.diagnostic().unwrap();
+        let span = diagnostic.span.unwrap();
+        assert_eq!((span.start.line, span.start.column), (2, 13));
+        assert_eq!(diagnostic.snippet, ")");
+        assert_eq!(diagnostic.source_line, "foo ::= 'b' ) ;");
+        // Errors in the syntax extensions are located as well.
+        let input = "start ::= 'a'{3,2};";
+        let error = kbnf::engine::Engine::new(input, vocab.clone()).unwrap_err();
+        let kbnf::engine::CreateEngineError::GrammarError(
+            kbnf::grammar::CreateGrammarError::Located { error, diagnostic },
+        ) = error
+        else {
+            panic!("{error}");
+        };
+        assert!(matches!(
+            *error,
+            kbnf::grammar::CreateGrammarError::PreprocessingError(_)
+        ));
+        let span = diagnostic.span.unwrap();
+        assert_eq!((span.start.line, span.start.column), (1, 14));
+        assert_eq!(diagnostic.snippet, "{");
+    }
+
+    #[test]
+    fn grammar_to_kbnf_string() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config::default();
+        let to_kbnf_string = |input: &str| {
+            let (grammar, metadata) = kbnf::utils::construct_kbnf_syntax_grammar_with_metadata(
+                input,
+                config.clone().internal_config(),
+            )
+            .unwrap();
+            kbnf::grammar::Grammar::<u16>::new_with_metadata(
+                grammar,
+                metadata,
+                &vocab,
+                config.regex_config,
+            )
+            .unwrap()
+            .to_kbnf_string()
+        };
+        let input = "start::=A '\"\\n' #ex'b' | #e'[0-9]+' B; A::='a'|'b'; B::=#substrs'xyz';";
+        let output = to_kbnf_string(input);
+        assert_eq!(
+            output,
+            "start ::= A \"\\\"\\n\" #ex\"b\" | #e\"[0-9]+\" #substrs\"xyz\" | #e\"[0-9]+\";\nA ::= \"a\" | \"b\";\n"
+        );
+        // The output is valid KBNF which is stable under another round trip.
+        assert_eq!(to_kbnf_string(&output), output);
+        let mut engine = kbnf::engine::Engine::new(&output, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes("a\"\nc".as_bytes()).unwrap(),
+            AcceptTokenResult::Ongoing
+        );
+        assert_eq!(
+            to_kbnf_string("start::=#substrs['a', \"b\"] | #substrs['c'];"),
+            "start ::= #substrs[\"a\", \"b\"] | #substrs\"c\";\n"
+        );
+        // The bonuses are printed as statements, and the nonterminals with them keep their names and productions.
+        assert_eq!(
+            to_kbnf_string("start::=x ';' | 'y';x::='a'|'b' 'c';bonus x = -1.5;"),
+            "start ::= x \";\" | \"y\";\nx ::= \"bc\" | \"a\";\nbonus x = -1.5;\n"
+        );
+        // Without the metadata, the grammar has no bonuses.
+        let grammar = kbnf::utils::construct_kbnf_syntax_grammar(
+            "start::=x ';' | 'y';x::='a'|'b' 'c';bonus x = -1.5;",
+            config.clone().internal_config(),
+        )
+        .unwrap();
+        assert_eq!(
+            kbnf::grammar::Grammar::<u16>::new(grammar, &vocab, config.regex_config)
+                .unwrap()
+                .to_kbnf_string(),
+            "start ::= x \";\" | \"y\";\nx ::= \"bc\" | \"a\";\n"
+        );
+        for input in [
+            "start::=x ';' | 'y';x::='a'|'b' 'c';bonus x = -1.5;",
+            "start::=\"null\" @0.8 | \"nan\" @0.2 | 'n';",
+        ] {
+            let output = to_kbnf_string(input);
+            assert!(!output.contains(r"[^\\s\\S]"), "{output}");
+            let logits = |input: &str| {
+                let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+                engine.compute_allowed_token_ids();
+                let mut logits = vec![0.0f32; vocab.vocab_size()];
+                engine.mask_logits(&mut logits).unwrap();
+                logits
+            };
+            assert_eq!(logits(&output), logits(input), "{output}");
+        }
+    }
+
+    #[test]
+    fn generator() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let config = kbnf::config::Config::default();
+        let input = r#"start::=item{1,3} '\n'; item::='[' list ']' | #e'[0-9]+' ',' | #'"[a-z]*"' | #substrs'hello' | #ex'x';
+list::=item | list ' ' item;"#;
+        let grammar =
+            kbnf::utils::construct_kbnf_syntax_grammar(input, config.clone().internal_config())
+                .unwrap();
+        let grammar =
+            kbnf::grammar::Grammar::<u16>::new(grammar, &vocab, config.regex_config).unwrap();
+        let generator_config = GeneratorConfig {
+            seed: 42,
+            max_depth: 8,
+            ..Default::default()
+        };
+        let mut generator = Generator::new(&grammar, generator_config.clone()).unwrap();
+        let strings: Vec<_> = (0..50).map(|_| generator.generate().unwrap()).collect();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for string in strings.iter() {
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(string),
+                Ok(AcceptTokenResult::Finished),
+                "{:?}",
+                String::from_utf8_lossy(string)
+            );
+        }
+        // The same seed produces the same strings.
+        let mut generator = Generator::new(&grammar, generator_config.clone()).unwrap();
+        assert_eq!(generator.generate().unwrap(), strings[0]);
+        // The generator can own the grammar built from the KBNF grammar string.
+        let mut generator =
+            Generator::<u16>::from_kbnf(input, config.clone(), generator_config).unwrap();
+        assert_eq!(generator.generate().unwrap(), strings[0]);
+        assert!(matches!(
+            Generator::<u16>::from_kbnf("start::=A;", config.clone(), GeneratorConfig::default()),
+            Err(CreateGeneratorError::GrammarError(_))
+        ));
+        let mut production_weights = AHashMap::default();
+        production_weights.insert("start".to_string(), vec![1.0]);
+        assert!(matches!(
+            Generator::new(
+                &grammar,
+                GeneratorConfig {
+                    production_weights,
+                    ..Default::default()
+                }
+            ),
+            Err(GenerateError::InvalidWeights(..))
+        ));
+        // A substring of multiple documents comes from a single document.
+        let input = r#"start::=#substrs["hello world", "world peace"] ';';"#;
+        let (grammar, metadata) = kbnf::utils::construct_kbnf_syntax_grammar_with_metadata(
+            input,
+            config.clone().internal_config(),
+        )
+        .unwrap();
+        let grammar = kbnf::grammar::Grammar::<u16>::new_with_metadata(
+            grammar,
+            metadata,
+            &vocab,
+            config.regex_config,
+        )
+        .unwrap();
+        assert!(grammar.lint_vocabulary_bytes(&vocab).is_empty());
+        let mut generator = Generator::new(&grammar, GeneratorConfig::default()).unwrap();
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for _ in 0..300 {
+            let string = generator.generate().unwrap();
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(&string),
+                Ok(AcceptTokenResult::Finished),
+                "{:?}",
+                String::from_utf8_lossy(&string)
+            );
+        }
+    }
+
+    #[test]
+    fn token_sampler() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let mut engine =
+            kbnf::engine::Engine::new("start::=#'[0-9]{1,5}' '\\n';", vocab.clone()).unwrap();
+        let mut sampler = TokenSampler::new(TokenSamplerConfig {
+            seed: 7,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            engine.reset();
+            let token_ids = sampler.sample(&mut engine).unwrap();
+            assert!(engine.is_finished());
+            let bytes: Vec<u8> = token_ids
+                .iter()
+                .flat_map(|&x| vocab.token(x).unwrap().0.iter().copied())
+                .collect();
+            let text = String::from_utf8(bytes).unwrap();
+            assert!(text.ends_with('\n'));
+            assert!((2..=6).contains(&text.len()), "{text:?}");
+            assert!(
+                text.trim_end().bytes().all(|x| x.is_ascii_digit()),
+                "{text:?}"
+            );
+        }
+        // The allowed set becomes empty before the grammar can finish.
+        let mut id_to_token = AHashMap::default();
+        let mut id_to_token_string = AHashMap::default();
+        id_to_token.insert(0, Token(b"a".to_vec().into_boxed_slice()));
+        id_to_token_string.insert(0, "a".to_string());
+        let small_vocab = Vocabulary::new(id_to_token, id_to_token_string).unwrap();
+        let mut engine = kbnf::engine::Engine::new("start::='a' 'b';", small_vocab).unwrap();
+        assert_eq!(
+            TokenSampler::new(TokenSamplerConfig::default()).sample(&mut engine),
+            Err(SampleTokensError::DeadEnd(vec![0]))
+        );
+        let mut engine = kbnf::engine::Engine::new("start::=#ex'a';", vocab.clone()).unwrap();
+        let mut sampler = TokenSampler::new(TokenSamplerConfig {
+            max_tokens: 4,
+            ..Default::default()
+        });
+        assert!(matches!(
+            sampler.sample(&mut engine),
+            Err(SampleTokensError::MaxTokensExceeded(x)) if x.len() == 4
+        ));
+        engine.reset();
+        let mut sampler = TokenSampler::new(TokenSamplerConfig {
+            prior: Some(vec![1.0]),
+            ..Default::default()
+        });
+        assert!(matches!(
+            sampler.sample(&mut engine),
+            Err(SampleTokensError::InvalidPrior(_))
+        ));
+    }
+
+    #[test]
+    fn dead_end() {
+        let mut id_to_token = AHashMap::default();
+        let mut id_to_token_string = AHashMap::default();
+        for (id, token) in ["a", "c"].into_iter().enumerate() {
+            id_to_token.insert(
+                id as u32,
+                Token(token.as_bytes().to_vec().into_boxed_slice()),
+            );
+            id_to_token_string.insert(id as u32, token.to_string());
+        }
+        let vocab = Vocabulary::new(id_to_token, id_to_token_string).unwrap();
+        let kinds = |input: &str| {
+            let engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), lint_config()).unwrap();
+            engine
+                .lint_warnings()
+                .iter()
+                .map(|x| x.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kinds("start::='a' #'[ac]+' #substrs'bc';"), vec![]);
+        assert_eq!(
+            kinds("start::='a' 'b' | #'[d-z]' | #substrs'xyz';"),
+            vec![LintKind::MissingVocabularyBytes; 3]
+        );
+        let mut engine = kbnf::engine::Engine::new("start::='a' 'b' | 'c';", vocab).unwrap();
+        engine.compute_allowed_token_ids();
+        let mut logits = vec![0.0; 2];
+        // The token is accepted, so the dead end is reported separately.
+        assert_eq!(
+            engine.update_logits(0, &mut logits),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(logits, vec![f32::NEG_INFINITY; 2]);
+        assert_eq!(
+            engine.allowed_token_ids_error_from_last_computation(),
+            Some(kbnf::engine_like::ComputeAllowedTokenIdsError::DeadEnd)
+        );
+        assert_eq!(
+            engine.try_compute_allowed_token_ids(),
+            Err(kbnf::engine_like::ComputeAllowedTokenIdsError::DeadEnd)
+        );
+        assert!(!engine.is_finished());
+    }
+
+    #[test]
+    fn terminal_modifiers() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#i\"true\" '.' | #i'a+b'#i\"\\n\";";
+        for (bytes, expected) in [
+            ("true.", Ok(AcceptTokenResult::Finished)),
+            ("TrUe.", Ok(AcceptTokenResult::Finished)),
+            ("A+B\n", Ok(AcceptTokenResult::Finished)),
+            ("aab\n", Err(kbnf::engine_like::AcceptTokenError::Rejected)),
+            ("truth", Err(kbnf::engine_like::AcceptTokenError::Rejected)),
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(engine.try_accept_new_bytes(bytes.as_bytes()), expected);
+        }
+        // An `i` followed by a terminal is still a nonterminal.
+        let input = "start::=i\"a\";i::='b';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"ba"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let input = "start::='e\u{301}' | #i\"\u{f6}\";";
+        let config = kbnf::config::Config {
+            nfc_normalization: true,
+            ..Default::default()
+        };
+        for (bytes, expected) in [
+            ("\u{e9}", Ok(AcceptTokenResult::Finished)),
+            (
+                "e\u{301}",
+                Err(kbnf::engine_like::AcceptTokenError::Rejected),
+            ),
+            ("\u{d6}", Ok(AcceptTokenResult::Finished)),
+        ] {
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(engine.try_accept_new_bytes(bytes.as_bytes()), expected);
+        }
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes("e\u{301}".as_bytes()),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn grammar_imports() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "import \"std/json.kbnf\" as json;\nimport 'std/uuid.kbnf' as id;\nstart::=json.value '\\n' | id.uuid '\\n';";
+        for (bytes, expected) in [
+            (
+                "{\"a\": [1, -2.5e3, true, null], \"b\": {}}\n",
+                Ok(AcceptTokenResult::Finished),
+            ),
+            (
+                "123e4567-e89b-12d3-a456-426614174000\n",
+                Ok(AcceptTokenResult::Finished),
+            ),
+            (
+                "{\"a\" 1}\n",
+                Err(kbnf::engine_like::AcceptTokenError::Rejected),
+            ),
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(engine.try_accept_new_bytes(bytes.as_bytes()), expected);
+        }
+        let engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), lint_config()).unwrap();
+        assert!(engine
+            .lint_warnings()
+            .iter()
+            .all(|x| x.kind != LintKind::UnreachableNonterminal));
+        let mut modules = AHashMap::default();
+        modules.insert(
+            "digits.kbnf".to_string(),
+            "import 'digits.kbnf' as self;digits::=digit+;digit::=#'[0-9]';".to_string(),
+        );
+        modules.insert(
+            "date.kbnf".to_string(),
+            "import 'std/datetime.kbnf' as dt;\nstart::=dt.date;".to_string(),
+        );
+        modules.insert(
+            "uses.kbnf".to_string(),
+            "list<X>::=X (',' X)*;\nstart::=list<digit>;".to_string(),
+        );
+        modules.insert(
+            "bonus.kbnf".to_string(),
+            "start::='a'|b;b::='b';bonus b=1.0;".to_string(),
+        );
+        let loader = std::sync::Arc::new((modules, kbnf::import::StandardLibrary));
+        let create = |input: &str| {
+            kbnf::engine::Engine::with_loader(
+                input,
+                vocab.clone(),
+                kbnf::config::Config::default(),
+                loader.clone(),
+            )
+        };
+        let mut engine = create("import 'date.kbnf' as d;start::=d.start;").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"2024-02-29"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        let mut engine = create("import 'bonus.kbnf' as b;start::=b.start;").unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"b"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // A name the imported grammar does not define is reported in the imported grammar
+        // instead of referring to the nonterminal of the importing grammar.
+        let error = create("import 'uses.kbnf' as u;start::=u.start;digit::='1';")
+            .err()
+            .unwrap();
+        let diagnostic = error.diagnostic().unwrap();
+        let span = diagnostic.span.unwrap();
+        assert_eq!((span.start.line, span.start.column), (2, 14));
+        assert_eq!(diagnostic.snippet, "digit");
+        assert_eq!(diagnostic.source_line, "start::=list<digit>;");
+        assert!(diagnostic
+            .message
+            .ends_with("`digit` is not defined in the imported grammar `uses.kbnf`"));
+        for (input, message) in [
+            (
+                "import 'digits.kbnf' as d;start::=d.digits;",
+                "Circular import",
+            ),
+            (
+                "import 'missing.kbnf' as d;start::=d.a;",
+                "Cannot find the imported grammar",
+            ),
+            (
+                "import 'date.kbnf' as d;start::=d.date;",
+                "Undefined nonterminal in the imported grammar",
+            ),
+        ] {
+            let error = create(input).err().unwrap();
+            assert!(error.diagnostic().unwrap().span.is_some());
+            let error = error.to_string();
+            assert!(error.contains(message), "{error}");
+        }
+    }
+
+    #[test]
+    fn parameterized_rules() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=list<'a'|'b'> ';' list<nested<digit>> '\n';
+list<X>::=X (',' X)*;
+nested<X>::=X | '[' nested<X> ']';
+digit::=#'[0-9]';";
+        for (bytes, expected) in [
+            ("a,b,a;1,[2],[[3]]\n", Ok(AcceptTokenResult::Finished)),
+            ("a;[1]]", Err(kbnf::engine_like::AcceptTokenError::Rejected)),
+            ("a,1", Err(kbnf::engine_like::AcceptTokenError::Rejected)),
+        ] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            assert_eq!(engine.try_accept_new_bytes(bytes.as_bytes()), expected);
+        }
+        for (input, message) in [
+            ("start::=list<'a'>;", "`list` is not a parameterized rule."),
+            (
+                "start::=pair<'a'>;pair<X,Y>::=X Y;",
+                "`pair` expects 2 argument(s) but 1 are given.",
+            ),
+            (
+                "start::=grow<'a'>;grow<X>::=X | grow<(X X)>;",
+                "Instantiating `grow` exceeds the maximum depth 32.",
+            ),
+            ("start::=f<'a';", "The arguments of `f` are not closed."),
+        ] {
+            let error = kbnf::engine::Engine::new(input, vocab.clone())
+                .err()
+                .unwrap();
+            let kbnf::engine::CreateEngineError::GrammarError(
+                kbnf::grammar::CreateGrammarError::Located { error, diagnostic },
+            ) = error
+            else {
+                panic!("{error}");
+            };
+            assert!(matches!(
+                *error,
+                kbnf::grammar::CreateGrammarError::ParameterizedRuleError(_)
+            ));
+            assert!(diagnostic.message.ends_with(message), "{diagnostic}");
+            assert!(diagnostic.span.is_some());
+        }
+    }
+
+    #[test]
+    fn dynamic_alternatives() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#dynamic'tool' '(' #'[0-9]+' ')';";
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = lint_config();
+            config.regex_config.fsa_type = fsa_type;
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            assert!(engine.lint_warnings().is_empty());
+            assert_eq!(
+                engine.try_accept_new_bytes(b"search"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            engine.set_dynamic("tool", &["search", "calc"]).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"calc(1)"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.set_dynamic("tool", &["lookup"]).unwrap();
+            assert!(!engine.is_finished());
+            assert_eq!(
+                engine.try_accept_new_bytes(b"calc"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"lookup(42)"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            assert!(matches!(
+                engine.set_dynamic("tools", &["a"]),
+                Err(kbnf::grammar::SetDynamicError::UnknownDynamicAlternative(_))
+            ));
+            assert!(matches!(
+                engine.set_dynamic("tool", &[""]),
+                Err(kbnf::grammar::SetDynamicError::EmptyString)
+            ));
+        }
+        // The state IDs are wide enough for the dynamic alternatives set after the engine is created.
+        let default = kbnf::config::Config::default();
+        let config = kbnf::config::Config {
+            expected_output_length: 1000,
+            regex_config: kbnf::config::RegexConfig {
+                min_tokens_required_for_eager_regex_cache: None,
+                ..default.regex_config
+            },
+            ..default
+        };
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        let strings: Vec<_> = (0..6000u64)
+            .map(|i| format!("{:024x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)))
+            .collect();
+        let strings: Vec<_> = strings.iter().map(String::as_str).collect();
+        engine.set_dynamic("tool", &strings).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(format!("{}(7)", strings[1234]).as_bytes()),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // User regexes are never taken for the placeholders generated by the preprocessor.
+        let input =
+            r#"start::='a' | 'x' #"[^\\s\\S]__kbnf_placeholder_0" | #dynamic"tool" | #int[0,2];"#;
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.set_dynamic("tool", &["b"]).unwrap();
+        for bytes in [b"a", b"b", b"2"] {
+            assert_eq!(
+                engine.try_accept_new_bytes(bytes),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.reset();
+        }
+        assert_eq!(
+            engine.try_accept_new_bytes(b"xb"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+    }
+
+    #[test]
+    fn multiple_documents_substrings() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#substrs['hello world', \"world peace\"] '\n';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"lo wor"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"d peace\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"hello world peace"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        let input = "start::=#documents'passage' '\n';";
+        assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+        let mut config = kbnf::config::Config::default();
+        config.documents.insert(
+            "passage".to_string(),
+            vec!["the quick brown fox".to_string(), "a lazy dog".to_string()],
+        );
+        let (grammar, metadata) = kbnf::utils::construct_kbnf_syntax_grammar_with_metadata(
+            input,
+            config.clone().internal_config(),
+        )
+        .unwrap();
+        assert_eq!(
+            kbnf::grammar::Grammar::<u16>::new_with_metadata(
+                grammar,
+                metadata,
+                &vocab,
+                config.regex_config
+            )
+            .unwrap()
+            .to_kbnf_string(),
+            "start ::= #documents\"passage\" \"\\n\" | \"\\n\";\n"
+        );
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"lazy dog\n"),
+            Ok(AcceptTokenResult::Finished)
+        );
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"fox a"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+    }
+
+    #[test]
+    fn grammar_difference() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=identifier - keyword - 'do' ';';
+identifier::=#'[a-z]+';
+keyword::='if' | 'else' | ('whi' 'le');";
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = kbnf::config::Config::default();
+            config.regex_config.fsa_type = fsa_type;
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"iff;"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            for rejected in [&b"if;"[..], b"while;", b"do;"] {
+                engine.reset();
+                assert_eq!(
+                    engine.try_accept_new_bytes(rejected),
+                    Err(kbnf::engine_like::AcceptTokenError::Rejected)
+                );
+            }
+            let input = "start::=('a'? - 'b') 'c';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"c"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        let input = "start::=x - 'a';x::='a' x | 'b';";
+        assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+        let input = "start::=#'[a-z]+' - #substrs'abc';";
+        assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+    }
+
+    #[test]
+    fn grammar_intersection() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=expr & #'.{0,7}' ';';
+expr::=term | expr '+' term;
+term::='1' | '(' expr ')';";
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = kbnf::config::Config::default();
+            config.regex_config.fsa_type = fsa_type;
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"(1+1)+1+1;"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"(1)+1+1"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            engine.compute_allowed_token_ids();
+            let allowed = engine.allowed_token_ids_from_last_computation();
+            let id = |token: &[u8]| vocab.token_id(&kbnf::Token(token.into())).unwrap() as usize;
+            assert!(allowed.contains(id(b";")));
+            assert!(!allowed.contains(id(b"+")));
+            assert_eq!(
+                engine.try_accept_new_bytes(b";"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            let (grammar, metadata) = kbnf::utils::construct_kbnf_syntax_grammar_with_metadata(
+                input,
+                config.clone().internal_config(),
+            )
+            .unwrap();
+            let output = kbnf::grammar::Grammar::<u16>::new_with_metadata(
+                grammar,
+                metadata,
+                &vocab,
+                config.regex_config,
+            )
+            .unwrap()
+            .to_kbnf_string();
+            assert!(output.contains("__kbnf_intersection_0_expr__operand & #\".{0,7}\";"));
+            let input = "start::='a' x & #'b+' 'c' | 'd' x & #'b*' 'c';x::='b'*;";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            for (bytes, expected) in [
+                (&b"ac"[..], false),
+                (b"abbc", true),
+                (b"dc", true),
+                (b"dbc", true),
+            ] {
+                engine.reset();
+                assert_eq!(engine.try_accept_new_bytes(bytes).is_ok(), expected);
+            }
+            let input = "start::=item+ ';';item::=x & #'aa';x::='a'+;";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"aaaa;"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.reset();
+            assert!(engine.try_accept_new_bytes(b"aaa;").is_err());
+            // The instances of `i` starting at 0 and 1 overlap after "a", and each is checked against its own tracker,
+            // so "abd" is not allowed although the tracker starting at 0 still matches it.
+            let input =
+                "start::=i '!' | 'a' i '?';i::=x & #'ab[cd]e?';x::='a' 'b' 'c' | 'b' 'd' 'e';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"ab"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            engine.compute_allowed_token_ids();
+            let allowed = engine.allowed_token_ids_from_last_computation();
+            assert!(allowed.contains(id(b"c")));
+            assert!(!allowed.contains(id(b"d")));
+            assert_eq!(
+                engine.try_accept_new_bytes(b"d"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"c!"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            engine.reset();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"aabc?"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        for input in [
+            "start::=x & #'a' & #'b';x::='a';",
+            "start::='a' & #'a';",
+            "start::=x & 'a';x::='a';",
+            "start::=x & #'a';x::=y & #'b';y::='a';",
+        ] {
+            assert!(
+                kbnf::engine::Engine::new(input, vocab.clone()).is_err(),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn compaction_reverts_rejected_tokens() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let id = |token: &str| get_token_id_from_str(&vocab, token).unwrap();
+        let input = "start::='[' items ']'; items::=#'[a-z]+' | items ',' #'[a-z]+';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"[abc"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        // The multi-byte inputs are rejected after their first bytes are accepted and compacted.
+        let state = format!("{engine:?}");
+        assert_eq!(
+            engine.try_accept_new_bytes(b"d!"),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(format!("{engine:?}"), state);
+        assert_eq!(
+            engine.try_accept_new_token(id("])")),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(format!("{engine:?}"), state);
+        assert_eq!(
+            engine.try_accept_new_token(id("de")),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        assert_eq!(
+            engine.try_accept_new_bytes(b",x]"),
+            Ok(AcceptTokenResult::Finished)
+        );
+    }
+
+    #[test]
+    fn max_output_length() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let id = |token: &str| get_token_id_from_str(&vocab, token).unwrap() as usize;
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = kbnf::config::Config::default();
+            config.regex_config.fsa_type = fsa_type;
+            config.engine_config.max_output_bytes = Some(4);
+            let input = "start::=#'[a-z]+' ';';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"abc"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            engine.compute_allowed_token_ids();
+            let allowed = engine.allowed_token_ids_from_last_computation();
+            assert!(allowed.contains(id(";")));
+            assert!(!allowed.contains(id("d")));
+            assert_eq!(
+                engine.try_accept_new_bytes(b"d"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b";"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            config.engine_config.max_output_bytes = Some(8);
+            let input = "start::=expr ';';expr::='1' | '(' expr ')';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"((("),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"("),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"1)));"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            // A multi-byte input rejected part-way leaves the engine as it was.
+            config.engine_config.max_output_bytes = Some(6);
+            let input = "start::='[' items ']'; items::=#'[a-z]+' | items ',' #'[a-z]+';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"[abc"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            for rejected in [&b"de"[..], b"d!"] {
+                assert_eq!(
+                    engine.try_accept_new_bytes(rejected),
+                    Err(kbnf::engine_like::AcceptTokenError::Rejected)
+                );
+            }
+            assert_eq!(
+                engine.try_accept_new_token(id("de") as u32),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"]"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            // The bytes needed to finish a regex are exact after its first byte as well.
+            config.engine_config.max_output_bytes = Some(4);
+            let input = "start::=#'a[0-9]{3}' ';' | 'b' ';';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            engine.compute_allowed_token_ids();
+            let allowed = engine.allowed_token_ids_from_last_computation();
+            assert!(allowed.contains(id("b")));
+            assert!(!allowed.contains(id("a")));
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a"),
+                Err(kbnf::engine_like::AcceptTokenError::Rejected)
+            );
+            assert_eq!(
+                engine.try_accept_new_bytes(b"b;"),
+                Ok(AcceptTokenResult::Finished)
+            );
+        }
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.max_output_tokens = Some(2);
+        let mut engine =
+            kbnf::engine::Engine::with_config("start::='a'+ ';';", vocab.clone(), config).unwrap();
+        assert_eq!(
+            engine.try_accept_new_token(id("a") as u32),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation();
+        assert!(allowed.contains(id(";")));
+        assert!(!allowed.contains(id("a")));
+        assert_eq!(
+            engine.try_accept_new_token(id("a") as u32),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        assert_eq!(
+            engine.try_accept_new_token(id(";") as u32),
+            Ok(AcceptTokenResult::Finished)
+        );
+        // The same Earley sets reached with a token to spare do not reuse the cached allowed tokens.
+        engine.reset();
+        assert_eq!(
+            engine.try_accept_new_bytes(b"a"),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        engine.compute_allowed_token_ids();
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(id("a")));
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.max_output_tokens = Some(0);
+        let mut engine =
+            kbnf::engine::Engine::with_config("start::='a'+ ';';", vocab.clone(), config).unwrap();
+        assert_eq!(
+            engine.try_compute_allowed_token_ids(),
+            Err(kbnf::engine_like::ComputeAllowedTokenIdsError::OutputLimitReached)
+        );
+        assert_eq!(
+            engine.allowed_token_ids_error_from_last_computation(),
+            Some(kbnf::engine_like::ComputeAllowedTokenIdsError::OutputLimitReached)
+        );
+        // The exhausted budget is told apart from the tokens disallowed by the grammar.
+        assert_eq!(
+            engine.try_accept_new_token(id("a") as u32),
+            Err(kbnf::engine_like::AcceptTokenError::OutputLimitReached)
+        );
+        let mut logits = vec![0.0; vocab.vocab_size()];
+        assert_eq!(
+            engine.update_logits(id("a") as u32, &mut logits),
+            Err(kbnf::engine_like::UpdateLogitsError::OutputLimitReached)
+        );
+        engine.reset();
+        assert_eq!(engine.allowed_token_ids_error_from_last_computation(), None);
+    }
+
+    #[test]
+    fn shortest_completion() {
+        use kbnf::completion::ShortestCompletionError;
+        use kbnf::engine_base::MAX_COMPLETION_SEARCH_STATES;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = kbnf::config::Config::default();
+            config.regex_config.fsa_type = fsa_type;
+            let input = "start::=expr ';';expr::='1' | '(' expr ')';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            engine.try_accept_new_bytes(b"((").unwrap();
+            let completion = engine.shortest_completion().unwrap();
+            assert_eq!(completion.bytes, b"1));");
+            let spelled: Vec<u8> = completion
+                .token_ids
+                .unwrap()
+                .into_iter()
+                .flat_map(|x| vocab.token(x).unwrap().0.to_vec())
+                .collect();
+            assert_eq!(spelled, b"1));");
+            assert_eq!(
+                engine.try_accept_new_bytes(b"1));"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            assert_eq!(engine.shortest_completion().unwrap().bytes, b"");
+            let input = "start::=#'[a-z]{3}[0-9]+' ';';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            engine.try_accept_new_bytes(b"x").unwrap();
+            assert_eq!(engine.shortest_completion().unwrap().bytes, b"aa0;");
+            assert_eq!(
+                engine.try_accept_new_bytes(b"yz1;"),
+                Ok(AcceptTokenResult::Finished)
+            );
+            let input = "start::=x & #'ab' ';';x::='a' 'c';";
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config.clone()).unwrap();
+            assert_eq!(
+                engine.try_accept_new_bytes(b"a"),
+                Ok(AcceptTokenResult::Ongoing)
+            );
+            assert_eq!(
+                engine.shortest_completion(),
+                Err(ShortestCompletionError::Unfinishable)
+            );
+        }
+        let input = format!(
+            "start::='{}';",
+            "a".repeat(MAX_COMPLETION_SEARCH_STATES + 1)
+        );
+        let engine = kbnf::engine::Engine::new(&input, vocab).unwrap();
+        assert_eq!(
+            engine.shortest_completion(),
+            Err(ShortestCompletionError::SearchLimitExceeded(
+                MAX_COMPLETION_SEARCH_STATES
+            ))
+        );
+    }
+
+    #[test]
+    fn numeric_ranges() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let cases: [(&str, &[&str], &[&str]); 4] = [
+            (
+                "#int[0,65535]",
+                &["0", "7", "65535", "1000"],
+                &["65536", "01", "-1", "1.5"],
+            ),
+            (
+                "#int[-20,-5]",
+                &["-5", "-20", "-13"],
+                &["-4", "-21", "-0", "5"],
+            ),
+            (
+                "#float[-1,1]",
+                &["-1", "-0.5", "0", "1.000", "0.999"],
+                &["1.01", "-1.5", "-0", "-0.0", ".5", "1."],
+            ),
+            (
+                "#float[0.25,3.5]",
+                &["0.25", "0.250", "3.5", "1", "3.49"],
+                &["0.2", "3.51", "4", "0.249"],
+            ),
+        ];
+        for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+            let mut config = kbnf::config::Config::default();
+            config.regex_config.fsa_type = fsa_type;
+            for (range, accepted, rejected) in cases {
+                let input = format!("start::={range} ';';");
+                let mut engine =
+                    kbnf::engine::Engine::with_config(&input, vocab.clone(), config.clone())
+                        .unwrap();
+                for number in accepted {
+                    engine.reset();
+                    assert_eq!(
+                        engine.try_accept_new_bytes(format!("{number};").as_bytes()),
+                        Ok(AcceptTokenResult::Finished),
+                        "{range} should accept {number}"
+                    );
+                }
+                for number in rejected {
+                    engine.reset();
+                    assert_eq!(
+                        engine.try_accept_new_bytes(format!("{number};").as_bytes()),
+                        Err(kbnf::engine_like::AcceptTokenError::Rejected),
+                        "{range} should reject {number}"
+                    );
+                }
+            }
+        }
+        for input in [
+            "start::=#int[5,1];",
+            "start::=#int[1.5,2];",
+            "start::=#float[a,1];",
+        ] {
+            assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+        }
+    }
+    #[test]
+    fn allowed_bitmask() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let input = "start::=#\"[0-9]+\" ';';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let allowed = engine.allowed_token_ids_from_last_computation().clone();
+        let vocab_size = vocab.vocab_size();
+        let mut words = vec![u32::MAX; vocab_size.div_ceil(32)];
+        engine.write_allowed_bitmask(&mut words).unwrap();
+        let mut wide_words = vec![u64::MAX; vocab_size.div_ceil(64)];
+        engine.write_allowed_bitmask_u64(&mut wide_words).unwrap();
+        for token_id in 0..vocab_size {
+            let expected = allowed.contains(token_id);
+            assert_eq!(words[token_id / 32] >> (token_id % 32) & 1 == 1, expected);
+            assert_eq!(
+                wide_words[token_id / 64] >> (token_id % 64) & 1 == 1,
+                expected
+            );
+        }
+        assert_eq!(
+            words.iter().map(|x| x.count_ones()).sum::<u32>() as usize,
+            allowed.count_ones(..)
+        );
+        assert_eq!(
+            wide_words.iter().map(|x| x.count_ones()).sum::<u32>() as usize,
+            allowed.count_ones(..)
+        );
+        let mut short_words = vec![0u32; vocab_size.div_ceil(32) - 1];
+        assert_eq!(
+            engine.write_allowed_bitmask(&mut short_words),
+            Err(kbnf::engine_like::WriteBufferError::BufferTooSmall)
+        );
+    }
+    #[test]
+    fn mask_logits_half_and_2d() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        // The first grammar allows few tokens and the second allows most of them.
+        for input in ["start::=#\"[0-9]+\" ';';", "start::=#\"[^;]+\" ';';"] {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            engine.compute_allowed_token_ids();
+            let mut expected: Vec<f32> = (0..vocab_size).map(|x| (x % 7) as f32).collect();
+            engine.mask_logits(&mut expected).unwrap();
+            let mut logits: Vec<half::f16> = (0..vocab_size)
+                .map(|x| half::f16::from_f32((x % 7) as f32))
+                .collect();
+            engine.mask_logits_f16(&mut logits).unwrap();
+            assert!(logits
+                .iter()
+                .map(|x| x.to_f32())
+                .eq(expected.iter().copied()));
+            let mut logits: Vec<half::bf16> = (0..vocab_size)
+                .map(|x| half::bf16::from_f32((x % 7) as f32))
+                .collect();
+            engine.mask_logits_bf16(&mut logits).unwrap();
+            assert!(logits
+                .iter()
+                .map(|x| x.to_f32())
+                .eq(expected.iter().copied()));
+            let row_stride = vocab_size + 3;
+            let rows = 3;
+            let mut logits: Vec<f32> = (0..rows * row_stride)
+                .map(|x| (x % row_stride % 7) as f32)
+                .collect();
+            engine.mask_logits_2d(&mut logits, row_stride).unwrap();
+            for row in logits.chunks_exact(row_stride) {
+                assert_eq!(&row[..vocab_size], expected.as_slice());
+                assert!(row[vocab_size..].iter().all(|x| *x == f32::NEG_INFINITY));
+            }
+            let mut logits: Vec<half::bf16> = (0..rows * row_stride)
+                .map(|x| half::bf16::from_f32((x % row_stride % 7) as f32))
+                .collect();
+            engine.mask_logits_2d_bf16(&mut logits, row_stride).unwrap();
+            for row in logits.chunks_exact(row_stride) {
+                assert!(row[..vocab_size]
+                    .iter()
+                    .map(|x| x.to_f32())
+                    .eq(expected.iter().copied()));
+            }
+            let mut logits = vec![half::f16::ZERO; rows * row_stride - 1];
+            assert_eq!(
+                engine.mask_logits_2d_f16(&mut logits, row_stride),
+                Err(kbnf::engine_like::MaskLogitsError::InvalidLogitsLength)
+            );
+            assert!(logits.iter().all(|x| *x == half::f16::ZERO));
+            let mut logits = vec![0.0; vocab_size - 1];
+            assert_eq!(
+                engine.mask_logits_2d(&mut logits, vocab_size - 1),
+                Err(kbnf::engine_like::MaskLogitsError::InvalidLogitsLength)
+            );
+        }
+    }
+    #[test]
+    fn soft_constraints() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        let input =
+            "start::=obj ';';obj::='{' value '}' | 'x';value::=\"null\" | \"true\" | num | obj;\
+            num::=#\"[0-9]+\";bonus \"null\" = 2.5; bonus num = -1; bonus obj = 0.5;";
+        let token_id = |token: &str| {
+            vocab
+                .token_id(&kbnf::Token(token.as_bytes().into()))
+                .unwrap() as usize
+        };
+        for penalty in [None, Some(-3.0)] {
+            let mut config = kbnf::config::Config::default();
+            config.engine_config.disallowed_token_penalty = penalty.map(kbnf::engine::Penalty::new);
+            let mut engine =
+                kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+            for prefix in ["{", "{{"] {
+                engine.reset();
+                engine.try_accept_new_bytes(prefix.as_bytes()).unwrap();
+                engine.compute_allowed_token_ids();
+                let mut logits = vec![0.0f32; vocab_size];
+                engine.mask_logits(&mut logits).unwrap();
+                // The innermost nonterminal with a bonus decides the bonus of a token,
+                // and only the nonterminals started by the token count.
+                assert_eq!(logits[token_id("null")], 2.5);
+                assert_eq!(logits[token_id("1")], -1.0);
+                assert_eq!(logits[token_id("true")], 0.0);
+                assert_eq!(logits[token_id("{")], 0.5);
+                assert_eq!(logits[token_id(";")], penalty.unwrap_or(f32::NEG_INFINITY));
+            }
+            engine.reset();
+            engine.try_accept_new_bytes(b"{{12}").unwrap();
+            engine.compute_allowed_token_ids();
+            let mut logits = vec![0.0f32; vocab_size];
+            engine.mask_logits(&mut logits).unwrap();
+            assert_eq!(logits[token_id("}")], 0.0);
+            assert_eq!(logits[token_id("1")], penalty.unwrap_or(f32::NEG_INFINITY));
+            // The tokens continuing a nonterminal with a bonus receive none.
+            engine.reset();
+            engine.try_accept_new_bytes(b"{nu").unwrap();
+            engine.compute_allowed_token_ids();
+            let mut logits = vec![0.0f32; vocab_size];
+            engine.mask_logits(&mut logits).unwrap();
+            assert_eq!(logits[token_id("ll")], 0.0);
+            assert_eq!(logits[token_id("l")], 0.0);
+            engine.reset();
+            engine.try_accept_new_bytes(b"{1").unwrap();
+            engine.compute_allowed_token_ids();
+            let mut logits = vec![0.0f32; vocab_size];
+            engine.mask_logits(&mut logits).unwrap();
+            assert_eq!(logits[token_id("2")], 0.0);
+        }
+        for input in [
+            "start::='a';bonus b = 1;",
+            "start::='a';bonus start = 1;bonus start = 2;",
+            "start::='a';bonus start = x;",
+            "start::='a';bonus 'b' = inf;",
+        ] {
+            assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+        }
+        // A penalized token can still be chosen, so the caller handles its rejection, e.g. by choosing again without it.
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.disallowed_token_penalty = Some(kbnf::engine::Penalty::new(-3.0));
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        engine.compute_allowed_token_ids();
+        let mut logits = vec![0.0f32; vocab_size];
+        logits[token_id(";")] = 10.0;
+        engine.mask_logits(&mut logits).unwrap();
+        let argmax = |logits: &[f32]| {
+            (0..logits.len())
+                .max_by(|&a, &b| logits[a].total_cmp(&logits[b]))
+                .unwrap() as u32
+        };
+        let chosen = argmax(&logits);
+        assert_eq!(chosen as usize, token_id(";"));
+        assert_eq!(
+            engine.try_accept_new_token(chosen),
+            Err(kbnf::engine_like::AcceptTokenError::Rejected)
+        );
+        logits[chosen as usize] = f32::NEG_INFINITY;
+        let chosen = argmax(&logits);
+        assert!(engine
+            .allowed_token_ids_from_last_computation()
+            .contains(chosen as usize));
+        assert_eq!(
+            engine.try_accept_new_token(chosen),
+            Ok(AcceptTokenResult::Ongoing)
+        );
+        // The configurations stay hashable, comparing the penalties by their bits.
+        let configs: ahash::AHashSet<_> = [None, Some(-3.0), Some(-3.0), Some(0.0), Some(-0.0)]
+            .into_iter()
+            .map(|penalty| {
+                let mut config = kbnf::config::Config::default();
+                config.engine_config.disallowed_token_penalty =
+                    penalty.map(kbnf::engine::Penalty::new);
+                config
+            })
+            .collect();
+        assert_eq!(configs.len(), 4);
+        // Nonterminals differing only in their first or last characters are different targets.
+        let input = "start::=ab | ac;ab::='x';ac::='y';bonus ab = 1;bonus ac = 2;";
+        assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_ok());
+        // A nonterminal matching only the empty string never receives its bonus, nor is warned about.
+        let input = "start::=x | 'b';x::='';bonus x = 2;";
+        let mut engine =
+            kbnf::engine::Engine::with_config(input, vocab.clone(), lint_config()).unwrap();
+        assert!(engine.lint_warnings().is_empty());
+        engine.compute_allowed_token_ids();
+        let mut logits = vec![0.0f32; vocab_size];
+        engine.mask_logits(&mut logits).unwrap();
+        assert!(logits.iter().all(|&x| x == 0.0 || x == f32::NEG_INFINITY));
+    }
+
+    #[test]
+    fn weighted_alternatives() {
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = |token: &str| {
+            vocab
+                .token_id(&kbnf::Token(token.as_bytes().into()))
+                .unwrap() as usize
+        };
+        let input = "start::=value ';';value::=\"null\" @0.8 | obj @0.2;obj::='{' value '}';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        for prefix in ["", "{{"] {
+            engine.reset();
+            engine.try_accept_new_bytes(prefix.as_bytes()).unwrap();
+            engine.compute_allowed_token_ids();
+            let mut logits = vec![0.0f32; vocab.vocab_size()];
+            engine.mask_logits(&mut logits).unwrap();
+            assert_eq!(logits[token_id("null")], 0.8f32.ln());
+            assert_eq!(logits[token_id("{")], 0.2f32.ln());
+            assert_eq!(logits[token_id("}")], f32::NEG_INFINITY);
+        }
+        // The tokens continuing a weighted alternative receive no bonus.
+        engine.reset();
+        engine.try_accept_new_bytes(b"nu").unwrap();
+        engine.compute_allowed_token_ids();
+        let mut logits = vec![0.0f32; vocab.vocab_size()];
+        engine.mask_logits(&mut logits).unwrap();
+        assert_eq!(logits[token_id("ll")], 0.0);
+        assert_eq!(logits[token_id("l")], 0.0);
+        // The innermost weight decides the offset, and an unweighted alternative has weight 1.
+        let input = "start::=(\"a\"+ @2 | 'b') @3 | #\"[0-9]\"{2} @1 | 'c';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        engine.compute_allowed_token_ids();
+        let mut logits = vec![0.0f32; vocab.vocab_size()];
+        engine.mask_logits(&mut logits).unwrap();
+        assert_eq!(logits[token_id("aa")], 2f32.ln());
+        assert_eq!(logits[token_id("b")], 3f32.ln());
+        assert_eq!(logits[token_id("1")], 0.0);
+        assert_eq!(logits[token_id("c")], 0.0);
+        // The alternatives sharing the first bytes of a token are told apart by its later bytes,
+        // and a token that is a prefix of several alternatives receives the largest bonus among them.
+        for input in [
+            "start::=\"null\" @0.8 | \"nan\" @0.2;",
+            "start::=#\"null\" @0.8 | #\"nan\" @0.2;",
+        ] {
+            for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+                let mut config = kbnf::config::Config::default();
+                config.regex_config.fsa_type = fsa_type;
+                let mut engine =
+                    kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+                engine.compute_allowed_token_ids();
+                let mut logits = vec![0.0f32; vocab.vocab_size()];
+                engine.mask_logits(&mut logits).unwrap();
+                assert_eq!(logits[token_id("null")], 0.8f32.ln(), "{input}");
+                assert_eq!(logits[token_id("nan")], 0.2f32.ln(), "{input}");
+                assert_eq!(logits[token_id("n")], 0.8f32.ln(), "{input}");
+            }
+        }
+        // A token only receives the bonus of an alternative that accepts it,
+        // including the bytes after the alternative is completed.
+        for input in [
+            "start::=(\"a\" @2 | \"ab\" @0.5) 'c';",
+            "start::=(#substrs\"ac\" @2 | \"ab\" @0.5) 'c';",
+        ] {
+            for fsa_type in [kbnf::config::Fsa::Dfa, kbnf::config::Fsa::LazyDfa] {
+                let mut config = kbnf::config::Config::default();
+                config.regex_config.fsa_type = fsa_type;
+                let mut engine =
+                    kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+                engine.compute_allowed_token_ids();
+                let mut logits = vec![0.0f32; vocab.vocab_size()];
+                engine.mask_logits(&mut logits).unwrap();
+                assert_eq!(logits[token_id("ab")], 0.5f32.ln(), "{input}");
+                assert_eq!(logits[token_id("ac")], 2f32.ln(), "{input}");
+                assert_eq!(logits[token_id("a")], 2f32.ln(), "{input}");
+            }
+        }
+        // The weights are stored by the nonterminals of their alternatives, which only match the alternatives.
+        let config = kbnf::config::Config::default();
+        let (grammar, metadata) = kbnf::utils::construct_kbnf_syntax_grammar_with_metadata(
+            "start::=\"null\" @0.8 | \"nan\" @0.2 | 'n';",
+            config.clone().internal_config(),
+        )
+        .unwrap();
+        let mut bonuses: Vec<_> = metadata.nonterminal_bonuses.clone().into_iter().collect();
+        bonuses.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            bonuses,
+            vec![
+                ("__kbnf_weighted_0".to_string(), 0.8f32.ln()),
+                ("__kbnf_weighted_1".to_string(), 0.2f32.ln())
+            ]
+        );
+        let output = kbnf::grammar::Grammar::<u16>::new_with_metadata(
+            grammar,
+            metadata,
+            &vocab,
+            config.regex_config,
+        )
+        .unwrap()
+        .to_kbnf_string();
+        assert!(
+            output.contains("\n__kbnf_weighted_0 ::= \"null\";\n"),
+            "{output}"
+        );
+        assert!(
+            output.contains("\n__kbnf_weighted_1 ::= \"nan\";\n"),
+            "{output}"
+        );
+        for input in [
+            "start::='a' @0 | 'b';",
+            "start::='a' @-1 | 'b';",
+            "start::='a' @x;",
+            "start::='a' @2 'c' | 'b';",
+            "start::=@2 'a';",
+        ] {
+            assert!(kbnf::engine::Engine::new(input, vocab.clone()).is_err());
+        }
+    }
+    #[test]
+    #[cfg(feature = "sampling")]
+    fn sampling() {
+        use kbnf::sampling::{SampleError, Sampler, SamplerConfig};
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        let token_id = |token: &str| {
+            vocab
+                .token_id(&kbnf::Token(token.as_bytes().into()))
+                .unwrap()
+        };
+        let input = "start::=#\"[0-9]{1,3}\" ';';";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let mut logits = vec![0.0f32; vocab_size + 5];
+        logits[token_id("a") as usize] = 10.0;
+        logits[token_id("7") as usize] = 2.0;
+        logits[token_id("8") as usize] = 1.0;
+        logits[token_id(";") as usize] = 5.0;
+        let greedy = SamplerConfig {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        let mut sampler = Sampler::new(greedy.clone());
+        assert_eq!(
+            sampler.sample(&mut engine, &logits),
+            Ok((token_id("7"), kbnf::AcceptTokenResult::Ongoing))
+        );
+        assert_eq!(
+            sampler.sample(&mut engine, &logits),
+            Ok((token_id(";"), kbnf::AcceptTokenResult::Finished))
+        );
+        assert_eq!(
+            sampler.sample(&mut engine, &logits),
+            Err(SampleError::Finished)
+        );
+        // The top-1 sampling is the same as the greedy decoding.
+        engine.reset();
+        let mut sampler = Sampler::new(SamplerConfig {
+            top_k: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(
+            sampler.sample(&mut engine, &logits).unwrap().0,
+            token_id("7")
+        );
+        // The same seed samples the same tokens, and every sampled token is allowed.
+        let sample_all = |seed: u64| {
+            let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+            let mut sampler = Sampler::new(SamplerConfig {
+                seed,
+                top_p: 0.9,
+                ..Default::default()
+            });
+            let mut token_ids = Vec::new();
+            loop {
+                let (token_id, result) = sampler.sample(&mut engine, &logits).unwrap();
+                token_ids.push(token_id);
+                if result == kbnf::AcceptTokenResult::Finished {
+                    return token_ids;
+                }
+            }
+        };
+        for seed in 0..8 {
+            assert_eq!(sample_all(seed), sample_all(seed));
+        }
+        // The bonuses are added to the logits.
+        let input = "start::=\"1\" | \"2\" @3;";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let mut sampler = Sampler::new(greedy);
+        let logits = vec![0.0f32; vocab_size];
+        assert_eq!(
+            sampler.sample(&mut engine, &logits).unwrap().0,
+            token_id("2")
+        );
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            sampler.sample(&mut engine, &logits[..vocab_size - 1]),
+            Err(SampleError::InvalidLogitsLength(vocab_size - 1, vocab_size))
+        );
+        let mut logits = vec![f32::NEG_INFINITY; vocab_size];
+        assert_eq!(
+            sampler.sample(&mut engine, &logits),
+            Err(SampleError::NoCandidate)
+        );
+        logits[token_id("1") as usize] = 0.0;
+        for config in [
+            SamplerConfig {
+                temperature: -1.0,
+                ..Default::default()
+            },
+            SamplerConfig {
+                top_k: Some(0),
+                ..Default::default()
+            },
+            SamplerConfig {
+                top_p: 0.0,
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                Sampler::new(config).sample(&mut engine, &logits),
+                Err(SampleError::InvalidConfig(_))
+            ));
+        }
+        assert!(!engine.is_finished());
+        let mut config = kbnf::config::Config::default();
+        config.engine_config.max_output_tokens = Some(0);
+        let mut engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        assert_eq!(
+            Sampler::new(SamplerConfig::default()).sample(&mut engine, &logits),
+            Err(SampleError::OutputLimitReached)
+        );
+    }
+    #[test]
+    fn beam_search() {
+        use kbnf::beam_search::{BeamSearch, BeamSearchConfig, BeamSearchError};
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let vocab_size = vocab.vocab_size();
+        let token_id = |token: &str| {
+            vocab
+                .token_id(&kbnf::Token(token.as_bytes().into()))
+                .unwrap()
+        };
+        let input = "start::=\"positive\" | \"negative\" | \"neutral\";";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let score = |token_ids: &[u32]| {
+            let mut logits = vec![0.0f32; vocab_size];
+            if token_ids.is_empty() {
+                logits[token_id("neg") as usize] = 6.0;
+                logits[token_id("positive") as usize] = 4.0;
+                logits[token_id("neutral") as usize] = 3.0;
+            } else if token_ids == [token_id("neg")] {
+                logits[token_id("ative") as usize] = 5.0;
+            }
+            logits
+        };
+        let config = BeamSearchConfig {
+            beam_width: 4,
+            n_best: 3,
+            ..Default::default()
+        };
+        let hypotheses = BeamSearch::new(config.clone())
+            .search(&engine, score)
+            .unwrap();
+        assert_eq!(
+            hypotheses
+                .iter()
+                .map(|x| x.token_ids.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![token_id("positive")],
+                vec![token_id("neutral")],
+                vec![token_id("neg"), token_id("ative")],
+            ]
+        );
+        let normalizer = score(&[])
+            .iter()
+            .map(|x| (*x as f64).exp())
+            .sum::<f64>()
+            .ln();
+        assert!((hypotheses[0].log_probability as f64 - (4.0 - normalizer)).abs() < 1e-4);
+        assert!(hypotheses.windows(2).all(|x| x[0].score >= x[1].score));
+        // The length penalty favors the longer hypothesis.
+        let hypotheses = BeamSearch::new(BeamSearchConfig {
+            length_penalty: 1.0,
+            ..config.clone()
+        })
+        .search(&engine, score)
+        .unwrap();
+        assert_eq!(
+            hypotheses[0].token_ids,
+            vec![token_id("neg"), token_id("ative")]
+        );
+        assert_eq!(hypotheses[0].score, hypotheses[0].log_probability / 2.0);
+        // A hypothesis worse than a finished one may overtake it later under a positive length penalty.
+        let late_score = |token_ids: &[u32]| {
+            let mut logits = score(token_ids);
+            if token_ids.is_empty() {
+                logits[token_id("neg") as usize] = 3.5;
+            }
+            logits
+        };
+        let hypotheses = BeamSearch::new(BeamSearchConfig {
+            n_best: 1,
+            length_penalty: 1.0,
+            ..config.clone()
+        })
+        .search(&engine, late_score)
+        .unwrap();
+        assert_eq!(
+            hypotheses[0].token_ids,
+            vec![token_id("neg"), token_id("ative")]
+        );
+        // The engine is not modified by the search.
+        assert!(!engine.is_finished());
+        let mut finished = engine.clone();
+        finished.try_accept_new_bytes(b"neutral").unwrap();
+        let hypotheses = BeamSearch::new(config.clone())
+            .search(&finished, score)
+            .unwrap();
+        assert_eq!(hypotheses.len(), 1);
+        assert!(hypotheses[0].token_ids.is_empty());
+        // The hypotheses that do not finish in time are discarded.
+        let input = "start::=\"positive\" ';';";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let hypotheses = BeamSearch::new(BeamSearchConfig {
+            max_tokens: 1,
+            ..config.clone()
+        })
+        .search(&engine, score)
+        .unwrap();
+        assert!(hypotheses.is_empty());
+        assert_eq!(
+            BeamSearch::new(config.clone()).search(&engine, |_| vec![0.0; vocab_size - 1]),
+            Err(BeamSearchError::InvalidLogitsLength(
+                vec![],
+                vocab_size - 1,
+                vocab_size
+            ))
+        );
+        assert!(matches!(
+            BeamSearch::new(BeamSearchConfig {
+                beam_width: 0,
+                ..config
+            })
+            .search(&engine, score),
+            Err(BeamSearchError::InvalidConfig(_))
+        ));
+    }
+    #[test]
+    fn enumerate_completions() {
+        use kbnf::completion::EnumerateCompletionsError;
+        let vocab = read_rwkv_world_vocab("tests/rwkv_vocab_v20230424.json").unwrap();
+        let token_id = |token: &str| {
+            vocab
+                .token_id(&kbnf::Token(token.as_bytes().into()))
+                .unwrap()
+        };
+        let decode = |token_ids: &[u32]| {
+            token_ids
+                .iter()
+                .flat_map(|x| vocab.token(*x).unwrap().0.iter().copied())
+                .collect::<Vec<u8>>()
+        };
+        let input = "start::=\"positive\" | \"negative\" | \"neutral\";";
+        let mut engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let completions = engine.enumerate_completions(100000, 16).unwrap();
+        assert!(completions.windows(2).all(|x| x[0] < x[1]));
+        assert!(completions.contains(&vec![token_id("positive")]));
+        assert!(completions.contains(&vec![token_id("neg"), token_id("ative")]));
+        let labels: std::collections::BTreeSet<Vec<u8>> =
+            completions.iter().map(|x| decode(x)).collect();
+        assert_eq!(
+            labels,
+            ["positive", "negative", "neutral"]
+                .iter()
+                .map(|x| x.as_bytes().to_vec())
+                .collect()
+        );
+        assert_eq!(
+            engine.enumerate_completions(1, 16),
+            Err(EnumerateCompletionsError::TooManyCompletions(1))
+        );
+        // The depth is capped without detecting cycles, so a finite language with longer completions exceeds it too.
+        assert_eq!(
+            engine.enumerate_completions(100000, 1),
+            Err(EnumerateCompletionsError::DepthLimitExceeded(1))
+        );
+        assert!(!engine.is_finished());
+        engine.try_accept_new_token(token_id("neg")).unwrap();
+        let completions = engine.enumerate_completions(100000, 16).unwrap();
+        assert!(completions.contains(&vec![token_id("ative")]));
+        assert!(completions.iter().all(|x| decode(x) == b"ative"));
+        engine.try_accept_new_token(token_id("ative")).unwrap();
+        assert_eq!(engine.enumerate_completions(100000, 16), Ok(vec![vec![]]));
+        // The engine finishes at the end of the first token that finishes the grammar, so this language is finite.
+        let input = "start::=#\"a+\";";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        let completions = engine.enumerate_completions(100000, 16).unwrap();
+        assert!(completions.contains(&vec![token_id("a")]));
+        assert!(completions
+            .iter()
+            .all(|x| x.len() == 1 && decode(x).iter().all(|x| *x == b'a')));
+        let input = "start::=#\"a+\" ';';";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Err(EnumerateCompletionsError::InfiniteLanguage)
+        );
+        let input = "start::=A ';'; A::=A 'b' | 'b';";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Err(EnumerateCompletionsError::InfiniteLanguage)
+        );
+        // The Earley sets only grow without compaction, so the same state is never detected.
+        let config = kbnf::config::Config {
+            engine_config: EngineConfig::new(true, false),
+            ..Default::default()
+        };
+        let engine = kbnf::engine::Engine::with_config(input, vocab.clone(), config).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Err(EnumerateCompletionsError::DepthLimitExceeded(16))
+        );
+        // Recursion in the middle of a production never returns to the same state.
+        let input = "start::='(' start ')' | 'x';";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Err(EnumerateCompletionsError::DepthLimitExceeded(16))
+        );
+        // A repeated state that cannot finish is skipped.
+        let input = "start::='a' | 'b' #'c+' A; A::=A 'x';";
+        let engine = kbnf::engine::Engine::new(input, vocab.clone()).unwrap();
+        assert_eq!(
+            engine.enumerate_completions(100000, 16),
+            Ok(vec![vec![token_id("a")]])
+        );
+    }
 }